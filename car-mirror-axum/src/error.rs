@@ -21,13 +21,62 @@ impl Display for AppError {
 }
 
 impl AppError {
-    /// Construct a new error from a status code and an error message
+    /// Construct a new error from a status code and an error message.
+    ///
+    /// To reuse the status codes that this crate picks for car mirror errors,
+    /// see `status_for` and `status_for_block_store_error`.
     pub fn new(status_code: StatusCode, msg: impl ToString) -> Self {
         Self {
             status_code,
             error_msg: msg.to_string(),
         }
     }
+
+    /// The status code this error responds with
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    /// The error message this error responds with
+    pub fn message(&self) -> &str {
+        &self.error_msg
+    }
+}
+
+/// The status code that a car mirror error maps to when converted into an `AppError`.
+pub fn status_for(err: &car_mirror::Error) -> StatusCode {
+    use car_mirror::Error;
+    match err {
+        Error::TooManyBytes { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::BlockSizeExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::UnsupportedCodec { .. } => StatusCode::BAD_REQUEST,
+        Error::UnsupportedHashCode { .. } => StatusCode::BAD_REQUEST,
+        Error::IncompatibleBlooms { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        Error::StreamIdleTimeout { .. } => StatusCode::REQUEST_TIMEOUT,
+        Error::MissingEndOfRound => StatusCode::BAD_REQUEST,
+        Error::InvalidCheckpoint { .. } => StatusCode::BAD_REQUEST,
+        Error::UnrelatedCarRoots { .. } => StatusCode::BAD_REQUEST,
+        Error::MismatchedCarRoots { .. } => StatusCode::BAD_REQUEST,
+        Error::BloomTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::TooManyRoots { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::InvalidBloom { .. } => StatusCode::BAD_REQUEST,
+        Error::FrontierTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::BlockStoreError(err) => status_for_block_store_error(err),
+        Error::ParsingError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        Error::IncrementalVerificationError(_) => StatusCode::BAD_REQUEST,
+        Error::CarFileError(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// The status code that a blockstore error maps to when converted into an `AppError`.
+pub fn status_for_block_store_error(err: &wnfs_common::BlockStoreError) -> StatusCode {
+    use wnfs_common::BlockStoreError;
+    match err {
+        BlockStoreError::MaximumBlockSizeExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        BlockStoreError::CIDNotFound(_) => StatusCode::NOT_FOUND,
+        BlockStoreError::CIDError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        BlockStoreError::Custom(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
 /// Helper type alias that defaults the error type to `AppError`
@@ -62,16 +111,9 @@ impl From<car_mirror::Error> for AppError {
 
 impl From<&car_mirror::Error> for AppError {
     fn from(err: &car_mirror::Error) -> Self {
-        use car_mirror::Error;
         match err {
-            Error::TooManyBytes { .. } => Self::new(StatusCode::PAYLOAD_TOO_LARGE, err),
-            Error::BlockSizeExceeded { .. } => Self::new(StatusCode::PAYLOAD_TOO_LARGE, err),
-            Error::UnsupportedCodec { .. } => Self::new(StatusCode::BAD_REQUEST, err),
-            Error::UnsupportedHashCode { .. } => Self::new(StatusCode::BAD_REQUEST, err),
-            Error::BlockStoreError(err) => Self::from(err),
-            Error::ParsingError(_) => Self::new(StatusCode::UNPROCESSABLE_ENTITY, err),
-            Error::IncrementalVerificationError(_) => Self::new(StatusCode::BAD_REQUEST, err),
-            Error::CarFileError(_) => Self::new(StatusCode::BAD_REQUEST, err),
+            car_mirror::Error::BlockStoreError(err) => Self::from(err),
+            _ => Self::new(status_for(err), err),
         }
     }
 }
@@ -84,15 +126,7 @@ impl From<wnfs_common::BlockStoreError> for AppError {
 
 impl From<&wnfs_common::BlockStoreError> for AppError {
     fn from(err: &wnfs_common::BlockStoreError) -> Self {
-        use wnfs_common::BlockStoreError;
-        match err {
-            BlockStoreError::MaximumBlockSizeExceeded(_) => {
-                Self::new(StatusCode::PAYLOAD_TOO_LARGE, err)
-            }
-            BlockStoreError::CIDNotFound(_) => Self::new(StatusCode::NOT_FOUND, err),
-            BlockStoreError::CIDError(_) => Self::new(StatusCode::INTERNAL_SERVER_ERROR, err),
-            BlockStoreError::Custom(_) => Self::new(StatusCode::INTERNAL_SERVER_ERROR, err),
-        }
+        Self::new(status_for_block_store_error(err), err)
     }
 }
 
@@ -123,3 +157,24 @@ impl From<std::io::Error> for AppError {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, err)
     }
 }
+
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::from(&err)
+    }
+}
+
+/// Errors from requests to other servers, e.g. when proxying car mirror
+/// requests, respond with `504 Gateway Timeout` on timeouts and
+/// `502 Bad Gateway` otherwise.
+#[cfg(feature = "reqwest")]
+impl From<&reqwest::Error> for AppError {
+    fn from(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::new(StatusCode::GATEWAY_TIMEOUT, err)
+        } else {
+            Self::new(StatusCode::BAD_GATEWAY, err)
+        }
+    }
+}
@@ -42,6 +42,12 @@ pub enum DagCborRejection {
     /// When dag-cbor deserialization into the target type fails
     #[error("Failed decoding dag-cbor: {0}")]
     FailedDecoding(#[from] DecodeError<Infallible>),
+
+    /// When json deserialization into the target type fails
+    #[cfg(feature = "json-debug")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "json-debug")))]
+    #[error("Failed decoding json: {0}")]
+    FailedDecodingJson(#[from] serde_json::Error),
 }
 
 impl IntoResponse for DagCborRejection {
@@ -53,6 +59,8 @@ impl IntoResponse for DagCborRejection {
                 Self::FailedToParseMime => StatusCode::BAD_REQUEST,
                 Self::FailedParsingRequestBytes => StatusCode::PAYLOAD_TOO_LARGE,
                 Self::FailedDecoding(_) => StatusCode::BAD_REQUEST,
+                #[cfg(feature = "json-debug")]
+                Self::FailedDecodingJson(_) => StatusCode::BAD_REQUEST,
             },
             self.to_string(),
         )
@@ -96,6 +104,13 @@ where
             .to_str()?
             .parse::<mime::Mime>()?;
 
+        // For debugging, e.g. with curl, also accept json
+        #[cfg(feature = "json-debug")]
+        if mime.essence_str() == mime::APPLICATION_JSON.essence_str() {
+            let bytes = Bytes::from_request(req, state).await?;
+            return Ok(DagCbor(serde_json::from_slice(bytes.as_ref())?));
+        }
+
         if mime.essence_str() != "application/vnd.ipld.dag-cbor" {
             return Err(DagCborRejection::UnexpectedContentType(mime));
         }
@@ -15,6 +15,13 @@
 //! At the moment, it's recommended to only make use of the `extract` module, and mostly
 //! use the rest of the library for tests or treat the rest of the code as an example
 //! to copy code from for actual production use.
+//!
+//! For debugging, e.g. using curl, enable the `json-debug` feature. This makes the server
+//! accept pull requests with `Content-Type: application/json` and answer push requests
+//! with json when they're sent with `Accept: application/json`.
+//!
+//! For servers that make requests to other servers, e.g. to proxy car mirror requests,
+//! enable the `reqwest` feature. It adds a conversion from `reqwest::Error` into `AppError`.
 
 mod error;
 pub mod extract;
@@ -1,20 +1,37 @@
-use crate::{extract::dag_cbor::DagCbor, AppResult};
+use crate::{extract::dag_cbor::DagCbor, AppError, AppResult};
 use anyhow::Result;
 use axum::{
     body::{Body, HttpBody},
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{get, post},
+    extract::{
+        multipart::{Field, MultipartError},
+        DefaultBodyLimit, FromRequest, Multipart, Path, Request, State,
+    },
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{get, head, post},
     Router,
 };
+use bytes::Bytes;
 use car_mirror::{
     cache::InMemoryCache,
+    checkpoint::{self, CheckpointKey, CHECKPOINT_HEADER},
     common::Config,
-    messages::{PullRequest, PushResponse},
+    messages::{CidEncoding, PullRequest, PushResponse},
 };
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
 use libipld::Cid;
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_util::io::StreamReader;
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -69,24 +86,80 @@ pub fn app(store: impl BlockStore + Clone + 'static) -> Router {
 /// This serves following routes:
 /// - `GET /pull/:cid` for pull requests (GET is generally not recommended here)
 /// - `POST /pull/:cid` for pull requests
-/// - `POST /push/:cid` for push requests
+/// - `HEAD /pull/:cid` for checking whether the DAG is available, see `car_mirror_pull_head`
+/// - `POST /push/:cid` for push requests, with a CAR file or `multipart/form-data` body
+///
+/// Pull request bodies are subject to axum's default body limit. Multipart push
+/// bodies are limited to the `Config::receive_maximum` of the server state's
+/// config, see `ServerState::with_config`, plus `MULTIPART_FRAMING_ALLOWANCE`.
+/// Plain CAR file push bodies are streamed, so they aren't limited that way.
 pub fn dag_router(store: impl BlockStore + Clone + 'static) -> Router {
+    dag_router_with_state(ServerState::new(store))
+}
+
+/// Like `dag_router`, but with given server state, e.g. to set a push policy
+/// via `ServerState::with_push_policy`.
+pub fn dag_router_with_state(state: ServerState<impl BlockStore + Clone + 'static>) -> Router {
+    let push_body_limit = state.config.receive_maximum + MULTIPART_FRAMING_ALLOWANCE;
     Router::new()
         .route("/pull/:cid", get(car_mirror_pull))
         .route("/pull/:cid", post(car_mirror_pull))
-        .route("/push/:cid", post(car_mirror_push))
-        .with_state(ServerState::new(store))
+        .route("/pull/:cid", head(car_mirror_pull_head))
+        .route(
+            "/push/:cid",
+            post(car_mirror_push).layer(DefaultBodyLimit::max(push_body_limit)),
+        )
+        .with_state(state)
 }
 
+/// How many bytes multipart push bodies may exceed `Config::receive_maximum`
+/// by, for the form's boundaries and field headers. See `dag_router`.
+pub const MULTIPART_FRAMING_ALLOWANCE: usize = 64 * 1024;
+
 /// The server state used for a basic car mirror server.
 ///
-/// Stores a block store and a car mirror operations cache.
-#[derive(Debug, Clone)]
+/// Stores a block store, a car mirror operations cache, a config,
+/// an optional push policy, an optional limit on concurrent transfers,
+/// an optional limit on concurrently generated pull responses,
+/// an optional key for push checkpoints, an optional cache of
+/// pull responses and a cache of the results of `HEAD` requests.
+#[derive(Clone)]
 pub struct ServerState<B: BlockStore + Clone + 'static> {
     store: B,
     cache: InMemoryCache,
+    config: Config,
+    push_policy: Option<PushPolicy>,
+    transfer_permits: Option<Arc<Semaphore>>,
+    pull_permits: Option<PullPermits>,
+    checkpoint_key: Option<CheckpointKey>,
+    pull_response_cache: Option<PullResponseCache>,
+    store_generation: Arc<AtomicU64>,
+    /// Whether the DAG below a root is complete, and the store generation
+    /// that this was computed for, see `car_mirror_pull_head`
+    head_results: Arc<quick_cache::sync::Cache<Cid, (bool, u64)>>,
+}
+
+/// Permits for generating pull responses, see `ServerState::with_max_concurrent_pulls`.
+#[derive(Debug, Clone)]
+struct PullPermits {
+    semaphore: Arc<Semaphore>,
+    max_wait: Duration,
 }
 
+/// Whether to accept a push for a given root CID. See `PushPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushDecision {
+    /// Accept the push and start receiving blocks
+    Accept,
+    /// Reject the push with `409 Conflict` before any blocks are received
+    Reject,
+}
+
+/// A hook that decides whether to accept a push for a root CID
+/// before streaming begins, e.g. to only accept newer versions of a DAG
+/// or to enforce quotas.
+pub type PushPolicy = Arc<dyn Fn(Cid) -> PushDecision + Send + Sync>;
+
 impl<B: BlockStore + Clone + 'static> ServerState<B> {
     /// Initialize the server state with given blockstore and
     /// a roughly 10MB car mirror operations cache.
@@ -94,39 +167,393 @@ impl<B: BlockStore + Clone + 'static> ServerState<B> {
         Self {
             store,
             cache: InMemoryCache::new(100_000),
+            config: Config::default(),
+            push_policy: None,
+            transfer_permits: None,
+            pull_permits: None,
+            checkpoint_key: None,
+            pull_response_cache: None,
+            store_generation: Arc::new(AtomicU64::new(0)),
+            head_results: Arc::new(quick_cache::sync::Cache::new(10_000)),
+        }
+    }
+
+    /// Use given config for handling requests instead of `Config::default`.
+    ///
+    /// Among others, its `Config::receive_maximum` limits multipart push
+    /// bodies, see `dag_router`.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Consult given policy before accepting any push.
+    ///
+    /// Pushes that are rejected get a `409 Conflict` response.
+    pub fn with_push_policy(
+        mut self,
+        policy: impl Fn(Cid) -> PushDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.push_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Limit the number of push and pull requests that are handled concurrently
+    /// across all clients, bounding the total memory used for verification.
+    ///
+    /// Requests beyond that limit get a `503 Service Unavailable` response.
+    /// A pull request counts as in-flight until its response is fully streamed.
+    pub fn with_max_concurrent_transfers(mut self, max_concurrent_transfers: usize) -> Self {
+        self.transfer_permits = Some(Arc::new(Semaphore::new(max_concurrent_transfers)));
+        self
+    }
+
+    /// Limit the number of pull responses that are generated concurrently,
+    /// protecting the store from many clients pulling large DAGs at once.
+    ///
+    /// Unlike `with_max_concurrent_transfers`, pulls beyond that limit are queued
+    /// for up to `max_wait` before they get a `503 Service Unavailable` response.
+    /// A pull counts as in-flight until its response is fully streamed. Responses
+    /// served from the pull response cache don't count.
+    pub fn with_max_concurrent_pulls(
+        mut self,
+        max_concurrent_pulls: usize,
+        max_wait: Duration,
+    ) -> Self {
+        self.pull_permits = Some(PullPermits {
+            semaphore: Arc::new(Semaphore::new(max_concurrent_pulls)),
+            max_wait,
+        });
+        self
+    }
+
+    /// Issue checkpoints with push responses, signed with given key.
+    ///
+    /// Clients echo them back via the `car-mirror-checkpoint` header, so the
+    /// server doesn't need to walk the DAG it already has on every push round.
+    /// Requests with invalid checkpoints get a `400 Bad Request` response.
+    /// See `car_mirror::checkpoint::CheckpointKey`.
+    pub fn with_checkpoint_key(mut self, key: CheckpointKey) -> Self {
+        self.checkpoint_key = Some(key);
+        self
+    }
+
+    /// Cache the CAR files of responses to first-round pull requests, i.e.
+    /// requests for only the root without a bloom filter, up to a total of
+    /// `max_bytes` bytes.
+    ///
+    /// This saves walking the store for popular DAGs, since first-round
+    /// requests for the same root are usually identical. It's separate from
+    /// the car mirror operations cache.
+    ///
+    /// Cached responses are served for at most `ttl`, and not at all anymore
+    /// after the next push or `ServerState::notify_store_changed`.
+    /// If the store is changed by other means, keep `ttl` short.
+    ///
+    /// To cache them, first-round responses are buffered in full instead of
+    /// streamed, up to the `Config::receive_maximum` of the server state's config.
+    /// Larger responses are streamed and not cached.
+    pub fn with_pull_response_cache(mut self, max_bytes: usize, ttl: Duration) -> Self {
+        // Assuming responses of roughly 16KB on average
+        let estimated_entries = (max_bytes / 16_384).max(1);
+        self.pull_response_cache = Some(PullResponseCache {
+            responses: Arc::new(quick_cache::sync::Cache::with_weighter(
+                estimated_entries,
+                max_bytes as u64,
+                PullResponseWeighter,
+            )),
+            ttl,
+        });
+        self
+    }
+
+    /// Invalidates all cached pull responses, see `with_pull_response_cache`,
+    /// and the cached results of `HEAD` requests, see `car_mirror_pull_head`.
+    ///
+    /// Call this when writing to the store outside of push requests.
+    pub fn notify_store_changed(&self) {
+        self.store_generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Acquire a permit for a transfer, if concurrent transfers are limited.
+    fn try_acquire_transfer_permit(&self) -> AppResult<Option<OwnedSemaphorePermit>> {
+        let Some(permits) = &self.transfer_permits else {
+            return Ok(None);
+        };
+
+        let permit = Arc::clone(permits).try_acquire_owned().map_err(|_| {
+            AppError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many concurrent transfers, try again later",
+            )
+        })?;
+
+        Ok(Some(permit))
+    }
+
+    /// Wait for a permit to generate a pull response, if concurrent pulls are limited.
+    async fn acquire_pull_permit(&self) -> AppResult<Option<OwnedSemaphorePermit>> {
+        let Some(PullPermits {
+            semaphore,
+            max_wait,
+        }) = &self.pull_permits
+        else {
+            return Ok(None);
+        };
+
+        let acquire = Arc::clone(semaphore).acquire_owned();
+        match tokio::time::timeout(*max_wait, acquire).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => {
+                tracing::debug!(?max_wait, "Timed out waiting for a pull permit");
+                Err(AppError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many concurrent pulls, try again later",
+                ))
+            }
+        }
+    }
+}
+
+impl<B: BlockStore + Clone + std::fmt::Debug + 'static> std::fmt::Debug for ServerState<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerState")
+            .field("store", &self.store)
+            .field("cache", &self.cache)
+            .field("config", &self.config)
+            .field(
+                "push_policy",
+                &self.push_policy.as_ref().map(|_| "<policy>"),
+            )
+            .field("transfer_permits", &self.transfer_permits)
+            .field("pull_permits", &self.pull_permits)
+            .field("checkpoint_key", &self.checkpoint_key)
+            .field("pull_response_cache", &self.pull_response_cache)
+            .field("store_generation", &self.store_generation)
+            .field("head_results", &self.head_results)
+            .finish()
+    }
+}
+
+/// Cached CAR files of pull responses, see `ServerState::with_pull_response_cache`.
+#[derive(Debug, Clone)]
+struct PullResponseCache {
+    /// Keyed by the root and the `max_blocks` hint, which is the only other
+    /// part of a first-round request that affects the response
+    responses:
+        Arc<quick_cache::sync::Cache<(Cid, Option<u64>), CachedPullResponse, PullResponseWeighter>>,
+    ttl: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct CachedPullResponse {
+    car_bytes: Bytes,
+    store_generation: u64,
+    cached_at: Instant,
+}
+
+/// Weighs cached pull responses by their size in bytes.
+#[derive(Debug, Clone)]
+struct PullResponseWeighter;
+
+impl quick_cache::Weighter<(Cid, Option<u64>), CachedPullResponse> for PullResponseWeighter {
+    fn weight(&self, _key: &(Cid, Option<u64>), val: &CachedPullResponse) -> u32 {
+        u32::try_from(val.car_bytes.len())
+            .unwrap_or(u32::MAX)
+            .max(1)
+    }
+}
+
+impl PullResponseCache {
+    /// Returns the cached response, unless it expired or the store changed since.
+    fn get(&self, key: &(Cid, Option<u64>), store_generation: u64) -> Option<Bytes> {
+        let cached = self.responses.get(key)?;
+        if cached.store_generation != store_generation || cached.cached_at.elapsed() > self.ttl {
+            self.responses.remove(key);
+            return None;
         }
+
+        Some(cached.car_bytes)
+    }
+
+    fn insert(&self, key: (Cid, Option<u64>), car_bytes: Bytes, store_generation: u64) {
+        self.responses.insert(
+            key,
+            CachedPullResponse {
+                car_bytes,
+                store_generation,
+                cached_at: Instant::now(),
+            },
+        );
     }
 }
 
+/// The media type for CAR files that's set as the `Content-Type` of
+/// pull responses.
+pub const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car; version=1";
+
+/// Checks whether a push request's `Content-Type` is one of the media types
+/// we accept for CAR file bodies.
+///
+/// These are `application/vnd.ipld.car` (optionally with `version=1`) and
+/// `application/octet-stream`. Requests without a `Content-Type` and requests
+/// with `application/vnd.ipld.dag-cbor`, which older car-mirror-reqwest
+/// versions sent, are accepted as well.
+pub fn is_accepted_car_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return true;
+    };
+
+    let Some(mime) = content_type
+        .to_str()
+        .ok()
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+    else {
+        return false;
+    };
+
+    match mime.essence_str() {
+        "application/vnd.ipld.car" => mime
+            .get_param("version")
+            .map_or(true, |version| version == "1"),
+        "application/octet-stream" | "application/vnd.ipld.dag-cbor" => true,
+        _ => false,
+    }
+}
+
+/// The name of the `multipart/form-data` field that push requests can send
+/// their CAR file in. See `car_mirror_push`.
+pub const CAR_FORM_FIELD: &str = "car";
+
+/// Checks whether a push request's `Content-Type` is `multipart/form-data`.
+fn is_multipart_form_data(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+        .is_some_and(|mime| mime.essence_str() == mime::MULTIPART_FORM_DATA.essence_str())
+}
+
 /// Handle a POST request for car mirror pushes.
 ///
 /// This will consume the incoming body as a car file stream.
-#[tracing::instrument(skip(state), err, ret)]
+///
+/// Bodies with a `multipart/form-data` `Content-Type` are accepted as well, e.g.
+/// from browser forms. In that case the CAR file is streamed from the field named
+/// `car` (see `CAR_FORM_FIELD`), other fields are ignored. Responds with
+/// `400 Bad Request` if there's no such field.
+/// Multipart bodies are limited by axum's `DefaultBodyLimit`, which is 2MB
+/// unless the router sets another limit, e.g. `DefaultBodyLimit::disable()`.
+///
+/// Responds with `415 Unsupported Media Type` if the request's `Content-Type`
+/// isn't accepted by `is_accepted_car_content_type` and isn't `multipart/form-data`.
+///
+/// Responds with `409 Conflict` if the server state's push policy
+/// rejects the root CID. See `ServerState::with_push_policy`.
+///
+/// Responds with `503 Service Unavailable` if too many transfers are in-flight.
+/// See `ServerState::with_max_concurrent_transfers`.
+///
+/// If the server state has a checkpoint key, the request's `car-mirror-checkpoint`
+/// header is used to resume verification. See `ServerState::with_checkpoint_key`.
+///
+/// With the `json-debug` feature, the response is encoded as json instead
+/// of dag-cbor if the request's `Accept` header asks for `application/json`.
+#[tracing::instrument(skip(state, request), err, ret)]
 pub async fn car_mirror_push<B: BlockStore + Clone + 'static>(
     State(state): State<ServerState<B>>,
     Path(cid_string): Path<String>,
-    body: Body,
-) -> AppResult<(StatusCode, DagCbor<PushResponse>)>
+    request: Request,
+) -> AppResult<(StatusCode, Response)>
 where {
     let cid = Cid::from_str(&cid_string)?;
+    let (parts, body) = request.into_parts();
+    let headers = parts.headers.clone();
+
+    let is_multipart = is_multipart_form_data(&headers);
+    if !is_multipart && !is_accepted_car_content_type(&headers) {
+        return Err(AppError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Expected a CAR file body, e.g. with Content-Type {CAR_MEDIA_TYPE}"),
+        ));
+    }
+
+    let _permit = state.try_acquire_transfer_permit()?;
+
+    if let Some(policy) = &state.push_policy {
+        if policy(cid) == PushDecision::Reject {
+            return Err(AppError::new(
+                StatusCode::CONFLICT,
+                format!("Push for {cid} was rejected by the server's push policy"),
+            ));
+        }
+    }
 
     let content_length = body.size_hint().exact();
-    let body_stream = body.into_data_stream();
 
-    tracing::info!(content_length, "Parsed content length hint");
+    tracing::info!(content_length, is_multipart, "Parsed content length hint");
 
-    let mut reader = StreamReader::new(
-        body_stream.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
-    );
+    let mut multipart;
+    let body_stream = if is_multipart {
+        // Keep the request's extensions, so `DefaultBodyLimit` still applies
+        let request = Request::from_parts(parts, body);
+        multipart = Multipart::from_request(request, &())
+            .await
+            .map_err(|rejection| AppError::new(rejection.status(), rejection.body_text()))?;
 
-    let response = car_mirror::push::response_streaming(
-        cid,
-        &mut reader,
-        &Config::default(),
-        &state.store,
-        &state.cache,
-    )
-    .await?;
+        car_form_field(&mut multipart)
+            .await?
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            .boxed()
+    } else {
+        body.into_data_stream()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            .boxed()
+    };
+
+    let mut reader = StreamReader::new(body_stream);
+
+    // Pushes store blocks, so cached pull responses may be outdated afterwards,
+    // even if the push fails or is cancelled halfway through
+    let _notify = NotifyStoreChangedOnDrop(&state);
+
+    let response = match &state.checkpoint_key {
+        Some(key) => {
+            // Clients don't send larger checkpoints, and without one
+            // we fall back to walking the DAG below the root
+            let checkpoint = headers
+                .get(CHECKPOINT_HEADER)
+                .filter(|value| value.len() <= checkpoint::MAX_CHECKPOINT_HEADER_LEN)
+                .map(|value| {
+                    let value = value.to_str().map_err(|_| {
+                        AppError::new(StatusCode::BAD_REQUEST, "Invalid checkpoint header")
+                    })?;
+                    Ok::<_, AppError>(checkpoint::from_header_value(cid, value)?)
+                })
+                .transpose()?;
+
+            car_mirror::push::response_streaming_with_checkpoint(
+                cid,
+                &mut reader,
+                checkpoint.as_deref(),
+                key,
+                &state.config,
+                &state.store,
+                &state.cache,
+            )
+            .await?
+        }
+        None => {
+            car_mirror::push::response_streaming(
+                cid,
+                &mut reader,
+                &state.config,
+                &state.store,
+                &state.cache,
+            )
+            .await?
+        }
+    };
 
     if content_length.is_some() {
         tracing::info!("Draining request");
@@ -139,41 +566,317 @@ where {
         tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
     }
 
-    if response.indicates_finished() {
-        Ok((StatusCode::OK, DagCbor(response)))
+    let status = if response.indicates_finished() {
+        StatusCode::OK
     } else {
-        Ok((StatusCode::ACCEPTED, DagCbor(response)))
+        StatusCode::ACCEPTED
+    };
+
+    #[cfg(feature = "json-debug")]
+    if accepts_json(&headers) {
+        return Ok((status, axum::Json(response).into_response()));
+    }
+
+    Ok((status, DagCbor(response).into_response()))
+}
+
+/// Calls `ServerState::notify_store_changed` when dropped.
+struct NotifyStoreChangedOnDrop<'a, B: BlockStore + Clone + 'static>(&'a ServerState<B>);
+
+impl<B: BlockStore + Clone + 'static> Drop for NotifyStoreChangedOnDrop<'_, B> {
+    fn drop(&mut self) {
+        self.0.notify_store_changed();
     }
 }
 
+/// Finds the `CAR_FORM_FIELD` in a multipart body, skipping any fields before it.
+async fn car_form_field(multipart: &mut Multipart) -> AppResult<Field<'_>> {
+    let bad_request = |e: MultipartError| AppError::new(e.status(), e.body_text());
+
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.name() == Some(CAR_FORM_FIELD) {
+            return Ok(field);
+        }
+    }
+
+    Err(AppError::new(
+        StatusCode::BAD_REQUEST,
+        format!("Missing multipart field '{CAR_FORM_FIELD}' with a CAR file"),
+    ))
+}
+
+/// Whether the `Accept` header asks for `application/json`.
+#[cfg(feature = "json-debug")]
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(axum::http::header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_type| media_type.trim().parse::<mime::Mime>().ok())
+        .any(|mime| mime.essence_str() == mime::APPLICATION_JSON.essence_str())
+}
+
 /// Handle an incoming GET or POST request for a car mirror pull.
 ///
-/// The response body will contain a stream of car file chunks.
-#[tracing::instrument(skip(state), err, ret)]
+/// The response body will contain a stream of car file chunks,
+/// with `CAR_MEDIA_TYPE` as its `Content-Type`.
+///
+/// Supports a single `Range: bytes=...` request header, so clients on
+/// unreliable connections can resume a dropped download at a byte offset.
+/// This works because the response is deterministic for a given request.
+/// To be able to compute the `Content-Range`, ranged responses are
+/// buffered in memory before they're sent, up to the `Config::receive_maximum`
+/// of the server state's config. Larger responses ignore the range and are
+/// streamed whole with `200 OK`, as are multiple or malformed ranges.
+///
+/// Responses to first-round requests are served from the pull response cache,
+/// if enabled via `ServerState::with_pull_response_cache`.
+///
+/// Responds with `503 Service Unavailable` if too many transfers are in-flight,
+/// or if too many pull responses are being generated for too long.
+/// See `ServerState::with_max_concurrent_transfers` and
+/// `ServerState::with_max_concurrent_pulls`.
+#[tracing::instrument(skip(state, headers), err, ret)]
 pub async fn car_mirror_pull<B: BlockStore + Clone + 'static>(
     State(state): State<ServerState<B>>,
     Path(cid_string): Path<String>,
+    headers: HeaderMap,
     pull_request: Option<DagCbor<PullRequest>>,
-) -> AppResult<(StatusCode, Body)> {
+) -> AppResult<Response> {
     let cid = Cid::from_str(&cid_string)?;
 
+    let permit = state.try_acquire_transfer_permit()?;
+
     let DagCbor(request) = pull_request.unwrap_or_else(|| {
         DagCbor(PullRequest {
+            cid_encoding: CidEncoding::default(),
             resources: vec![cid],
             bloom_hash_count: 3,
             bloom_bytes: vec![],
+            max_blocks: None,
         })
     });
 
-    let car_chunks = car_mirror::pull::response_streaming(
-        cid,
-        request,
-        state.store.clone(),
-        state.cache.clone(),
+    let config = &state.config;
+    request.validate(config)?;
+
+    let range = ByteRange::from_headers(&headers);
+
+    // Only first-round requests are likely to be identical across clients
+    let is_first_round = request.resources == [cid] && request.bloom_bytes.is_empty();
+    let response_cache = state
+        .pull_response_cache
+        .as_ref()
+        .filter(|_| is_first_round);
+    let store_generation = state.store_generation.load(Ordering::Relaxed);
+    let cache_key = (cid, request.max_blocks);
+
+    let cached =
+        response_cache.and_then(|response_cache| response_cache.get(&cache_key, store_generation));
+
+    let car_bytes = match cached {
+        Some(car_bytes) => {
+            tracing::debug!(%cid, "Serving cached pull response");
+            car_bytes
+        }
+        None => {
+            let pull_permit = state.acquire_pull_permit().await?;
+            let mut car_chunks = car_mirror::pull::response_streaming(
+                cid,
+                request,
+                config,
+                state.store.clone(),
+                state.cache.clone(),
+            )
+            .await?;
+
+            if range.is_none() && response_cache.is_none() {
+                return Ok((
+                    StatusCode::OK,
+                    [(CONTENT_TYPE, CAR_MEDIA_TYPE), (ACCEPT_RANGES, "bytes")],
+                    // Hold on to the permits until the response is streamed
+                    Body::from_stream(car_chunks.inspect(move |_| {
+                        let _permits = (&permit, &pull_permit);
+                    })),
+                )
+                    .into_response());
+            }
+
+            let mut buffered = Vec::new();
+            while let Some(chunk) = car_chunks.try_next().await? {
+                buffered.extend_from_slice(&chunk);
+
+                // Don't buffer arbitrarily large DAGs in memory
+                if buffered.len() > config.receive_maximum {
+                    tracing::info!(
+                        receive_maximum = config.receive_maximum,
+                        "Pull response too large to buffer, sending it whole"
+                    );
+                    let buffered = futures::stream::once(async move {
+                        Ok::<_, car_mirror::Error>(Bytes::from(buffered))
+                    });
+                    return Ok((
+                        StatusCode::OK,
+                        [(CONTENT_TYPE, CAR_MEDIA_TYPE), (ACCEPT_RANGES, "bytes")],
+                        Body::from_stream(buffered.chain(car_chunks).inspect(move |_| {
+                            let _permits = (&permit, &pull_permit);
+                        })),
+                    )
+                        .into_response());
+                }
+            }
+            let car_bytes = Bytes::from(buffered);
+
+            if let Some(response_cache) = response_cache {
+                response_cache.insert(cache_key, car_bytes.clone(), store_generation);
+            }
+
+            car_bytes
+        }
+    };
+
+    let Some(range) = range else {
+        return Ok((
+            StatusCode::OK,
+            [(CONTENT_TYPE, CAR_MEDIA_TYPE), (ACCEPT_RANGES, "bytes")],
+            Body::from(car_bytes),
+        )
+            .into_response());
+    };
+
+    let total = car_bytes.len() as u64;
+
+    let Some((start, end)) = range.resolve(total) else {
+        tracing::info!(?range, total, "Unsatisfiable range");
+        return Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(CONTENT_RANGE, format!("bytes */{total}"))],
+        )
+            .into_response());
+    };
+
+    tracing::info!(start, end, total, "Serving byte range");
+
+    let bytes = car_bytes.slice(start as usize..=end as usize);
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        [
+            (CONTENT_TYPE, CAR_MEDIA_TYPE.to_string()),
+            (ACCEPT_RANGES, "bytes".to_string()),
+            (CONTENT_RANGE, format!("bytes {start}-{end}/{total}")),
+        ],
+        Body::from(bytes),
+    )
+        .into_response())
+}
+
+/// The response header of `HEAD` requests on the pull route that tells
+/// whether the server has the complete DAG below the requested root.
+pub const COMPLETE_HEADER: &str = "x-car-mirror-complete";
+
+/// Answers `HEAD` requests on the pull route without streaming a CAR file,
+/// e.g. for health checks or for clients to check before pulling.
+///
+/// Responds with `404 Not Found` if the root block isn't in the store.
+/// Otherwise responds with `200 OK` and the `COMPLETE_HEADER` set to `true`
+/// if all blocks below the root are in the store, `false` otherwise.
+///
+/// Finding out whether the DAG is complete walks all of it, so `HEAD` requests
+/// count as transfers: Responds with `503 Service Unavailable` if too many
+/// transfers are in-flight, see `ServerState::with_max_concurrent_transfers`.
+/// Results are cached until the next push or `ServerState::notify_store_changed`.
+#[tracing::instrument(skip(state), err, ret)]
+pub async fn car_mirror_pull_head<B: BlockStore + Clone + 'static>(
+    State(state): State<ServerState<B>>,
+    Path(cid_string): Path<String>,
+) -> AppResult<Response> {
+    let cid = Cid::from_str(&cid_string)?;
+
+    let _permit = state.try_acquire_transfer_permit()?;
+
+    if !state.store.has_block(&cid).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let store_generation = state.store_generation.load(Ordering::Relaxed);
+    let complete = match state.head_results.get(&cid) {
+        Some((complete, generation)) if generation == store_generation => complete,
+        _ => {
+            let complete = car_mirror::common::compute_missing(cid, &state.store, &state.cache)
+                .await?
+                .is_empty();
+            state.head_results.insert(cid, (complete, store_generation));
+            complete
+        }
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_static(CAR_MEDIA_TYPE)),
+            (
+                HeaderName::from_static(COMPLETE_HEADER),
+                HeaderValue::from_static(if complete { "true" } else { "false" }),
+            ),
+        ],
     )
-    .await?;
+        .into_response())
+}
+
+/// A single byte range from a `Range: bytes=...` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    /// `bytes=<start>-` or `bytes=<start>-<end>`, with an inclusive end.
+    FromStart { start: u64, end: Option<u64> },
+    /// `bytes=-<length>`, i.e. the last `length` bytes.
+    Suffix { length: u64 },
+}
+
+impl ByteRange {
+    /// Parses the `Range` header, if there is one with a single byte range.
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let spec = headers.get(RANGE)?.to_str().ok()?.trim();
+        let spec = spec.strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            // We don't support multipart/byteranges responses
+            return None;
+        }
+
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
 
-    Ok((StatusCode::OK, Body::from_stream(car_chunks)))
+        if start.is_empty() {
+            return Some(Self::Suffix {
+                length: end.parse().ok()?,
+            });
+        }
+
+        let start = start.parse().ok()?;
+        let end = match end {
+            "" => None,
+            end => Some(end.parse().ok()?),
+        };
+
+        if matches!(end, Some(end) if end < start) {
+            return None;
+        }
+
+        Some(Self::FromStart { start, end })
+    }
+
+    /// Resolves this range into inclusive start and end offsets into
+    /// a body of `total` bytes, or returns `None` if it's unsatisfiable.
+    fn resolve(self, total: u64) -> Option<(u64, u64)> {
+        let last = total.checked_sub(1)?;
+        match self {
+            Self::FromStart { start, end } if start <= last => {
+                Some((start, end.map_or(last, |end| end.min(last))))
+            }
+            Self::Suffix { length } if length > 0 => Some((total.saturating_sub(length), last)),
+            _ => None,
+        }
+    }
 }
 
 #[axum_macros::debug_handler]
@@ -0,0 +1,51 @@
+use car_mirror::{
+    cache::NoCache,
+    test_utils::{
+        arb_ipld_dag, dag_blocks_breadth_first, links_to_ipld, setup_blockstore,
+        verify_blocks_one_by_one,
+    },
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use wnfs_common::MemoryBlockStore;
+
+/// Measures `IncrementalDagVerification::verify_and_store_block` in isolation.
+///
+/// Each verified block re-walks the want-set via `update_have_cids`, so the
+/// time per block grows with the DAG. Criterion reports the throughput in
+/// blocks per second for each DAG size, so a flat throughput across sizes
+/// means verification scales linearly.
+pub fn verify_block_by_block(c: &mut Criterion) {
+    let mut rvg = car_mirror::test_utils::Rvg::deterministic();
+    let mut group = c.benchmark_group("verify block by block");
+
+    for dag_size in [32, 128, 512] {
+        // Blocks without padding, so hashing doesn't dominate the re-walk cost
+        let (blocks, root) = rvg.sample(&arb_ipld_dag(dag_size..dag_size + 1, 0.9, links_to_ipld));
+        let source_store = async_std::task::block_on(setup_blockstore(blocks)).unwrap();
+        let blocks =
+            async_std::task::block_on(dag_blocks_breadth_first(root, &source_store)).unwrap();
+
+        group.throughput(Throughput::Elements(blocks.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(blocks.len()),
+            &blocks,
+            |b, blocks| {
+                b.iter_batched(
+                    || (blocks.clone(), MemoryBlockStore::new()),
+                    |(blocks, ref store)| {
+                        async_std::task::block_on(verify_blocks_one_by_one(
+                            root, blocks, store, &NoCache,
+                        ))
+                        .unwrap();
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, verify_block_by_block);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+use libipld::Cid;
 use reqwest::Response;
 use std::{collections::TryReserveError, convert::Infallible};
 
@@ -19,6 +20,21 @@ pub enum Error {
     #[error("Body must not be set on request builder")]
     RequestBuilderBodyAlreadySet,
 
+    /// Raised when the server didn't confirm having the complete DAG
+    /// after a verified push, see `run_car_mirror_push_verified`.
+    #[error("Server is missing {} subgraph root(s) below {root}", missing_subgraph_roots.len())]
+    IncompleteDag {
+        /// The root of the DAG that was pushed
+        root: Cid,
+        /// Some of the subgraph roots the server reported missing
+        missing_subgraph_roots: Vec<Cid>,
+    },
+
+    /// Raised when a transfer was stopped via its `CancellationToken`,
+    /// see e.g. `run_car_mirror_push_cancellable`.
+    #[error("The transfer was cancelled")]
+    Cancelled,
+
     /// reqwest errors
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
@@ -0,0 +1,196 @@
+use bytes::Bytes;
+use libipld::Cid;
+use reqwest::{
+    multipart::{Form, Part},
+    Response, StatusCode,
+};
+use serde::Deserialize;
+use std::str::FromStr;
+use wnfs_common::{utils::CondSend, BlockStore, BlockStoreError};
+
+/// A block store that reads and writes blocks via the HTTP RPC API
+/// of an IPFS node, e.g. [Kubo].
+///
+/// This makes it possible to mirror content straight out of an IPFS node,
+/// without copying it into another store first, e.g. with
+/// `car_mirror_axum::serve(KuboBlockStore::new("http://127.0.0.1:5001"))`.
+///
+/// Blocks are looked up by their multihash, like IPFS nodes do, so CIDv0 and
+/// CIDv1 of the same block are interchangeable. If the node responds with
+/// `404 Not Found` or an error with the RPC API's not-found error code,
+/// that's reported as `BlockStoreError::CIDNotFound`.
+///
+/// By default, requests are sent with `offline=true`, so the node only answers
+/// from its local blockstore, instead of searching the IPFS network for blocks
+/// it doesn't have, which can take a long time. See `with_offline`.
+///
+/// [Kubo]: https://docs.ipfs.tech/reference/kubo/rpc/
+#[derive(Debug, Clone)]
+pub struct KuboBlockStore {
+    client: reqwest::Client,
+    api_url: String,
+    offline: bool,
+}
+
+/// The response to `/api/v0/block/put`.
+#[derive(Debug, Deserialize)]
+struct BlockPutResponse {
+    #[serde(rename = "Key")]
+    key: String,
+}
+
+/// The body of error responses of the RPC API.
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "Code")]
+    code: u32,
+}
+
+/// The RPC API's error code for things that weren't found.
+const ERROR_CODE_NOT_FOUND: u32 = 3;
+
+impl KuboBlockStore {
+    /// Use the RPC API of the IPFS node at `api_url`, e.g. `http://127.0.0.1:5001`.
+    pub fn new(api_url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_url: api_url.into(),
+            offline: true,
+        }
+    }
+
+    /// Use given client for requests, e.g. to set timeouts or authentication headers.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Whether the node should only answer from its local blockstore.
+    ///
+    /// Set this to `false` to make the node fetch missing blocks from the
+    /// IPFS network. Since car mirror checks for blocks that may be missing
+    /// a lot, this can make transfers very slow.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    fn endpoint(&self, command: &str) -> String {
+        format!("{}/api/v0/{command}", self.api_url.trim_end_matches('/'))
+    }
+
+    /// Calls given command on the block with given CID.
+    async fn call(&self, command: &str, cid: &Cid) -> Result<Response, BlockStoreError> {
+        let response = self
+            .client
+            .post(self.endpoint(command))
+            .query(&[
+                ("arg", cid.to_string()),
+                ("offline", self.offline.to_string()),
+            ])
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Err(BlockStoreError::CIDNotFound(*cid));
+        }
+
+        let message = match response.json::<ErrorResponse>().await {
+            Ok(error) if error.code == ERROR_CODE_NOT_FOUND => {
+                return Err(BlockStoreError::CIDNotFound(*cid));
+            }
+            Ok(error) => error.message,
+            Err(e) => format!("unexpected error response ({e})"),
+        };
+
+        Err(
+            anyhow::anyhow!("IPFS node responded to {command} for {cid} with {status}: {message}")
+                .into(),
+        )
+    }
+}
+
+impl BlockStore for KuboBlockStore {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        let response = self.call("block/get", cid).await?;
+        Ok(response.bytes().await.map_err(anyhow::Error::from)?)
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        let codec = codec_name(cid.codec()).ok_or_else(|| {
+            anyhow::anyhow!("Unsupported codec {} for IPFS node in {cid}", cid.codec())
+        })?;
+        let mhtype = multihash_name(cid.hash().code()).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unsupported hash code {} for IPFS node in {cid}",
+                cid.hash().code()
+            )
+        })?;
+
+        let bytes: Bytes = bytes.into();
+        let form = Form::new().part("file", Part::stream(bytes));
+        let response = self
+            .client
+            .post(self.endpoint("block/put"))
+            .query(&[("cid-codec", codec), ("mhtype", mhtype), ("pin", "false")])
+            .multipart(form)
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .map_err(anyhow::Error::from)?;
+
+        let BlockPutResponse { key } = response.json().await.map_err(anyhow::Error::from)?;
+        let stored_cid = Cid::from_str(&key).map_err(anyhow::Error::from)?;
+
+        // The node may pick another CID version, e.g. CIDv0 for DAG-PB
+        if stored_cid.hash() != cid.hash() {
+            return Err(anyhow::anyhow!("IPFS node stored block {cid} as {stored_cid}").into());
+        }
+
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        match self.call("block/stat", cid).await {
+            Ok(_) => Ok(true),
+            Err(BlockStoreError::CIDNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The name of a codec in the multicodec table, as IPFS nodes expect it.
+fn codec_name(codec: u64) -> Option<&'static str> {
+    match codec {
+        0x55 => Some("raw"),
+        0x70 => Some("dag-pb"),
+        0x71 => Some("dag-cbor"),
+        0x0129 => Some("dag-json"),
+        _ => None,
+    }
+}
+
+/// The name of a hash function in the multicodec table, as IPFS nodes expect it.
+fn multihash_name(code: u64) -> Option<&'static str> {
+    match code {
+        0x00 => Some("identity"),
+        0x12 => Some("sha2-256"),
+        0x13 => Some("sha2-512"),
+        0x14 => Some("sha3-512"),
+        0x16 => Some("sha3-256"),
+        0x1e => Some("blake3"),
+        0xb220 => Some("blake2b-256"),
+        _ => None,
+    }
+}
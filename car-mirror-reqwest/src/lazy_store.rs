@@ -0,0 +1,150 @@
+use crate::{Error, DAG_CBOR_MEDIA_TYPE};
+use bytes::Bytes;
+use car_mirror::{
+    cache::NoCache,
+    common::{CarFile, Config},
+    pull,
+};
+use libipld::Cid;
+use reqwest::header::CONTENT_TYPE;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use wnfs_common::{utils::CondSend, BlockStore, BlockStoreError};
+
+/// A block store that fetches missing blocks on demand from a car mirror server.
+///
+/// When `get_block` misses in the local store, this runs a single round of
+/// the pull protocol for the missing CID and stores what it receives locally,
+/// so apps can navigate a DAG, e.g. in a file browser, without pulling it
+/// all upfront. Since a round starts with the requested block and continues
+/// with the blocks below it, navigating further usually hits the local store.
+///
+/// Each miss fetches at most `Config::receive_maximum` bytes and, if set,
+/// `Config::max_blocks_per_round` blocks, see `with_config`.
+/// Concurrent misses for the same CID share a single request.
+///
+/// `has_block` only checks the local store, so walking the DAG via e.g.
+/// `DagWalk::next` doesn't trigger fetches. Writes go to the local store.
+#[derive(Debug, Clone)]
+pub struct LazyBlockStore<S> {
+    local: S,
+    client: reqwest::Client,
+    pull_url: String,
+    config: Config,
+    in_flight: Arc<Mutex<HashMap<Cid, Arc<futures::lock::Mutex<()>>>>>,
+}
+
+impl<S: BlockStore> LazyBlockStore<S> {
+    /// Wrap given local store, fetching missing blocks with `client` via
+    /// pull requests to `{pull_url}/{cid}`, e.g. with a `pull_url` of
+    /// `http://localhost:3344/dag/pull` for a `car_mirror_axum` server.
+    pub fn new(local: S, client: reqwest::Client, pull_url: impl Into<String>) -> Self {
+        Self {
+            local,
+            client,
+            pull_url: pull_url.into(),
+            config: Config::default(),
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Use given config for the pull rounds on misses, e.g. to bound how many
+    /// blocks each miss fetches via `Config::max_blocks_per_round`.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The wrapped local store.
+    pub fn local(&self) -> &S {
+        &self.local
+    }
+
+    /// Fetch the block with given CID into the local store, unless another
+    /// fetch for it is already running, in which case this waits for that one.
+    async fn fetch(&self, cid: Cid) -> Result<(), BlockStoreError> {
+        let lock = self.lock_in_flight()?.entry(cid).or_default().clone();
+
+        let result = async {
+            let _guard = lock.lock().await;
+            // Another fetch may have gotten the block while we were waiting
+            if self.local.has_block(&cid).await? {
+                return Ok(());
+            }
+
+            self.pull_round(cid).await.map_err(|err| {
+                BlockStoreError::Custom(anyhow::anyhow!("Couldn't fetch block {cid}: {err}"))
+            })
+        }
+        .await;
+
+        // Clean up, unless other fetches are still waiting for this one
+        let mut in_flight = self.lock_in_flight()?;
+        if Arc::strong_count(&lock) <= 2 {
+            in_flight.remove(&cid);
+        }
+
+        result
+    }
+
+    async fn pull_round(&self, cid: Cid) -> Result<(), Error> {
+        let request = pull::request(cid, None, &self.config, &self.local, NoCache).await?;
+        if request.indicates_finished() {
+            return Ok(());
+        }
+
+        tracing::debug!(%cid, "Fetching missing block");
+
+        let response = self
+            .client
+            .post(format!("{}/{cid}", self.pull_url))
+            .header(CONTENT_TYPE, DAG_CBOR_MEDIA_TYPE)
+            .body(request.to_dag_cbor()?)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let car = CarFile {
+            bytes: response.bytes().await?,
+        };
+        pull::request(cid, Some(car), &self.config, &self.local, NoCache).await?;
+
+        Ok(())
+    }
+
+    fn lock_in_flight(
+        &self,
+    ) -> Result<
+        std::sync::MutexGuard<'_, HashMap<Cid, Arc<futures::lock::Mutex<()>>>>,
+        BlockStoreError,
+    > {
+        Ok(self
+            .in_flight
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?)
+    }
+}
+
+impl<S: BlockStore> BlockStore for LazyBlockStore<S> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        if !self.local.has_block(cid).await? {
+            self.fetch(*cid).await?;
+        }
+
+        self.local.get_block(cid).await
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        self.local.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.local.has_block(cid).await
+    }
+}
@@ -41,9 +41,47 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! ## Streaming pushes over HTTP/2
+//!
+//! Push requests stream their CAR file. A server may interrupt that stream with
+//! its response as soon as it finds a block it already has, which saves sending
+//! the rest of the CAR file. Whether this works depends on the HTTP version:
+//! With HTTP/2 the server can answer mid-upload and the connection stays usable.
+//! With HTTP/1.1 servers usually need to wait for the upload to finish.
+//!
+//! Clients negotiate HTTP/2 automatically via TLS. For unencrypted connections,
+//! e.g. to a local `car_mirror_axum` server, use prior knowledge:
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! use car_mirror::cache::NoCache;
+//! use car_mirror_reqwest::RequestBuilderExt;
+//! use reqwest::Client;
+//! # use wnfs_common::{BlockStore, MemoryBlockStore, CODEC_RAW};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<()> {
+//! # let store = MemoryBlockStore::new();
+//! # let root = store.put_block(b"Hello, world!".to_vec(), CODEC_RAW).await?;
+//! let client = Client::builder().http2_prior_knowledge().build()?;
+//! client
+//!     .post(format!("http://localhost:3344/dag/push/{root}"))
+//!     .run_car_mirror_push(root, &store, &NoCache)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
 
 mod error;
+#[cfg(feature = "kubo")]
+mod kubo_store;
+mod lazy_store;
 mod request;
 
 pub use error::*;
+#[cfg(feature = "kubo")]
+#[cfg_attr(docsrs, doc(cfg(feature = "kubo")))]
+pub use kubo_store::*;
+pub use lazy_store::*;
 pub use request::*;
@@ -1,14 +1,60 @@
 use crate::Error;
 use anyhow::Result;
-use car_mirror::{cache::Cache, common::Config, messages::PushResponse};
-use futures::{Future, TryStreamExt};
+use bytes::Bytes;
+use car_mirror::{
+    cache::Cache,
+    checkpoint::{self, CHECKPOINT_HEADER},
+    common::{stream_car_frames, CarStream, Config},
+    messages::PushResponse,
+    transport::{drive_pull, drive_push, TransferStats, Transport},
+};
+use futures::{
+    future::{self, Either},
+    Future, StreamExt, TryStreamExt,
+};
 use libipld::Cid;
-use reqwest::{Body, Response, StatusCode};
-use std::{collections::TryReserveError, convert::Infallible};
-use tokio_util::io::StreamReader;
-use wnfs_common::BlockStore;
+use reqwest::{
+    header::{HeaderName, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use std::{
+    collections::TryReserveError,
+    convert::Infallible,
+    pin::pin,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use wnfs_common::{
+    utils::{boxed_stream, BoxStream},
+    BlockStore,
+};
+
+/// The media type for CAR files, which is used as the default `Content-Type`
+/// for push request bodies.
+pub const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car; version=1";
+
+/// The media type for dag-cbor encoded messages, which is used as the default
+/// `Content-Type` for pull request bodies.
+pub const DAG_CBOR_MEDIA_TYPE: &str = "application/vnd.ipld.dag-cbor";
+
+/// Statistics about a finished car mirror protocol run.
+///
+/// Returned from `run_car_mirror_push` and `run_car_mirror_pull`, e.g. for
+/// logging or displaying sync stats.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferSummary {
+    /// Rounds, bytes and blocks transferred, as counted by the protocol driver.
+    pub stats: TransferStats,
+    /// How long the whole protocol run took.
+    pub duration: Duration,
+}
 
 /// Extension methods on `RequestBuilder`s for sending car mirror protocol requests.
+///
+/// Requests get a `Content-Type` header of `CAR_MEDIA_TYPE` for pushes and
+/// `DAG_CBOR_MEDIA_TYPE` for pulls, unless the request builder already has
+/// a `Content-Type` header set, in which case that one is kept. This makes it
+/// possible to use different media types, e.g. for gateways that expect them.
 pub trait RequestBuilderExt {
     /// Initiate a car mirror push request to send some data to the
     /// server via HTTP.
@@ -33,12 +79,49 @@ pub trait RequestBuilderExt {
     /// lifetimes work with `reqwest`.
     /// Usually blockstores and caches satisfy these conditions due to
     /// using atomic reference counters.
+    ///
+    /// If the server issues push checkpoints, they're echoed back with the
+    /// next round's request via the `car_mirror::checkpoint::CHECKPOINT_HEADER`.
+    ///
+    /// Returns a `TransferSummary` of all rounds.
     fn run_car_mirror_push(
         &self,
         root: Cid,
         store: &(impl BlockStore + Clone + 'static),
         cache: &(impl Cache + Clone + 'static),
-    ) -> impl Future<Output = Result<(), Error>> + Send;
+    ) -> impl Future<Output = Result<TransferSummary, Error>> + Send;
+
+    /// Like `run_car_mirror_push`, but once the protocol indicates
+    /// it's finished, sends one more request to confirm that the server
+    /// actually has the complete DAG under `root`.
+    ///
+    /// This confirmation request only contains the root block. The server
+    /// will re-check the whole DAG it has stored below the root and
+    /// answer with any subgraph roots it's still missing.
+    ///
+    /// Returns `Error::IncompleteDag` if the server doesn't confirm
+    /// having all blocks. Otherwise returns the `TransferSummary` of the
+    /// push rounds, which doesn't include the confirmation request.
+    fn run_car_mirror_push_verified(
+        &self,
+        root: Cid,
+        store: &(impl BlockStore + Clone + 'static),
+        cache: &(impl Cache + Clone + 'static),
+    ) -> impl Future<Output = Result<TransferSummary, Error>> + Send;
+
+    /// Like `run_car_mirror_push`, but stops once `cancel` is cancelled,
+    /// e.g. when the user aborts a sync or the application shuts down.
+    ///
+    /// Cancelling aborts the request that's in flight, including its
+    /// streaming body, and returns `Error::Cancelled`. Blocks the server
+    /// received before that stay stored, so a later push picks up from there.
+    fn run_car_mirror_push_cancellable(
+        &self,
+        root: Cid,
+        store: &(impl BlockStore + Clone + 'static),
+        cache: &(impl Cache + Clone + 'static),
+        cancel: &CancellationToken,
+    ) -> impl Future<Output = Result<TransferSummary, Error>> + Send;
 
     /// Initiate a car mirror pull request to load some data from
     /// a server via HTTP.
@@ -56,13 +139,30 @@ pub trait RequestBuilderExt {
     /// This will call `try_clone()` and `send()` on this
     /// request builder, so it must not have a `body` set yet.
     /// There is no need to set a body, this function will do so automatically.
+    ///
+    /// Returns a `TransferSummary` of all rounds.
     fn run_car_mirror_pull(
         &self,
         root: Cid,
         config: &Config,
         store: &impl BlockStore,
         cache: &impl Cache,
-    ) -> impl Future<Output = Result<(), Error>> + Send;
+    ) -> impl Future<Output = Result<TransferSummary, Error>> + Send;
+
+    /// Like `run_car_mirror_pull`, but stops once `cancel` is cancelled,
+    /// e.g. when the user aborts a sync or the application shuts down.
+    ///
+    /// Cancelling aborts the request that's in flight and returns
+    /// `Error::Cancelled`. Blocks that were verified before that stay in
+    /// `store`, so a later pull picks up from there.
+    fn run_car_mirror_pull_cancellable(
+        &self,
+        root: Cid,
+        config: &Config,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+        cancel: &CancellationToken,
+    ) -> impl Future<Output = Result<TransferSummary, Error>> + Send;
 }
 
 impl RequestBuilderExt for reqwest_middleware::RequestBuilder {
@@ -71,9 +171,36 @@ impl RequestBuilderExt for reqwest_middleware::RequestBuilder {
         root: Cid,
         store: &(impl BlockStore + Clone + 'static),
         cache: &(impl Cache + Clone + 'static),
-    ) -> Result<(), Error> {
-        push_with(root, store, cache, |body| {
-            send_middleware_reqwest(self, body)
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, CAR_MEDIA_TYPE);
+        push_with_checkpoints(root, store, cache, None, move |body, checkpoint| {
+            sender.send(body, checkpoint)
+        })
+        .await
+    }
+
+    async fn run_car_mirror_push_verified(
+        &self,
+        root: Cid,
+        store: &(impl BlockStore + Clone + 'static),
+        cache: &(impl Cache + Clone + 'static),
+    ) -> Result<TransferSummary, Error> {
+        let summary = self.run_car_mirror_push(root, store, cache).await?;
+        let sender = &RequestSender::new(self, CAR_MEDIA_TYPE);
+        verify_push_with(root, store, move |body| sender.send(body, None)).await?;
+        Ok(summary)
+    }
+
+    async fn run_car_mirror_push_cancellable(
+        &self,
+        root: Cid,
+        store: &(impl BlockStore + Clone + 'static),
+        cache: &(impl Cache + Clone + 'static),
+        cancel: &CancellationToken,
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, CAR_MEDIA_TYPE);
+        push_with_checkpoints(root, store, cache, Some(cancel), move |body, checkpoint| {
+            sender.send(body, checkpoint)
         })
         .await
     }
@@ -84,25 +211,31 @@ impl RequestBuilderExt for reqwest_middleware::RequestBuilder {
         config: &Config,
         store: &impl BlockStore,
         cache: &impl Cache,
-    ) -> Result<(), Error> {
-        pull_with(root, config, store, cache, |body| {
-            send_middleware_reqwest(self, body)
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, DAG_CBOR_MEDIA_TYPE);
+        pull_with(root, config, store, cache, move |body| {
+            sender.send(body, None)
         })
         .await
     }
-}
 
-async fn send_middleware_reqwest(
-    builder: &reqwest_middleware::RequestBuilder,
-    body: reqwest::Body,
-) -> Result<Response, Error> {
-    Ok(builder
-        .try_clone()
-        .ok_or(Error::RequestBuilderBodyAlreadySet)?
-        .header("Content-Type", "application/vnd.ipld.dag-cbor")
-        .body(body)
-        .send()
-        .await?)
+    async fn run_car_mirror_pull_cancellable(
+        &self,
+        root: Cid,
+        config: &Config,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+        cancel: &CancellationToken,
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, DAG_CBOR_MEDIA_TYPE);
+        cancellable(
+            Some(cancel),
+            pull_with(root, config, store, cache, move |body| {
+                sender.send(body, None)
+            }),
+        )
+        .await
+    }
 }
 
 impl RequestBuilderExt for reqwest::RequestBuilder {
@@ -111,8 +244,38 @@ impl RequestBuilderExt for reqwest::RequestBuilder {
         root: Cid,
         store: &(impl BlockStore + Clone + 'static),
         cache: &(impl Cache + Clone + 'static),
-    ) -> Result<(), Error> {
-        push_with(root, store, cache, |body| send_reqwest(self, body)).await
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, CAR_MEDIA_TYPE);
+        push_with_checkpoints(root, store, cache, None, move |body, checkpoint| {
+            sender.send(body, checkpoint)
+        })
+        .await
+    }
+
+    async fn run_car_mirror_push_verified(
+        &self,
+        root: Cid,
+        store: &(impl BlockStore + Clone + 'static),
+        cache: &(impl Cache + Clone + 'static),
+    ) -> Result<TransferSummary, Error> {
+        let summary = self.run_car_mirror_push(root, store, cache).await?;
+        let sender = &RequestSender::new(self, CAR_MEDIA_TYPE);
+        verify_push_with(root, store, move |body| sender.send(body, None)).await?;
+        Ok(summary)
+    }
+
+    async fn run_car_mirror_push_cancellable(
+        &self,
+        root: Cid,
+        store: &(impl BlockStore + Clone + 'static),
+        cache: &(impl Cache + Clone + 'static),
+        cancel: &CancellationToken,
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, CAR_MEDIA_TYPE);
+        push_with_checkpoints(root, store, cache, Some(cancel), move |body, checkpoint| {
+            sender.send(body, checkpoint)
+        })
+        .await
     }
 
     async fn run_car_mirror_pull(
@@ -121,22 +284,126 @@ impl RequestBuilderExt for reqwest::RequestBuilder {
         config: &Config,
         store: &impl BlockStore,
         cache: &impl Cache,
-    ) -> Result<(), Error> {
-        pull_with(root, config, store, cache, |body| send_reqwest(self, body)).await
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, DAG_CBOR_MEDIA_TYPE);
+        pull_with(root, config, store, cache, move |body| {
+            sender.send(body, None)
+        })
+        .await
+    }
+
+    async fn run_car_mirror_pull_cancellable(
+        &self,
+        root: Cid,
+        config: &Config,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+        cancel: &CancellationToken,
+    ) -> Result<TransferSummary, Error> {
+        let sender = &RequestSender::new(self, DAG_CBOR_MEDIA_TYPE);
+        cancellable(
+            Some(cancel),
+            pull_with(root, config, store, cache, move |body| {
+                sender.send(body, None)
+            }),
+        )
+        .await
+    }
+}
+
+/// Sends the requests of a car mirror transfer, each built from the same
+/// request builder with a new body.
+struct RequestSender<'a, B> {
+    builder: &'a B,
+    /// The `Content-Type` to set, unless the builder sets one already
+    content_type: Option<&'static str>,
+}
+
+impl<'a, B: CarMirrorRequestBuilder> RequestSender<'a, B> {
+    /// Requests get given `content_type`, unless `builder` sets a `Content-Type`
+    /// already. Finding out builds a request, so it's only done once per transfer.
+    fn new(builder: &'a B, content_type: &'static str) -> Self {
+        let has_content_type = builder
+            .try_clone_builder()
+            .and_then(|builder| builder.build_request().ok())
+            .is_some_and(|request| request.headers().contains_key(CONTENT_TYPE));
+
+        Self {
+            builder,
+            content_type: (!has_content_type).then_some(content_type),
+        }
+    }
+
+    async fn send(&self, body: Body, checkpoint: Option<Vec<u8>>) -> Result<Response, Error> {
+        let mut builder = self
+            .builder
+            .try_clone_builder()
+            .ok_or(Error::RequestBuilderBodyAlreadySet)?;
+
+        if let Some(content_type) = self.content_type {
+            builder = builder.with_header(CONTENT_TYPE, content_type.to_string());
+        }
+
+        // Servers walk the DAG from the root again without a checkpoint
+        if let Some(checkpoint) =
+            checkpoint.filter(|checkpoint| checkpoint::fits_header(checkpoint))
+        {
+            builder = builder.with_header(
+                HeaderName::from_static(CHECKPOINT_HEADER),
+                checkpoint::to_header_value(&checkpoint),
+            );
+        }
+
+        builder.send_body(body).await
     }
 }
 
-async fn send_reqwest(
-    builder: &reqwest::RequestBuilder,
-    body: reqwest::Body,
-) -> Result<Response, Error> {
-    Ok(builder
-        .try_clone()
-        .ok_or(Error::RequestBuilderBodyAlreadySet)?
-        .header("Content-Type", "application/vnd.ipld.dag-cbor")
-        .body(body)
-        .send()
-        .await?)
+/// The parts of the request builders of reqwest and reqwest-middleware
+/// that `RequestSender` needs, since they don't share a trait.
+trait CarMirrorRequestBuilder: Sized {
+    fn try_clone_builder(&self) -> Option<Self>;
+
+    fn build_request(self) -> reqwest::Result<reqwest::Request>;
+
+    fn with_header(self, name: HeaderName, value: String) -> Self;
+
+    async fn send_body(self, body: Body) -> Result<Response, Error>;
+}
+
+impl CarMirrorRequestBuilder for reqwest::RequestBuilder {
+    fn try_clone_builder(&self) -> Option<Self> {
+        self.try_clone()
+    }
+
+    fn build_request(self) -> reqwest::Result<reqwest::Request> {
+        self.build()
+    }
+
+    fn with_header(self, name: HeaderName, value: String) -> Self {
+        self.header(name, value)
+    }
+
+    async fn send_body(self, body: Body) -> Result<Response, Error> {
+        Ok(self.body(body).send().await?)
+    }
+}
+
+impl CarMirrorRequestBuilder for reqwest_middleware::RequestBuilder {
+    fn try_clone_builder(&self) -> Option<Self> {
+        self.try_clone()
+    }
+
+    fn build_request(self) -> reqwest::Result<reqwest::Request> {
+        self.build()
+    }
+
+    fn with_header(self, name: HeaderName, value: String) -> Self {
+        self.header(name, value)
+    }
+
+    async fn send_body(self, body: Body) -> Result<Response, Error> {
+        Ok(self.body(body).send().await?)
+    }
 }
 
 /// Run (possibly multiple rounds of) the car mirror push protocol.
@@ -145,12 +412,25 @@ async fn send_reqwest(
 ///
 /// Unlike `run_car_mirror_push`, this allows customizing the
 /// request every time it gets built, e.g. to refresh authentication tokens.
+///
+/// Each round emits an info-level event with the `car_mirror::round` target,
+/// reporting request & response bytes and whether the round made progress.
+///
+/// The request body is streamed. Once the server answers, which it may do
+/// before the upload is finished, the rest of the upload is skipped.
+/// This works best with HTTP/2, where the server can answer mid-upload on the
+/// same connection. See the crate documentation for how to set that up.
+///
+/// Unlike `run_car_mirror_push`, this doesn't echo push checkpoints back
+/// to the server, since `make_request` only gets the request body.
+///
+/// Returns a `TransferSummary` of all rounds.
 pub async fn push_with<F, Fut, E>(
     root: Cid,
     store: &(impl BlockStore + Clone + 'static),
     cache: &(impl Cache + Clone + 'static),
     mut make_request: F,
-) -> Result<(), E>
+) -> Result<TransferSummary, E>
 where
     F: FnMut(reqwest::Body) -> Fut,
     Fut: Future<Output = Result<Response, E>>,
@@ -159,35 +439,130 @@ where
     E: From<reqwest::Error>,
     E: From<serde_ipld_dagcbor::DecodeError<Infallible>>,
 {
-    let mut push_state = None;
-
-    loop {
-        let car_stream =
-            car_mirror::push::request_streaming(root, push_state, store.clone(), cache.clone())
-                .await?;
-        let reqwest_stream = Body::wrap_stream(car_stream);
-
-        let response = make_request(reqwest_stream).await?.error_for_status()?;
-
-        match response.status() {
-            StatusCode::OK => {
-                return Ok(());
-            }
-            StatusCode::ACCEPTED => {
-                // We need to continue.
-            }
-            _ => {
-                // Some unexpected response code
-                return Err(Error::UnexpectedStatusCode { response }.into());
-            }
+    push_with_checkpoints(root, store, cache, None, |body, _| make_request(body)).await
+}
+
+/// Like `push_with`, but `make_request` also gets the checkpoint from the
+/// last push response, if any, which should be sent via `CHECKPOINT_HEADER`.
+///
+/// Stops with `Error::Cancelled` once `cancel` is cancelled, if given.
+async fn push_with_checkpoints<F, Fut, E>(
+    root: Cid,
+    store: &(impl BlockStore + Clone + 'static),
+    cache: &(impl Cache + Clone + 'static),
+    cancel: Option<&CancellationToken>,
+    make_request: F,
+) -> Result<TransferSummary, E>
+where
+    F: FnMut(reqwest::Body, Option<Vec<u8>>) -> Fut,
+    Fut: Future<Output = Result<Response, E>>,
+    E: From<Error>,
+    E: From<car_mirror::Error>,
+    E: From<reqwest::Error>,
+    E: From<serde_ipld_dagcbor::DecodeError<Infallible>>,
+{
+    let start = Instant::now();
+    let stats = cancellable(
+        cancel,
+        drive_push(
+            root,
+            &Config::default(),
+            &mut ReqwestTransport { make_request },
+            store.clone(),
+            cache.clone(),
+        ),
+    )
+    .await?;
+    Ok(TransferSummary {
+        stats,
+        duration: start.elapsed(),
+    })
+}
+
+/// Runs `transfer` until it finishes, or until `cancel` is cancelled,
+/// in which case it's dropped and `Error::Cancelled` is returned.
+///
+/// Dropping the transfer aborts any request that's in flight, including
+/// its streaming body, so this stops promptly even mid-round.
+async fn cancellable<T, E: From<Error>>(
+    cancel: Option<&CancellationToken>,
+    transfer: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let Some(cancel) = cancel else {
+        return transfer.await;
+    };
+
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled.into());
+    }
+
+    match future::select(pin!(transfer), pin!(cancel.cancelled())).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => {
+            tracing::debug!("Transfer cancelled");
+            Err(Error::Cancelled.into())
         }
+    }
+}
 
-        let response_bytes = response.bytes().await?;
+/// Ask the server to confirm it has the complete DAG under `root`,
+/// usually after a finished push protocol run.
+///
+/// See `run_car_mirror_push_verified` for a more ergonomic interface.
+///
+/// This sends a push request containing only the root block, which
+/// makes the server re-check which blocks below the root it has.
+/// Only the root block needs to be available in `store`.
+///
+/// Returns `Error::IncompleteDag` if the server answers with any
+/// missing subgraph roots.
+pub async fn verify_push_with<F, Fut, E>(
+    root: Cid,
+    store: &impl BlockStore,
+    mut make_request: F,
+) -> Result<(), E>
+where
+    F: FnMut(reqwest::Body) -> Fut,
+    Fut: Future<Output = Result<Response, E>>,
+    E: From<Error>,
+    E: From<car_mirror::Error>,
+    E: From<reqwest::Error>,
+    E: From<serde_ipld_dagcbor::DecodeError<Infallible>>,
+{
+    let root_block = store
+        .get_block(&root)
+        .await
+        .map_err(car_mirror::Error::BlockStoreError)?;
+
+    let block_stream = futures::stream::once(async move { Ok((root, root_block)) }).boxed();
+    let car_stream = stream_car_frames(block_stream).await?;
 
-        let push_response = PushResponse::from_dag_cbor(&response_bytes)?;
+    let response = make_request(Body::wrap_stream(car_stream))
+        .await?
+        .error_for_status()?;
 
-        push_state = Some(push_response);
+    match response.status() {
+        StatusCode::OK | StatusCode::ACCEPTED => {}
+        _ => return Err(Error::UnexpectedStatusCode { response }.into()),
     }
+
+    let response_bytes = response.bytes().await?;
+    let push_response = PushResponse::from_dag_cbor(&response_bytes)?;
+
+    if !push_response.indicates_finished() {
+        tracing::warn!(
+            %root,
+            num_missing = push_response.subgraph_roots.len(),
+            "Server didn't confirm having the complete DAG"
+        );
+        return Err(Error::IncompleteDag {
+            root,
+            missing_subgraph_roots: push_response.subgraph_roots,
+        }
+        .into());
+    }
+
+    Ok(())
 }
 
 /// Run (possibly multiple rounds of) the car mirror pull protocol.
@@ -197,34 +572,83 @@ where
 /// Unlike `run_car_mirror_pull`, this allows customizing the
 /// request every time it gets built, e.g. to refresh authentication tokens.
 ///
+/// Each round emits an info-level event with the `car_mirror::round` target,
+/// reporting request & response bytes and whether the round made progress.
+///
 /// **Important:** Don't forget to set the `Content-Type` header to
-/// `application/vnd.ipld.dag-cbor` on your requests.
+/// `DAG_CBOR_MEDIA_TYPE` on your requests.
+///
+/// Returns a `TransferSummary` of all rounds.
 pub async fn pull_with<F, Fut, E>(
     root: Cid,
     config: &Config,
     store: &impl BlockStore,
     cache: &impl Cache,
     mut make_request: F,
-) -> Result<(), E>
+) -> Result<TransferSummary, E>
 where
     F: FnMut(reqwest::Body) -> Fut,
     Fut: Future<Output = Result<Response, E>>,
+    E: From<Error>,
     E: From<car_mirror::Error>,
     E: From<reqwest::Error>,
     E: From<serde_ipld_dagcbor::EncodeError<TryReserveError>>,
 {
-    let mut pull_request = car_mirror::pull::request(root, None, config, store, cache).await?;
+    let start = Instant::now();
+    let transport = &mut ReqwestTransport {
+        make_request: |body: Body, _: Option<Vec<u8>>| make_request(body),
+    };
+    let stats = drive_pull(root, config, transport, store, cache).await?;
+    Ok(TransferSummary {
+        stats,
+        duration: start.elapsed(),
+    })
+}
+
+/// A car mirror `Transport` that sends a request for each round via `make_request`.
+struct ReqwestTransport<F> {
+    make_request: F,
+}
+
+impl<F, Fut, E> Transport for ReqwestTransport<F>
+where
+    F: FnMut(reqwest::Body, Option<Vec<u8>>) -> Fut,
+    Fut: Future<Output = Result<Response, E>>,
+    E: From<Error>,
+    E: From<reqwest::Error>,
+{
+    type Error = E;
 
-    while !pull_request.indicates_finished() {
-        let answer = make_request(pull_request.to_dag_cbor()?.into())
+    async fn send_push_round(
+        &mut self,
+        _root: Cid,
+        request: CarStream<'static>,
+        checkpoint: Option<Vec<u8>>,
+    ) -> Result<Bytes, E> {
+        let response = (self.make_request)(Body::wrap_stream(request), checkpoint)
             .await?
             .error_for_status()?;
 
-        let stream = StreamReader::new(answer.bytes_stream().map_err(std::io::Error::other));
+        let status = response.status();
+        if status != StatusCode::OK && status != StatusCode::ACCEPTED {
+            // Some unexpected response code
+            return Err(Error::UnexpectedStatusCode { response }.into());
+        }
 
-        pull_request =
-            car_mirror::pull::handle_response_streaming(root, stream, config, store, cache).await?;
+        Ok(response.bytes().await?)
     }
 
-    Ok(())
+    async fn recv_pull_round(
+        &mut self,
+        _root: Cid,
+        request: Bytes,
+    ) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, E> {
+        let response = (self.make_request)(request.into(), None)
+            .await?
+            .error_for_status()?;
+
+        Ok(boxed_stream(
+            response.bytes_stream().map_err(std::io::Error::other),
+        ))
+    }
 }
@@ -1,10 +1,40 @@
 //! A copy of the doctest in lib.rs, because code coverage is buggy
 //! with doctests.
-use car_mirror::{cache::NoCache, common::Config};
-use car_mirror_reqwest::RequestBuilderExt;
-use reqwest::Client;
-use testresult::TestResult;
-use wnfs_common::{BlockStore, MemoryBlockStore, CODEC_RAW};
+use bytes::Bytes;
+use car_mirror::{
+    cache::NoCache,
+    checkpoint::{self, CheckpointKey, CHECKPOINT_HEADER},
+    common::{compute_missing, Config},
+    incremental_verification::UnrelatedRootsLogSampler,
+    messages::PushResponse,
+};
+#[cfg(feature = "kubo")]
+use car_mirror_reqwest::KuboBlockStore;
+use car_mirror_reqwest::{push_with, Error, LazyBlockStore, RequestBuilderExt, CAR_MEDIA_TYPE};
+use libipld::{
+    cbor::DagCborCodec,
+    multihash::{Code, MultihashDigest},
+    Cid, Ipld,
+};
+use reqwest::{
+    header::{ACCEPT, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    multipart::{Form, Part},
+    Client, StatusCode,
+};
+use std::{
+    future::IntoFuture,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+};
+use testresult::{TestError, TestResult};
+use tokio_util::sync::CancellationToken;
+use wnfs_common::{
+    encode, utils::CondSend, BlockStore, BlockStoreError, MemoryBlockStore, CODEC_RAW,
+};
+use wnfs_unixfs_file::builder::FileBuilder;
 
 #[test_log::test(tokio::test)]
 async fn test_car_mirror_reqwest_axum_integration() -> TestResult {
@@ -15,17 +45,1181 @@ async fn test_car_mirror_reqwest_axum_integration() -> TestResult {
     let root = store.put_block(data, CODEC_RAW).await?;
 
     let client = Client::new();
-    client
+    let push_summary = client
         .post(format!("http://localhost:3344/dag/push/{root}"))
         .run_car_mirror_push(root, &store, &NoCache)
         .await?;
 
     let store = MemoryBlockStore::new(); // clear out data
-    client
+    let pull_summary = client
         .post(format!("http://localhost:3344/dag/pull/{root}"))
         .run_car_mirror_pull(root, &Config::default(), &store, &NoCache)
         .await?;
 
     assert!(store.has_block(&root).await?);
+
+    assert_eq!(push_summary.stats.rounds, 1);
+    assert_eq!(push_summary.stats.blocks_transferred, 1);
+    assert!(push_summary.stats.bytes_sent > 0);
+    assert!(push_summary.stats.bytes_received > 0);
+
+    assert_eq!(pull_summary.stats.rounds, 1);
+    assert_eq!(pull_summary.stats.blocks_transferred, 1);
+    assert!(pull_summary.stats.bytes_sent > 0);
+    assert!(pull_summary.stats.bytes_received > 0);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_reqwest_push_verified() -> TestResult {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(
+        axum::serve(listener, car_mirror_axum::app(MemoryBlockStore::new())).into_future(),
+    );
+
+    let store = MemoryBlockStore::new();
+    let data = b"Hello, verified world!".to_vec();
+    let root = store.put_block(data, CODEC_RAW).await?;
+
+    Client::new()
+        .post(format!("http://{addr}/dag/push/{root}"))
+        .run_car_mirror_push_verified(root, &store, &NoCache)
+        .await?;
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_byte_range() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let data = b"Hello, resumable world!".to_vec();
+    let root = store.put_block(data, CODEC_RAW).await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(store)).into_future());
+
+    let client = Client::new();
+    let url = format!("http://{addr}/dag/pull/{root}");
+
+    let full = client.get(&url).send().await?.error_for_status()?;
+    assert_eq!(full.headers()[ACCEPT_RANGES], "bytes");
+    let full = full.bytes().await?;
+
+    let partial = client.get(&url).header(RANGE, "bytes=10-").send().await?;
+    assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        partial.headers()[CONTENT_RANGE],
+        format!("bytes 10-{}/{}", full.len() - 1, full.len())
+    );
+    assert_eq!(partial.bytes().await?, full.slice(10..));
+
+    let unsatisfiable = client
+        .get(&url)
+        .header(RANGE, format!("bytes={}-", full.len()))
+        .send()
+        .await?;
+    assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        unsatisfiable.headers()[CONTENT_RANGE],
+        format!("bytes */{}", full.len())
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_byte_range_too_large() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let root = FileBuilder::new()
+        .content_bytes(vec![42; 3_000_000])
+        .build()?
+        .store(&store)
+        .await?;
+
+    // Ranged responses larger than this aren't buffered
+    let state = car_mirror_axum::ServerState::new(store).with_config(Config {
+        receive_maximum: 1_000_000,
+        ..Config::default()
+    });
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::new();
+    let url = format!("http://{addr}/dag/pull/{root}");
+
+    let full = client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    assert!(full.len() > 1_000_000);
+
+    // The range is ignored and the whole response is sent instead
+    let response = client.get(&url).header(RANGE, "bytes=10-").send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(CONTENT_RANGE).is_none());
+    assert_eq!(response.bytes().await?, full);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_head() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let complete_root = store.put_block(b"leaf".to_vec(), CODEC_RAW).await?;
+    let missing = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(b"missing"));
+    let incomplete_root = store
+        .put_block(
+            encode(&Ipld::List(vec![Ipld::Link(missing)]), DagCborCodec)?,
+            DagCborCodec.into(),
+        )
+        .await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(store)).into_future());
+
+    let client = Client::new();
+    let head = |root: Cid| client.head(format!("http://{addr}/dag/pull/{root}")).send();
+
+    let response = head(complete_root).await?.error_for_status()?;
+    assert_eq!(response.headers()[car_mirror_axum::COMPLETE_HEADER], "true");
+    assert_eq!(response.headers()[CONTENT_TYPE], CAR_MEDIA_TYPE);
+    assert!(response.bytes().await?.is_empty());
+
+    let response = head(incomplete_root).await?.error_for_status()?;
+    assert_eq!(
+        response.headers()[car_mirror_axum::COMPLETE_HEADER],
+        "false"
+    );
+
+    let response = head(missing).await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Pushing the missing block invalidates the cached result
+    let client_store = MemoryBlockStore::new();
+    client_store
+        .put_block(b"missing".to_vec(), CODEC_RAW)
+        .await?;
+    client_store
+        .put_block(
+            encode(&Ipld::List(vec![Ipld::Link(missing)]), DagCborCodec)?,
+            DagCborCodec.into(),
+        )
+        .await?;
+    client
+        .post(format!("http://{addr}/dag/push/{incomplete_root}"))
+        .run_car_mirror_push(incomplete_root, &client_store, &NoCache)
+        .await?;
+
+    let response = head(incomplete_root).await?.error_for_status()?;
+    assert_eq!(response.headers()[car_mirror_axum::COMPLETE_HEADER], "true");
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_head_max_concurrent_transfers() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let root = store.put_block(b"leaf".to_vec(), CODEC_RAW).await?;
+
+    let state = car_mirror_axum::ServerState::new(store).with_max_concurrent_transfers(0);
+    let router = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, router).into_future());
+
+    let response = Client::new()
+        .head(format!("http://{addr}/dag/pull/{root}"))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_media_types() -> TestResult {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(
+        axum::serve(listener, car_mirror_axum::app(MemoryBlockStore::new())).into_future(),
+    );
+
+    let store = MemoryBlockStore::new();
+    let data = b"Hello, media typed world!".to_vec();
+    let root = store.put_block(data, CODEC_RAW).await?;
+
+    let client = Client::new();
+    let push_url = format!("http://{addr}/dag/push/{root}");
+
+    // A Content-Type that's already set on the builder is kept
+    client
+        .post(&push_url)
+        .header(CONTENT_TYPE, "application/vnd.ipld.car")
+        .run_car_mirror_push(root, &store, &NoCache)
+        .await?;
+
+    let result = client
+        .post(&push_url)
+        .header(CONTENT_TYPE, "text/plain")
+        .run_car_mirror_push(root, &store, &NoCache)
+        .await;
+    assert!(
+        matches!(&result, Err(Error::ReqwestError(err)) if err.status() == Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)),
+        "expected 415, got {result:?}"
+    );
+
+    let pull_response = client
+        .get(format!("http://{addr}/dag/pull/{root}"))
+        .send()
+        .await?
+        .error_for_status()?;
+    assert_eq!(pull_response.headers()[CONTENT_TYPE], CAR_MEDIA_TYPE);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_push_multipart() -> TestResult {
+    let server_store = MemoryBlockStore::new();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(server_store.clone())).into_future());
+
+    let store = MemoryBlockStore::new();
+    let data = b"Hello, form-based world!".to_vec();
+    let root = store.put_block(data, CODEC_RAW).await?;
+    let car = car_mirror::push::request(root, None, &Config::default(), &store, &NoCache).await?;
+
+    let client = Client::new();
+    let push_url = format!("http://{addr}/dag/push/{root}");
+
+    // Other fields before the CAR file are skipped
+    let form = Form::new().text("comment", "ignored").part(
+        car_mirror_axum::CAR_FORM_FIELD,
+        Part::bytes(car.bytes.to_vec())
+            .file_name("dag.car")
+            .mime_str(CAR_MEDIA_TYPE)?,
+    );
+    let response = client.post(&push_url).multipart(form).send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(server_store.has_block(&root).await?);
+
+    let form = Form::new().text("comment", "no CAR file");
+    let response = client.post(&push_url).multipart(form).send().await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_push_multipart_large() -> TestResult {
+    let data: Vec<u8> = (0..5_000_000u32).map(|i| (i % 251) as u8).collect();
+    let store = MemoryBlockStore::new();
+    let root = FileBuilder::new()
+        .content_bytes(data)
+        .build()?
+        .store(&store)
+        .await?;
+    let config = &Config::builder().receive_maximum(16_000_000).build()?;
+    let car = car_mirror::push::request(root, None, config, &store, &NoCache).await?;
+    assert!(car.bytes.len() > 4_000_000);
+
+    // Multipart bodies are limited according to the server's `receive_maximum`
+    let servers = [
+        (car_mirror_axum::app(MemoryBlockStore::new()), false),
+        (
+            axum::Router::new().nest(
+                "/dag",
+                car_mirror_axum::dag_router_with_state(
+                    car_mirror_axum::ServerState::new(MemoryBlockStore::new())
+                        .with_config(config.clone()),
+                ),
+            ),
+            true,
+        ),
+    ];
+    for (router, accepts) in servers {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(axum::serve(listener, router).into_future());
+
+        let form = Form::new().part(
+            car_mirror_axum::CAR_FORM_FIELD,
+            Part::bytes(car.bytes.to_vec())
+                .file_name("dag.car")
+                .mime_str(CAR_MEDIA_TYPE)?,
+        );
+        // Rejected uploads may also fail with the connection closing early
+        let accepted = Client::new()
+            .post(format!("http://{addr}/dag/push/{root}"))
+            .multipart(form)
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success());
+        assert_eq!(accepted, accepts);
+    }
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_json_debug() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let data = b"Hello, debuggable world!".to_vec();
+    let root = store.put_block(data, CODEC_RAW).await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(store.clone())).into_future());
+
+    let client = Client::new();
+
+    let pull_response = client
+        .post(format!("http://{addr}/dag/pull/{root}"))
+        .header(CONTENT_TYPE, "application/json")
+        .body(format!(r#"{{"bb":"","bk":3,"rs":["{root}"]}}"#))
+        .send()
+        .await?
+        .error_for_status()?;
+    assert_eq!(pull_response.headers()[CONTENT_TYPE], CAR_MEDIA_TYPE);
+
+    let car = car_mirror::push::request(root, None, &Config::default(), &store, &NoCache).await?;
+    let push_response = client
+        .post(format!("http://{addr}/dag/push/{root}"))
+        .header(CONTENT_TYPE, CAR_MEDIA_TYPE)
+        .header(ACCEPT, "application/json")
+        .body(car.bytes)
+        .send()
+        .await?
+        .error_for_status()?;
+    assert_eq!(push_response.headers()[CONTENT_TYPE], "application/json");
+    assert!(push_response.text().await?.contains(r#""sr":[]"#));
+
+    Ok(())
+}
+
+/// A block store that counts how many bytes were read from it.
+#[derive(Debug, Clone, Default)]
+struct ReadCountingBlockStore {
+    store: MemoryBlockStore,
+    bytes_read: Arc<AtomicUsize>,
+}
+
+impl BlockStore for ReadCountingBlockStore {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        let bytes = self.store.get_block(cid).await?;
+        self.bytes_read.fetch_add(bytes.len(), Ordering::SeqCst);
+        Ok(bytes)
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        self.store.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.store.has_block(cid).await
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_push_interrupted_over_http2() -> TestResult {
+    // Deterministic data that doesn't repeat within a chunk
+    let data: Vec<u8> = (0..10_000_000u32).map(|i| (i % 251) as u8).collect();
+
+    // The server already has the first couple of chunks of the file
+    let server_store = MemoryBlockStore::new();
+    FileBuilder::new()
+        .content_bytes(data[..1_000_000].to_vec())
+        .build()?
+        .store(&server_store)
+        .await?;
+
+    let store = ReadCountingBlockStore::default();
+    let root = FileBuilder::new()
+        .content_bytes(data.clone())
+        .build()?
+        .store(&store)
+        .await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(server_store.clone())).into_future());
+
+    // Speak HTTP/2 without TLS, so the server can interrupt the upload
+    // and answer on the same connection.
+    let client = &Client::builder().http2_prior_knowledge().build()?;
+    let read_at_first_response = &OnceLock::new();
+    let counting_store = &store;
+    push_with(root, &store, &NoCache, |body| async move {
+        let response = client
+            .post(format!("http://{addr}/dag/push/{root}"))
+            .header(CONTENT_TYPE, CAR_MEDIA_TYPE)
+            .body(body)
+            .send()
+            .await?;
+        read_at_first_response.get_or_init(|| counting_store.bytes_read.load(Ordering::SeqCst));
+        Ok::<_, Error>(response)
+    })
+    .await?;
+
+    // The server answers the first round once it sees the first block it
+    // already has, long before the client produced the whole round's body.
+    let read_at_first_response = *read_at_first_response
+        .get()
+        .expect("The push should take at least one round");
+    assert!(
+        read_at_first_response < data.len() / 2,
+        "Read {read_at_first_response} bytes of a {} byte file before the first response",
+        data.len()
+    );
+
+    // And after being interrupted, we shouldn't have read the whole file twice.
+    let bytes_read = store.bytes_read.load(Ordering::SeqCst);
+    assert!(
+        bytes_read < data.len() * 3 / 2,
+        "Read {bytes_read} bytes for a {} byte file",
+        data.len()
+    );
+
+    let pulled_store = MemoryBlockStore::new();
+    client
+        .post(format!("http://{addr}/dag/pull/{root}"))
+        .run_car_mirror_pull(root, &Config::default(), &pulled_store, &NoCache)
+        .await?;
+    assert!(pulled_store.has_block(&root).await?);
+
+    Ok(())
+}
+
+/// Runs a single push round against the server at `addr`.
+async fn push_round(
+    client: &Client,
+    addr: SocketAddr,
+    root: Cid,
+    last_response: Option<PushResponse>,
+    config: &Config,
+    store: &impl BlockStore,
+) -> TestResult<PushResponse> {
+    let car = car_mirror::push::request(root, last_response, config, store, &NoCache).await?;
+    let response_bytes = client
+        .post(format!("http://{addr}/dag/push/{root}"))
+        .header(CONTENT_TYPE, CAR_MEDIA_TYPE)
+        .body(car.bytes)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(PushResponse::from_dag_cbor(response_bytes)?)
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_push_resumes_after_server_restart() -> TestResult {
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let client_store = MemoryBlockStore::new();
+    let root = FileBuilder::new()
+        .content_bytes(data)
+        .build()?
+        .store(&client_store)
+        .await?;
+    // Small rounds, so the push needs several of them
+    let config = &Config::builder().receive_maximum(300_000).build()?;
+
+    // The server's store persists blocks across restarts
+    let server_store = MemoryBlockStore::new();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(
+        axum::serve(listener, car_mirror_axum::app(server_store.clone())).into_future(),
+    );
+
+    let mut response = push_round(&Client::new(), addr, root, None, config, &client_store).await?;
+    assert!(!response.indicates_finished());
+
+    // The server restarts mid-push, with fresh in-memory state
+    server.abort();
+    let _ = server.await;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(server_store.clone())).into_future());
+
+    // The client continues from the last response it got before the restart
+    let client = Client::new();
+    let mut rounds = 0;
+    while !response.indicates_finished() {
+        rounds += 1;
+        assert!(rounds < 20, "push didn't converge");
+        response = push_round(&client, addr, root, Some(response), config, &client_store).await?;
+    }
+
+    assert!(compute_missing(root, &server_store, &NoCache)
+        .await?
+        .is_empty());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_push_policy() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let accepted = store.put_block(b"accepted".to_vec(), CODEC_RAW).await?;
+    let rejected = store.put_block(b"rejected".to_vec(), CODEC_RAW).await?;
+
+    let state =
+        car_mirror_axum::ServerState::new(MemoryBlockStore::new()).with_push_policy(move |cid| {
+            if cid == rejected {
+                car_mirror_axum::PushDecision::Reject
+            } else {
+                car_mirror_axum::PushDecision::Accept
+            }
+        });
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::new();
+    client
+        .post(format!("http://{addr}/dag/push/{accepted}"))
+        .run_car_mirror_push(accepted, &store, &NoCache)
+        .await?;
+
+    let result = client
+        .post(format!("http://{addr}/dag/push/{rejected}"))
+        .run_car_mirror_push(rejected, &store, &NoCache)
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::ReqwestError(e)) if e.status() == Some(StatusCode::CONFLICT)
+    ));
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_push_checkpoints() -> TestResult {
+    let data: Vec<u8> = (0..5_000_000u32).map(|i| (i % 251) as u8).collect();
+
+    // The server already has the first chunks of the file, so the first
+    // round gets interrupted and the second one resumes from a checkpoint.
+    let server_store = MemoryBlockStore::new();
+    FileBuilder::new()
+        .content_bytes(data[..1_000_000].to_vec())
+        .build()?
+        .store(&server_store)
+        .await?;
+
+    let store = MemoryBlockStore::new();
+    let root = FileBuilder::new()
+        .content_bytes(data)
+        .build()?
+        .store(&store)
+        .await?;
+
+    let state = car_mirror_axum::ServerState::new(server_store)
+        .with_checkpoint_key(CheckpointKey::new([1; 32]));
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::builder().http2_prior_knowledge().build()?;
+    let url = format!("http://{addr}/dag/push/{root}");
+    let summary = client
+        .post(&url)
+        .run_car_mirror_push_verified(root, &store, &NoCache)
+        .await?;
+    assert!(summary.stats.rounds > 1);
+
+    // Forged checkpoints are rejected
+    let response = client
+        .post(&url)
+        .header(CHECKPOINT_HEADER, checkpoint::to_header_value(&[0; 64]))
+        .body(Vec::new())
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_max_concurrent_transfers() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let root = store
+        .put_block(b"Hello, busy world!".to_vec(), CODEC_RAW)
+        .await?;
+
+    // The push policy is checked once the push got its permit
+    let push_started = Arc::new(tokio::sync::Notify::new());
+    let state = car_mirror_axum::ServerState::new(store.clone())
+        .with_max_concurrent_transfers(1)
+        .with_push_policy({
+            let push_started = Arc::clone(&push_started);
+            move |_| {
+                push_started.notify_one();
+                car_mirror_axum::PushDecision::Accept
+            }
+        });
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    // A push that never sends any data occupies the only permit
+    let client = Client::new();
+    let stalled_push = tokio::spawn(
+        client
+            .post(format!("http://{addr}/dag/push/{root}"))
+            .body(reqwest::Body::wrap_stream(futures::stream::pending::<
+                Result<Bytes, std::io::Error>,
+            >()))
+            .send(),
+    );
+    push_started.notified().await;
+
+    let pull = || client.get(format!("http://{addr}/dag/pull/{root}")).send();
+    assert_eq!(pull().await?.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // Once the server noticed that the push is gone, pulls work again
+    stalled_push.abort();
+    let _ = stalled_push.await;
+    let response = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        loop {
+            let response = pull().await?;
+            if response.status() != StatusCode::SERVICE_UNAVAILABLE {
+                return Ok::<_, reqwest::Error>(response);
+            }
+            tokio::task::yield_now().await;
+        }
+    })
+    .await??;
+    assert_eq!(response.status(), StatusCode::OK);
+    response.bytes().await?;
+
+    Ok(())
+}
+
+/// A block store with slow reads that tracks how many reads run at once.
+#[derive(Debug, Clone, Default)]
+struct SlowBlockStore {
+    store: MemoryBlockStore,
+    active_reads: Arc<AtomicUsize>,
+    max_active_reads: Arc<AtomicUsize>,
+}
+
+impl BlockStore for SlowBlockStore {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        let active = self.active_reads.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_active_reads.fetch_max(active, Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let result = self.store.get_block(cid).await;
+        self.active_reads.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        self.store.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.store.has_block(cid).await
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_max_concurrent_pulls() -> TestResult {
+    let store = SlowBlockStore::default();
+    let root = FileBuilder::new()
+        .content_bytes(vec![42; 1_000_000])
+        .build()?
+        .store(&store.store)
+        .await?;
+
+    let serve = |state: car_mirror_axum::ServerState<SlowBlockStore>| async move {
+        let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(axum::serve(listener, app).into_future());
+        Ok::<_, TestError>(addr)
+    };
+
+    // A herd of pulls is queued, so only two responses are generated at once
+    let state = car_mirror_axum::ServerState::new(store.clone())
+        .with_max_concurrent_pulls(2, std::time::Duration::from_secs(60));
+    let addr = serve(state).await?;
+
+    let client = Client::new();
+    let pulls = (0..16).map(|_| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let store = MemoryBlockStore::new();
+            client
+                .post(format!("http://{addr}/dag/pull/{root}"))
+                .run_car_mirror_pull(root, &Config::default(), &store, &NoCache)
+                .await?;
+            Ok::<_, TestError>(compute_missing(root, &store, NoCache).await?)
+        })
+    });
+    for pull in futures::future::join_all(pulls).await {
+        assert!(pull??.is_empty());
+    }
+    assert!(store.max_active_reads.load(Ordering::SeqCst) <= 2);
+
+    // Without waiting, excess pulls are rejected
+    let state = car_mirror_axum::ServerState::new(store.clone())
+        .with_max_concurrent_pulls(1, std::time::Duration::ZERO);
+    let addr = serve(state).await?;
+
+    let first = client
+        .get(format!("http://{addr}/dag/pull/{root}"))
+        .send()
+        .await?;
+    assert_eq!(first.status(), StatusCode::OK);
+    let second = client
+        .get(format!("http://{addr}/dag/pull/{root}"))
+        .send()
+        .await?;
+    assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+    first.bytes().await?;
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_reqwest_cancel() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let root = store
+        .put_block(b"Hello, impatient world!".to_vec(), CODEC_RAW)
+        .await?;
+
+    // A server that accepts connections, but never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let client = Client::new();
+    let cancel = CancellationToken::new();
+    let push_url = format!("http://{addr}/dag/push/{root}");
+    let pull_url = format!("http://{addr}/dag/pull/{root}");
+
+    // Cancel once the request reached the server
+    let cancel_soon = cancel.clone();
+    let server = tokio::spawn(async move {
+        let (connection, _) = listener.accept().await?;
+        cancel_soon.cancel();
+        Ok::<_, std::io::Error>((listener, connection))
+    });
+
+    let result = client
+        .post(&push_url)
+        .run_car_mirror_push_cancellable(root, &store, &NoCache, &cancel)
+        .await;
+    assert!(matches!(result, Err(Error::Cancelled)));
+
+    // Already cancelled tokens stop transfers before they start
+    let result = client
+        .post(&pull_url)
+        .run_car_mirror_pull_cancellable(
+            root,
+            &Config::default(),
+            &MemoryBlockStore::new(),
+            &NoCache,
+            &cancel,
+        )
+        .await;
+    assert!(matches!(result, Err(Error::Cancelled)));
+
+    drop(server.await??);
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_response_cache() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let leaf_data = b"Hello, popular world!".to_vec();
+    let leaf = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(&leaf_data));
+    let root = store
+        .put_block(
+            encode(&Ipld::List(vec![Ipld::Link(leaf)]), DagCborCodec)?,
+            DagCborCodec.into(),
+        )
+        .await?;
+
+    let state = car_mirror_axum::ServerState::new(store.clone())
+        .with_pull_response_cache(1_000_000, std::time::Duration::from_secs(60));
+    let app = axum::Router::new().nest(
+        "/dag",
+        car_mirror_axum::dag_router_with_state(state.clone()),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::new();
+    let pull = || async {
+        client
+            .get(format!("http://{addr}/dag/pull/{root}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    };
+
+    let root_only = pull().await?;
+
+    // The store changed behind the server's back, so the cached response is served
+    store.put_block(leaf_data, CODEC_RAW).await?;
+    assert_eq!(pull().await?, root_only);
+
+    // Until the server is told about the change
+    state.notify_store_changed();
+    let complete = pull().await?;
+    assert!(complete.len() > root_only.len());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_response_cache_too_large() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let leaf_data = b"Hello, oversized world!".to_vec();
+    let leaf = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(&leaf_data));
+    let root = store
+        .put_block(
+            encode(&Ipld::List(vec![Ipld::Link(leaf)]), DagCborCodec)?,
+            DagCborCodec.into(),
+        )
+        .await?;
+
+    // Smaller than any response
+    let state = car_mirror_axum::ServerState::new(store.clone())
+        .with_pull_response_cache(10, std::time::Duration::from_secs(60));
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::new();
+    let pull = || async {
+        client
+            .get(format!("http://{addr}/dag/pull/{root}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    };
+
+    let root_only = pull().await?;
+
+    // The response wasn't cached, so the change in the store shows right away
+    store.put_block(leaf_data, CODEC_RAW).await?;
+    let complete = pull().await?;
+    assert!(complete.len() > root_only.len());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_pull_response_cache_failed_push() -> TestResult {
+    let leaf_data = b"Hello, popular world!".to_vec();
+    let leaf = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(&leaf_data));
+    let root_block = encode(&Ipld::List(vec![Ipld::Link(leaf)]), DagCborCodec)?;
+
+    let server_store = MemoryBlockStore::new();
+    let root = server_store
+        .put_block(root_block, DagCborCodec.into())
+        .await?;
+    let state = car_mirror_axum::ServerState::new(server_store)
+        .with_pull_response_cache(1_000_000, std::time::Duration::from_secs(60));
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::new();
+    let pull = || async {
+        client
+            .get(format!("http://{addr}/dag/pull/{root}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    };
+
+    let root_only = pull().await?;
+
+    // Push another DAG that shares the leaf, but whose last block is corrupted
+    let client_store = MemoryBlockStore::new();
+    client_store.put_block(leaf_data, CODEC_RAW).await?;
+    let other_leaf = client_store
+        .put_block(b"Hello, other world!".to_vec(), CODEC_RAW)
+        .await?;
+    let other_root = client_store
+        .put_block(
+            encode(
+                &Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(other_leaf)]),
+                DagCborCodec,
+            )?,
+            DagCborCodec.into(),
+        )
+        .await?;
+    let car = car_mirror::push::request(
+        other_root,
+        None,
+        &Config::default(),
+        &client_store,
+        &NoCache,
+    )
+    .await?;
+    let mut car_bytes = car.bytes.to_vec();
+    let last = car_bytes.len() - 1;
+    car_bytes[last] ^= 1;
+
+    let response = client
+        .post(format!("http://{addr}/dag/push/{other_root}"))
+        .header(CONTENT_TYPE, CAR_MEDIA_TYPE)
+        .body(car_bytes)
+        .send()
+        .await?;
+    assert!(!response.status().is_success());
+
+    // The push failed, but it stored the leaf before, so the cached response is outdated
+    let complete = pull().await?;
+    assert!(complete.len() > root_only.len());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_car_mirror_axum_unrelated_roots_sampled_across_requests() -> TestResult {
+    let store = MemoryBlockStore::new();
+    let root = store
+        .put_block(b"Hello, probed world!".to_vec(), CODEC_RAW)
+        .await?;
+    let unrelated = store
+        .put_block(b"Hello, secret world!".to_vec(), CODEC_RAW)
+        .await?;
+
+    let sampler = Arc::new(UnrelatedRootsLogSampler::new(10));
+    let state = car_mirror_axum::ServerState::new(store).with_config(Config {
+        verification_observer: Some(sampler.clone()),
+        ..Config::default()
+    });
+    let app = axum::Router::new().nest("/dag", car_mirror_axum::dag_router_with_state(state));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = Client::new();
+    for _ in 0..3 {
+        client
+            .post(format!("http://{addr}/dag/pull/{root}"))
+            .header(CONTENT_TYPE, "application/json")
+            .body(format!(
+                r#"{{"bb":"","bk":3,"rs":["{root}","{unrelated}"]}}"#
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    // Every request reached the same observer, the one from the server's config
+    assert_eq!(sampler.requests(), 3);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_lazy_block_store() -> TestResult {
+    let server_store = MemoryBlockStore::new();
+    let root = FileBuilder::new()
+        .content_bytes(vec![42; 1_000_000])
+        .build()?
+        .store(&server_store)
+        .await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(server_store.clone())).into_future());
+
+    // Fetch only a single block per miss
+    let config = Config::builder().max_blocks_per_round(Some(1)).build()?;
+    let store = LazyBlockStore::new(
+        MemoryBlockStore::new(),
+        Client::new(),
+        format!("http://{addr}/dag/pull"),
+    )
+    .with_config(config);
+
+    assert!(!store.has_block(&root).await?);
+
+    // Concurrent misses of the same block
+    let (a, b) = tokio::join!(store.get_block(&root), store.get_block(&root));
+    assert_eq!(a?, server_store.get_block(&root).await?);
+    assert_eq!(b?, server_store.get_block(&root).await?);
+
+    assert!(store.local().has_block(&root).await?);
+
+    // Blocks the server doesn't have aren't found
+    let unknown = MemoryBlockStore::new()
+        .put_block(b"Unknown".to_vec(), CODEC_RAW)
+        .await?;
+    assert!(store.get_block(&unknown).await.is_err());
+
+    Ok(())
+}
+
+/// A minimal mock of the Kubo RPC API's block commands, backed by `store`.
+#[cfg(feature = "kubo")]
+fn mock_kubo(store: MemoryBlockStore) -> axum::Router {
+    use axum::{
+        extract::{Multipart, Query, State},
+        response::{IntoResponse, Response},
+        routing::post,
+        Json,
+    };
+    use std::{collections::HashMap, str::FromStr};
+
+    /// Depending on the command, the node responds with either a
+    /// `404 Not Found` or the error code for things that weren't found
+    fn not_found(cid: &Cid, status: StatusCode) -> Response {
+        (
+            status,
+            Json(serde_json::json!({
+                "Message": format!("block {cid} was not found locally (offline)"),
+                "Code": 3,
+                "Type": "error",
+            })),
+        )
+            .into_response()
+    }
+
+    async fn block_get(
+        State(store): State<MemoryBlockStore>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        let cid = Cid::from_str(&query["arg"]).unwrap();
+        assert_eq!(query["offline"], "true");
+        match store.get_block(&cid).await {
+            Ok(block) => block.into_response(),
+            Err(_) => not_found(&cid, StatusCode::NOT_FOUND),
+        }
+    }
+
+    async fn block_stat(
+        State(store): State<MemoryBlockStore>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        let cid = Cid::from_str(&query["arg"]).unwrap();
+        match store.get_block(&cid).await {
+            Ok(block) => Json(serde_json::json!({ "Key": cid.to_string(), "Size": block.len() }))
+                .into_response(),
+            Err(_) => not_found(&cid, StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    async fn block_put(
+        State(store): State<MemoryBlockStore>,
+        Query(query): Query<HashMap<String, String>>,
+        mut multipart: Multipart,
+    ) -> Response {
+        let codec = match query["cid-codec"].as_str() {
+            "raw" => CODEC_RAW,
+            "dag-cbor" => DagCborCodec.into(),
+            "dag-pb" => 0x70,
+            codec => panic!("unexpected codec {codec}"),
+        };
+        assert_eq!(query["mhtype"], "sha2-256");
+        let field = multipart.next_field().await.unwrap().unwrap();
+        let bytes = field.bytes().await.unwrap();
+        let cid = store.put_block(bytes, codec).await.unwrap();
+        Json(serde_json::json!({ "Key": cid.to_string(), "Size": 0 })).into_response()
+    }
+
+    axum::Router::new()
+        .route("/api/v0/block/get", post(block_get))
+        .route("/api/v0/block/stat", post(block_stat))
+        .route("/api/v0/block/put", post(block_put))
+        .with_state(store)
+}
+
+#[cfg(feature = "kubo")]
+#[test_log::test(tokio::test)]
+async fn test_kubo_block_store() -> TestResult {
+    let kubo_store = MemoryBlockStore::new();
+    let root = FileBuilder::new()
+        .content_bytes(vec![42; 500_000])
+        .build()?
+        .store(&kubo_store)
+        .await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let kubo_addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, mock_kubo(kubo_store.clone())).into_future());
+
+    // Serve the blocks of the IPFS node via car mirror
+    let store = KuboBlockStore::new(format!("http://{kubo_addr}"));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::serve(listener, car_mirror_axum::app(store.clone())).into_future());
+
+    let client = Client::new();
+    let client_store = MemoryBlockStore::new();
+    client
+        .post(format!("http://{addr}/dag/pull/{root}"))
+        .run_car_mirror_pull(root, &Config::default(), &client_store, &NoCache)
+        .await?;
+    assert!(compute_missing(root, &client_store, NoCache)
+        .await?
+        .is_empty());
+
+    // Pushes write into the IPFS node
+    let pushed_root = FileBuilder::new()
+        .content_bytes(vec![7; 500_000])
+        .build()?
+        .store(&client_store)
+        .await?;
+    client
+        .post(format!("http://{addr}/dag/push/{pushed_root}"))
+        .run_car_mirror_push(pushed_root, &client_store, &NoCache)
+        .await?;
+    assert!(compute_missing(pushed_root, &kubo_store, NoCache)
+        .await?
+        .is_empty());
+
+    // Missing blocks map to `CIDNotFound`
+    let unknown = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(b"Unknown"));
+    assert!(!store.has_block(&unknown).await?);
+    assert!(matches!(
+        store.get_block(&unknown).await,
+        Err(BlockStoreError::CIDNotFound(cid)) if cid == unknown
+    ));
+
     Ok(())
 }
@@ -1,11 +1,14 @@
 //! The bindgen API for WNFS block store.
 
+use crate::utils::{handle_err, parse_cid};
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use js_sys::{Promise, Uint8Array};
 use libipld::cid::Cid;
-use wasm_bindgen::{prelude::wasm_bindgen, JsCast};
-use wasm_bindgen_futures::JsFuture;
+use std::rc::Rc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
 use wnfs_common::{BlockStore as WnfsBlockStore, BlockStoreError};
 
 //--------------------------------------------------------------------------------------------------
@@ -42,11 +45,112 @@ extern "C" {
 
 /// A block store provided by the host (JavaScript) for custom implementation like connection to the IPFS network.
 #[wasm_bindgen]
-pub struct ForeignBlockStore(pub(crate) BlockStore);
+pub struct ForeignBlockStore {
+    pub(crate) store: BlockStore,
+    pub(crate) limit: Option<Rc<Semaphore>>,
+}
 
 impl Clone for ForeignBlockStore {
     fn clone(&self) -> Self {
-        Self(BlockStore::unchecked_from_js(self.0.clone()))
+        Self {
+            store: BlockStore::unchecked_from_js(self.store.clone()),
+            limit: self.limit.clone(),
+        }
+    }
+}
+
+impl ForeignBlockStore {
+    /// Wrap a host-provided block store without limiting concurrent calls into it.
+    pub fn new(store: BlockStore) -> Self {
+        Self { store, limit: None }
+    }
+
+    /// Wrap a host-provided block store, making sure that at most `n`
+    /// calls into it are in-flight at the same time.
+    ///
+    /// Further calls wait until a previous call's promise resolved.
+    /// This is useful e.g. for IndexedDB-backed stores that run into
+    /// transaction limits when flooded with requests.
+    ///
+    /// The limit is shared between clones of the returned store.
+    /// A limit of `0` is treated like `1`.
+    pub fn with_concurrency_limit(store: BlockStore, n: usize) -> Self {
+        Self {
+            store,
+            limit: Some(Rc::new(Semaphore::new(n.max(1)))),
+        }
+    }
+
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        acquire(&self.limit).await
+    }
+}
+
+#[wasm_bindgen]
+impl ForeignBlockStore {
+    /// Wrap a host-provided block store, making sure that at most
+    /// `max_concurrent_calls` calls into it are in-flight at the same time.
+    /// Calls aren't limited if `max_concurrent_calls` isn't given.
+    ///
+    /// The wrapper implements the `BlockStore` interface itself, so it can
+    /// be passed to any function that expects a `BlockStore`, e.g.
+    /// `pull_request(cid, new ForeignBlockStore(idbStore, 4))`.
+    ///
+    /// See `with_concurrency_limit`.
+    #[wasm_bindgen(constructor)]
+    pub fn new_js(store: BlockStore, max_concurrent_calls: Option<usize>) -> Self {
+        match max_concurrent_calls {
+            Some(n) => Self::with_concurrency_limit(store, n),
+            None => Self::new(store),
+        }
+    }
+
+    /// Stores given bytes under given byte-encoded CID.
+    #[wasm_bindgen(js_name = "putBlockKeyed")]
+    pub fn js_put_block_keyed(&self, cid: Vec<u8>, bytes: Vec<u8>) -> Promise {
+        let store = self.clone();
+        future_to_promise(async move {
+            let cid = parse_cid(cid)?;
+            WnfsBlockStore::put_block_keyed(&store, cid, bytes)
+                .await
+                .map_err(handle_err)?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Retrieves the bytes of the block with given byte-encoded CID,
+    /// or `undefined` if the wrapped store doesn't have it.
+    #[wasm_bindgen(js_name = "getBlock")]
+    pub fn js_get_block(&self, cid: Vec<u8>) -> Promise {
+        let store = self.clone();
+        future_to_promise(async move {
+            let cid = parse_cid(cid)?;
+            match WnfsBlockStore::get_block(&store, &cid).await {
+                Ok(bytes) => Ok(Uint8Array::from(bytes.as_ref()).into()),
+                Err(BlockStoreError::CIDNotFound(_)) => Ok(JsValue::UNDEFINED),
+                Err(e) => Err(handle_err(e).into()),
+            }
+        })
+    }
+
+    /// Finds out whether the wrapped store has the block with given byte-encoded CID.
+    #[wasm_bindgen(js_name = "hasBlock")]
+    pub fn js_has_block(&self, cid: Vec<u8>) -> Promise {
+        let store = self.clone();
+        future_to_promise(async move {
+            let cid = parse_cid(cid)?;
+            let has_block = WnfsBlockStore::has_block(&store, &cid)
+                .await
+                .map_err(handle_err)?;
+            Ok(JsValue::from_bool(has_block))
+        })
+    }
+}
+
+async fn acquire(limit: &Option<Rc<Semaphore>>) -> Option<SemaphorePermit<'_>> {
+    match limit {
+        Some(semaphore) => semaphore.acquire().await.ok(),
+        None => None,
     }
 }
 
@@ -62,7 +166,8 @@ impl WnfsBlockStore for ForeignBlockStore {
     ) -> Result<(), BlockStoreError> {
         let bytes: Bytes = bytes.into();
 
-        JsFuture::from(self.0.put_block_keyed(cid.to_bytes(), bytes.into()))
+        let _permit = self.acquire().await;
+        JsFuture::from(self.store.put_block_keyed(cid.to_bytes(), bytes.into()))
             .await
             .map_err(|e| anyhow!("Cannot put block: {e:?}"))?;
 
@@ -70,7 +175,8 @@ impl WnfsBlockStore for ForeignBlockStore {
     }
 
     async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
-        let value = JsFuture::from(self.0.get_block(cid.to_bytes()))
+        let _permit = self.acquire().await;
+        let value = JsFuture::from(self.store.get_block(cid.to_bytes()))
             .await
             .map_err(|e| anyhow!("Cannot get block: {e:?}"))?;
 
@@ -84,7 +190,8 @@ impl WnfsBlockStore for ForeignBlockStore {
     }
 
     async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
-        let value = JsFuture::from(self.0.has_block(cid.to_bytes()))
+        let _permit = self.acquire().await;
+        let value = JsFuture::from(self.store.has_block(cid.to_bytes()))
             .await
             .map_err(|e| anyhow!("Cannot run has_block: {e:?}"))?;
 
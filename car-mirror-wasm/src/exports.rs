@@ -1,16 +1,17 @@
 use crate::{
     blockstore::{BlockStore, ForeignBlockStore},
     messages::{PullRequest, PushResponse},
-    utils::{handle_jserr, parse_cid},
+    utils::{handle_err, handle_jserr, parse_cid},
 };
 use bytes::BytesMut;
 use car_mirror::{cache::NoCache, common::Config};
 use futures::{StreamExt, TryStreamExt};
-use js_sys::{Error, Promise, Uint8Array};
+use js_sys::{Error, Function, Promise, Uint8Array};
+use libipld::Cid;
 use std::rc::Rc;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
-use wasm_bindgen_futures::future_to_promise;
+use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
 use wasm_streams::ReadableStream;
 
 /// Compute the bytes for a non-streaming push request, given
@@ -25,7 +26,7 @@ pub fn push_request(
     last_response: Option<PushResponse>,
     store: BlockStore,
 ) -> Result<Promise, Error> {
-    let store = ForeignBlockStore(store);
+    let store = ForeignBlockStore::new(store);
     let root = parse_cid(root_cid)?;
     let last_response = if let Some(push_response) = last_response {
         Some(Rc::try_unwrap(push_response.0).unwrap_or_else(|rc| rc.as_ref().clone()))
@@ -61,7 +62,7 @@ pub fn push_request_streaming(
     last_response: Option<PushResponse>,
     store: BlockStore,
 ) -> Result<Promise, Error> {
-    let store = ForeignBlockStore(store);
+    let store = ForeignBlockStore::new(store);
     let root = parse_cid(root_cid)?;
     let last_response = if let Some(push_response) = last_response {
         Some(Rc::try_unwrap(push_response.0).unwrap_or_else(|rc| rc.as_ref().clone()))
@@ -70,10 +71,15 @@ pub fn push_request_streaming(
     };
 
     Ok(future_to_promise(async move {
-        let car_stream =
-            car_mirror::push::request_streaming(root, last_response, store.clone(), NoCache)
-                .await
-                .map_err(handle_jserr)?;
+        let car_stream = car_mirror::push::request_streaming(
+            root,
+            last_response,
+            &Config::default(),
+            store.clone(),
+            NoCache,
+        )
+        .await
+        .map_err(handle_jserr)?;
 
         let js_car_stream = car_stream
             .map_ok(|bytes| JsValue::from(Uint8Array::from(bytes.as_ref())))
@@ -90,7 +96,7 @@ pub fn push_request_streaming(
 /// class.
 #[wasm_bindgen]
 pub fn pull_request(root_cid: Vec<u8>, store: BlockStore) -> Result<Promise, Error> {
-    let store = ForeignBlockStore(store);
+    let store = ForeignBlockStore::new(store);
     let root = parse_cid(root_cid)?;
 
     Ok(future_to_promise(async move {
@@ -121,44 +127,145 @@ pub fn pull_handle_response_streaming(
     readable_stream: web_sys::ReadableStream,
     store: BlockStore,
 ) -> Result<Promise, Error> {
-    let store = ForeignBlockStore(store);
+    let store = ForeignBlockStore::new(store);
     let root = parse_cid(root_cid)?;
     let readable_stream = ReadableStream::from_raw(readable_stream);
 
     Ok(future_to_promise(async move {
-        let pull_request = match readable_stream.try_into_async_read() {
-            Ok(async_read) => car_mirror::pull::handle_response_streaming(
+        let pull_request = handle_pull_response(root, readable_stream, &store).await?;
+        Ok(PullRequest(Rc::new(pull_request)).into())
+    }))
+}
+
+/// Run (possibly multiple rounds of) the car mirror pull protocol for given
+/// byte-encoded root CID into given `BlockStore`.
+///
+/// Like the native `pull_with` in `car-mirror-reqwest`, this leaves making
+/// requests to the caller, e.g. to add authorization headers to each round:
+/// `make_request` is called with the `PullRequest` of each round and needs to
+/// return a promise that resolves to the `ReadableStream<Uint8Array>` of the
+/// response body, e.g. via `fetch` and `response.body`.
+///
+/// Returns a promise that resolves once the store has the complete DAG.
+#[wasm_bindgen]
+pub fn pull_with(
+    root_cid: Vec<u8>,
+    store: BlockStore,
+    make_request: Function,
+) -> Result<Promise, Error> {
+    let store = ForeignBlockStore::new(store);
+    let root = parse_cid(root_cid)?;
+
+    Ok(future_to_promise(async move {
+        let config = &Config::default();
+        let mut pull_request = car_mirror::pull::request(root, None, config, &store, NoCache)
+            .await
+            .map_err(handle_jserr)?;
+
+        while !pull_request.indicates_finished() {
+            let request = JsValue::from(PullRequest(Rc::new(pull_request)));
+            let response = call_make_request(&make_request, &request).await?;
+            let readable_stream = response.dyn_into::<web_sys::ReadableStream>()?;
+
+            pull_request =
+                handle_pull_response(root, ReadableStream::from_raw(readable_stream), &store)
+                    .await?;
+        }
+
+        Ok(JsValue::UNDEFINED)
+    }))
+}
+
+/// Run (possibly multiple rounds of) the car mirror push protocol for given
+/// byte-encoded root CID from given `BlockStore`.
+///
+/// Like the native `push_with` in `car-mirror-reqwest`, this leaves making
+/// requests to the caller, e.g. to add authorization headers to each round:
+/// `make_request` is called with the `Uint8Array` of the CAR file of each
+/// round and needs to return a promise that resolves to a `Uint8Array` of
+/// the dag-cbor encoded response body, e.g. via `fetch` and `response.arrayBuffer()`.
+///
+/// Returns a promise that resolves once the server has the complete DAG.
+#[wasm_bindgen]
+pub fn push_with(
+    root_cid: Vec<u8>,
+    store: BlockStore,
+    make_request: Function,
+) -> Result<Promise, Error> {
+    let store = ForeignBlockStore::new(store);
+    let root = parse_cid(root_cid)?;
+
+    Ok(future_to_promise(async move {
+        let config = &Config::default();
+        let mut last_response = None;
+
+        loop {
+            let car_file = car_mirror::push::request(root, last_response, config, &store, NoCache)
+                .await
+                .map_err(handle_jserr)?;
+
+            let request = JsValue::from(Uint8Array::from(car_file.bytes.as_ref()));
+            let response = call_make_request(&make_request, &request).await?;
+            let response_bytes = Uint8Array::new(&response).to_vec();
+
+            let push_response = car_mirror::messages::PushResponse::from_dag_cbor(response_bytes)
+                .map_err(handle_err)?;
+            push_response.validate(config).map_err(handle_jserr)?;
+
+            if push_response.indicates_finished() {
+                return Ok(JsValue::UNDEFINED);
+            }
+
+            last_response = Some(push_response);
+        }
+    }))
+}
+
+/// Calls the JS `make_request` callback and waits for the promise it returns.
+async fn call_make_request(make_request: &Function, request: &JsValue) -> Result<JsValue, JsValue> {
+    let promise = Promise::resolve(&make_request.call1(&JsValue::NULL, request)?);
+    JsFuture::from(promise).await
+}
+
+/// Reads a pull response body, preferring a BYOB reader if supported.
+async fn handle_pull_response(
+    root: Cid,
+    readable_stream: ReadableStream,
+    store: &ForeignBlockStore,
+) -> Result<car_mirror::messages::PullRequest, JsValue> {
+    let pull_request = match readable_stream.try_into_async_read() {
+        Ok(async_read) => car_mirror::pull::handle_response_streaming(
+            root,
+            async_read.compat(),
+            &Config::default(),
+            store,
+            NoCache,
+        )
+        .await
+        .map_err(handle_jserr)?,
+
+        // If BYOB readers are unsupported:
+        Err((_, readable_stream)) => {
+            let stream = readable_stream
+                    .into_stream()
+                    .map(|result| result.and_then(convert_jsvalue_to_bytes))
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Error while trying to read item from stream or trying to convert the item into bytes on the rust side."));
+
+            let async_read = tokio_util::io::StreamReader::new(stream);
+
+            car_mirror::pull::handle_response_streaming(
                 root,
-                async_read.compat(),
+                async_read,
                 &Config::default(),
                 store,
                 NoCache,
             )
             .await
-            .map_err(handle_jserr)?,
-
-            // If BYOB readers are unsupported:
-            Err((_, readable_stream)) => {
-                let stream = readable_stream
-                        .into_stream()
-                        .map(|result| result.and_then(convert_jsvalue_to_bytes))
-                        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Error while trying to read item from stream or trying to convert the item into bytes on the rust side."));
-
-                let async_read = tokio_util::io::StreamReader::new(stream);
-
-                car_mirror::pull::handle_response_streaming(
-                    root,
-                    async_read,
-                    &Config::default(),
-                    store,
-                    NoCache,
-                )
-                .await
-                .map_err(handle_jserr)?
-            }
-        };
-        Ok(PullRequest(Rc::new(pull_request)).into())
-    }))
+            .map_err(handle_jserr)?
+        }
+    };
+
+    Ok(pull_request)
 }
 
 fn convert_jsvalue_to_bytes(js_value: JsValue) -> Result<BytesMut, JsValue> {
@@ -1,6 +1,16 @@
 //! # car-mirror-wasm
 //!
 //! This crate exposes wasm bindings to car-mirror *client* functions.
+//!
+//! ## Errors
+//!
+//! Errors raised by car mirror itself are thrown as JS `Error`s with a `code`
+//! property that tells what went wrong, e.g. `"TOO_MANY_BYTES"` or
+//! `"DIGEST_MISMATCH"`, so they can be handled without parsing error messages.
+//! Codes are stable across versions.
+//!
+//! Structured cloning, e.g. via `postMessage` to or from a worker, only keeps
+//! an error's `name` and `message`, so forward the `code` explicitly if needed.
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![warn(missing_docs, rust_2018_idioms)]
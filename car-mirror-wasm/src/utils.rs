@@ -1,4 +1,4 @@
-use js_sys::Error;
+use js_sys::{Error, Reflect};
 use libipld::Cid;
 use wasm_bindgen::JsValue;
 
@@ -6,10 +6,50 @@ pub(crate) fn parse_cid(bytes: Vec<u8>) -> Result<Cid, Error> {
     Cid::read_bytes(&bytes[..]).map_err(|e| Error::new(&format!("Couldn't parse CID: {e:?}")))
 }
 
-pub(crate) fn handle_jserr<E: ToString>(e: E) -> JsValue {
-    JsValue::from(Error::new(&e.to_string()))
+/// Turns a car mirror error into a JS `Error` with a `code` property,
+/// see `error_code`.
+pub(crate) fn handle_jserr(e: car_mirror::Error) -> JsValue {
+    let error = Error::new(&e.to_string());
+    // Setting a property on a fresh `Error` object can't fail
+    let _ = Reflect::set(
+        &error,
+        &JsValue::from_str("code"),
+        &JsValue::from_str(error_code(&e)),
+    );
+    JsValue::from(error)
 }
 
 pub(crate) fn handle_err<E: ToString>(e: E) -> Error {
     Error::new(&e.to_string())
 }
+
+/// A stable, machine-readable code for each kind of car mirror error,
+/// so JS code can `switch` on it instead of parsing error messages.
+///
+/// These codes are part of the public JS API, so existing codes must not change.
+pub(crate) fn error_code(err: &car_mirror::Error) -> &'static str {
+    use car_mirror::{Error, IncrementalVerificationError};
+    match err {
+        Error::TooManyBytes { .. } => "TOO_MANY_BYTES",
+        Error::BlockSizeExceeded { .. } => "BLOCK_SIZE_EXCEEDED",
+        Error::UnsupportedCodec { .. } => "UNSUPPORTED_CODEC",
+        Error::UnsupportedHashCode { .. } => "UNSUPPORTED_HASH_CODE",
+        Error::IncompatibleBlooms { .. } => "INCOMPATIBLE_BLOOMS",
+        Error::StreamIdleTimeout { .. } => "STREAM_IDLE_TIMEOUT",
+        Error::MissingEndOfRound => "MISSING_END_OF_ROUND",
+        Error::InvalidCheckpoint { .. } => "INVALID_CHECKPOINT",
+        Error::UnrelatedCarRoots { .. } => "UNRELATED_CAR_ROOTS",
+        Error::MismatchedCarRoots { .. } => "MISMATCHED_CAR_ROOTS",
+        Error::BloomTooLarge { .. } => "BLOOM_TOO_LARGE",
+        Error::TooManyRoots { .. } => "TOO_MANY_ROOTS",
+        Error::InvalidBloom { .. } => "INVALID_BLOOM",
+        Error::FrontierTooLarge { .. } => "FRONTIER_TOO_LARGE",
+        Error::BlockStoreError(_) => "BLOCK_STORE_ERROR",
+        Error::ParsingError(_) => "PARSING_ERROR",
+        Error::IncrementalVerificationError(err) => match err {
+            IncrementalVerificationError::ExpectedWantedBlock { .. } => "EXPECTED_WANTED_BLOCK",
+            IncrementalVerificationError::DigestMismatch { .. } => "DIGEST_MISMATCH",
+        },
+        Error::CarFileError(_) => "CAR_FILE_ERROR",
+    }
+}
@@ -1,4 +1,5 @@
 import { MemoryBlockStore, exampleFile, runCarMirrorPull, runCarMirrorPush } from "./index.js"
+import { ForeignBlockStore, push_request, pull_with, push_with } from "../dist/bundler/car_mirror_wasm.js"
 import { CID } from "multiformats"
 import { assert, suite } from 'playwright-test/taps'
 
@@ -13,6 +14,14 @@ test("car mirror incremental push https", () => testIncrementalPush("https"));
 test("car mirror both push then pull http", () => testPushThenPull("http"));
 test("car mirror both push then pull https", () => testPushThenPull("https"));
 
+test("foreign block store with bounded concurrent writes", testBoundedConcurrentWrites);
+
+test("foreign block store with bounded concurrent calls", testBoundedConcurrentCalls);
+
+test("car mirror push & pull with custom requests", testPushPullWith);
+
+test("car mirror errors have a code", testErrorCode);
+
 
 async function testPull(protocol) {
   const store = new MemoryBlockStore();
@@ -64,3 +73,134 @@ async function testPushThenPull(protocol) {
   assert.equal(await store.hasBlock(wasmCid.bytes), true);
   assert.equal(store.store.size > 10, true);
 }
+
+
+async function testBoundedConcurrentWrites() {
+  const store = new MemoryBlockStore();
+  let inFlight = 0;
+  let maxInFlight = 0;
+  const putBlockKeyed = store.putBlockKeyed.bind(store);
+  store.putBlockKeyed = async (cid, bytes) => {
+    inFlight++;
+    maxInFlight = Math.max(maxInFlight, inFlight);
+    try {
+      // Simulate a slow store
+      await new Promise((resolve) => setTimeout(resolve, 1));
+      await putBlockKeyed(cid, bytes);
+    } finally {
+      inFlight--;
+    }
+  };
+
+  // Writes aren't awaited one by one here, so only the limit keeps them from piling up
+  const limited = new ForeignBlockStore(store, 1);
+  const writes = [];
+  const unawaitedStore = {
+    putBlockKeyed: async (cid, bytes) => {
+      writes.push(limited.putBlockKeyed(cid, bytes));
+    },
+  };
+  const wasmCid = await exampleFile(unawaitedStore, async (file) => {
+    const wasm = await (await fetch("./dist/web/car_mirror_wasm_bg.wasm")).arrayBuffer();
+    for (let i = 0; i < 5; i++) {
+      file.write(new Uint8Array(wasm));
+    }
+  });
+  await Promise.all(writes);
+  assert.equal(writes.length > 1, true);
+  assert.equal(maxInFlight, 1);
+  assert.equal(await store.hasBlock(wasmCid.bytes), true);
+}
+
+
+async function testBoundedConcurrentCalls() {
+  const store = new MemoryBlockStore();
+  let inFlight = 0;
+  let maxInFlight = 0;
+  const counted = (call) => async (...args) => {
+    inFlight++;
+    maxInFlight = Math.max(maxInFlight, inFlight);
+    try {
+      // Simulate a slow store
+      await new Promise((resolve) => setTimeout(resolve, 1));
+      return await call(...args);
+    } finally {
+      inFlight--;
+    }
+  };
+  store.getBlock = counted(store.getBlock.bind(store));
+  store.hasBlock = counted(store.hasBlock.bind(store));
+
+  const limited = new ForeignBlockStore(store, 2);
+  const wasmCid = await exampleFile(limited, async (file) => {
+    const wasm = await (await fetch("./dist/web/car_mirror_wasm_bg.wasm")).arrayBuffer();
+    file.write(new Uint8Array(wasm));
+  });
+
+  const calls = [];
+  for (let i = 0; i < 10; i++) {
+    calls.push(i % 2 === 0 ? limited.getBlock(wasmCid.bytes) : limited.hasBlock(wasmCid.bytes));
+  }
+  const results = await Promise.all(calls);
+  assert.equal(maxInFlight, 2);
+  assert.equal(results[0] instanceof Uint8Array, true);
+  assert.equal(results[1], true);
+
+  // Limited stores can be passed wherever a block store is expected
+  const car = await push_request(wasmCid.bytes, undefined, limited);
+  assert.equal(car.length > 0, true);
+  assert.equal(maxInFlight, 2);
+}
+
+
+async function testPushPullWith() {
+  let store = new MemoryBlockStore();
+  const wasmCid = await exampleFile(store, async (file) => {
+    const wasm = await (await fetch("./dist/web/car_mirror_wasm_bg.wasm")).arrayBuffer();
+    file.write(new Uint8Array(wasm));
+  });
+  const headers = { "Authorization": "Bearer some-token" };
+
+  let pushRounds = 0;
+  await push_with(wasmCid.bytes, store, async (body) => {
+    pushRounds++;
+    const response = await fetch(`http://localhost:3344/dag/push/${wasmCid.toString()}`, {
+      method: "POST",
+      headers: { ...headers, "Content-Type": "application/vnd.ipld.car" },
+      body,
+    });
+    assert.equal(response.ok, true);
+    return new Uint8Array(await response.arrayBuffer());
+  });
+  assert.equal(pushRounds > 0, true);
+
+  // Clear the store
+  store = new MemoryBlockStore();
+  let pullRounds = 0;
+  await pull_with(wasmCid.bytes, store, async (request) => {
+    pullRounds++;
+    const response = await fetch(`http://localhost:3344/dag/pull/${wasmCid.toString()}`, {
+      method: "POST",
+      headers: { ...headers, "Content-Type": "application/vnd.ipld.dag-cbor" },
+      body: request.encode(),
+    });
+    assert.equal(response.ok, true);
+    return response.body;
+  });
+  assert.equal(pullRounds > 0, true);
+  assert.equal(await store.hasBlock(wasmCid.bytes), true);
+}
+
+
+async function testErrorCode() {
+  // The store doesn't have the root block
+  const store = new MemoryBlockStore();
+  const cid = CID.parse("bafyb4ifjd76kkpos2uiv5mqifs4vi2xtywhf7pnu2pqtlg2vzmtmpyzdfa");
+
+  try {
+    await push_request(cid.bytes, undefined, store);
+    assert.fail("Expected push request to fail");
+  } catch (e) {
+    assert.equal(e.code, "BLOCK_STORE_ERROR");
+  }
+}
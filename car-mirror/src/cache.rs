@@ -1,6 +1,7 @@
 use crate::common::references;
 use futures::Future;
 use libipld::{Cid, IpldCodec};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use wnfs_common::{
     utils::{CondSend, CondSync},
     BlockStore, BlockStoreError,
@@ -14,7 +15,8 @@ use wnfs_common::{
 /// monotonous, so you don't need to be careful about cache eviction.
 ///
 /// See `InMemoryCache` for a `quick_cache`-based implementation
-/// (enable the `quick-cache` feature), and `NoCache` for disabling the cache.
+/// (enable the `quick-cache` feature), `NoCache` for disabling the cache,
+/// and `TieredCache` for combining caches.
 pub trait Cache: CondSync {
     /// This returns further references from the block referenced by given CID,
     /// if the cache is hit.
@@ -55,11 +57,55 @@ pub trait Cache: CondSync {
             }
 
             let block = store.get_block(&cid).await?;
-            let refs = references(cid, block, Vec::new())?;
+            let refs = self.reference_extractor().references(cid, &block)?;
             self.put_references_cache(cid, refs.clone()).await?;
             Ok(refs)
         }
     }
+
+    /// The extractor that finds the references of blocks on cache misses.
+    ///
+    /// By default, this supports the codecs that `common::references` supports.
+    /// See `WithReferenceExtractor` for supporting additional codecs.
+    fn reference_extractor(&self) -> &dyn ReferenceExtractor {
+        &BuiltinReferenceExtractor
+    }
+}
+
+/// Finds the CIDs that a block links to, see `Cache::reference_extractor`.
+///
+/// Implement this for application-specific IPLD codecs, or register handlers
+/// for them with `CodecReferenceExtractor`.
+pub trait ReferenceExtractor: CondSync {
+    /// Find all CIDs that the block with given CID links to.
+    fn references(&self, cid: Cid, block: &[u8]) -> Result<Vec<Cid>, anyhow::Error>;
+}
+
+/// A `ReferenceExtractor` for the codecs that `common::references` supports,
+/// i.e. DAG-CBOR, DAG-PB, DAG-JSON and raw.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuiltinReferenceExtractor;
+
+/// A function that finds the references of blocks with a particular codec,
+/// see `CodecReferenceExtractor::with_codec`.
+pub type ReferenceHandler =
+    Arc<dyn Fn(Cid, &[u8]) -> Result<Vec<Cid>, anyhow::Error> + Send + Sync>;
+
+/// A `ReferenceExtractor` with handlers for additional codecs, which falls
+/// back to `BuiltinReferenceExtractor` for all other codecs.
+#[derive(Clone, Default)]
+pub struct CodecReferenceExtractor {
+    handlers: HashMap<u64, ReferenceHandler>,
+}
+
+/// A `Cache` that finds references on cache misses with a custom
+/// `ReferenceExtractor`, e.g. a `CodecReferenceExtractor`.
+#[derive(Debug, Clone)]
+pub struct WithReferenceExtractor<C, E> {
+    /// The cache for the extracted references
+    pub cache: C,
+    /// The extractor to use on cache misses
+    pub extractor: E,
 }
 
 impl<C: Cache> Cache for &C {
@@ -74,6 +120,10 @@ impl<C: Cache> Cache for &C {
     ) -> Result<(), BlockStoreError> {
         (**self).put_references_cache(cid, references).await
     }
+
+    fn reference_extractor(&self) -> &dyn ReferenceExtractor {
+        (**self).reference_extractor()
+    }
 }
 
 impl<C: Cache> Cache for Box<C> {
@@ -88,6 +138,76 @@ impl<C: Cache> Cache for Box<C> {
     ) -> Result<(), BlockStoreError> {
         (**self).put_references_cache(cid, references).await
     }
+
+    fn reference_extractor(&self) -> &dyn ReferenceExtractor {
+        (**self).reference_extractor()
+    }
+}
+
+impl ReferenceExtractor for BuiltinReferenceExtractor {
+    fn references(&self, cid: Cid, block: &[u8]) -> Result<Vec<Cid>, anyhow::Error> {
+        references(cid, block, Vec::new())
+    }
+}
+
+impl CodecReferenceExtractor {
+    /// Create an extractor without handlers for additional codecs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use given handler for blocks with given codec, instead of the
+    /// built-in support for it, if there is any.
+    pub fn with_codec(
+        mut self,
+        codec: u64,
+        handler: impl Fn(Cid, &[u8]) -> Result<Vec<Cid>, anyhow::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.handlers.insert(codec, Arc::new(handler));
+        self
+    }
+}
+
+impl ReferenceExtractor for CodecReferenceExtractor {
+    fn references(&self, cid: Cid, block: &[u8]) -> Result<Vec<Cid>, anyhow::Error> {
+        match self.handlers.get(&cid.codec()) {
+            Some(handler) => handler(cid, block),
+            None => BuiltinReferenceExtractor.references(cid, block),
+        }
+    }
+}
+
+impl Debug for CodecReferenceExtractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodecReferenceExtractor")
+            .field("codecs", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<C: Cache, E: ReferenceExtractor> WithReferenceExtractor<C, E> {
+    /// Use given extractor on misses of given cache.
+    pub fn new(cache: C, extractor: E) -> Self {
+        Self { cache, extractor }
+    }
+}
+
+impl<C: Cache, E: ReferenceExtractor> Cache for WithReferenceExtractor<C, E> {
+    async fn get_references_cache(&self, cid: Cid) -> Result<Option<Vec<Cid>>, BlockStoreError> {
+        self.cache.get_references_cache(cid).await
+    }
+
+    async fn put_references_cache(
+        &self,
+        cid: Cid,
+        references: Vec<Cid>,
+    ) -> Result<(), BlockStoreError> {
+        self.cache.put_references_cache(cid, references).await
+    }
+
+    fn reference_extractor(&self) -> &dyn ReferenceExtractor {
+        &self.extractor
+    }
 }
 
 /// An implementation of `Cache` that doesn't cache at all.
@@ -104,6 +224,60 @@ impl Cache for NoCache {
     }
 }
 
+/// A `Cache` that combines a fast cache `A`, e.g. an `InMemoryCache`,
+/// with a slower but bigger or persistent cache `B`, e.g. one backed by disk
+/// or redis, that may be shared between processes and survive restarts.
+///
+/// Lookups check `A` first and fall through to `B` on a miss, populating `A`
+/// with what's found in `B`. Puts write through to both caches.
+/// References are extracted with the `ReferenceExtractor` of `A`.
+#[derive(Debug, Clone)]
+pub struct TieredCache<A, B> {
+    /// The first level, which is checked first
+    pub l1: A,
+    /// The second level, which is checked on misses in the first level
+    pub l2: B,
+}
+
+impl<A: Cache, B: Cache> TieredCache<A, B> {
+    /// Combine given caches, checking `l1` before `l2`.
+    pub fn new(l1: A, l2: B) -> Self {
+        Self { l1, l2 }
+    }
+}
+
+impl<A: Cache, B: Cache> Cache for TieredCache<A, B> {
+    async fn get_references_cache(&self, cid: Cid) -> Result<Option<Vec<Cid>>, BlockStoreError> {
+        if let Some(references) = self.l1.get_references_cache(cid).await? {
+            return Ok(Some(references));
+        }
+
+        let Some(references) = self.l2.get_references_cache(cid).await? else {
+            return Ok(None);
+        };
+
+        self.l1
+            .put_references_cache(cid, references.clone())
+            .await?;
+        Ok(Some(references))
+    }
+
+    async fn put_references_cache(
+        &self,
+        cid: Cid,
+        references: Vec<Cid>,
+    ) -> Result<(), BlockStoreError> {
+        self.l1
+            .put_references_cache(cid, references.clone())
+            .await?;
+        self.l2.put_references_cache(cid, references).await
+    }
+
+    fn reference_extractor(&self) -> &dyn ReferenceExtractor {
+        self.l1.reference_extractor()
+    }
+}
+
 #[cfg(feature = "quick_cache")]
 pub use quick_cache::*;
 
@@ -192,6 +366,8 @@ mod quick_cache {
         ///
         /// In these cases a more advanced caching strategy that may answer
         /// `has_block` early from a cache with a cache TTL & eviction strategy.
+        /// Alternatively, call `CacheMissing::invalidate` for any blocks that
+        /// were added out-of-band.
         ///
         /// The additional memory requirements for this cache can be estimated
         /// using the `approx_capacity`: Each cache line is roughly ~100 bytes
@@ -202,6 +378,15 @@ mod quick_cache {
                 has_blocks: Arc::new(sync::Cache::new(approx_capacity)),
             }
         }
+
+        /// Forget whether the block with given CID is available or not, so
+        /// the next `has_block` or `get_block` call asks the inner blockstore again.
+        ///
+        /// Use this when the block was added to or removed from the inner
+        /// blockstore without going through this wrapper.
+        pub fn invalidate(&self, cid: &Cid) {
+            self.has_blocks.remove(cid);
+        }
     }
 
     impl<B: BlockStore> BlockStore for CacheMissing<B> {
@@ -265,10 +450,11 @@ mod quick_cache {
 
     #[cfg(test)]
     mod tests {
-        use super::{Cache, InMemoryCache};
+        use super::{Cache, CacheMissing, InMemoryCache};
+        use assert_matches::assert_matches;
         use libipld::{cbor::DagCborCodec, Ipld, IpldCodec};
         use testresult::TestResult;
-        use wnfs_common::{encode, BlockStore, MemoryBlockStore};
+        use wnfs_common::{encode, BlockStore, BlockStoreError, MemoryBlockStore};
 
         #[test_log::test(async_std::test)]
         async fn test_references_cache() -> TestResult {
@@ -308,12 +494,40 @@ mod quick_cache {
 
             Ok(())
         }
+
+        #[test_log::test(async_std::test)]
+        async fn test_cache_missing_invalidate() -> TestResult {
+            let store = &CacheMissing::new(100_000, MemoryBlockStore::new());
+            let block = b"Hello, out-of-band?".to_vec();
+            let cid = store.create_cid(&block, IpldCodec::Raw.into())?;
+
+            // This caches that the block is missing
+            assert!(!store.has_block(&cid).await?);
+
+            // Adding the block to the inner store bypasses the cache
+            store.inner.put_block_keyed(cid, block.clone()).await?;
+
+            // So the block still looks like it's missing
+            assert!(!store.has_block(&cid).await?);
+            assert_matches!(
+                store.get_block(&cid).await,
+                Err(BlockStoreError::CIDNotFound(_))
+            );
+
+            // Until the stale cache entry is invalidated
+            store.invalidate(&cid);
+            assert!(store.has_block(&cid).await?);
+            assert_eq!(store.get_block(&cid).await?, block);
+
+            Ok(())
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Cache, NoCache};
+    use super::{Cache, CodecReferenceExtractor, NoCache, TieredCache, WithReferenceExtractor};
+    use crate::common::{block_receive, block_send, Config};
     use anyhow::Result;
     use libipld::{cbor::DagCborCodec, Cid, Ipld, IpldCodec};
     use std::{collections::HashMap, sync::RwLock};
@@ -417,4 +631,72 @@ mod tests {
 
         Ok(())
     }
+
+    #[test_log::test(async_std::test)]
+    async fn test_tiered_cache() -> TestResult {
+        let store = &MemoryBlockStore::new();
+        let cache = TieredCache::new(HashMapCache::default(), HashMapCache::default());
+
+        let leaf = store
+            .put_block(b"Hello, Tiers?".to_vec(), IpldCodec::Raw.into())
+            .await?;
+        let cid = store
+            .put_block(
+                encode(&Ipld::List(vec![Ipld::Link(leaf)]), DagCborCodec)?,
+                DagCborCodec.into(),
+            )
+            .await?;
+
+        // Populating writes through to both levels
+        assert_eq!(cache.references(cid, store).await?, vec![leaf]);
+        assert_eq!(cache.l1.get_references_cache(cid).await?, Some(vec![leaf]));
+        assert_eq!(cache.l2.get_references_cache(cid).await?, Some(vec![leaf]));
+
+        // Misses in the first level read through to the second one,
+        // e.g. after a restart with a persistent second level
+        let cache = TieredCache::new(HashMapCache::default(), cache.l2);
+        assert_eq!(cache.l1.get_references_cache(cid).await?, None);
+        assert_eq!(cache.get_references_cache(cid).await?, Some(vec![leaf]));
+        assert_eq!(cache.l1.get_references_cache(cid).await?, Some(vec![leaf]));
+
+        // Misses in both levels are misses
+        assert_eq!(cache.get_references_cache(leaf).await?, None);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_custom_codec_references() -> TestResult {
+        // A made-up codec whose blocks are concatenated binary CIDs
+        const CODEC_CIDS: u64 = 0x300000;
+        let extractor = CodecReferenceExtractor::new().with_codec(CODEC_CIDS, |_, block| {
+            let mut reader = std::io::Cursor::new(block);
+            let mut refs = Vec::new();
+            while (reader.position() as usize) < block.len() {
+                refs.push(Cid::read_bytes(&mut reader)?);
+            }
+            Ok(refs)
+        });
+        let cache = &WithReferenceExtractor::new(HashMapCache::default(), extractor);
+
+        let store = &MemoryBlockStore::new();
+        let leaf = store
+            .put_block(b"Hello, custom codec!".to_vec(), IpldCodec::Raw.into())
+            .await?;
+        let root = store.put_block(leaf.to_bytes(), CODEC_CIDS).await?;
+
+        assert!(NoCache.references(root, store).await.is_err());
+        assert_eq!(cache.references(root, store).await?, vec![leaf]);
+
+        // DAGs with the custom codec can be transferred, too
+        let config = &Config::default();
+        let receiver_store = &MemoryBlockStore::new();
+        let car = block_send(root, None, config, store, cache).await?;
+        let state = block_receive(root, Some(car), config, receiver_store, cache).await?;
+
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(receiver_store.has_block(&leaf).await?);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,310 @@
+use crate::error::Error;
+use hmac::{Hmac, Mac};
+use libipld::Cid;
+use sha2::Sha256;
+use std::io::Cursor;
+
+/// The HTTP header that clients use to echo the last `PushResponse::checkpoint`
+/// back to the server, encoded with `to_header_value`.
+pub const CHECKPOINT_HEADER: &str = "car-mirror-checkpoint";
+
+/// The maximum length of a `CHECKPOINT_HEADER` value.
+///
+/// Many HTTP servers and proxies reject requests with headers larger than a
+/// few KB. Checkpoints that don't fit are neither issued nor sent back, so the
+/// server falls back to walking the DAG it has below the root, see `fits_header`.
+pub const MAX_CHECKPOINT_HEADER_LEN: usize = 4096;
+
+/// The length of the authentication tag in front of each checkpoint
+const TAG_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A server secret for issuing and checking push checkpoints.
+///
+/// Without checkpoints, a server that doesn't keep state between push rounds
+/// needs to walk the whole DAG it has below the root on every round, to find
+/// out which blocks it still wants. With checkpoints, the server hands the set
+/// of wanted CIDs to the client in `PushResponse::checkpoint`, and the client
+/// echoes it back with its next request, so the server only needs to walk from
+/// there. See `push::response_streaming_with_checkpoint`.
+///
+/// Checkpoints are authenticated with an HMAC over the root and the wanted
+/// CIDs, so clients can't forge checkpoints to make the server accept blocks
+/// unrelated to the root. Checkpoints of a root are only issued while it's
+/// incomplete, so an older checkpoint that gets replayed only makes the server
+/// ask for blocks it may already have. This assumes that blocks aren't deleted
+/// from the server's store while a push is ongoing.
+///
+/// All servers that may handle rounds of the same push need to use the same
+/// secret, and it needs to be kept private.
+#[derive(Clone)]
+pub struct CheckpointKey {
+    secret: [u8; 32],
+}
+
+impl CheckpointKey {
+    /// Create a checkpoint key from a secret, which should be randomly generated.
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self { secret }
+    }
+
+    /// Create a checkpoint for resuming verification of the DAG below
+    /// `root` from given `want_cids`.
+    pub fn seal(&self, root: Cid, want_cids: &[Cid]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for cid in want_cids {
+            cid.write_bytes(&mut payload)
+                .expect("writing to a Vec doesn't fail");
+        }
+
+        let tag = self.mac(root, &payload).finalize().into_bytes();
+        [tag.as_slice(), &payload].concat()
+    }
+
+    /// Check that given checkpoint was created for `root` with this key and
+    /// return the CIDs to resume verification from.
+    ///
+    /// Fails with `Error::InvalidCheckpoint` otherwise.
+    pub fn open(&self, root: Cid, checkpoint: &[u8]) -> Result<Vec<Cid>, Error> {
+        let invalid = || Error::InvalidCheckpoint { root };
+
+        if checkpoint.len() < TAG_LEN {
+            return Err(invalid());
+        }
+
+        let (tag, payload) = checkpoint.split_at(TAG_LEN);
+        self.mac(root, payload)
+            .verify_slice(tag)
+            .map_err(|_| invalid())?;
+
+        let mut reader = Cursor::new(payload);
+        let mut want_cids = Vec::new();
+        while (reader.position() as usize) < payload.len() {
+            want_cids.push(Cid::read_bytes(&mut reader).map_err(|_| invalid())?);
+        }
+
+        Ok(want_cids)
+    }
+
+    fn mac(&self, root: Cid, payload: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(&root.to_bytes());
+        mac.update(payload);
+        mac
+    }
+}
+
+impl std::fmt::Debug for CheckpointKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CheckpointKey")
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Encode a checkpoint for the `CHECKPOINT_HEADER`.
+pub fn to_header_value(checkpoint: &[u8]) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(checkpoint)
+}
+
+/// Whether given checkpoint, encoded with `to_header_value`, is at most
+/// `MAX_CHECKPOINT_HEADER_LEN` bytes long.
+///
+/// Checkpoints grow with the number of missing subgraph roots, so this
+/// fails for pushes of wide DAGs that were only received partially.
+pub fn fits_header(checkpoint: &[u8]) -> bool {
+    data_encoding::BASE64URL_NOPAD.encode_len(checkpoint.len()) <= MAX_CHECKPOINT_HEADER_LEN
+}
+
+/// Decode a checkpoint from the `CHECKPOINT_HEADER`.
+///
+/// Fails with `Error::InvalidCheckpoint` if it's not valid base64url.
+pub fn from_header_value(root: Cid, value: &str) -> Result<Vec<u8>, Error> {
+    data_encoding::BASE64URL_NOPAD
+        .decode(value.as_bytes())
+        .map_err(|_| Error::InvalidCheckpoint { root })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::Config,
+        messages::PushResponse,
+        push,
+        test_utils::{setup_random_dag, total_dag_blocks},
+    };
+    use assert_matches::assert_matches;
+    use futures::{StreamExt, TryStreamExt};
+    use libipld::{cbor::DagCborCodec, Ipld};
+    use libipld_core::multihash::{Code, MultihashDigest};
+    use testresult::TestResult;
+    use tokio_util::io::StreamReader;
+    use wnfs_common::{encode, BlockStore, MemoryBlockStore, CODEC_RAW};
+
+    fn raw_cid(data: &[u8]) -> Cid {
+        Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(data))
+    }
+
+    #[test]
+    fn test_checkpoint_roundtrip() -> TestResult {
+        let key = CheckpointKey::new([1; 32]);
+        let root = raw_cid(b"root");
+        let want_cids = vec![raw_cid(b"a"), raw_cid(b"b")];
+
+        let checkpoint = key.seal(root, &want_cids);
+        assert_eq!(key.open(root, &checkpoint)?, want_cids);
+
+        let header = to_header_value(&checkpoint);
+        assert_eq!(from_header_value(root, &header)?, checkpoint);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_rejects_forgeries() {
+        let key = CheckpointKey::new([1; 32]);
+        let root = raw_cid(b"root");
+        let checkpoint = key.seal(root, &[raw_cid(b"a")]);
+
+        // Tampered with want CIDs
+        let mut forged = checkpoint.clone();
+        raw_cid(b"unrelated").write_bytes(&mut forged).unwrap();
+        assert_matches!(
+            key.open(root, &forged),
+            Err(Error::InvalidCheckpoint { .. })
+        );
+
+        // Issued for another root
+        assert_matches!(
+            key.open(raw_cid(b"other root"), &checkpoint),
+            Err(Error::InvalidCheckpoint { .. })
+        );
+
+        // Issued with another key
+        assert_matches!(
+            CheckpointKey::new([2; 32]).open(root, &checkpoint),
+            Err(Error::InvalidCheckpoint { .. })
+        );
+
+        // Truncated
+        assert_matches!(
+            key.open(root, &checkpoint[..TAG_LEN - 1]),
+            Err(Error::InvalidCheckpoint { .. })
+        );
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_push_with_checkpoints() -> TestResult {
+        let (root, ref client_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
+        let server_store = &MemoryBlockStore::new();
+        let key = &CheckpointKey::new([1; 32]);
+        let config = &Config::default();
+
+        let mut last_response: Option<PushResponse> = None;
+        let mut rounds = 0;
+        loop {
+            rounds += 1;
+            let checkpoint = last_response
+                .as_ref()
+                .and_then(|response| response.checkpoint.clone());
+
+            // Simulate rounds getting interrupted after the CAR header and 20 blocks
+            let stream =
+                push::request_streaming(root, last_response, config, client_store, NoCache)
+                    .await?
+                    .take(21);
+            let reader = StreamReader::new(stream.map_err(std::io::Error::other));
+
+            let response = push::response_streaming_with_checkpoint(
+                root,
+                reader,
+                checkpoint.as_deref(),
+                key,
+                config,
+                server_store,
+                NoCache,
+            )
+            .await?;
+
+            if response.indicates_finished() {
+                assert_eq!(response.checkpoint, None);
+                break;
+            }
+
+            assert!(response.checkpoint.is_some());
+            last_response = Some(response);
+        }
+
+        assert!(rounds > 1);
+        assert_eq!(
+            total_dag_blocks(root, server_store).await?,
+            total_dag_blocks(root, client_store).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_push_omits_checkpoints_too_large_for_header() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let mut leaves = Vec::new();
+        for i in 0..200u32 {
+            let leaf = client_store
+                .put_block(i.to_be_bytes().to_vec(), CODEC_RAW)
+                .await?;
+            leaves.push(Ipld::Link(leaf));
+        }
+        let root = client_store
+            .put_block(
+                encode(&Ipld::List(leaves), DagCborCodec)?,
+                DagCborCodec.into(),
+            )
+            .await?;
+
+        let server_store = &MemoryBlockStore::new();
+        let key = &CheckpointKey::new([1; 32]);
+        let config = &Config::default();
+
+        // Interrupt the first round after the root, so all leaves are missing
+        let stream = push::request_streaming(root, None, config, client_store, NoCache)
+            .await?
+            .take(2);
+        let reader = StreamReader::new(stream.map_err(std::io::Error::other));
+        let response = push::response_streaming_with_checkpoint(
+            root,
+            reader,
+            None,
+            key,
+            config,
+            server_store,
+            NoCache,
+        )
+        .await?;
+
+        assert_eq!(response.subgraph_roots.len(), 200);
+        assert!(!fits_header(&key.seal(root, &response.subgraph_roots)));
+        assert_eq!(response.checkpoint, None);
+
+        // Without a checkpoint, the server walks from the root again
+        let stream =
+            push::request_streaming(root, Some(response), config, client_store, NoCache).await?;
+        let reader = StreamReader::new(stream.map_err(std::io::Error::other));
+        let response = push::response_streaming_with_checkpoint(
+            root,
+            reader,
+            None,
+            key,
+            config,
+            server_store,
+            NoCache,
+        )
+        .await?;
+        assert!(response.indicates_finished());
+
+        Ok(())
+    }
+}
@@ -1,34 +1,59 @@
 use crate::{
-    cache::Cache,
-    dag_walk::DagWalk,
-    error::Error,
-    incremental_verification::{BlockState, IncrementalDagVerification},
-    messages::{PullRequest, PushResponse},
+    cache::{Cache, ReferenceExtractor},
+    dag_cbor,
+    dag_walk::{DagWalk, TraversedItem},
+    error::{ConfigError, Error, IncrementalVerificationError},
+    incremental_verification::{
+        log_unrelated_roots, BlockState, DigestSpawner, IncrementalDagVerification,
+        VerificationObserver,
+    },
+    messages::{CidEncoding, Direction, Message, PullRequest, PushResponse},
 };
 use bytes::Bytes;
 use deterministic_bloom::runtime_size::BloomFilter;
-use futures::{StreamExt, TryStreamExt};
+use futures::{future::Either, Future, SinkExt, StreamExt, TryFutureExt, TryStreamExt};
 use iroh_car::{CarHeader, CarReader, CarWriter};
 use libipld::{Ipld, IpldCodec};
-use libipld_core::{cid::Cid, codec::References};
-use std::io::Cursor;
+use libipld_core::{
+    cid::Cid,
+    codec::References,
+    multihash::{Code, MultihashDigest},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use wnfs_common::{
     utils::{boxed_stream, BoxStream, CondSend},
-    BlockStore,
+    BlockStore, BlockStoreError, CODEC_RAW,
 };
 
 //--------------------------------------------------------------------------------------------------
 // Types
 //--------------------------------------------------------------------------------------------------
 
+/// The maximum size of a single CAR file frame (CID + block) that `iroh-car` supports.
+pub const MAX_CAR_FRAME_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+
+/// The contents of the raw block that marks the end of a round in a CAR stream.
+/// See `with_end_of_round`.
+pub const END_OF_ROUND_MARKER: &[u8] = b"car-mirror: end of round";
+
 /// Configuration values (such as byte limits) for the CAR mirror protocol
+///
+/// Prefer constructing a `Config` via `Config::builder`, which checks that
+/// the values are consistent. The fields stay public for backwards compatibility.
 #[derive(Clone, Debug)]
 pub struct Config {
     /// The maximum number of bytes per request that a recipient should accept.
     ///
     /// This only has an effect in non-streaming versions of this protocol.
     /// In streaming versions, car-mirror will check the validity of each block
-    /// while streaming.
+    /// while streaming. To cap the bytes of streaming rounds, too, see
+    /// `stream_receive_maximum`.
     ///
     /// By default this is 2MB.
     pub receive_maximum: usize,
@@ -60,6 +85,242 @@ pub struct Config {
     /// one order of magnitude under the number of elements. E.g. for 100_000 elements,
     /// a false positive probability of 1 in 1 million.
     pub bloom_fpr: fn(u64) -> f64,
+    /// The maximum number of blocks per round that the recipient asks the
+    /// sender to send.
+    ///
+    /// This is sent as an advisory hint in pull requests, e.g. for interactive
+    /// clients that prefer small, frequent rounds. Senders may still send fewer
+    /// blocks, e.g. due to their `receive_maximum`.
+    ///
+    /// By default this is `None`, so there's no limit on the number of blocks.
+    pub max_blocks_per_round: Option<usize>,
+    /// The maximum time to wait for the next frame when receiving a CAR stream.
+    ///
+    /// The timeout starts over with every frame received, so it doesn't limit
+    /// the total time a transfer can take, but it prevents peers from keeping
+    /// a transfer open indefinitely without sending any data.
+    ///
+    /// By default this is `None`, so there's no timeout.
+    pub stream_idle_timeout: Option<Duration>,
+    /// The maximum number of blocks the sender reads ahead from its store,
+    /// before they're framed into the CAR file. See `buffer_block_stream`.
+    ///
+    /// This improves throughput when reading from the store is fast, but the
+    /// consumer of the CAR file, e.g. the network, is slow.
+    ///
+    /// By default this is `0`, so blocks are read in lockstep with the consumer.
+    pub send_buffer_blocks: usize,
+    /// An optional observer that gets notified about blocks failing
+    /// verification while receiving, with their CIDs, and about rounds
+    /// getting interrupted early.
+    ///
+    /// By default this is `None`.
+    pub verification_observer: Option<Arc<dyn VerificationObserver>>,
+    /// Whether a received CAR stream must end with the end-of-round block,
+    /// see `with_end_of_round`.
+    ///
+    /// If set, a stream that ends without it is considered truncated and fails
+    /// with `Error::MissingEndOfRound`, instead of being treated as a clean end
+    /// of the round. Only enable this if the sender appends that block.
+    ///
+    /// By default this is `false`.
+    pub require_end_of_round: bool,
+    /// The maximum size in bytes of bloom filters in received push responses
+    /// or pull requests.
+    ///
+    /// Messages with larger bloom filters are rejected with `Error::BloomTooLarge`
+    /// in `ReceiverState::from_message_checked`, so peers can't make us allocate
+    /// arbitrarily large bloom filters.
+    ///
+    /// By default this is 2MB.
+    pub max_bloom_bytes: usize,
+    /// The maximum number of root CIDs in received push responses or pull requests.
+    ///
+    /// Messages with more roots are rejected with `Error::TooManyRoots` in
+    /// `ReceiverState::from_message_checked`, so peers can't make us request or
+    /// walk arbitrarily many subgraphs. While `max_roots_per_round` limits the
+    /// roots in messages we send, this limits the ones we accept.
+    ///
+    /// By default this is 10,000.
+    pub max_incoming_roots: usize,
+    /// The maximum number of CIDs in the frontier of DAG traversals, which
+    /// bounds memory usage for very wide DAGs. Exceeding it fails the transfer
+    /// with `Error::FrontierTooLarge`.
+    ///
+    /// This applies to all receiving and sending functions.
+    ///
+    /// By default this is `None`, so there's no limit.
+    pub max_frontier_size: Option<usize>,
+    /// Whether to transfer only the structure of DAGs, leaving out leaf data,
+    /// i.e. blocks with the raw codec.
+    ///
+    /// When sending, raw blocks are left out, unless they're explicitly asked for
+    /// as subgraph roots. When receiving, missing raw blocks aren't asked for.
+    /// So both ends need this set for a structure-only transfer. Afterwards,
+    /// `compute_missing` returns the leaf CIDs, so they can be fetched on demand.
+    ///
+    /// By default this is `false`.
+    pub skip_leaf_data: bool,
+    /// Whether to treat CIDv0s and CIDv1s of the same DAG-PB block as equivalent
+    /// while verifying received blocks, e.g. when a DAG links to a block via its
+    /// CIDv1, but the store holds it under its CIDv0.
+    ///
+    /// This only affects the receiving end. The missing subgraph roots in the
+    /// resulting `ReceiverState` keep the CID version they're linked with,
+    /// since senders look them up as-is.
+    ///
+    /// By default this is `false`.
+    pub normalize_cid_versions: bool,
+    /// An optional set of codecs that received blocks may have, e.g. only
+    /// DAG-PB and raw for a UnixFS-only service. Receiving a block with any other
+    /// codec fails with `Error::UnsupportedCodec`, even if this library supports it.
+    ///
+    /// By default this is `None`, so all supported codecs are allowed.
+    pub allowed_codecs: Option<HashSet<u64>>,
+    /// Whether exports emit blocks leaves-first, i.e. each block only after
+    /// all blocks it links to, e.g. for importers that build indexes bottom-up.
+    /// See `export::to_car_writer_with`.
+    ///
+    /// This needs to load the CIDs of the whole DAG before writing the first block.
+    ///
+    /// Car mirror receivers verify blocks starting from the root, so they can't
+    /// make progress with leaves-first rounds. Sending protocol rounds, e.g. with
+    /// `block_send`, fails with `Error::LeafFirstRound` if this is set.
+    ///
+    /// By default this is `false`.
+    pub leaf_first: bool,
+    /// How often the sender retries reading a block from its store, if that fails
+    /// with anything but `BlockStoreError::CIDNotFound`, e.g. for stores backed by
+    /// remote object storage where reads occasionally fail.
+    ///
+    /// Retries back off exponentially, starting at 10ms.
+    ///
+    /// By default this is `0`, so failed reads fail the transfer immediately.
+    pub block_fetch_retries: usize,
+    /// How often `transport::drive_pull` starts a fresh round after a round failed
+    /// with a recoverable error, e.g. a corrupted block in the middle of the stream.
+    /// See `Error::is_recoverable`. This is a limit for the whole pull, not per round.
+    ///
+    /// By default this is `3`.
+    pub round_retries: usize,
+    /// How many levels below the requested subgraph roots `block_send` sends
+    /// regardless of the receiver's bloom, e.g. so the structure of UnixFS
+    /// directories shows up quickly on the first round of a pull.
+    ///
+    /// The subgraph roots themselves are always sent. With this set to `2`, their
+    /// children and grandchildren are sent, too, even if the bloom indicates that
+    /// the receiver has them. Deeper blocks are only sent if they're not in the bloom.
+    /// This only costs bandwidth, since receivers still verify every block.
+    ///
+    /// This has no effect with `leaf_first`.
+    ///
+    /// By default this is `0`.
+    pub eager_prefetch_depth: usize,
+    /// An optional maximum for the total bytes of blocks received in a single
+    /// round, which unlike `receive_maximum` also applies to streaming rounds.
+    ///
+    /// Receiving fails with `Error::TooManyBytes` once a round exceeds it.
+    /// Blocks received up to that point stay stored, so the next round continues
+    /// from there. Without this, only each block's size is bounded by
+    /// `max_block_size`, so a streaming round can carry any number of bytes.
+    ///
+    /// By default this is `None`.
+    pub stream_receive_maximum: Option<usize>,
+    /// Whether `transport::drive_push` and `transport::drive_pull` record a
+    /// `transport::Manifest` of all transferred blocks, e.g. for audit logs.
+    ///
+    /// This keeps the CIDs of all transferred blocks in memory.
+    ///
+    /// By default this is `false`.
+    pub record_manifest: bool,
+    /// Whether receivers reject CAR files with header roots that are neither
+    /// the requested root nor one of its missing subgraph roots, with
+    /// `Error::MismatchedCarRoots`. Also rejects CAR files without roots.
+    ///
+    /// Senders name the first block of each round as the CAR root, which is
+    /// always one of the requested subgraph roots, so this catches misconfigured
+    /// senders early. Otherwise, only CAR roots that can't be part of the DAG at
+    /// all are rejected, see `Error::UnrelatedCarRoots`.
+    ///
+    /// By default this is `false`.
+    pub require_matching_car_roots: bool,
+    /// How CID lists are encoded in the pull requests and push responses
+    /// that receivers create, see `CidEncoding`.
+    ///
+    /// Receivers decode either encoding, but peers from before
+    /// `CidEncoding::Binary` was introduced can't, so only use it if you
+    /// know the peer supports it.
+    ///
+    /// By default this is `CidEncoding::Strings`.
+    pub cid_encoding: CidEncoding,
+    /// An optional spawner for hashing received blocks, so that hashing
+    /// runs in parallel to receiving the next blocks, see `DigestSpawner`.
+    ///
+    /// Receivers then read up to 4 blocks ahead of the block they're verifying.
+    /// Blocks are still checked against the DAG and stored one by one, in the
+    /// order they're received. This mostly pays off for large blocks on fast links.
+    ///
+    /// By default this is `None`, so blocks are hashed one by one as they arrive.
+    pub digest_spawner: Option<Arc<dyn DigestSpawner>>,
+    /// What receivers do with blocks they can't show to be linked to from
+    /// the root (yet), e.g. because a bloom filter false positive made the
+    /// sender skip a block in between. See `UnexpectedPolicy`.
+    ///
+    /// By default this is `UnexpectedPolicy::Stop`.
+    pub on_unexpected_block: UnexpectedPolicy,
+    /// How many blocks receivers read in a round before they stop it early
+    /// because of a block they already have or a block they can't verify yet.
+    ///
+    /// Until then, such blocks are skipped and receiving continues. For DAGs
+    /// that overlap a lot with what the receiver already has, this trades
+    /// receiving a few redundant blocks for far fewer rounds.
+    ///
+    /// By default this is `0`, so rounds are stopped at the first such block.
+    pub min_blocks_before_interrupt: usize,
+    /// Whether receivers skip blocks that were already received earlier in
+    /// the same round, e.g. from sloppy CAR producers that repeat blocks.
+    ///
+    /// Otherwise, a repeated block is treated like any other block the receiver
+    /// already has, which stops the round early.
+    ///
+    /// By default this is `false`.
+    pub tolerate_duplicate_blocks: bool,
+    /// Receivers pass a snapshot of their `ReceiverState` to
+    /// `VerificationObserver::on_receiver_state` every n blocks they store
+    /// in a round, so long rounds can be followed up on before they end.
+    ///
+    /// Computing a snapshot builds the bloom filter of all blocks the
+    /// receiver has, so don't pick intervals that are too small.
+    ///
+    /// By default this is `0`, so no snapshots are taken.
+    pub receiver_state_every: usize,
+    /// Receivers reject DAG-CBOR blocks with malformed CID links, i.e. links
+    /// that aren't exactly the multibase identity prefix followed by one CID.
+    /// See `check_cid_links`.
+    ///
+    /// This makes blocks from non-conformant encoders visible, at the cost
+    /// of not being able to sync DAGs that contain them.
+    ///
+    /// By default this is `false`, so links are read as leniently as libipld
+    /// does: A missing prefix is tolerated and bytes trailing the CID are ignored.
+    pub strict_cid_links: bool,
+}
+
+/// What to do when receiving a block with `BlockState::Unexpected`,
+/// see `Config::on_unexpected_block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnexpectedPolicy {
+    /// Stop reading the round and tell the sender what's still missing
+    /// in the next request. This costs a round-trip, but doesn't waste
+    /// bandwidth on blocks that follow the unexpected one.
+    #[default]
+    Stop,
+    /// Discard the block without storing it and keep reading the round.
+    ///
+    /// This avoids the extra round-trip with high bloom false positive rates,
+    /// e.g. on high-latency links. Blocks below the skipped one are discarded,
+    /// too, so they're sent again in the next round.
+    DiscardAndContinue,
 }
 
 impl Default for Config {
@@ -69,10 +330,43 @@ impl Default for Config {
             max_block_size: 1_000_000,  // 1 MB
             max_roots_per_round: 1000,  // max. ~41KB of CIDs
             bloom_fpr: |num_of_elems| f64::min(0.001, 0.1 / num_of_elems as f64),
+            max_blocks_per_round: None,
+            stream_idle_timeout: None,
+            send_buffer_blocks: 0,
+            verification_observer: None,
+            require_end_of_round: false,
+            max_bloom_bytes: 2_000_000, // 2 MB
+            max_incoming_roots: 10_000, // max. ~410KB of CIDs
+            max_frontier_size: None,
+            skip_leaf_data: false,
+            normalize_cid_versions: false,
+            allowed_codecs: None,
+            leaf_first: false,
+            block_fetch_retries: 0,
+            round_retries: 3,
+            eager_prefetch_depth: 0,
+            stream_receive_maximum: None,
+            record_manifest: false,
+            require_matching_car_roots: false,
+            cid_encoding: CidEncoding::Strings,
+            digest_spawner: None,
+            on_unexpected_block: UnexpectedPolicy::Stop,
+            min_blocks_before_interrupt: 0,
+            tolerate_duplicate_blocks: false,
+            receiver_state_every: 0,
+            strict_cid_links: false,
         }
     }
 }
 
+/// A builder for `Config` values that validates them on `build`.
+///
+/// Starts out with the values from `Config::default`.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
 /// Some information that the block receiving end provides the block sending end
 /// in order to deduplicate block transfers.
 #[derive(Clone)]
@@ -81,6 +375,9 @@ pub struct ReceiverState {
     pub missing_subgraph_roots: Vec<Cid>,
     /// An optional bloom filter of all CIDs below the root that the receiving end has.
     pub have_cids_bloom: Option<BloomFilter>,
+    /// An optional hint for the maximum number of blocks the receiving end
+    /// wants to get in the next round.
+    pub max_blocks: Option<usize>,
 }
 
 /// Newtype around bytes that are supposed to represent a CAR file
@@ -91,6 +388,28 @@ pub struct CarFile {
     pub bytes: Bytes,
 }
 
+/// The result of `diff_dags`: Which blocks of a DAG are in which of two stores.
+///
+/// All lists are sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DagDiff {
+    /// Blocks that only store A has.
+    pub only_in_a: Vec<Cid>,
+    /// Blocks that only store B has.
+    pub only_in_b: Vec<Cid>,
+    /// Blocks that both stores have.
+    pub common: Vec<Cid>,
+}
+
+/// The result of `prefetch_dag`: How many blocks of a DAG were fetched.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// Blocks that were fetched from the store and had their references cached.
+    pub blocks_fetched: usize,
+    /// Blocks that are linked to from fetched blocks, but missing from the store.
+    pub blocks_missing: usize,
+}
+
 /// A stream of blocks. This requires the underlying futures to be `Send`, except when the target is `wasm32`.
 pub type BlockStream<'a> = BoxStream<'a, Result<(Cid, Bytes), Error>>;
 
@@ -117,19 +436,37 @@ pub async fn block_send(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<CarFile, Error> {
-    let bytes = block_send_car_stream(
-        root,
-        last_state,
-        Vec::new(),
-        Some(config.receive_maximum),
-        store,
-        cache,
-    )
-    .await?;
+    block_send_excluding(root, last_state, HashSet::new(), config, store, cache).await
+}
 
-    Ok(CarFile {
-        bytes: bytes.into(),
-    })
+/// Like `block_send`, but for a receiving end that's known to have the
+/// complete DAG below `base_root`, e.g. because it was synced before.
+///
+/// The DAG below `base_root` is treated as already sent, so only blocks
+/// below `root` that aren't part of it are walked and sent. Unlike relying
+/// on the receiver's bloom filter, this has no false positives and saves
+/// walking the shared parts of both DAGs.
+///
+/// The complete DAG below `base_root` needs to be available in `store`.
+/// If the receiving end doesn't actually have all of it, the transfer
+/// can't finish, since blocks that are missing from the base DAG are never sent.
+#[tracing::instrument(skip_all, fields(root, base_root, last_state))]
+pub async fn block_send_delta(
+    root: Cid,
+    base_root: Cid,
+    last_state: Option<ReceiverState>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<CarFile, Error> {
+    let base_cids = DagWalk::breadth_first([base_root])
+        .with_max_frontier_size(config.max_frontier_size)
+        .stream(&store, &cache)
+        .and_then(|item| async move { item.to_cid() })
+        .try_collect::<HashSet<_>>()
+        .await?;
+
+    block_send_excluding(root, last_state, base_cids, config, store, cache).await
 }
 
 /// This is the streaming equivalent of `block_send`.
@@ -141,10 +478,11 @@ pub async fn block_send_car_stream<W: tokio::io::AsyncWrite + Unpin + Send>(
     last_state: Option<ReceiverState>,
     writer: W,
     send_limit: Option<usize>,
+    config: &Config,
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<W, Error> {
-    let mut block_stream = block_send_block_stream(root, last_state, store, cache).await?;
+    let mut block_stream = block_send_block_stream(root, last_state, config, store, cache).await?;
     write_blocks_into_car(writer, &mut block_stream, send_limit).await
 }
 
@@ -153,26 +491,92 @@ pub async fn block_send_car_stream<W: tokio::io::AsyncWrite + Unpin + Send>(
 pub async fn block_send_block_stream<'a>(
     root: Cid,
     last_state: Option<ReceiverState>,
+    config: &Config,
     store: impl BlockStore + 'a,
     cache: impl Cache + 'a,
 ) -> Result<BlockStream<'a>, Error> {
-    let ReceiverState {
-        missing_subgraph_roots,
-        have_cids_bloom,
-    } = last_state.unwrap_or(ReceiverState {
-        missing_subgraph_roots: vec![root],
-        have_cids_bloom: None,
-    });
+    block_send_block_stream_prioritized(root, last_state, |_| 0, config, store, cache).await
+}
 
-    // Verify that all missing subgraph roots are in the relevant DAG:
-    let subgraph_roots =
-        verify_missing_subgraph_roots(root, &missing_subgraph_roots, &store, &cache).await?;
+/// Like `block_send_block_stream`, but walks the missing subgraph roots
+/// with higher `root_priority` first.
+///
+/// Roots with the same priority keep their order. This can be used to send
+/// the "shape" of a DAG first and fill in leaves later, e.g. by prioritizing
+/// directory nodes over raw file contents with
+/// `|cid| if cid.codec() == CODEC_RAW { 0 } else { 1 }`.
+pub async fn block_send_block_stream_prioritized<'a>(
+    root: Cid,
+    last_state: Option<ReceiverState>,
+    root_priority: impl Fn(&Cid) -> i32,
+    config: &Config,
+    store: impl BlockStore + 'a,
+    cache: impl Cache + 'a,
+) -> Result<BlockStream<'a>, Error> {
+    if config.leaf_first {
+        return Err(Error::LeafFirstRound);
+    }
 
-    let bloom = handle_missing_bloom(have_cids_bloom);
+    block_send_block_stream_with(
+        root,
+        last_state,
+        root_priority,
+        HashSet::new(),
+        config,
+        store,
+        cache,
+    )
+    .await
+}
+
+/// Reads up to `config.send_buffer_blocks` blocks ahead from given block
+/// stream into a bounded buffer, so blocks can be fetched from the store
+/// while the consumer is still busy with previous blocks.
+///
+/// Put this between `block_send_block_stream` and `stream_car_frames` or
+/// other CAR framing. Reading ahead stops once the blocks read add up to
+/// `size_limit` bytes, so no blocks are read that won't be sent anyway.
+///
+/// Blocks are only read ahead while the returned stream is polled, so this
+/// doesn't need a specific async runtime. If `config.send_buffer_blocks`
+/// is `0`, the block stream is returned as-is.
+pub fn buffer_block_stream<'a>(
+    mut blocks: BlockStream<'a>,
+    config: &Config,
+    size_limit: Option<usize>,
+) -> BlockStream<'a> {
+    let buffer_blocks = config.send_buffer_blocks;
+    if buffer_blocks == 0 {
+        return blocks;
+    }
+
+    // The channel's capacity is its buffer size plus one slot per sender
+    let (mut sender, receiver) = futures::channel::mpsc::channel(buffer_blocks - 1);
+
+    let read_ahead = async move {
+        let mut bytes_read = 0;
+        while let Some(item) = blocks.next().await {
+            let is_last = match &item {
+                Ok((_, block)) => {
+                    bytes_read += block.len();
+                    size_limit.is_some_and(|limit| bytes_read >= limit)
+                }
+                Err(_) => true,
+            };
 
-    let stream = stream_blocks_from_roots(subgraph_roots, bloom, store, cache);
+            if sender.send(item).await.is_err() || is_last {
+                // Either the consumer is gone or it won't want further blocks
+                break;
+            }
+        }
+    };
 
-    Ok(Box::pin(stream))
+    // Drive reading ahead whenever the consumer polls for the next block.
+    // Once reading ahead is done, the sender is dropped, ending the receiver.
+    let read_ahead = futures::stream::once(read_ahead)
+        .filter_map(|()| futures::future::ready(None::<Result<(Cid, Bytes), Error>>));
+
+    Box::pin(futures::stream::select(receiver, read_ahead))
 }
 
 /// This function is run on the block receiving end of the protocol.
@@ -202,9 +606,16 @@ pub async fn block_receive(
 
             block_receive_car_stream(root, Cursor::new(car.bytes), config, store, cache).await?
         }
-        None => IncrementalDagVerification::new([root], &store, &cache)
-            .await?
-            .into_receiver_state(config.bloom_fpr),
+        None => receiver_state_for(
+            &IncrementalDagVerification::from_config(
+                [root],
+                config,
+                &InlineBlockStore { store: &store },
+                &cache,
+            )
+            .await?,
+            config,
+        ),
     };
 
     receiver_state
@@ -223,7 +634,32 @@ pub async fn block_receive_car_stream<R: tokio::io::AsyncRead + Unpin + CondSend
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<ReceiverState, Error> {
-    let reader = CarReader::new(reader).await?;
+    block_receive_car_stream_resumed(root, vec![root], reader, config, store, cache).await
+}
+
+/// Like `block_receive_car_stream`, but resumes verification from given
+/// `want_cids` instead of walking the whole DAG below `root` first.
+///
+/// The `want_cids` need to be known to be part of the DAG below `root`, e.g.
+/// the missing subgraph roots from an earlier round that were authenticated
+/// via a `checkpoint::CheckpointKey`. Otherwise blocks unrelated to `root`
+/// would be accepted.
+#[tracing::instrument(skip_all, fields(root))]
+pub async fn block_receive_car_stream_resumed<R: tokio::io::AsyncRead + Unpin + CondSend>(
+    root: Cid,
+    want_cids: Vec<Cid>,
+    reader: R,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<ReceiverState, Error> {
+    let reader = with_idle_timeout(
+        config.stream_idle_timeout,
+        CarReader::new(reader).map_err(Error::CarFileError),
+    )
+    .await?;
+
+    let car_roots = reader.header().roots().to_vec();
 
     let mut stream: BlockStream<'_> = Box::pin(
         reader
@@ -232,7 +668,39 @@ pub async fn block_receive_car_stream<R: tokio::io::AsyncRead + Unpin + CondSend
             .map_err(Error::CarFileError),
     );
 
-    block_receive_block_stream(root, &mut stream, config, store, cache).await
+    block_receive_block_stream_from(
+        root,
+        want_cids,
+        &car_roots,
+        &mut stream,
+        config,
+        store,
+        cache,
+    )
+    .await
+}
+
+/// Like `block_receive_car_stream`, but only verifies the received blocks
+/// instead of storing them.
+///
+/// This is useful for relays that forward CAR data between peers without
+/// persisting it, but still want to detect and reject malformed transfers.
+///
+/// Block bytes are discarded after verification, only the links of verified
+/// blocks are kept in memory until this function returns.
+///
+/// Returns the `ReceiverState` that a receiver starting out with an empty
+/// store would produce.
+#[tracing::instrument(skip_all, fields(root))]
+pub async fn block_receive_verify_only<R: tokio::io::AsyncRead + Unpin + CondSend>(
+    root: Cid,
+    reader: R,
+    config: &Config,
+    cache: impl Cache,
+) -> Result<ReceiverState, Error> {
+    let verify_only = VerifyOnlyStore::new(cache);
+
+    block_receive_car_stream(root, reader, config, &verify_only, &verify_only).await
 }
 
 /// Consumes a stream of blocks, verifying their integrity and
@@ -244,14 +712,97 @@ pub async fn block_receive_block_stream(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<ReceiverState, Error> {
+    block_receive_block_stream_from(root, vec![root], &[], stream, config, store, cache).await
+}
+
+/// Like `block_receive_block_stream`, but starts verification from given
+/// `want_cids`, which need to be part of the DAG below `root`.
+///
+/// Before consuming any blocks, this checks that all `car_roots` declared
+/// in the CAR header are wanted or already available, so CAR files that are
+/// unrelated to `root` get rejected early.
+async fn block_receive_block_stream_from(
+    root: Cid,
+    want_cids: Vec<Cid>,
+    car_roots: &[Cid],
+    stream: &mut BlockStream<'_>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<ReceiverState, Error> {
+    let store = InlineBlockStore { store };
     let max_block_size = config.max_block_size;
-    let mut dag_verification = IncrementalDagVerification::new([root], &store, &cache).await?;
+    let mut dag_verification =
+        IncrementalDagVerification::from_config(want_cids, config, &store, &cache).await?;
+
+    // Senders put the first block they send into the CAR header, which is
+    // always one of the subgraph roots that we asked for. In empty rounds,
+    // that's the end of round marker instead, which isn't part of any DAG.
+    let end_of_round = end_of_round_cid();
+    let is_empty_round = car_roots == [end_of_round];
+    let car_roots = if is_empty_round { &[][..] } else { car_roots };
+
+    if car_roots
+        .iter()
+        .any(|cid| dag_verification.block_state(*cid) == BlockState::Unexpected)
+    {
+        tracing::warn!(%root, ?car_roots, "Received CAR with unrelated roots");
+        return Err(Error::UnrelatedCarRoots {
+            root,
+            car_roots: car_roots.to_vec(),
+        });
+    }
+
+    if config.require_matching_car_roots
+        && ((car_roots.is_empty() && !is_empty_round)
+            || car_roots
+                .iter()
+                .any(|cid| *cid != root && dag_verification.block_state(*cid) != BlockState::Want))
+    {
+        tracing::warn!(%root, ?car_roots, "Received CAR with mismatched roots");
+        return Err(Error::MismatchedCarRoots {
+            root,
+            car_roots: car_roots.to_vec(),
+        });
+    }
+
+    // Whether the round ended intentionally, as opposed to the stream just ending
+    let mut ended_intentionally = false;
+    let mut bytes_read = 0;
+    let mut blocks_read = 0;
+    // Blocks received this round, to recognize duplicates
+    let mut received = HashSet::new();
+    let mut blocks_stored = 0;
+
+    let mut stream = match &config.digest_spawner {
+        Some(spawner) => pipeline_digests(stream, Arc::clone(spawner), max_block_size),
+        None => boxed_stream(stream.map_ok(|block| (block, None))),
+    };
+
+    while let Some(((cid, block), actual_cid)) =
+        with_idle_timeout(config.stream_idle_timeout, stream.try_next()).await?
+    {
+        if cid == end_of_round {
+            tracing::debug!("Received end of round marker");
+            ended_intentionally = true;
+            break;
+        }
+
+        if let Some(allowed_codecs) = &config.allowed_codecs {
+            if !allowed_codecs.contains(&cid.codec()) {
+                tracing::debug!(%cid, codec = cid.codec(), "Received block with disallowed codec");
+                return Err(Error::UnsupportedCodec { cid });
+            }
+        }
 
-    while let Some((cid, block)) = stream.try_next().await? {
         let block_bytes = block.len();
         // TODO(matheus23): Find a way to restrict size *before* framing. Possibly inside `CarReader`?
         // Possibly needs making `MAX_ALLOC` in `iroh-car` configurable.
         if block_bytes > config.max_block_size {
+            if let Some(observer) = &config.verification_observer {
+                observer.on_oversized_block(root, cid, block_bytes, max_block_size);
+            }
+
             return Err(Error::BlockSizeExceeded {
                 cid,
                 block_bytes,
@@ -259,14 +810,76 @@ pub async fn block_receive_block_stream(
             });
         }
 
-        match read_and_verify_block(&mut dag_verification, (cid, block), &store, &cache).await? {
+        bytes_read += block_bytes;
+        blocks_read += 1;
+        if let Some(receive_maximum) = config.stream_receive_maximum {
+            if bytes_read > receive_maximum {
+                tracing::debug!(%cid, bytes_read, receive_maximum, "Round exceeded stream receive maximum");
+                return Err(Error::TooManyBytes {
+                    receive_maximum,
+                    bytes_read,
+                });
+            }
+        }
+
+        if config.tolerate_duplicate_blocks && received.contains(&cid) {
+            tracing::debug!(%cid, "Skipping block received twice in this round");
+            continue;
+        }
+
+        let result = read_and_verify_block(
+            &mut dag_verification,
+            (cid, block),
+            actual_cid,
+            config,
+            &store,
+            &cache,
+        )
+        .await;
+
+        if let (
+            Some(observer),
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::DigestMismatch { cid, actual_cid },
+            )),
+        ) = (&config.verification_observer, &result)
+        {
+            observer.on_digest_mismatch(root, **cid, **actual_cid);
+        }
+
+        match result? {
             BlockState::Have => {
+                if blocks_read < config.min_blocks_before_interrupt {
+                    tracing::debug!(%cid, "Skipping block we already have");
+                    continue;
+                }
+
                 // This can happen because we've just discovered a subgraph we already have.
                 // Let's update the endpoint with our new receiver state.
+                if let Some(observer) = &config.verification_observer {
+                    observer.on_round_interrupted(root, cid, BlockState::Have);
+                }
+
                 tracing::debug!(%cid, "Received block we already have, stopping transfer");
+                ended_intentionally = true;
                 break;
             }
             BlockState::Unexpected => {
+                if let Some(observer) = &config.verification_observer {
+                    observer.on_unexpected_block(root, cid);
+                }
+
+                if config.on_unexpected_block == UnexpectedPolicy::DiscardAndContinue
+                    || blocks_read < config.min_blocks_before_interrupt
+                {
+                    tracing::debug!(%cid, "Discarding block received out of order");
+                    continue;
+                }
+
+                if let Some(observer) = &config.verification_observer {
+                    observer.on_round_interrupted(root, cid, BlockState::Unexpected);
+                }
+
                 // We received a block out-of-order. This is weird, but can
                 // happen due to bloom filter false positives.
                 // Essentially, the sender could've skipped a block that was
@@ -274,15 +887,32 @@ pub async fn block_receive_block_stream(
                 // to the root.
                 // We should update the endpoint about the skipped block.
                 tracing::debug!(%cid, "Received block out of order, stopping transfer");
+                ended_intentionally = true;
                 break;
             }
             BlockState::Want => {
                 // Perfect, we're just getting what we want. Let's continue!
+                if config.tolerate_duplicate_blocks {
+                    received.insert(cid);
+                }
+
+                blocks_stored += 1;
+                let every = config.receiver_state_every;
+                if every > 0 && blocks_stored % every == 0 {
+                    if let Some(observer) = &config.verification_observer {
+                        let state = receiver_state_for(&dag_verification, config);
+                        observer.on_receiver_state(root, &state);
+                    }
+                }
             }
         }
     }
 
-    Ok(dag_verification.into_receiver_state(config.bloom_fpr))
+    if config.require_end_of_round && !ended_intentionally {
+        return Err(Error::MissingEndOfRound);
+    }
+
+    Ok(receiver_state_for(&dag_verification, config))
 }
 
 /// Turns a stream of blocks (tuples of CIDs and Bytes) into a stream
@@ -293,6 +923,10 @@ pub async fn block_receive_block_stream(
 /// The frame boundaries are after the header section and between each block.
 ///
 /// The first frame will always be a CAR file header frame.
+///
+/// The resulting stream just ends after the last block, so receivers can't
+/// tell a clean end from a dropped connection. To make that distinguishable,
+/// wrap the blocks in `with_end_of_round` first.
 pub async fn stream_car_frames(mut blocks: BlockStream<'_>) -> Result<CarStream<'_>, Error> {
     // https://github.com/wnfs-wg/car-mirror-spec/issues/6
     // CAR files *must* have at least one CID in them, and all of them
@@ -317,78 +951,655 @@ pub async fn stream_car_frames(mut blocks: BlockStream<'_>) -> Result<CarStream<
     ))
 }
 
-/// Find all CIDs that a block references.
+/// Computes the CIDv1 of a block with given codec and hash function.
 ///
-/// This will error out if
-/// - the codec is not supported
-/// - the block can't be parsed.
-pub fn references<E: Extend<Cid>>(
-    cid: Cid,
-    block: impl AsRef<[u8]>,
-    mut refs: E,
-) -> Result<E, anyhow::Error> {
-    let codec: IpldCodec = cid
-        .codec()
-        .try_into()
-        .map_err(|_| Error::UnsupportedCodec { cid })?;
+/// This is how the protocol hashes blocks, e.g. when verifying received blocks.
+/// Stores with a custom `BlockStore::create_cid` should produce the same CIDs
+/// for the hash function they use, otherwise blocks they create won't verify.
+pub fn compute_cid(codec: u64, hash_code: Code, bytes: &[u8]) -> Cid {
+    Cid::new_v1(codec, hash_code.digest(bytes))
+}
 
-    <Ipld as References<IpldCodec>>::references(codec, &mut Cursor::new(block), &mut refs)?;
-    Ok(refs)
+/// The CID of the raw block containing `END_OF_ROUND_MARKER`.
+pub fn end_of_round_cid() -> Cid {
+    compute_cid(CODEC_RAW, Code::Sha2_256, END_OF_ROUND_MARKER)
 }
 
-//--------------------------------------------------------------------------------------------------
-// Private
-//--------------------------------------------------------------------------------------------------
+/// Appends a block that marks the end of the round to given block stream,
+/// if it ends without errors.
+///
+/// Framed as a CAR file, this lets receivers tell a sender that finished the
+/// round intentionally apart from a connection that dropped. Receivers stop at
+/// this block without storing it, and fail with `Error::MissingEndOfRound` if
+/// they set `Config::require_end_of_round` and the stream ends without it.
+///
+/// Older receivers that don't know about this block treat it like any
+/// other unexpected block and simply stop reading.
+pub fn with_end_of_round(blocks: BlockStream<'_>) -> BlockStream<'_> {
+    let end_of_round = (end_of_round_cid(), Bytes::from_static(END_OF_ROUND_MARKER));
+    boxed_stream(blocks.chain(futures::stream::once(async move { Ok(end_of_round) })))
+}
 
-async fn car_frame_from_block(block: (Cid, Bytes)) -> Result<Bytes, Error> {
-    // TODO(matheus23): I wish this were exposed in iroh-car somehow
-    // Instead of having to allocate so many things.
+/// Computes exactly which blocks below `root` are missing from `store`,
+/// without running a transfer.
+///
+/// Unlike the bloom filters in protocol messages, this isn't probabilistic.
+/// Only blocks linked from blocks in `store` (or `root` itself) can be found,
+/// since the links of missing blocks aren't known. So these are the roots of
+/// all missing subgraphs. The result is sorted.
+pub async fn compute_missing(
+    root: Cid,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<Vec<Cid>, Error> {
+    let store = InlineBlockStore { store };
+    let dag_verification = IncrementalDagVerification::new([root], &store, &cache).await?;
+    let mut missing: Vec<Cid> = dag_verification.want_cids.into_iter().collect();
+    missing.sort_unstable();
+    Ok(missing)
+}
 
-    // The writer will always first emit a header.
-    // If we don't force it here, it'll do so in `writer.write()`.
-    // We do it here so we find out how many bytes we need to skip.
-    let bogus_header = CarHeader::new_v1(vec![Cid::default()]);
-    let mut writer = CarWriter::new(bogus_header, Vec::new());
-    let start = writer.write_header().await?;
+/// Computes which blocks of the DAG below `root` are in which of two stores,
+/// without running a transfer.
+///
+/// This walks the DAG over both stores at once, so blocks that only one
+/// of the stores can reach are found, too, e.g. when store A lacks a block
+/// that links to blocks it has. Blocks that are missing from both stores,
+/// as well as blocks that are only linked to from these, can't be found and
+/// aren't part of the result.
+///
+/// This is useful e.g. for repair tooling, or for deciding whether to push
+/// or pull: `only_in_a` are the blocks a push from A to B would transfer,
+/// `only_in_b` the ones a pull would.
+pub async fn diff_dags(
+    root: Cid,
+    store_a: impl BlockStore,
+    store_b: impl BlockStore,
+    cache: impl Cache,
+) -> Result<DagDiff, Error> {
+    let union = UnionStore {
+        a: &store_a,
+        b: &store_b,
+    };
+    let mut diff = DagDiff::default();
+    let mut dag_walk = DagWalk::breadth_first([root]);
 
-    writer.write(block.0, block.1).await?;
-    let mut bytes = writer.finish().await?;
+    while let Some(item) = dag_walk.next(&union, &cache).await? {
+        let TraversedItem::Have(cid) = item else {
+            // Missing from both stores
+            continue;
+        };
 
-    // This removes the bogus header bytes
-    bytes.drain(0..start);
+        let in_a = store_a
+            .has_block(&cid)
+            .await
+            .map_err(Error::BlockStoreError)?;
 
-    Ok(bytes.into())
-}
+        if !in_a {
+            diff.only_in_b.push(cid);
+        } else if store_b
+            .has_block(&cid)
+            .await
+            .map_err(Error::BlockStoreError)?
+        {
+            diff.common.push(cid);
+        } else {
+            diff.only_in_a.push(cid);
+        }
+    }
+
+    diff.only_in_a.sort_unstable();
+    diff.only_in_b.sort_unstable();
+    diff.common.sort_unstable();
+
+    Ok(diff)
+}
+
+/// Computes a fingerprint of the blocks of the DAG below `root` in `store`.
+///
+/// This is the SHA-256 hash of the sorted, binary CIDs of all blocks that
+/// are reachable from `root` and present in `store`. So two stores have the
+/// same fingerprint for a root if and only if they hold the same part of its
+/// DAG, barring hash collisions.
+///
+/// Peers can exchange fingerprints as a cheap check whether they're already
+/// in sync before running a transfer. Computing one walks the whole DAG,
+/// so consider caching them for large DAGs.
+pub async fn dag_fingerprint(
+    root: Cid,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<[u8; 32], Error> {
+    let store = InlineBlockStore { store };
+    let mut cids = Vec::new();
+    let mut dag_walk = DagWalk::breadth_first([root]);
+
+    while let Some(item) = dag_walk.next(&store, &cache).await? {
+        if let TraversedItem::Have(cid) = item {
+            cids.push(cid);
+        }
+    }
+
+    cids.sort_unstable();
+    cids.dedup();
+
+    // Binary CIDs are self-delimiting, so concatenating them is unambiguous
+    let mut hasher = Sha256::new();
+    for cid in cids {
+        hasher.update(cid.to_bytes());
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Fetches all blocks of the DAG below `root` from `store` and puts their
+/// references into `cache`, with up to `concurrency` blocks fetched at once.
+///
+/// This is a warm-up for stores with high latency per block, e.g. remote
+/// stores with a caching layer, so that the first transfer of hot content
+/// doesn't have to wait on them. It doesn't send or store any blocks.
+///
+/// Unlike `DagWalk`, blocks are fetched level by level in no particular order.
+/// Blocks missing from `store` are counted, but don't stop the prefetch.
+pub async fn prefetch_dag(
+    root: Cid,
+    store: impl BlockStore,
+    cache: impl Cache,
+    concurrency: usize,
+) -> Result<PrefetchStats, Error> {
+    let store = &InlineBlockStore { store };
+    let cache = &cache;
+    let mut stats = PrefetchStats::default();
+    let mut visited = HashSet::from([root]);
+    let mut frontier = vec![root];
+
+    while !frontier.is_empty() {
+        let mut fetches = futures::stream::iter(frontier.drain(..))
+            .map(|cid| prefetch_block(cid, store, cache))
+            .buffer_unordered(concurrency.max(1));
+
+        let mut next_frontier = Vec::new();
+        while let Some(refs) = fetches.try_next().await? {
+            let Some(refs) = refs else {
+                stats.blocks_missing += 1;
+                continue;
+            };
+
+            stats.blocks_fetched += 1;
+            next_frontier.extend(refs.into_iter().filter(|cid| visited.insert(*cid)));
+        }
+
+        frontier = next_frontier;
+    }
+
+    Ok(stats)
+}
+
+/// Find all CIDs that a block references.
+///
+/// DAG-CBOR blocks may contain CBOR tags other than the CID link tag.
+/// These are skipped, while links inside them are still found.
+///
+/// Values tagged as CID links in DAG-CBOR are read leniently, like libipld
+/// does: The multibase identity prefix may be missing, and bytes trailing the
+/// CID are ignored. Use `check_cid_links` to reject such links.
+/// Tagged values that aren't byte strings starting with a CID are rejected
+/// instead of treating them as data, since missing a link would make the DAG
+/// never fully sync. CIDs that aren't tagged as links, e.g. plain byte strings,
+/// can't be told apart from data and aren't found.
+///
+/// This will error out if
+/// - the codec is not supported
+/// - the block can't be parsed
+/// - the block is a DAG-CBOR block with unreadable CID links
+/// - the block is a DAG-CBOR block with more than 256 levels of nesting.
+pub fn references<E: Extend<Cid>>(
+    cid: Cid,
+    block: impl AsRef<[u8]>,
+    mut refs: E,
+) -> Result<E, anyhow::Error> {
+    let codec: IpldCodec = cid
+        .codec()
+        .try_into()
+        .map_err(|_| Error::UnsupportedCodec { cid })?;
+
+    if codec == IpldCodec::DagCbor {
+        dag_cbor::references(block.as_ref(), false, &mut refs)?;
+        return Ok(refs);
+    }
+
+    <Ipld as References<IpldCodec>>::references(codec, &mut Cursor::new(block), &mut refs)?;
+    Ok(refs)
+}
+
+/// Checks that all CID links in a DAG-CBOR block are exactly the multibase
+/// identity prefix followed by one CID, which `references` doesn't require.
+///
+/// Blocks with other codecs always pass. See `Config::strict_cid_links`.
+pub fn check_cid_links(cid: Cid, block: impl AsRef<[u8]>) -> Result<(), anyhow::Error> {
+    if cid.codec() == u64::from(IpldCodec::DagCbor) {
+        dag_cbor::references(block.as_ref(), true, &mut Vec::new())?;
+    }
+
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private
+//--------------------------------------------------------------------------------------------------
+
+/// Fetches a block and caches its references for `prefetch_dag`.
+///
+/// Returns `None` if the block is missing from `store`.
+async fn prefetch_block(
+    cid: Cid,
+    store: &impl BlockStore,
+    cache: &impl Cache,
+) -> Result<Option<Vec<Cid>>, Error> {
+    let block = match store.get_block(&cid).await {
+        Ok(block) => block,
+        Err(BlockStoreError::CIDNotFound(_)) => return Ok(None),
+        Err(e) => return Err(Error::BlockStoreError(e)),
+    };
+
+    if cid.codec() == CODEC_RAW {
+        return Ok(Some(Vec::new()));
+    }
+
+    if let Some(refs) = cache.get_references_cache(cid).await? {
+        return Ok(Some(refs));
+    }
+
+    let refs = cache
+        .reference_extractor()
+        .references(cid, &block)
+        .map_err(Error::ParsingError)?;
+    cache.put_references_cache(cid, refs.clone()).await?;
+    Ok(Some(refs))
+}
+
+/// Computes the receiver state for given verification state, leaving out
+/// missing leaf data if `config.skip_leaf_data` is set.
+fn receiver_state_for(
+    dag_verification: &IncrementalDagVerification,
+    config: &Config,
+) -> ReceiverState {
+    let mut receiver_state = dag_verification.receiver_state(config.bloom_fpr);
+
+    if config.skip_leaf_data {
+        receiver_state
+            .missing_subgraph_roots
+            .retain(|cid| cid.codec() != CODEC_RAW);
+    }
+
+    receiver_state
+}
+
+/// Implements `block_send`, not walking any of the `excluded` CIDs.
+pub(crate) async fn block_send_excluding(
+    root: Cid,
+    last_state: Option<ReceiverState>,
+    excluded: HashSet<Cid>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<CarFile, Error> {
+    if config.leaf_first {
+        return Err(Error::LeafFirstRound);
+    }
+
+    let send_limit = Some(config.receive_maximum);
+    let block_stream =
+        block_send_block_stream_with(root, last_state, |_| 0, excluded, config, store, cache)
+            .await?;
+    let mut block_stream = buffer_block_stream(block_stream, config, send_limit);
+    let bytes = write_blocks_into_car(Vec::new(), &mut block_stream, send_limit).await?;
+
+    Ok(CarFile {
+        bytes: bytes.into(),
+    })
+}
+
+/// Streams the blocks to send for given receiver state. The `excluded` CIDs
+/// are neither sent nor walked, as if they had been visited already.
+///
+/// Unlike the public send functions, this sends leaves-first with `Config::leaf_first`.
+pub(crate) async fn block_send_block_stream_with<'a>(
+    root: Cid,
+    last_state: Option<ReceiverState>,
+    root_priority: impl Fn(&Cid) -> i32,
+    excluded: HashSet<Cid>,
+    config: &Config,
+    store: impl BlockStore + 'a,
+    cache: impl Cache + 'a,
+) -> Result<BlockStream<'a>, Error> {
+    let store = RetryingBlockStore {
+        store: InlineBlockStore { store },
+        retries: config.block_fetch_retries,
+    };
+
+    let ReceiverState {
+        missing_subgraph_roots,
+        have_cids_bloom,
+        max_blocks,
+    } = last_state.unwrap_or(ReceiverState {
+        missing_subgraph_roots: vec![root],
+        have_cids_bloom: None,
+        max_blocks: None,
+    });
+
+    // Verify that all missing subgraph roots are in the relevant DAG:
+    let mut subgraph_roots = verify_missing_subgraph_roots(
+        root,
+        &missing_subgraph_roots,
+        &excluded,
+        config,
+        &store,
+        &cache,
+    )
+    .await?;
+    subgraph_roots.sort_by_key(|cid| std::cmp::Reverse(root_priority(cid)));
+
+    // Fast path for single raw blocks, e.g. DAGs that consist of only their root:
+    // Raw blocks don't link anywhere and subgraph roots are always sent,
+    // so there's nothing to walk and no need to look at the bloom.
+    if let [cid] = subgraph_roots[..] {
+        if cid.codec() == CODEC_RAW && !excluded.contains(&cid) {
+            return Ok(boxed_stream(futures::stream::once(async move {
+                let bytes = store
+                    .get_block(&cid)
+                    .await
+                    .map_err(Error::BlockStoreError)?;
+                Ok((cid, bytes))
+            })));
+        }
+    }
+
+    let bloom = handle_missing_bloom(have_cids_bloom);
+
+    let stream = if config.leaf_first {
+        stream_blocks_leaf_first(
+            subgraph_roots,
+            bloom,
+            excluded,
+            config.max_frontier_size,
+            config.skip_leaf_data,
+            store,
+            cache,
+        )
+    } else {
+        let mut dag_walk = DagWalk::breadth_first(subgraph_roots.clone())
+            .with_max_frontier_size(config.max_frontier_size)
+            .with_skip_raw_blocks(config.skip_leaf_data);
+        dag_walk.visited = excluded;
+
+        stream_blocks_from_roots(
+            dag_walk,
+            subgraph_roots,
+            bloom,
+            config.eager_prefetch_depth,
+            store,
+            cache,
+        )
+    };
+
+    match max_blocks {
+        // Never send less than one block, otherwise the protocol can't make progress
+        Some(max_blocks) => Ok(Box::pin(stream.take(max_blocks.max(1)))),
+        None => Ok(Box::pin(stream)),
+    }
+}
+
+/// A block store that doesn't keep any block bytes, only which blocks were
+/// verified and what they link to. Used in `block_receive_verify_only`
+/// and `CarValidator`.
+///
+/// It doubles as the cache for references of these blocks, since they
+/// can't be recomputed from the store later.
+pub(crate) struct VerifyOnlyStore<C: Cache> {
+    references: RwLock<HashMap<Cid, Vec<Cid>>>,
+    cache: C,
+}
+
+impl<C: Cache> VerifyOnlyStore<C> {
+    pub(crate) fn new(cache: C) -> Self {
+        Self {
+            references: RwLock::new(HashMap::new()),
+            cache,
+        }
+    }
+}
+
+impl<C: Cache> BlockStore for VerifyOnlyStore<C> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        Err(BlockStoreError::CIDNotFound(*cid))
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        let bytes: Bytes = bytes.into();
+        let refs = self.cache.reference_extractor().references(cid, &bytes)?;
+        self.references
+            .write()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .insert(cid, refs);
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        Ok(self
+            .references
+            .read()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .contains_key(cid))
+    }
+}
+
+impl<C: Cache> Cache for VerifyOnlyStore<C> {
+    async fn get_references_cache(&self, cid: Cid) -> Result<Option<Vec<Cid>>, BlockStoreError> {
+        let verified = self
+            .references
+            .read()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .get(&cid)
+            .cloned();
+
+        match verified {
+            Some(refs) => Ok(Some(refs)),
+            None => self.cache.get_references_cache(cid).await,
+        }
+    }
+
+    async fn put_references_cache(
+        &self,
+        cid: Cid,
+        references: Vec<Cid>,
+    ) -> Result<(), BlockStoreError> {
+        self.cache.put_references_cache(cid, references).await
+    }
+
+    fn reference_extractor(&self) -> &dyn ReferenceExtractor {
+        self.cache.reference_extractor()
+    }
+}
+
+/// A read-only view of the blocks of two stores, used in `diff_dags`.
+struct UnionStore<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: BlockStore, B: BlockStore> BlockStore for UnionStore<A, B> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        if self.a.has_block(cid).await? {
+            self.a.get_block(cid).await
+        } else {
+            self.b.get_block(cid).await
+        }
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        _bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        Err(BlockStoreError::Custom(anyhow::anyhow!(
+            "Can't put block {cid} into a read-only union of stores"
+        )))
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        Ok(self.a.has_block(cid).await? || self.b.has_block(cid).await?)
+    }
+}
+
+/// The delay before the first retry in `RetryingBlockStore`, doubling with each retry.
+const BLOCK_FETCH_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// How many received blocks are hashed in parallel with `Config::digest_spawner`.
+const DIGEST_PIPELINE_DEPTH: usize = 4;
+
+/// Wraps a block store to retry failing `get_block` calls on the sending end.
+/// See `Config::block_fetch_retries`.
+struct RetryingBlockStore<S> {
+    store: S,
+    retries: usize,
+}
+
+impl<S: BlockStore> BlockStore for RetryingBlockStore<S> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        let mut backoff = BLOCK_FETCH_RETRY_BACKOFF;
+        let mut retry = 0;
+        loop {
+            match self.store.get_block(cid).await {
+                // Missing blocks won't show up by retrying
+                Err(err)
+                    if retry < self.retries && !matches!(err, BlockStoreError::CIDNotFound(_)) =>
+                {
+                    retry += 1;
+                    tracing::debug!(%cid, retry, ?backoff, %err, "Retrying failed block fetch");
+                    futures_timer::Delay::new(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        self.store.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.store.has_block(cid).await
+    }
+}
+
+/// The multihash code of the identity hash, whose digest is the block itself.
+const IDENTITY_HASH_CODE: u64 = 0x00;
+
+/// A block store that resolves blocks behind identity-hash CIDs from the
+/// CID itself, since they carry their data inline.
+///
+/// This makes every peer "have" these blocks, even if they were never put
+/// into the underlying store, e.g. for DAGs that consist of only an identity
+/// CID root. Transfers of such DAGs finish in a single round.
+struct InlineBlockStore<S> {
+    store: S,
+}
+
+impl<S: BlockStore> BlockStore for InlineBlockStore<S> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        if cid.hash().code() == IDENTITY_HASH_CODE {
+            return Ok(Bytes::copy_from_slice(cid.hash().digest()));
+        }
+
+        self.store.get_block(cid).await
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        self.store.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        if cid.hash().code() == IDENTITY_HASH_CODE {
+            return Ok(true);
+        }
+
+        self.store.has_block(cid).await
+    }
+}
+
+async fn car_frame_from_block(block: (Cid, Bytes)) -> Result<Bytes, Error> {
+    // TODO(matheus23): I wish this were exposed in iroh-car somehow
+    // Instead of having to allocate so many things.
+
+    // The writer will always first emit a header.
+    // If we don't force it here, it'll do so in `writer.write()`.
+    // We do it here so we find out how many bytes we need to skip.
+    let bogus_header = CarHeader::new_v1(vec![Cid::default()]);
+    let mut writer = CarWriter::new(bogus_header, Vec::new());
+    let start = writer.write_header().await?;
+
+    writer.write(block.0, block.1).await?;
+    let mut bytes = writer.finish().await?;
+
+    // This removes the bogus header bytes
+    bytes.drain(0..start);
+
+    Ok(bytes.into())
+}
 
 /// Ensure that any requested subgraph roots are actually part
 /// of the DAG from the root.
+///
+/// The `excluded` CIDs aren't walked, so roots only reachable through
+/// them are treated as unrelated.
 async fn verify_missing_subgraph_roots(
     root: Cid,
     missing_subgraph_roots: &[Cid],
+    excluded: &HashSet<Cid>,
+    config: &Config,
     store: &impl BlockStore,
     cache: &impl Cache,
 ) -> Result<Vec<Cid>, Error> {
-    let subgraph_roots: Vec<Cid> = DagWalk::breadth_first([root])
-        .stream(store, cache)
-        .try_filter_map(|item| async move {
-            let cid = item.to_cid()?;
-            Ok(missing_subgraph_roots.contains(&cid).then_some(cid))
-        })
-        .try_collect()
-        .await?;
+    let mut remaining: HashSet<Cid> = missing_subgraph_roots.iter().copied().collect();
+    let mut subgraph_roots = Vec::with_capacity(remaining.len());
+    let mut dag_walk =
+        DagWalk::breadth_first([root]).with_max_frontier_size(config.max_frontier_size);
+    dag_walk.visited = excluded.clone();
 
-    if subgraph_roots.len() != missing_subgraph_roots.len() {
-        let unrelated_roots = missing_subgraph_roots
-            .iter()
-            .filter(|cid| !subgraph_roots.contains(cid))
-            .map(|cid| cid.to_string())
-            .collect::<Vec<_>>()
-            .join(", ");
-
-        tracing::warn!(
-            unrelated_roots = %unrelated_roots,
-            "got asked for DAG-unrelated blocks"
-        );
+    // Stop walking as soon as we've found all requested roots
+    while !remaining.is_empty() {
+        let Some(item) = dag_walk.next(store, cache).await? else {
+            break;
+        };
+
+        let cid = item.to_cid()?;
+        if remaining.remove(&cid) {
+            subgraph_roots.push(cid);
+        }
+    }
+
+    if !remaining.is_empty() {
+        let mut unrelated_roots: Vec<Cid> = remaining.into_iter().collect();
+        unrelated_roots.sort_unstable();
+
+        match &config.verification_observer {
+            Some(observer) => observer.on_unrelated_roots(root, &unrelated_roots),
+            None => log_unrelated_roots(root, &unrelated_roots),
+        }
     }
 
     Ok(subgraph_roots)
@@ -408,45 +1619,140 @@ fn handle_missing_bloom(have_cids_bloom: Option<BloomFilter>) -> BloomFilter {
     have_cids_bloom.unwrap_or_else(|| BloomFilter::new_with(1, Box::new([0]))) // An empty bloom that contains nothing
 }
 
+/// Streams the blocks that `dag_walk` visits, skipping ones in the `bloom`
+/// unless they're at most `eager_prefetch_depth` levels below the subgraph roots.
 fn stream_blocks_from_roots<'a>(
+    mut dag_walk: DagWalk,
     subgraph_roots: Vec<Cid>,
     bloom: BloomFilter,
+    eager_prefetch_depth: usize,
     store: impl BlockStore + 'a,
     cache: impl Cache + 'a,
 ) -> BlockStream<'a> {
     Box::pin(async_stream::try_stream! {
-        let mut dag_walk = DagWalk::breadth_first(subgraph_roots.clone());
+        // The depths of blocks that get sent eagerly. Since the walk is
+        // breadth-first, the first depth that's found for a block is its lowest.
+        let mut eager_depths: HashMap<Cid, usize> =
+            subgraph_roots.iter().map(|cid| (*cid, 0)).collect();
 
-        while let Some(item) = dag_walk.next(&store, &cache).await? {
-            let cid = item.to_cid()?;
+        while let Some((cid, bytes)) = dag_walk.next_with_block(&store, &cache).await? {
+            let eager_depth = eager_depths.get(&cid).copied();
 
-            if should_block_be_skipped(&cid, &bloom, &subgraph_roots) {
-                continue;
+            if let Some(depth) = eager_depth.filter(|depth| *depth < eager_prefetch_depth) {
+                let refs = cache
+                    .references(cid, &store)
+                    .await
+                    .map_err(Error::BlockStoreError)?;
+                for link in refs {
+                    eager_depths.entry(link).or_insert(depth + 1);
+                }
             }
 
-            let bytes = store.get_block(&cid).await.map_err(Error::BlockStoreError)?;
+            if eager_depth.is_none() && should_block_be_skipped(&cid, &bloom, &subgraph_roots) {
+                continue;
+            }
 
             yield (cid, bytes);
         }
     })
 }
 
-async fn write_blocks_into_car<W: tokio::io::AsyncWrite + Unpin + Send>(
-    write: W,
-    blocks: &mut BlockStream<'_>,
-    size_limit: Option<usize>,
-) -> Result<W, Error> {
-    let mut block_bytes = 0;
+/// Like `stream_blocks_from_roots`, but emits each block only after all
+/// blocks it links to, i.e. in reverse topological order.
+fn stream_blocks_leaf_first<'a>(
+    subgraph_roots: Vec<Cid>,
+    bloom: BloomFilter,
+    excluded: HashSet<Cid>,
+    max_frontier_size: Option<usize>,
+    skip_leaf_data: bool,
+    store: impl BlockStore + 'a,
+    cache: impl Cache + 'a,
+) -> BlockStream<'a> {
+    Box::pin(async_stream::try_stream! {
+        let order = post_order(&subgraph_roots, excluded, max_frontier_size, skip_leaf_data, &store, &cache).await?;
 
-    // https://github.com/wnfs-wg/car-mirror-spec/issues/6
-    // CAR files *must* have at least one CID in them, and all of them
-    // need to appear as a block in the payload.
-    // It would probably make most sense to just write all subgraph roots into this,
-    // but we don't know how many of the subgraph roots fit into this round yet,
-    // so we're simply writing the first one in here, since we know
-    // at least one block will be written (and it'll be that one).
-    let Some((cid, block)) = blocks.try_next().await? else {
-        tracing::debug!("No blocks to write.");
+        for cid in order {
+            if should_block_be_skipped(&cid, &bloom, &subgraph_roots) {
+                continue;
+            }
+
+            let block = store.get_block(&cid).await.map_err(Error::BlockStoreError)?;
+            yield (cid, block);
+        }
+    })
+}
+
+/// Computes a depth-first post-order of the DAG below given roots,
+/// so each CID comes after all CIDs it links to.
+///
+/// The `excluded` CIDs and the DAGs below them are left out.
+async fn post_order(
+    roots: &[Cid],
+    excluded: HashSet<Cid>,
+    max_frontier_size: Option<usize>,
+    skip_leaf_data: bool,
+    store: &impl BlockStore,
+    cache: &impl Cache,
+) -> Result<Vec<Cid>, Error> {
+    let mut order = Vec::new();
+    let mut visited = excluded;
+    // CIDs to visit, together with whether their links were visited already
+    let mut stack: Vec<(Cid, bool)> = roots.iter().rev().map(|cid| (*cid, false)).collect();
+
+    while let Some((cid, links_visited)) = stack.pop() {
+        if links_visited {
+            order.push(cid);
+            continue;
+        }
+
+        // Since DAGs don't have cycles, a CID that was visited
+        // before is already in `order`, unless it's excluded.
+        if !visited.insert(cid) {
+            continue;
+        }
+
+        let mut refs = cache
+            .references(cid, store)
+            .await
+            .map_err(Error::BlockStoreError)?;
+        if skip_leaf_data {
+            refs.retain(|cid| cid.codec() != CODEC_RAW);
+        }
+
+        stack.push((cid, true));
+        stack.extend(
+            refs.into_iter()
+                .rev()
+                .filter(|cid| !visited.contains(cid))
+                .map(|cid| (cid, false)),
+        );
+
+        if let Some(max_frontier_size) = max_frontier_size {
+            if stack.len() > max_frontier_size {
+                return Err(Error::FrontierTooLarge { max_frontier_size });
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+pub(crate) async fn write_blocks_into_car<W: tokio::io::AsyncWrite + Unpin + Send>(
+    write: W,
+    blocks: &mut BlockStream<'_>,
+    size_limit: Option<usize>,
+) -> Result<W, Error> {
+    let mut block_bytes = 0;
+
+    // https://github.com/wnfs-wg/car-mirror-spec/issues/6
+    // CAR files *must* have at least one CID in them, and all of them
+    // need to appear as a block in the payload.
+    // It would probably make most sense to just write all subgraph roots into this,
+    // but we don't know how many of the subgraph roots fit into this round yet,
+    // so we're simply writing the first one in here, since we know
+    // at least one block will be written (and it'll be that one).
+    let Some((cid, block)) = blocks.try_next().await? else {
+        tracing::debug!("No blocks to write.");
         return Ok(write);
     };
 
@@ -478,15 +1784,70 @@ async fn write_blocks_into_car<W: tokio::io::AsyncWrite + Unpin + Send>(
     Ok(writer.finish().await?)
 }
 
+/// Resolves given future, unless it takes longer than `timeout`, in which case
+/// this returns `Error::StreamIdleTimeout`.
+async fn with_idle_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+
+    let future = std::pin::pin!(future);
+    match futures::future::select(future, futures_timer::Delay::new(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => {
+            tracing::debug!(?timeout, "Timed out waiting for the next frame");
+            Err(Error::StreamIdleTimeout { timeout })
+        }
+    }
+}
+
 fn should_block_be_skipped(cid: &Cid, bloom: &BloomFilter, subgraph_roots: &[Cid]) -> bool {
     bloom.contains(&cid.to_bytes()) && !subgraph_roots.contains(cid)
 }
 
+/// Hashes the blocks of given stream via the `spawner`, while up to
+/// `DIGEST_PIPELINE_DEPTH` blocks are buffered. Yields each block in order,
+/// with the CID its bytes hash to, unless that couldn't be computed.
+fn pipeline_digests<'a>(
+    stream: &'a mut BlockStream<'_>,
+    spawner: Arc<dyn DigestSpawner>,
+    max_block_size: usize,
+) -> BoxStream<'a, Result<((Cid, Bytes), Option<Cid>), Error>> {
+    boxed_stream(
+        stream
+            .map_ok(move |(cid, block)| {
+                let (sender, receiver) = futures::channel::oneshot::channel();
+                // Oversized blocks get rejected without being hashed
+                if block.len() <= max_block_size {
+                    let bytes = block.clone();
+                    spawner.spawn(Box::new(move || {
+                        let _ = sender.send(digest_cid(cid, &bytes));
+                    }));
+                }
+
+                async move { Ok(((cid, block), receiver.await.ok().flatten())) }
+            })
+            .try_buffered(DIGEST_PIPELINE_DEPTH),
+    )
+}
+
+/// Computes the CID that given bytes hash to with the hash function
+/// of `cid`, if it's supported.
+fn digest_cid(cid: Cid, bytes: &[u8]) -> Option<Cid> {
+    let hash_func = Code::try_from(cid.hash().code()).ok()?;
+    Some(compute_cid(cid.codec(), hash_func, bytes))
+}
+
 /// Takes a block and stores it iff it's one of the blocks we're currently trying to retrieve.
 /// Returns the block state of the received block.
 async fn read_and_verify_block(
     dag_verification: &mut IncrementalDagVerification,
     (cid, block): (Cid, Bytes),
+    actual_cid: Option<Cid>,
+    config: &Config,
     store: &impl BlockStore,
     cache: &impl Cache,
 ) -> Result<BlockState, Error> {
@@ -500,222 +1861,2262 @@ async fn read_and_verify_block(
             Ok(BlockState::Unexpected)
         }
         BlockState::Want => {
+            if config.strict_cid_links {
+                check_cid_links(cid, &block).map_err(Error::ParsingError)?;
+            }
+
             dag_verification
-                .verify_and_store_block((cid, block), store, cache)
+                .verify_and_store_block_with_digest((cid, block), actual_cid, store, cache)
                 .await?;
             Ok(BlockState::Want)
         }
     }
-}
+}
+
+//--------------------------------------------------------------------------------------------------
+// Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl Config {
+    /// Start building a `Config` from the default values.
+    ///
+    /// Unlike constructing a `Config` directly, `ConfigBuilder::build`
+    /// returns an error for inconsistent values.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Checks that the values in this config are consistent:
+    /// - `max_block_size` must not exceed `MAX_CAR_FRAME_SIZE`,
+    /// - `receive_maximum` must be at least `max_block_size`,
+    /// - `max_roots_per_round` must not be zero.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_block_size > MAX_CAR_FRAME_SIZE {
+            return Err(ConfigError::BlockSizeOverFrameLimit {
+                max_block_size: self.max_block_size,
+                max_frame_size: MAX_CAR_FRAME_SIZE,
+            });
+        }
+
+        if self.receive_maximum < self.max_block_size {
+            return Err(ConfigError::ReceiveMaximumBelowBlockSize {
+                receive_maximum: self.receive_maximum,
+                max_block_size: self.max_block_size,
+            });
+        }
+
+        if self.max_roots_per_round == 0 {
+            return Err(ConfigError::ZeroMaxRootsPerRound);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that given bloom filter bytes don't exceed `max_bloom_bytes`.
+    pub fn check_bloom_bytes(&self, bloom_bytes: &[u8]) -> Result<(), Error> {
+        if bloom_bytes.len() > self.max_bloom_bytes {
+            return Err(Error::BloomTooLarge {
+                bloom_bytes: bloom_bytes.len(),
+                max_bloom_bytes: self.max_bloom_bytes,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that given root CIDs don't exceed `max_incoming_roots`.
+    pub fn check_incoming_roots(&self, roots: &[Cid]) -> Result<(), Error> {
+        if roots.len() > self.max_incoming_roots {
+            return Err(Error::TooManyRoots {
+                num_roots: roots.len(),
+                max_incoming_roots: self.max_incoming_roots,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl ConfigBuilder {
+    /// Set `Config::receive_maximum`.
+    pub fn receive_maximum(mut self, receive_maximum: usize) -> Self {
+        self.config.receive_maximum = receive_maximum;
+        self
+    }
+
+    /// Set `Config::max_block_size`.
+    pub fn max_block_size(mut self, max_block_size: usize) -> Self {
+        self.config.max_block_size = max_block_size;
+        self
+    }
+
+    /// Set `Config::max_roots_per_round`.
+    pub fn max_roots_per_round(mut self, max_roots_per_round: usize) -> Self {
+        self.config.max_roots_per_round = max_roots_per_round;
+        self
+    }
+
+    /// Set `Config::bloom_fpr`.
+    pub fn bloom_fpr(mut self, bloom_fpr: fn(u64) -> f64) -> Self {
+        self.config.bloom_fpr = bloom_fpr;
+        self
+    }
+
+    /// Set `Config::max_blocks_per_round`.
+    pub fn max_blocks_per_round(mut self, max_blocks_per_round: Option<usize>) -> Self {
+        self.config.max_blocks_per_round = max_blocks_per_round;
+        self
+    }
+
+    /// Set `Config::stream_idle_timeout`.
+    pub fn stream_idle_timeout(mut self, stream_idle_timeout: Option<Duration>) -> Self {
+        self.config.stream_idle_timeout = stream_idle_timeout;
+        self
+    }
+
+    /// Set `Config::send_buffer_blocks`.
+    pub fn send_buffer_blocks(mut self, send_buffer_blocks: usize) -> Self {
+        self.config.send_buffer_blocks = send_buffer_blocks;
+        self
+    }
+
+    /// Set `Config::verification_observer`.
+    pub fn verification_observer(
+        mut self,
+        verification_observer: Option<Arc<dyn VerificationObserver>>,
+    ) -> Self {
+        self.config.verification_observer = verification_observer;
+        self
+    }
+
+    /// Set `Config::require_end_of_round`.
+    pub fn require_end_of_round(mut self, require_end_of_round: bool) -> Self {
+        self.config.require_end_of_round = require_end_of_round;
+        self
+    }
+
+    /// Set `Config::max_bloom_bytes`.
+    pub fn max_bloom_bytes(mut self, max_bloom_bytes: usize) -> Self {
+        self.config.max_bloom_bytes = max_bloom_bytes;
+        self
+    }
+
+    /// Set `Config::max_incoming_roots`.
+    pub fn max_incoming_roots(mut self, max_incoming_roots: usize) -> Self {
+        self.config.max_incoming_roots = max_incoming_roots;
+        self
+    }
+
+    /// Set `Config::max_frontier_size`.
+    pub fn max_frontier_size(mut self, max_frontier_size: Option<usize>) -> Self {
+        self.config.max_frontier_size = max_frontier_size;
+        self
+    }
+
+    /// Set `Config::skip_leaf_data`.
+    pub fn skip_leaf_data(mut self, skip_leaf_data: bool) -> Self {
+        self.config.skip_leaf_data = skip_leaf_data;
+        self
+    }
+
+    /// Set `Config::normalize_cid_versions`.
+    pub fn normalize_cid_versions(mut self, normalize_cid_versions: bool) -> Self {
+        self.config.normalize_cid_versions = normalize_cid_versions;
+        self
+    }
+
+    /// Set `Config::allowed_codecs`.
+    pub fn allowed_codecs(mut self, allowed_codecs: Option<HashSet<u64>>) -> Self {
+        self.config.allowed_codecs = allowed_codecs;
+        self
+    }
+
+    /// Set `Config::leaf_first`.
+    pub fn leaf_first(mut self, leaf_first: bool) -> Self {
+        self.config.leaf_first = leaf_first;
+        self
+    }
+
+    /// Set `Config::block_fetch_retries`.
+    pub fn block_fetch_retries(mut self, block_fetch_retries: usize) -> Self {
+        self.config.block_fetch_retries = block_fetch_retries;
+        self
+    }
+
+    /// Set `Config::round_retries`.
+    pub fn round_retries(mut self, round_retries: usize) -> Self {
+        self.config.round_retries = round_retries;
+        self
+    }
+
+    /// Set `Config::eager_prefetch_depth`.
+    pub fn eager_prefetch_depth(mut self, eager_prefetch_depth: usize) -> Self {
+        self.config.eager_prefetch_depth = eager_prefetch_depth;
+        self
+    }
+
+    /// Set `Config::stream_receive_maximum`.
+    pub fn stream_receive_maximum(mut self, stream_receive_maximum: Option<usize>) -> Self {
+        self.config.stream_receive_maximum = stream_receive_maximum;
+        self
+    }
+
+    /// Set `Config::record_manifest`.
+    pub fn record_manifest(mut self, record_manifest: bool) -> Self {
+        self.config.record_manifest = record_manifest;
+        self
+    }
+
+    /// Set `Config::require_matching_car_roots`.
+    pub fn require_matching_car_roots(mut self, require_matching_car_roots: bool) -> Self {
+        self.config.require_matching_car_roots = require_matching_car_roots;
+        self
+    }
+
+    /// Set `Config::cid_encoding`.
+    pub fn cid_encoding(mut self, cid_encoding: CidEncoding) -> Self {
+        self.config.cid_encoding = cid_encoding;
+        self
+    }
+
+    /// Set `Config::digest_spawner`.
+    pub fn digest_spawner(mut self, digest_spawner: Option<Arc<dyn DigestSpawner>>) -> Self {
+        self.config.digest_spawner = digest_spawner;
+        self
+    }
+
+    /// Set `Config::on_unexpected_block`.
+    pub fn on_unexpected_block(mut self, on_unexpected_block: UnexpectedPolicy) -> Self {
+        self.config.on_unexpected_block = on_unexpected_block;
+        self
+    }
+
+    /// Set `Config::min_blocks_before_interrupt`.
+    pub fn min_blocks_before_interrupt(mut self, min_blocks_before_interrupt: usize) -> Self {
+        self.config.min_blocks_before_interrupt = min_blocks_before_interrupt;
+        self
+    }
+
+    /// Set `Config::tolerate_duplicate_blocks`.
+    pub fn tolerate_duplicate_blocks(mut self, tolerate_duplicate_blocks: bool) -> Self {
+        self.config.tolerate_duplicate_blocks = tolerate_duplicate_blocks;
+        self
+    }
+
+    /// Set `Config::receiver_state_every`.
+    pub fn receiver_state_every(mut self, receiver_state_every: usize) -> Self {
+        self.config.receiver_state_every = receiver_state_every;
+        self
+    }
+
+    /// Set `Config::strict_cid_links`.
+    pub fn strict_cid_links(mut self, strict_cid_links: bool) -> Self {
+        self.config.strict_cid_links = strict_cid_links;
+        self
+    }
+
+    /// Validate the configured values and return the `Config`.
+    ///
+    /// See `Config::validate` for the checks.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+impl From<PushResponse> for ReceiverState {
+    fn from(push: PushResponse) -> Self {
+        let PushResponse {
+            cid_encoding: _,
+            subgraph_roots,
+            bloom_hash_count: hash_count,
+            bloom_bytes: bytes,
+            checkpoint: _,
+        } = push;
+
+        Self {
+            missing_subgraph_roots: subgraph_roots,
+            have_cids_bloom: Self::bloom_deserialize(hash_count, bytes),
+            max_blocks: None,
+        }
+    }
+}
+
+impl From<PullRequest> for ReceiverState {
+    fn from(pull: PullRequest) -> Self {
+        let PullRequest {
+            cid_encoding: _,
+            resources,
+            bloom_hash_count: hash_count,
+            bloom_bytes: bytes,
+            max_blocks,
+        } = pull;
+
+        Self {
+            missing_subgraph_roots: resources,
+            have_cids_bloom: Self::bloom_deserialize(hash_count, bytes),
+            max_blocks: max_blocks.map(|max| usize::try_from(max).unwrap_or(usize::MAX)),
+        }
+    }
+}
+
+impl From<ReceiverState> for PushResponse {
+    fn from(receiver_state: ReceiverState) -> PushResponse {
+        let ReceiverState {
+            missing_subgraph_roots,
+            have_cids_bloom,
+            ..
+        } = receiver_state;
+
+        let (hash_count, bytes) = ReceiverState::bloom_serialize(have_cids_bloom);
+
+        PushResponse {
+            cid_encoding: CidEncoding::default(),
+            subgraph_roots: missing_subgraph_roots,
+            bloom_hash_count: hash_count,
+            bloom_bytes: bytes,
+            checkpoint: None,
+        }
+    }
+}
+
+impl From<ReceiverState> for PullRequest {
+    fn from(receiver_state: ReceiverState) -> PullRequest {
+        let ReceiverState {
+            missing_subgraph_roots,
+            have_cids_bloom,
+            max_blocks,
+        } = receiver_state;
+
+        let (hash_count, bytes) = ReceiverState::bloom_serialize(have_cids_bloom);
+
+        PullRequest {
+            cid_encoding: CidEncoding::default(),
+            resources: missing_subgraph_roots,
+            bloom_hash_count: hash_count,
+            bloom_bytes: bytes,
+            max_blocks: max_blocks.map(|max| max as u64),
+        }
+    }
+}
+
+impl From<Message> for ReceiverState {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::PushResponse(response) => response.into(),
+            Message::PullRequest(request) => request.into(),
+        }
+    }
+}
+
+impl ReceiverState {
+    /// Turns this state into the wire message that the block receiving end
+    /// sends in given protocol direction, i.e. a `PushResponse` for
+    /// `Direction::Push` and a `PullRequest` for `Direction::Pull`.
+    ///
+    /// Push responses don't carry a `max_blocks` hint, so that's dropped
+    /// for `Direction::Push`.
+    pub fn to_message(&self, direction: Direction) -> Message {
+        match direction {
+            Direction::Push => Message::PushResponse(self.clone().into()),
+            Direction::Pull => Message::PullRequest(self.clone().into()),
+        }
+    }
+
+    /// Reads the receiver state from either wire message.
+    ///
+    /// This is the inverse of `to_message`.
+    pub fn from_message(message: Message) -> Self {
+        message.into()
+    }
+
+    /// Whether this state describes a cold transfer of `root`, i.e. the receiving
+    /// end doesn't have any blocks of the DAG yet, nor asks for fewer blocks.
+    ///
+    /// The first round of such a transfer always sends the same blocks, so it
+    /// can be served from a precomputed CAR file.
+    pub fn is_cold(&self, root: Cid) -> bool {
+        self.missing_subgraph_roots == [root]
+            && self.have_cids_bloom.is_none()
+            && self.max_blocks.is_none()
+    }
+
+    /// Computes the `(hash_count, byte_size)` of the "have cids" bloom filter that
+    /// the receiving end sends when it has `have_count` blocks of the DAG, for a
+    /// target false positive rate `fpr`, e.g. `(config.bloom_fpr)(have_count)`.
+    ///
+    /// This is how `IncrementalDagVerification::into_receiver_state` sizes blooms:
+    /// Their size grows linearly with `have_count` and logarithmically with `1 / fpr`,
+    /// rounded up to a power of two bytes. Receivers that don't have any blocks
+    /// yet don't send a bloom, so that's `(0, 0)`.
+    ///
+    /// Useful e.g. for estimating wire sizes or for picking `Config::max_bloom_bytes`.
+    pub fn optimal_bloom_params(have_count: u64, fpr: f64) -> (usize, usize) {
+        if have_count == 0 {
+            return (0, 0);
+        }
+
+        let bloom = BloomFilter::new_from_fpr_po2(have_count, fpr);
+        (bloom.hash_count(), bloom.as_bytes().len())
+    }
+
+    /// Like `from_message`, but rejects messages that don't pass
+    /// `PullRequest::validate` or `PushResponse::validate`, e.g. with bloom filters
+    /// larger than `config.max_bloom_bytes` or with more than
+    /// `config.max_incoming_roots` roots.
+    ///
+    /// Use this for messages received from untrusted peers.
+    pub fn from_message_checked(
+        message: impl Into<Message>,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let message = message.into();
+        match &message {
+            Message::PushResponse(response) => response.validate(config)?,
+            Message::PullRequest(request) => request.validate(config)?,
+        }
+        Ok(Self::from_message(message))
+    }
+
+    /// Merge multiple receiver states into one, e.g. when pulling parts of
+    /// a DAG from multiple servers and combining their results into a single
+    /// request for the next round.
+    ///
+    /// The merged state contains all missing subgraph roots from given states
+    /// (without duplicates) and a bloom filter that contains all CIDs that any
+    /// of the given bloom filters contain.
+    /// If multiple states have a `max_blocks` hint, the smallest one is kept.
+    ///
+    /// Bloom filters can only be merged if they have the same size and hash
+    /// count, otherwise this returns `Error::IncompatibleBlooms`.
+    pub fn merge(states: impl IntoIterator<Item = ReceiverState>) -> Result<Self, Error> {
+        let mut missing_subgraph_roots = Vec::new();
+        let mut seen_roots = HashSet::new();
+        let mut have_cids_bloom: Option<BloomFilter> = None;
+        let mut max_blocks: Option<usize> = None;
+
+        for state in states {
+            for cid in state.missing_subgraph_roots {
+                if seen_roots.insert(cid) {
+                    missing_subgraph_roots.push(cid);
+                }
+            }
+
+            have_cids_bloom = match (have_cids_bloom, state.have_cids_bloom) {
+                (Some(bloom), Some(other)) => Some(Self::bloom_union(bloom, other)?),
+                (bloom, other) => bloom.or(other),
+            };
+
+            max_blocks = match (max_blocks, state.max_blocks) {
+                (Some(max), Some(other)) => Some(usize::min(max, other)),
+                (max, other) => max.or(other),
+            };
+        }
+
+        Ok(Self {
+            missing_subgraph_roots,
+            have_cids_bloom,
+            max_blocks,
+        })
+    }
+
+    fn bloom_union(bloom: BloomFilter, other: BloomFilter) -> Result<BloomFilter, Error> {
+        if bloom.hash_count() != other.hash_count()
+            || bloom.as_bytes().len() != other.as_bytes().len()
+        {
+            return Err(Error::IncompatibleBlooms {
+                hash_count: bloom.hash_count(),
+                bytes: bloom.as_bytes().len(),
+                other_hash_count: other.hash_count(),
+                other_bytes: other.as_bytes().len(),
+            });
+        }
+
+        let bytes = bloom
+            .as_bytes()
+            .iter()
+            .zip(other.as_bytes())
+            .map(|(byte, other_byte)| byte | other_byte)
+            .collect::<Vec<u8>>();
+
+        Ok(BloomFilter::new_with(
+            bloom.hash_count(),
+            bytes.into_boxed_slice(),
+        ))
+    }
+
+    fn bloom_serialize(bloom: Option<BloomFilter>) -> (u32, Vec<u8>) {
+        match bloom {
+            Some(bloom) => (bloom.hash_count() as u32, bloom.as_bytes().to_vec()),
+            None => (3, Vec::new()),
+        }
+    }
+
+    fn bloom_deserialize(hash_count: u32, bytes: Vec<u8>) -> Option<BloomFilter> {
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(BloomFilter::new_with(
+                hash_count as usize,
+                bytes.into_boxed_slice(),
+            ))
+        }
+    }
+}
+
+impl std::fmt::Debug for ReceiverState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let have_cids_bloom = self
+            .have_cids_bloom
+            .as_ref()
+            .map_or("None".into(), |bloom| {
+                format!(
+                    "Some(BloomFilter(k_hashes = {}, {} bytes))",
+                    bloom.hash_count(),
+                    bloom.as_bytes().len()
+                )
+            });
+        f.debug_struct("ReceiverState")
+            .field(
+                "missing_subgraph_roots.len() == ",
+                &self.missing_subgraph_roots.len(),
+            )
+            .field("have_cids_bloom", &have_cids_bloom)
+            .field("max_blocks", &self.max_blocks)
+            .finish()
+    }
+}
+
+impl CarFile {
+    /// Re-frames the blocks of this CAR file into multiple CAR files that are
+    /// each at most `max_bytes` in size, e.g. `Config::receive_maximum`.
+    ///
+    /// Blocks keep their order, so feeding the resulting CAR files one after
+    /// the other through `block_receive` verifies just like the original CAR file.
+    /// Each resulting CAR file declares its first block as its root.
+    ///
+    /// Fails with `Error::TooManyBytes` if a single block doesn't fit into a
+    /// CAR file of `max_bytes`.
+    pub async fn split(&self, max_bytes: usize) -> Result<Vec<CarFile>, Error> {
+        let reader = CarReader::new(Cursor::new(&self.bytes)).await?;
+        let mut blocks = Box::pin(reader.stream());
+
+        let mut car_files = Vec::new();
+        let mut current: Vec<u8> = Vec::new();
+
+        while let Some((cid, block)) = blocks.try_next().await? {
+            let frame = car_frame_from_block((cid, Bytes::from(block))).await?;
+
+            if !current.is_empty() && current.len() + frame.len() <= max_bytes {
+                current.extend_from_slice(&frame);
+                continue;
+            }
+
+            let mut writer = CarWriter::new(CarHeader::new_v1(vec![cid]), Vec::new());
+            writer.write_header().await?;
+            let header = writer.finish().await?;
+
+            let car_bytes = header.len() + frame.len();
+            if car_bytes > max_bytes {
+                return Err(Error::TooManyBytes {
+                    receive_maximum: max_bytes,
+                    bytes_read: car_bytes,
+                });
+            }
+
+            if !current.is_empty() {
+                car_files.push(CarFile {
+                    bytes: std::mem::take(&mut current).into(),
+                });
+            }
+
+            current = header;
+            current.extend_from_slice(&frame);
+        }
+
+        if !current.is_empty() {
+            car_files.push(CarFile {
+                bytes: current.into(),
+            });
+        }
+
+        Ok(car_files)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        cache::{InMemoryCache, NoCache},
+        test_utils::{
+            assert_cond_send_sync, corrupt_car_stream, get_cid_at_approx_path, setup_random_dag,
+            total_dag_blocks, BoundedMemoryBlockStore,
+        },
+    };
+    use assert_matches::assert_matches;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use testresult::TestResult;
+    use tokio_util::io::StreamReader;
+    use wnfs_common::{encode, MemoryBlockStore, CODEC_RAW};
+
+    #[allow(clippy::unreachable, unused)]
+    fn test_assert_send() {
+        assert_cond_send_sync(|| {
+            block_send(
+                unimplemented!(),
+                unimplemented!(),
+                unimplemented!(),
+                unimplemented!() as MemoryBlockStore,
+                NoCache,
+            )
+        });
+        assert_cond_send_sync(|| {
+            block_receive(
+                unimplemented!(),
+                unimplemented!(),
+                unimplemented!(),
+                unimplemented!() as &MemoryBlockStore,
+                &NoCache,
+            )
+        })
+    }
+
+    #[test]
+    fn test_receiver_state_is_not_a_huge_debug() -> TestResult {
+        let state = ReceiverState {
+            have_cids_bloom: Some(BloomFilter::new_from_size(4096, 1000)),
+            missing_subgraph_roots: vec![Cid::default(); 1000],
+            max_blocks: None,
+        };
+
+        let debug_print = format!("{state:#?}");
+
+        assert!(debug_print.len() < 1000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimal_bloom_params() {
+        let config = Config::default();
+        let have_cids = (0..1000u32)
+            .map(|i| compute_cid(CODEC_RAW, Code::Sha2_256, &i.to_le_bytes()))
+            .collect::<HashSet<_>>();
+        let dag_verification = IncrementalDagVerification {
+            want_cids: HashSet::from([Cid::default()]),
+            have_cids,
+            max_frontier_size: None,
+            normalize_cid_versions: false,
+        };
+
+        let bloom = dag_verification
+            .into_receiver_state(config.bloom_fpr)
+            .have_cids_bloom
+            .expect("receiver state with a bloom");
+        assert_eq!(
+            ReceiverState::optimal_bloom_params(1000, (config.bloom_fpr)(1000)),
+            (bloom.hash_count(), bloom.as_bytes().len())
+        );
+
+        assert_eq!(ReceiverState::optimal_bloom_params(0, 0.001), (0, 0));
+        let (_, small) = ReceiverState::optimal_bloom_params(1_000, 0.001);
+        let (_, large) = ReceiverState::optimal_bloom_params(100_000, 0.001);
+        assert!(small < large);
+    }
+
+    #[test]
+    fn test_receiver_state_merge() -> TestResult {
+        let cid_a = Cid::default();
+        let cid_b = Cid::try_from("bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e")?;
+
+        let mut bloom_a = BloomFilter::new_from_size(256, 10);
+        bloom_a.insert(&cid_a.to_bytes());
+        let mut bloom_b = BloomFilter::new_from_size(256, 10);
+        bloom_b.insert(&cid_b.to_bytes());
+
+        let merged = ReceiverState::merge([
+            ReceiverState {
+                missing_subgraph_roots: vec![cid_a, cid_b],
+                have_cids_bloom: Some(bloom_a),
+                max_blocks: Some(10),
+            },
+            ReceiverState {
+                missing_subgraph_roots: vec![cid_b],
+                have_cids_bloom: Some(bloom_b),
+                max_blocks: Some(5),
+            },
+            ReceiverState {
+                missing_subgraph_roots: vec![],
+                have_cids_bloom: None,
+                max_blocks: None,
+            },
+        ])?;
+
+        assert_eq!(merged.missing_subgraph_roots, vec![cid_a, cid_b]);
+        assert_eq!(merged.max_blocks, Some(5));
+        let bloom = merged.have_cids_bloom.expect("merged bloom");
+        assert!(bloom.contains(&cid_a.to_bytes()));
+        assert!(bloom.contains(&cid_b.to_bytes()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_receiver_state_merge_incompatible_blooms() {
+        let state = |bloom_bytes| ReceiverState {
+            missing_subgraph_roots: vec![],
+            have_cids_bloom: Some(BloomFilter::new_from_size(bloom_bytes, 10)),
+            max_blocks: None,
+        };
+
+        let result = ReceiverState::merge([state(256), state(512)]);
+
+        assert_matches!(result, Err(Error::IncompatibleBlooms { .. }));
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_stream_car_frame_empty() -> TestResult {
+        let car_frames = stream_car_frames(futures::stream::empty().boxed()).await?;
+        let frames: Vec<Bytes> = car_frames.try_collect().await?;
+
+        assert!(frames.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_write_blocks_into_car_empty() -> TestResult {
+        let car_file =
+            write_blocks_into_car(Vec::new(), &mut futures::stream::empty().boxed(), None).await?;
+
+        assert!(car_file.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_verify_only_matches_block_receive() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            receive_maximum: 16 * 1024,
+            ..Config::default()
+        };
+
+        let car = block_send(root, None, config, sender_store, NoCache).await?;
+
+        let relay_state =
+            block_receive_verify_only(root, Cursor::new(car.bytes.clone()), config, NoCache)
+                .await?;
+
+        let receiver_store = &MemoryBlockStore::new();
+        let receiver_state = block_receive_car_stream(
+            root,
+            Cursor::new(car.bytes),
+            config,
+            receiver_store,
+            NoCache,
+        )
+        .await?;
+
+        assert_eq!(
+            relay_state
+                .missing_subgraph_roots
+                .into_iter()
+                .collect::<HashSet<_>>(),
+            receiver_state
+                .missing_subgraph_roots
+                .into_iter()
+                .collect::<HashSet<_>>(),
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_buffered_matches_unbuffered() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            receive_maximum: 16 * 1024,
+            ..Config::default()
+        };
+        let buffered_config = &Config {
+            send_buffer_blocks: 4,
+            ..config.clone()
+        };
+
+        let car = block_send(root, None, config, store, NoCache).await?;
+        let buffered_car = block_send(root, None, buffered_config, store, NoCache).await?;
+
+        assert_eq!(car.bytes, buffered_car.bytes);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_store_rejects_write_mid_transfer() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config::default();
+
+        let car = block_send(root, None, config, sender_store, NoCache).await?;
+
+        // Room for a couple of blocks, but not the whole transfer
+        let receiver_store = &BoundedMemoryBlockStore::new(4 * 1024);
+        let result = block_receive(root, Some(car), config, receiver_store, NoCache).await;
+
+        assert_matches!(
+            result,
+            Err(Error::BlockStoreError(BlockStoreError::Custom(_)))
+        );
+        assert!(receiver_store.total_bytes() <= 4 * 1024);
+        assert!(receiver_store.has_block(&root).await?);
+
+        Ok(())
+    }
+
+    /// A block store where every other `get_block` call fails
+    struct FlakyBlockStore {
+        store: MemoryBlockStore,
+        get_block_calls: AtomicUsize,
+    }
+
+    impl BlockStore for FlakyBlockStore {
+        async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+            if self.get_block_calls.fetch_add(1, Ordering::Relaxed) % 2 == 0 {
+                return Err(BlockStoreError::Custom(anyhow::anyhow!("Flaky read")));
+            }
+            self.store.get_block(cid).await
+        }
+
+        async fn put_block_keyed(
+            &self,
+            cid: Cid,
+            bytes: impl Into<Bytes> + CondSend,
+        ) -> Result<(), BlockStoreError> {
+            self.store.put_block_keyed(cid, bytes).await
+        }
+
+        async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+            self.store.has_block(cid).await
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_retries_flaky_block_fetches() -> TestResult {
+        let (root, store) = setup_random_dag(64, 1024).await?;
+        let sender_store = &FlakyBlockStore {
+            store: store.clone(),
+            get_block_calls: AtomicUsize::new(0),
+        };
+
+        let result = block_send(root, None, &Config::default(), sender_store, NoCache).await;
+        assert_matches!(
+            result,
+            Err(Error::BlockStoreError(BlockStoreError::Custom(_)))
+        );
+
+        let config = &Config {
+            block_fetch_retries: 1,
+            ..Config::default()
+        };
+        let car = block_send(root, None, config, sender_store, NoCache).await?;
+        assert_eq!(
+            car.bytes,
+            block_send(root, None, config, &store, NoCache).await?.bytes
+        );
+
+        // Missing blocks aren't retried, only the flaky read before, if any
+        let calls_before = sender_store.get_block_calls.load(Ordering::Relaxed);
+        let result = RetryingBlockStore {
+            store: sender_store,
+            retries: 3,
+        }
+        .get_block(&Cid::default())
+        .await;
+        assert_matches!(result, Err(BlockStoreError::CIDNotFound(_)));
+        assert!(sender_store.get_block_calls.load(Ordering::Relaxed) - calls_before <= 2);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_block_stream_respects_max_blocks() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let total_blocks = total_dag_blocks(root, store).await?;
+
+        let state = ReceiverState {
+            missing_subgraph_roots: vec![root],
+            have_cids_bloom: None,
+            max_blocks: Some(3),
+        };
+
+        let blocks: Vec<_> =
+            block_send_block_stream(root, Some(state), &Config::default(), store, NoCache)
+                .await?
+                .try_collect()
+                .await?;
+
+        assert_eq!(blocks.len(), usize::min(3, total_blocks));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_block_stream_uses_config() -> TestResult {
+        let store = &MemoryBlockStore::new();
+        let leaf = store
+            .put_block(b"Hello, leaf data!".to_vec(), CODEC_RAW)
+            .await?;
+        let root = store
+            .put_block(
+                encode(&Ipld::List(vec![Ipld::Link(leaf)]), IpldCodec::DagCbor)?,
+                IpldCodec::DagCbor.into(),
+            )
+            .await?;
+
+        let config = &Config {
+            skip_leaf_data: true,
+            ..Config::default()
+        };
+        let sent: Vec<_> = block_send_block_stream(root, None, config, store, NoCache)
+            .await?
+            .map_ok(|(cid, _)| cid)
+            .try_collect()
+            .await?;
+        assert_eq!(sent, vec![root]);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_block_stream_block_size_exceeded() -> TestResult {
+        let store = &MemoryBlockStore::new();
+
+        let block_small: Bytes = b"This one is small".to_vec().into();
+        let block_big: Bytes = b"This one is very very very big".to_vec().into();
+        let root_small = store.put_block(block_small.clone(), CODEC_RAW).await?;
+        let root_big = store.put_block(block_big.clone(), CODEC_RAW).await?;
+
+        let config = &Config {
+            max_block_size: 20,
+            ..Config::default()
+        };
+
+        block_receive_block_stream(
+            root_small,
+            &mut futures::stream::iter(vec![Ok((root_small, block_small))]).boxed(),
+            config,
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await?;
+
+        let result = block_receive_block_stream(
+            root_small,
+            &mut futures::stream::iter(vec![Ok((root_big, block_big))]).boxed(),
+            config,
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+
+        assert_matches!(result, Err(Error::BlockSizeExceeded { .. }));
+
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl VerificationObserver for RecordingObserver {
+        fn on_digest_mismatch(&self, _root: Cid, cid: Cid, _actual_cid: Cid) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("digest mismatch {cid}"));
+        }
+
+        fn on_oversized_block(
+            &self,
+            _root: Cid,
+            cid: Cid,
+            _block_bytes: usize,
+            _max_block_size: usize,
+        ) {
+            self.events.lock().unwrap().push(format!("oversized {cid}"));
+        }
+
+        fn on_round_interrupted(&self, _root: Cid, cid: Cid, reason: BlockState) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("interrupted at {cid}: {reason:?}"));
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_block_stream_verification_observer() -> TestResult {
+        let store = &MemoryBlockStore::new();
+
+        let block: Bytes = b"Hello, observed world!".to_vec().into();
+        let root = store.put_block(block.clone(), CODEC_RAW).await?;
+
+        let observer = Arc::new(RecordingObserver::default());
+        let config = &Config {
+            max_block_size: 30,
+            verification_observer: Some(observer.clone()),
+            ..Config::default()
+        };
+
+        let corrupted: Bytes = b"Hello, corrupted world!".to_vec().into();
+        let result = block_receive_block_stream(
+            root,
+            &mut futures::stream::iter(vec![Ok((root, corrupted))]).boxed(),
+            config,
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+        assert_matches!(result, Err(Error::IncrementalVerificationError(_)));
+
+        let oversized: Bytes = b"Hello, very very very oversized world!".to_vec().into();
+        let result = block_receive_block_stream(
+            root,
+            &mut futures::stream::iter(vec![Ok((root, oversized))]).boxed(),
+            config,
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+        assert_matches!(result, Err(Error::BlockSizeExceeded { .. }));
+
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![
+                format!("digest mismatch {root}"),
+                format!("oversized {root}")
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_block_stream_round_interrupted_observer() -> TestResult {
+        let sender_store = &MemoryBlockStore::new();
+        let leaf_a = Bytes::from(b"Hello, leaf A!".to_vec());
+        let leaf_b = Bytes::from(b"Hello, leaf B!".to_vec());
+        let cid_a = sender_store.put_block(leaf_a.clone(), CODEC_RAW).await?;
+        let cid_b = sender_store.put_block(leaf_b.clone(), CODEC_RAW).await?;
+        let root_block = Bytes::from(encode(
+            &Ipld::List(vec![Ipld::Link(cid_a), Ipld::Link(cid_b)]),
+            IpldCodec::DagCbor,
+        )?);
+        let root = sender_store
+            .put_block(root_block.clone(), IpldCodec::DagCbor.into())
+            .await?;
+
+        let observer = Arc::new(RecordingObserver::default());
+        let config = &Config {
+            verification_observer: Some(observer.clone()),
+            ..Config::default()
+        };
+
+        // The receiver already has leaf A, so the round stops there
+        let receiver_store = &MemoryBlockStore::new();
+        receiver_store.put_block(leaf_a.clone(), CODEC_RAW).await?;
+        let blocks = vec![
+            Ok((root, root_block.clone())),
+            Ok((cid_a, leaf_a)),
+            Ok((cid_b, leaf_b)),
+        ];
+        block_receive_block_stream(
+            root,
+            &mut futures::stream::iter(blocks).boxed(),
+            config,
+            receiver_store,
+            NoCache,
+        )
+        .await?;
+        assert!(!receiver_store.has_block(&cid_b).await?);
+
+        // A block the receiver doesn't know about yet stops the round, too
+        let unrelated = Bytes::from(b"Hello, unrelated world!".to_vec());
+        let unrelated_cid = sender_store.put_block(unrelated.clone(), CODEC_RAW).await?;
+        block_receive_block_stream(
+            root,
+            &mut futures::stream::iter(vec![Ok((unrelated_cid, unrelated))]).boxed(),
+            config,
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await?;
+
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![
+                format!("interrupted at {cid_a}: Have"),
+                format!("interrupted at {unrelated_cid}: Unexpected"),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct UnrelatedRootsObserver {
+        requests: std::sync::Mutex<Vec<(Cid, Vec<Cid>)>>,
+    }
+
+    impl VerificationObserver for UnrelatedRootsObserver {
+        fn on_unrelated_roots(&self, root: Cid, unrelated_roots: &[Cid]) {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((root, unrelated_roots.to_vec()));
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_reports_unrelated_roots() -> TestResult {
+        let (root, ref store) = setup_random_dag(16, 1024).await?;
+        let unrelated_cid = store
+            .put_block(b"Hello, unrelated world!".to_vec(), CODEC_RAW)
+            .await?;
+
+        let observer = Arc::new(UnrelatedRootsObserver::default());
+        let config = &Config {
+            verification_observer: Some(observer.clone()),
+            ..Config::default()
+        };
+
+        let state = ReceiverState {
+            missing_subgraph_roots: vec![root, unrelated_cid],
+            have_cids_bloom: None,
+            max_blocks: None,
+        };
+        let car = block_send(root, Some(state), config, store, NoCache).await?;
+
+        // The unrelated block is skipped, but the rest of the DAG still gets sent
+        let expected = block_send(root, None, &Config::default(), store, NoCache).await?;
+        assert_eq!(car.bytes, expected.bytes);
+        assert_eq!(
+            *observer.requests.lock().unwrap(),
+            vec![(root, vec![unrelated_cid])]
+        );
+
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct ReceiverStateObserver {
+        states: std::sync::Mutex<Vec<ReceiverState>>,
+    }
+
+    impl VerificationObserver for ReceiverStateObserver {
+        fn on_receiver_state(&self, _: Cid, state: &ReceiverState) {
+            self.states.lock().unwrap().push(state.clone());
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_receiver_state_snapshots() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let total_blocks = total_dag_blocks(root, sender_store).await?;
+
+        let observer = Arc::new(ReceiverStateObserver::default());
+        let config = &Config {
+            verification_observer: Some(observer.clone()),
+            receiver_state_every: 4,
+            ..Config::default()
+        };
+
+        let blocks =
+            block_send_block_stream(root, None, &Config::default(), sender_store, NoCache).await?;
+        let car_stream = stream_car_frames(blocks).await?;
+        let reader = StreamReader::new(car_stream.map_err(std::io::Error::other));
+        let store = &MemoryBlockStore::new();
+        let final_state = block_receive_car_stream(root, reader, config, store, NoCache).await?;
+        assert!(final_state.missing_subgraph_roots.is_empty());
+
+        let states = observer.states.lock().unwrap().clone();
+        assert_eq!(states.len(), total_blocks / 4);
+
+        // Each snapshot requests less of the DAG than the one before
+        let full_car = block_send(root, None, config, sender_store, NoCache).await?;
+        let mut last_len = full_car.bytes.len();
+        for state in states {
+            let car = block_send(root, Some(state), config, sender_store, NoCache).await?;
+            assert!(car.bytes.len() < last_len);
+            last_len = car.bytes.len();
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_block_stream_idle_timeout() -> TestResult {
+        let store = &MemoryBlockStore::new();
+
+        let block: Bytes = b"Hello, stalled world!".to_vec().into();
+        let root = store.put_block(block.clone(), CODEC_RAW).await?;
+
+        let config = &Config {
+            stream_idle_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+
+        // Sends the first block, then stalls forever
+        let mut stalling_stream = futures::stream::once(async move { Ok((root, block)) })
+            .chain(futures::stream::pending())
+            .boxed();
+
+        let result = block_receive_block_stream(
+            root,
+            &mut stalling_stream,
+            config,
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+
+        assert_matches!(result, Err(Error::StreamIdleTimeout { .. }));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_end_of_round() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            require_end_of_round: true,
+            ..Config::default()
+        };
+
+        let blocks =
+            block_send_block_stream(root, None, &Config::default(), sender_store, NoCache).await?;
+        let frames = stream_car_frames(with_end_of_round(blocks))
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        // The complete stream ends the round cleanly
+        let store = &MemoryBlockStore::new();
+        let car = Cursor::new(frames.concat());
+        let state = block_receive_car_stream(root, car, config, store, NoCache).await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(!store.has_block(&end_of_round_cid()).await?);
+
+        // Without the last frame, the stream looks truncated
+        let truncated = Cursor::new(frames[..frames.len() - 1].concat());
+        let result =
+            block_receive_car_stream(root, truncated, config, &MemoryBlockStore::new(), NoCache)
+                .await;
+        assert_matches!(result, Err(Error::MissingEndOfRound));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_empty_round_with_end_of_round() -> TestResult {
+        let (root, _) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            require_end_of_round: true,
+            require_matching_car_roots: true,
+            ..Config::default()
+        };
+
+        // The end of round marker is the only block, so it ends up as the CAR root
+        let frames = stream_car_frames(with_end_of_round(boxed_stream(futures::stream::empty())))
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let store = &MemoryBlockStore::new();
+        let car = Cursor::new(frames.concat());
+        let state = block_receive_car_stream(root, car, config, store, NoCache).await?;
+        assert_eq!(state.missing_subgraph_roots, vec![root]);
+        assert!(!store.has_block(&end_of_round_cid()).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_interrupted_block_is_invisible() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config::default();
+
+        let cids = DagWalk::breadth_first([root])
+            .stream(sender_store, &NoCache)
+            .and_then(|item| async move { item.to_cid() })
+            .try_collect::<Vec<_>>()
+            .await?;
+        let blocks =
+            block_send_block_stream(root, None, &Config::default(), sender_store, NoCache).await?;
+        let frames = stream_car_frames(blocks)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        // Cut the stream in the middle of the 11th block. The first frame is the CAR header.
+        let interrupted_frame = &frames[11];
+        let mut car = frames[..11].concat();
+        car.extend_from_slice(&interrupted_frame[..interrupted_frame.len() / 2]);
+
+        let store = &MemoryBlockStore::new();
+        let result = block_receive_car_stream(root, Cursor::new(car), config, store, NoCache).await;
+        assert!(result.is_err());
+
+        // Blocks before the interruption are stored, the interrupted one isn't visible
+        for cid in &cids[..10] {
+            assert!(store.has_block(cid).await?);
+        }
+        assert!(!store.has_block(&cids[10]).await?);
+        assert!(compute_missing(root, store, NoCache)
+            .await?
+            .contains(&cids[10]));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_corrupt_block() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let corrupt_cid = get_cid_at_approx_path(vec![0, 0], root, sender_store).await?;
+
+        let car_stream = corrupt_car_stream(root, corrupt_cid, sender_store).await?;
+        let reader = StreamReader::new(car_stream.map_err(std::io::Error::other));
+        let store = &MemoryBlockStore::new();
+        let result =
+            block_receive_car_stream(root, reader, &Config::default(), store, NoCache).await;
+
+        assert_matches!(
+            result,
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::DigestMismatch { cid, .. }
+            )) if *cid == corrupt_cid
+        );
+        assert!(!store.has_block(&corrupt_cid).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_block_stream_discard_unexpected() -> TestResult {
+        let sender_store = &MemoryBlockStore::new();
+        let leaf_a = Bytes::from(b"Hello, leaf A!".to_vec());
+        let leaf_b = Bytes::from(b"Hello, leaf B!".to_vec());
+        let unrelated = Bytes::from(b"Hello, unrelated world!".to_vec());
+        let cid_a = sender_store.put_block(leaf_a.clone(), CODEC_RAW).await?;
+        let cid_b = sender_store.put_block(leaf_b.clone(), CODEC_RAW).await?;
+        let unrelated_cid = sender_store.put_block(unrelated.clone(), CODEC_RAW).await?;
+        let root_block = Bytes::from(encode(
+            &Ipld::List(vec![Ipld::Link(cid_a), Ipld::Link(cid_b)]),
+            IpldCodec::DagCbor,
+        )?);
+        let root = sender_store
+            .put_block(root_block.clone(), IpldCodec::DagCbor.into())
+            .await?;
+
+        let blocks = || {
+            futures::stream::iter(vec![
+                Ok((root, root_block.clone())),
+                Ok((cid_a, leaf_a.clone())),
+                Ok((unrelated_cid, unrelated.clone())),
+                Ok((cid_b, leaf_b.clone())),
+            ])
+            .boxed()
+        };
+
+        // By default, the round stops at the unexpected block
+        let receiver_store = &MemoryBlockStore::new();
+        let state = block_receive_block_stream(
+            root,
+            &mut blocks(),
+            &Config::default(),
+            receiver_store,
+            NoCache,
+        )
+        .await?;
+        assert_eq!(state.missing_subgraph_roots, vec![cid_b]);
+
+        let config = &Config {
+            on_unexpected_block: UnexpectedPolicy::DiscardAndContinue,
+            ..Config::default()
+        };
+        let receiver_store = &MemoryBlockStore::new();
+        let state =
+            block_receive_block_stream(root, &mut blocks(), config, receiver_store, NoCache)
+                .await?;
+
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(receiver_store.has_block(&cid_b).await?);
+        assert!(!receiver_store.has_block(&unrelated_cid).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_strict_cid_links() -> TestResult {
+        let leaf = Bytes::from(b"Hello, leniently linked leaf!".to_vec());
+        let leaf_cid = compute_cid(CODEC_RAW, Code::Sha2_256, &leaf);
+
+        // A link without the multibase identity prefix
+        let leaf_cid_bytes = leaf_cid.to_bytes();
+        let mut root_block = vec![0xd8, 0x2a, 0x58, leaf_cid_bytes.len() as u8];
+        root_block.extend_from_slice(&leaf_cid_bytes);
+        let root_block = Bytes::from(root_block);
+        let root = compute_cid(IpldCodec::DagCbor.into(), Code::Sha2_256, &root_block);
+
+        let bytes = write_blocks_into_car(
+            Vec::new(),
+            &mut futures::stream::iter(vec![Ok((root, root_block)), Ok((leaf_cid, leaf))]).boxed(),
+            None,
+        )
+        .await?;
+        let car = || CarFile {
+            bytes: bytes.clone().into(),
+        };
+
+        // By default, the link is found and the DAG syncs
+        let store = &MemoryBlockStore::new();
+        let state = block_receive(root, Some(car()), &Config::default(), store, NoCache).await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(store.has_block(&leaf_cid).await?);
+
+        let config = &Config {
+            strict_cid_links: true,
+            ..Config::default()
+        };
+        let store = &MemoryBlockStore::new();
+        let result = block_receive(root, Some(car()), config, store, NoCache).await;
+        assert_matches!(result, Err(Error::ParsingError(_)));
+        assert!(!store.has_block(&root).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_duplicate_blocks() -> TestResult {
+        let leaf_a = Bytes::from(b"Hello, leaf A!".to_vec());
+        let leaf_b = Bytes::from(b"Hello, leaf B!".to_vec());
+        let cid_a = compute_cid(CODEC_RAW, Code::Sha2_256, &leaf_a);
+        let cid_b = compute_cid(CODEC_RAW, Code::Sha2_256, &leaf_b);
+        let root_block = Bytes::from(encode(
+            &Ipld::List(vec![Ipld::Link(cid_a), Ipld::Link(cid_b)]),
+            IpldCodec::DagCbor,
+        )?);
+        let root = compute_cid(IpldCodec::DagCbor.into(), Code::Sha2_256, &root_block);
+
+        // A CAR file that repeats leaf A
+        let bytes = write_blocks_into_car(
+            Vec::new(),
+            &mut futures::stream::iter(vec![
+                Ok((root, root_block)),
+                Ok((cid_a, leaf_a.clone())),
+                Ok((cid_a, leaf_a)),
+                Ok((cid_b, leaf_b)),
+            ])
+            .boxed(),
+            None,
+        )
+        .await?;
+        let car = || CarFile {
+            bytes: bytes.clone().into(),
+        };
+
+        // By default, the round stops at the repeated block
+        let receiver_store = &MemoryBlockStore::new();
+        let state = block_receive(
+            root,
+            Some(car()),
+            &Config::default(),
+            receiver_store,
+            NoCache,
+        )
+        .await?;
+        assert_eq!(state.missing_subgraph_roots, vec![cid_b]);
+
+        let config = &Config {
+            tolerate_duplicate_blocks: true,
+            ..Config::default()
+        };
+        let receiver_store = &MemoryBlockStore::new();
+        let state = block_receive(root, Some(car()), config, receiver_store, NoCache).await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(receiver_store.has_block(&cid_b).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_block_stream_min_blocks_before_interrupt() -> TestResult {
+        let leaf_a = Bytes::from(b"Hello, leaf A!".to_vec());
+        let leaf_b = Bytes::from(b"Hello, leaf B!".to_vec());
+        let receiver_store = &MemoryBlockStore::new();
+        let cid_a = receiver_store.put_block(leaf_a.clone(), CODEC_RAW).await?;
+        let cid_b = compute_cid(CODEC_RAW, Code::Sha2_256, &leaf_b);
+        let root_block = Bytes::from(encode(
+            &Ipld::List(vec![Ipld::Link(cid_a), Ipld::Link(cid_b)]),
+            IpldCodec::DagCbor,
+        )?);
+        let root = compute_cid(IpldCodec::DagCbor.into(), Code::Sha2_256, &root_block);
+
+        let blocks = || {
+            futures::stream::iter(vec![
+                Ok((root, root_block.clone())),
+                Ok((cid_a, leaf_a.clone())),
+                Ok((cid_b, leaf_b.clone())),
+            ])
+            .boxed()
+        };
+
+        // The round stops at the second block, which the receiver already has
+        let config = &Config {
+            min_blocks_before_interrupt: 2,
+            ..Config::default()
+        };
+        let state =
+            block_receive_block_stream(root, &mut blocks(), config, receiver_store, NoCache)
+                .await?;
+        assert_eq!(state.missing_subgraph_roots, vec![cid_b]);
+
+        let config = &Config {
+            min_blocks_before_interrupt: 3,
+            ..Config::default()
+        };
+        let state =
+            block_receive_block_stream(root, &mut blocks(), config, receiver_store, NoCache)
+                .await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(receiver_store.has_block(&cid_b).await?);
+
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct ThreadSpawner {
+        jobs: AtomicUsize,
+    }
+
+    impl DigestSpawner for ThreadSpawner {
+        fn spawn(&self, job: Box<dyn FnOnce() + Send>) {
+            self.jobs.fetch_add(1, Ordering::SeqCst);
+            std::thread::spawn(job);
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_digest_spawner() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let spawner = Arc::new(ThreadSpawner::default());
+        let config = &Config {
+            digest_spawner: Some(spawner.clone()),
+            ..Config::default()
+        };
+
+        let car = block_send(root, None, config, sender_store, NoCache).await?;
+        let receiver_store = &MemoryBlockStore::new();
+        let state = block_receive_car_stream(
+            root,
+            Cursor::new(car.bytes.clone()),
+            config,
+            receiver_store,
+            NoCache,
+        )
+        .await?;
+        let expected_state = block_receive_car_stream(
+            root,
+            Cursor::new(car.bytes),
+            &Config::default(),
+            &MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await?;
+
+        assert_eq!(
+            state.missing_subgraph_roots,
+            expected_state.missing_subgraph_roots
+        );
+        assert!(spawner.jobs.load(Ordering::SeqCst) > 0);
+
+        // Corrupt blocks are still caught
+        let corrupt_cid = get_cid_at_approx_path(vec![0, 0], root, sender_store).await?;
+        let car_stream = corrupt_car_stream(root, corrupt_cid, sender_store).await?;
+        let reader = StreamReader::new(car_stream.map_err(std::io::Error::other));
+        let store = &MemoryBlockStore::new();
+        let result = block_receive_car_stream(root, reader, config, store, NoCache).await;
+
+        assert_matches!(
+            result,
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::DigestMismatch { cid, .. }
+            )) if *cid == corrupt_cid
+        );
+        assert!(!store.has_block(&corrupt_cid).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_stream_receive_maximum() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            stream_receive_maximum: Some(10 * 1024),
+            ..Config::default()
+        };
+
+        async fn receive(
+            root: Cid,
+            config: &Config,
+            sender_store: &MemoryBlockStore,
+            store: &MemoryBlockStore,
+        ) -> Result<ReceiverState, Error> {
+            let blocks =
+                block_send_block_stream(root, None, &Config::default(), sender_store, NoCache)
+                    .await?;
+            let car_stream = stream_car_frames(blocks).await?;
+            let reader = StreamReader::new(car_stream.map_err(std::io::Error::other));
+            block_receive_car_stream(root, reader, config, store, NoCache).await
+        }
+
+        let store = &MemoryBlockStore::new();
+        let result = receive(root, config, sender_store, store).await;
+        assert_matches!(
+            result,
+            Err(Error::TooManyBytes { receive_maximum, bytes_read })
+                if receive_maximum == 10 * 1024 && bytes_read > receive_maximum
+        );
+        // Blocks up to the maximum were stored
+        assert!(store.has_block(&root).await?);
+
+        let state = receive(
+            root,
+            &Config::default(),
+            sender_store,
+            &MemoryBlockStore::new(),
+        )
+        .await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_require_matching_car_roots() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let strict = &Config {
+            receive_maximum: 16 * 1024,
+            require_matching_car_roots: true,
+            ..Config::default()
+        };
+
+        // Transfers where each round's CAR root is a missing subgraph root pass
+        let receiver_store = &MemoryBlockStore::new();
+        let mut last_state = None;
+        loop {
+            let car = block_send(root, last_state, strict, sender_store, NoCache).await?;
+            let state = block_receive(root, Some(car), strict, receiver_store, NoCache).await?;
+            if state.missing_subgraph_roots.is_empty() {
+                break;
+            }
+            last_state = Some(state);
+        }
+
+        // A receiver that has the root and one of its children, but not the rest
+        let child = get_cid_at_approx_path(vec![0], root, sender_store).await?;
+        let receiver_store = &MemoryBlockStore::new();
+        for cid in [root, child] {
+            receiver_store
+                .put_block_keyed(cid, sender_store.get_block(&cid).await?)
+                .await?;
+        }
+
+        // A CAR file rooted at a block the receiver already has is accepted leniently
+        let car = block_send(child, None, strict, sender_store, NoCache).await?;
+        let lenient = &Config {
+            require_matching_car_roots: false,
+            ..strict.clone()
+        };
+        block_receive(root, Some(car.clone()), lenient, receiver_store, NoCache).await?;
+
+        let result = block_receive(root, Some(car), strict, receiver_store, NoCache).await;
+        assert_matches!(
+            result,
+            Err(Error::MismatchedCarRoots { root: r, car_roots }) if r == root && car_roots == vec![child]
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_car_stream_rejects_unrelated_car_roots() -> TestResult {
+        let (root, _) = setup_random_dag(16, 1024).await?;
+        let (other_root, ref other_store) = setup_random_dag(16, 1024).await?;
+        let config = &Config::default();
+
+        let car = block_send(other_root, None, config, other_store, NoCache).await?;
+
+        let store = &MemoryBlockStore::new();
+        let result =
+            block_receive_car_stream(root, Cursor::new(car.bytes), config, store, NoCache).await;
+        assert_matches!(
+            result,
+            Err(Error::UnrelatedCarRoots { root: r, car_roots }) if r == root && car_roots == vec![other_root]
+        );
+        // Nothing got stored
+        assert!(!store.has_block(&other_root).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_car_file_split() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let max_bytes = 8 * 1024;
+        let car = block_send(
+            root,
+            None,
+            &Config {
+                receive_maximum: 10_000_000,
+                ..Config::default()
+            },
+            sender_store,
+            NoCache,
+        )
+        .await?;
+
+        let car_files = car.split(max_bytes).await?;
+        assert!(car_files.len() > 1);
+        assert!(car_files.iter().all(|car| car.bytes.len() <= max_bytes));
+
+        // Feeding the chunks one after the other transfers the whole DAG
+        let config = &Config {
+            receive_maximum: max_bytes,
+            ..Config::default()
+        };
+        let store = &MemoryBlockStore::new();
+        let mut state = None;
+        for car in car_files {
+            state = Some(block_receive(root, Some(car), config, store, NoCache).await?);
+        }
+
+        assert_matches!(state, Some(state) if state.missing_subgraph_roots.is_empty());
+        assert_eq!(
+            total_dag_blocks(root, store).await?,
+            total_dag_blocks(root, sender_store).await?
+        );
+
+        // A single block needs to fit
+        assert_matches!(car.split(100).await, Err(Error::TooManyBytes { .. }));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_block_receive_allowed_codecs() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(16, 1024).await?;
+        let car = block_send(root, None, &Config::default(), sender_store, NoCache).await?;
+
+        // The root is a DAG-CBOR block
+        let raw_only = &Config {
+            allowed_codecs: Some(HashSet::from([CODEC_RAW])),
+            ..Config::default()
+        };
+        let result = block_receive(
+            root,
+            Some(car.clone()),
+            raw_only,
+            &MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+        assert_matches!(result, Err(Error::UnsupportedCodec { cid }) if cid == root);
+
+        let raw_and_dag_cbor = &Config {
+            allowed_codecs: Some(HashSet::from([CODEC_RAW, IpldCodec::DagCbor.into()])),
+            ..Config::default()
+        };
+        block_receive(
+            root,
+            Some(car),
+            raw_and_dag_cbor,
+            &MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await?;
+
+        Ok(())
+    }
 
-//--------------------------------------------------------------------------------------------------
-// Implementations
-//--------------------------------------------------------------------------------------------------
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_rejects_leaf_first() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            leaf_first: true,
+            ..Config::default()
+        };
 
-impl From<PushResponse> for ReceiverState {
-    fn from(push: PushResponse) -> Self {
-        let PushResponse {
-            subgraph_roots,
-            bloom_hash_count: hash_count,
-            bloom_bytes: bytes,
-        } = push;
+        let result = block_send(root, None, config, store, NoCache).await;
+        assert_matches!(result, Err(Error::LeafFirstRound));
 
-        Self {
-            missing_subgraph_roots: subgraph_roots,
-            have_cids_bloom: Self::bloom_deserialize(hash_count, bytes),
-        }
+        let result = block_send_block_stream(root, None, config, store, NoCache).await;
+        assert_matches!(result, Err(Error::LeafFirstRound));
+
+        Ok(())
     }
-}
 
-impl From<PullRequest> for ReceiverState {
-    fn from(pull: PullRequest) -> Self {
-        let PullRequest {
-            resources,
-            bloom_hash_count: hash_count,
-            bloom_bytes: bytes,
-        } = pull;
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_eager_prefetch_depth() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
 
-        Self {
-            missing_subgraph_roots: resources,
-            have_cids_bloom: Self::bloom_deserialize(hash_count, bytes),
+        // A receiver that claims to have every block, but still asks for the root
+        let mut all_cids = Vec::new();
+        let mut dag_walk = DagWalk::breadth_first([root]);
+        while let Some((cid, _)) = dag_walk.next_with_block(store, &NoCache).await? {
+            all_cids.push(cid);
         }
+        let mut bloom = BloomFilter::new_from_fpr_po2(all_cids.len() as u64, 0.0001);
+        all_cids
+            .iter()
+            .for_each(|cid| bloom.insert(&cid.to_bytes()));
+        let state = ReceiverState {
+            missing_subgraph_roots: vec![root],
+            have_cids_bloom: Some(bloom),
+            max_blocks: None,
+        };
+
+        let sent_cids = |config: Config| {
+            let state = state.clone();
+            async move {
+                let car = block_send(root, Some(state), &config, store, NoCache).await?;
+                let reader = CarReader::new(Cursor::new(car.bytes)).await?;
+                let blocks = reader.stream().try_collect::<Vec<_>>().await?;
+                Ok::<_, anyhow::Error>(
+                    blocks
+                        .into_iter()
+                        .map(|(cid, _)| cid)
+                        .collect::<HashSet<_>>(),
+                )
+            }
+        };
+
+        assert_eq!(sent_cids(Config::default()).await?, HashSet::from([root]));
+
+        let mut expected = HashSet::from([root]);
+        expected.extend(NoCache.references(root, store).await?);
+        let config = Config {
+            eager_prefetch_depth: 1,
+            ..Config::default()
+        };
+        assert_eq!(sent_cids(config).await?, expected);
+
+        Ok(())
     }
-}
 
-impl From<ReceiverState> for PushResponse {
-    fn from(receiver_state: ReceiverState) -> PushResponse {
-        let ReceiverState {
-            missing_subgraph_roots,
-            have_cids_bloom,
-        } = receiver_state;
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_block_stream_prioritized() -> TestResult {
+        let store = &MemoryBlockStore::new();
+        let leaf = store
+            .put_block(b"file contents".to_vec(), CODEC_RAW)
+            .await?;
+        let dir = store
+            .put_block(
+                encode(&Ipld::String("directory".into()), IpldCodec::DagCbor)?,
+                IpldCodec::DagCbor.into(),
+            )
+            .await?;
+        let root = store
+            .put_block(
+                encode(
+                    &Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(dir)]),
+                    IpldCodec::DagCbor,
+                )?,
+                IpldCodec::DagCbor.into(),
+            )
+            .await?;
 
-        let (hash_count, bytes) = ReceiverState::bloom_serialize(have_cids_bloom);
+        let last_state = ReceiverState {
+            missing_subgraph_roots: vec![leaf, dir],
+            have_cids_bloom: None,
+            max_blocks: None,
+        };
 
-        PushResponse {
-            subgraph_roots: missing_subgraph_roots,
-            bloom_hash_count: hash_count,
-            bloom_bytes: bytes,
-        }
+        let sent = block_send_block_stream(
+            root,
+            Some(last_state.clone()),
+            &Config::default(),
+            store,
+            NoCache,
+        )
+        .await?
+        .map_ok(|(cid, _)| cid)
+        .try_collect::<Vec<_>>()
+        .await?;
+        assert_eq!(sent, vec![leaf, dir]);
+
+        let root_priority = |cid: &Cid| if cid.codec() == CODEC_RAW { 0 } else { 1 };
+        let sent = block_send_block_stream_prioritized(
+            root,
+            Some(last_state),
+            root_priority,
+            &Config::default(),
+            store,
+            NoCache,
+        )
+        .await?
+        .map_ok(|(cid, _)| cid)
+        .try_collect::<Vec<_>>()
+        .await?;
+        assert_eq!(sent, vec![dir, leaf]);
+
+        Ok(())
     }
-}
 
-impl From<ReceiverState> for PullRequest {
-    fn from(receiver_state: ReceiverState) -> PullRequest {
-        let ReceiverState {
-            missing_subgraph_roots,
-            have_cids_bloom,
-        } = receiver_state;
+    #[test_log::test(async_std::test)]
+    async fn test_compute_missing() -> TestResult {
+        let leaf_block = b"file contents".to_vec();
+        let dir_block = encode(&Ipld::String("directory".into()), IpldCodec::DagCbor)?;
+        let full_store = &MemoryBlockStore::new();
+        let leaf = full_store.put_block(leaf_block.clone(), CODEC_RAW).await?;
+        let dir = full_store
+            .put_block(dir_block, IpldCodec::DagCbor.into())
+            .await?;
+        let root_block = encode(
+            &Ipld::List(vec![Ipld::Link(leaf), Ipld::Link(dir)]),
+            IpldCodec::DagCbor,
+        )?;
+        let root = full_store
+            .put_block(root_block.clone(), IpldCodec::DagCbor.into())
+            .await?;
 
-        let (hash_count, bytes) = ReceiverState::bloom_serialize(have_cids_bloom);
+        let partial_store = &MemoryBlockStore::new();
+        partial_store.put_block(leaf_block, CODEC_RAW).await?;
+        partial_store
+            .put_block(root_block, IpldCodec::DagCbor.into())
+            .await?;
 
-        PullRequest {
-            resources: missing_subgraph_roots,
-            bloom_hash_count: hash_count,
-            bloom_bytes: bytes,
-        }
+        assert_eq!(
+            compute_missing(root, &MemoryBlockStore::new(), NoCache).await?,
+            vec![root]
+        );
+        assert_eq!(
+            compute_missing(root, partial_store, NoCache).await?,
+            vec![dir]
+        );
+        assert!(compute_missing(root, full_store, NoCache).await?.is_empty());
+
+        Ok(())
     }
-}
 
-impl ReceiverState {
-    fn bloom_serialize(bloom: Option<BloomFilter>) -> (u32, Vec<u8>) {
-        match bloom {
-            Some(bloom) => (bloom.hash_count() as u32, bloom.as_bytes().to_vec()),
-            None => (3, Vec::new()),
+    /// A store that hashes blocks with SHA-256 instead of the default BLAKE3.
+    #[derive(Debug, Default)]
+    struct Sha256BlockStore(MemoryBlockStore);
+
+    impl BlockStore for Sha256BlockStore {
+        async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+            self.0.get_block(cid).await
         }
-    }
 
-    fn bloom_deserialize(hash_count: u32, bytes: Vec<u8>) -> Option<BloomFilter> {
-        if bytes.is_empty() {
-            None
-        } else {
-            Some(BloomFilter::new_with(
-                hash_count as usize,
-                bytes.into_boxed_slice(),
-            ))
+        async fn put_block_keyed(
+            &self,
+            cid: Cid,
+            bytes: impl Into<Bytes> + CondSend,
+        ) -> Result<(), BlockStoreError> {
+            self.0.put_block_keyed(cid, bytes).await
+        }
+
+        async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+            self.0.has_block(cid).await
+        }
+
+        fn create_cid(&self, bytes: &[u8], codec: u64) -> Result<Cid, BlockStoreError> {
+            Ok(compute_cid(codec, Code::Sha2_256, bytes))
         }
     }
-}
 
-impl std::fmt::Debug for ReceiverState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let have_cids_bloom = self
-            .have_cids_bloom
-            .as_ref()
-            .map_or("None".into(), |bloom| {
-                format!(
-                    "Some(BloomFilter(k_hashes = {}, {} bytes))",
-                    bloom.hash_count(),
-                    bloom.as_bytes().len()
-                )
-            });
-        f.debug_struct("ReceiverState")
-            .field(
-                "missing_subgraph_roots.len() == ",
-                &self.missing_subgraph_roots.len(),
+    #[test_log::test(async_std::test)]
+    async fn test_compute_cid_agrees_with_store_cids() -> TestResult {
+        let sender_store = &Sha256BlockStore::default();
+        let leaf = sender_store.put_block(b"leaf".to_vec(), CODEC_RAW).await?;
+        let root = sender_store
+            .put_block(
+                encode(&Ipld::List(vec![Ipld::Link(leaf)]), IpldCodec::DagCbor)?,
+                IpldCodec::DagCbor.into(),
             )
-            .field("have_cids_bloom", &have_cids_bloom)
-            .finish()
+            .await?;
+
+        for cid in [leaf, root] {
+            let bytes = sender_store.get_block(&cid).await?;
+            assert_eq!(compute_cid(cid.codec(), Code::Sha2_256, &bytes), cid);
+        }
+
+        // Blocks created by the store verify on the receiving end
+        let config = &Config::default();
+        let receiver_store = &MemoryBlockStore::new();
+        let car = block_send(root, None, config, sender_store, NoCache).await?;
+        let state = block_receive(root, Some(car), config, receiver_store, NoCache).await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(receiver_store.has_block(&leaf).await?);
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use super::*;
-    use crate::{cache::NoCache, test_utils::assert_cond_send_sync};
-    use assert_matches::assert_matches;
-    use testresult::TestResult;
-    use wnfs_common::{MemoryBlockStore, CODEC_RAW};
+    #[test]
+    fn test_receiver_state_from_message_checked_rejects_large_blooms() {
+        let config = &Config::builder().max_bloom_bytes(64).build().unwrap();
+        let response = |bloom_bytes: usize| PushResponse {
+            cid_encoding: CidEncoding::default(),
+            subgraph_roots: Vec::new(),
+            bloom_hash_count: 3,
+            bloom_bytes: vec![0; bloom_bytes],
+            checkpoint: None,
+        };
 
-    #[allow(clippy::unreachable, unused)]
-    fn test_assert_send() {
-        assert_cond_send_sync(|| {
-            block_send(
-                unimplemented!(),
-                unimplemented!(),
-                unimplemented!(),
-                unimplemented!() as MemoryBlockStore,
-                NoCache,
-            )
-        });
-        assert_cond_send_sync(|| {
-            block_receive(
-                unimplemented!(),
-                unimplemented!(),
-                unimplemented!(),
-                unimplemented!() as &MemoryBlockStore,
-                &NoCache,
-            )
-        })
+        assert!(ReceiverState::from_message_checked(response(64), config).is_ok());
+        assert_matches!(
+            ReceiverState::from_message_checked(response(65), config),
+            Err(Error::BloomTooLarge {
+                bloom_bytes: 65,
+                max_bloom_bytes: 64
+            })
+        );
     }
 
     #[test]
-    fn test_receiver_state_is_not_a_huge_debug() -> TestResult {
-        let state = ReceiverState {
-            have_cids_bloom: Some(BloomFilter::new_from_size(4096, 1000)),
-            missing_subgraph_roots: vec![Cid::default(); 1000],
+    fn test_receiver_state_from_message_checked_rejects_many_roots() {
+        let config = &Config::builder().max_incoming_roots(2).build().unwrap();
+        let request = |num_roots: u8| PullRequest {
+            cid_encoding: CidEncoding::default(),
+            resources: (0..num_roots)
+                .map(|i| Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(&[i])))
+                .collect(),
+            bloom_hash_count: 3,
+            bloom_bytes: Vec::new(),
+            max_blocks: None,
         };
 
-        let debug_print = format!("{state:#?}");
+        assert!(ReceiverState::from_message_checked(request(2), config).is_ok());
+        assert_matches!(
+            ReceiverState::from_message_checked(request(3), config),
+            Err(Error::TooManyRoots {
+                num_roots: 3,
+                max_incoming_roots: 2
+            })
+        );
+    }
 
-        assert!(debug_print.len() < 1000);
+    #[test_log::test(async_std::test)]
+    async fn test_block_send_stops_walking_once_roots_are_found() -> TestResult {
+        let store = &MemoryBlockStore::new();
+        let found = store.put_block(b"found".to_vec(), CODEC_RAW).await?;
+        let not_stored = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(b"not stored"));
+        let root = store
+            .put_block(
+                encode(
+                    &Ipld::List(vec![Ipld::Link(found), Ipld::Link(not_stored)]),
+                    IpldCodec::DagCbor,
+                )?,
+                IpldCodec::DagCbor.into(),
+            )
+            .await?;
+
+        // Walking the whole DAG would fail on the block that's not stored,
+        // but we only need to walk until we've found the requested root.
+        let last_state = ReceiverState {
+            missing_subgraph_roots: vec![found],
+            have_cids_bloom: None,
+            max_blocks: None,
+        };
+        let sent =
+            block_send_block_stream(root, Some(last_state), &Config::default(), store, NoCache)
+                .await?
+                .map_ok(|(cid, _)| cid)
+                .try_collect::<Vec<_>>()
+                .await?;
+
+        assert_eq!(sent, vec![found]);
 
         Ok(())
     }
 
     #[test_log::test(async_std::test)]
-    async fn test_stream_car_frame_empty() -> TestResult {
-        let car_frames = stream_car_frames(futures::stream::empty().boxed()).await?;
-        let frames: Vec<Bytes> = car_frames.try_collect().await?;
+    async fn test_max_frontier_size_wide_dag() -> TestResult {
+        let store = &MemoryBlockStore::new();
+        let mut links = Vec::new();
+        for i in 0..1000u32 {
+            let leaf = store.put_block(i.to_be_bytes().to_vec(), CODEC_RAW).await?;
+            links.push(Ipld::Link(leaf));
+        }
+        let root_block = encode(&Ipld::List(links), IpldCodec::DagCbor)?;
+        let root = store
+            .put_block(root_block.clone(), IpldCodec::DagCbor.into())
+            .await?;
 
-        assert!(frames.is_empty());
+        let config = &Config {
+            max_frontier_size: Some(100),
+            ..Config::default()
+        };
+
+        let result = block_send(root, None, config, store, NoCache).await;
+        assert_matches!(
+            result,
+            Err(Error::FrontierTooLarge {
+                max_frontier_size: 100
+            })
+        );
+
+        // A receiver that has the root, but none of its children
+        let receiver_store = &MemoryBlockStore::new();
+        receiver_store
+            .put_block(root_block, IpldCodec::DagCbor.into())
+            .await?;
+        let result = block_receive(root, None, config, receiver_store, NoCache).await;
+        assert_matches!(result, Err(Error::FrontierTooLarge { .. }));
 
         Ok(())
     }
 
     #[test_log::test(async_std::test)]
-    async fn test_write_blocks_into_car_empty() -> TestResult {
-        let car_file =
-            write_blocks_into_car(Vec::new(), &mut futures::stream::empty().boxed(), None).await?;
+    async fn test_diff_dags() -> TestResult {
+        let (root, ref full_store) = setup_random_dag(64, 1024).await?;
+        let store_a = &MemoryBlockStore::new();
+        let store_b = &MemoryBlockStore::new();
+        let mut expected = DagDiff::default();
 
-        assert!(car_file.is_empty());
+        // Spread blocks over both stores, so that each has some blocks
+        // that it can only reach via blocks of the other store.
+        let mut dag_walk = DagWalk::breadth_first([root]);
+        let mut i = 0;
+        while let Some((cid, block)) = dag_walk.next_with_block(full_store, &NoCache).await? {
+            if i % 3 != 1 {
+                store_a.put_block_keyed(cid, block.clone()).await?;
+            }
+            if i % 3 != 2 {
+                store_b.put_block_keyed(cid, block).await?;
+            }
+            match i % 3 {
+                0 => expected.common.push(cid),
+                1 => expected.only_in_b.push(cid),
+                _ => expected.only_in_a.push(cid),
+            }
+            i += 1;
+        }
+        expected.only_in_a.sort_unstable();
+        expected.only_in_b.sort_unstable();
+        expected.common.sort_unstable();
+
+        assert_eq!(diff_dags(root, store_a, store_b, NoCache).await?, expected);
+
+        // Blocks missing from both stores aren't part of the diff
+        let empty = &MemoryBlockStore::new();
+        assert_eq!(
+            diff_dags(root, empty, empty, NoCache).await?,
+            DagDiff::default()
+        );
 
         Ok(())
     }
 
     #[test_log::test(async_std::test)]
-    async fn test_block_receive_block_stream_block_size_exceeded() -> TestResult {
-        let store = &MemoryBlockStore::new();
+    async fn test_dag_fingerprint() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let copy = &MemoryBlockStore::new();
+        let partial = &MemoryBlockStore::new();
 
-        let block_small: Bytes = b"This one is small".to_vec().into();
-        let block_big: Bytes = b"This one is very very very big".to_vec().into();
-        let root_small = store.put_block(block_small.clone(), CODEC_RAW).await?;
-        let root_big = store.put_block(block_big.clone(), CODEC_RAW).await?;
+        let mut dag_walk = DagWalk::breadth_first([root]);
+        let mut last = None;
+        while let Some((cid, block)) = dag_walk.next_with_block(store, &NoCache).await? {
+            copy.put_block_keyed(cid, block.clone()).await?;
+            if let Some((cid, block)) = last.replace((cid, block)) {
+                partial.put_block_keyed(cid, block).await?;
+            }
+        }
 
-        let config = &Config {
-            max_block_size: 20,
-            ..Config::default()
-        };
+        let fingerprint = dag_fingerprint(root, store, NoCache).await?;
+        assert_eq!(dag_fingerprint(root, copy, NoCache).await?, fingerprint);
+        assert_ne!(dag_fingerprint(root, partial, NoCache).await?, fingerprint);
+        assert_ne!(
+            dag_fingerprint(root, &MemoryBlockStore::new(), NoCache).await?,
+            fingerprint
+        );
 
-        block_receive_block_stream(
-            root_small,
-            &mut futures::stream::iter(vec![Ok((root_small, block_small))]).boxed(),
-            config,
-            MemoryBlockStore::new(),
-            NoCache,
-        )
-        .await?;
+        Ok(())
+    }
 
-        let result = block_receive_block_stream(
-            root_small,
-            &mut futures::stream::iter(vec![Ok((root_big, block_big))]).boxed(),
-            config,
-            MemoryBlockStore::new(),
-            NoCache,
-        )
-        .await;
+    #[test_log::test(async_std::test)]
+    async fn test_prefetch_dag() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let total_blocks = total_dag_blocks(root, store).await?;
 
-        assert_matches!(result, Err(Error::BlockSizeExceeded { .. }));
+        let cache = &InMemoryCache::new(10_000);
+        let stats = prefetch_dag(root, store, cache, 8).await?;
+        assert_eq!(
+            stats,
+            PrefetchStats {
+                blocks_fetched: total_blocks,
+                blocks_missing: 0,
+            }
+        );
+
+        let mut dag_walk = DagWalk::breadth_first([root]);
+        while let Some(item) = dag_walk.next(store, &NoCache).await? {
+            let cid = item.to_cid()?;
+            if cid.codec() != CODEC_RAW {
+                assert!(cache.get_references_cache(cid).await?.is_some());
+            }
+        }
+
+        // Only the root is available in a partial store
+        let partial = &MemoryBlockStore::new();
+        let root_block = store.get_block(&root).await?;
+        partial.put_block_keyed(root, root_block.clone()).await?;
+        let num_links = references(root, &root_block, Vec::new())?.len();
+        let stats = prefetch_dag(root, partial, NoCache, 0).await?;
+        assert_eq!(
+            stats,
+            PrefetchStats {
+                blocks_fetched: 1,
+                blocks_missing: num_links,
+            }
+        );
 
         Ok(())
     }
+
+    #[test]
+    fn test_config_builder_validation() {
+        assert!(Config::builder().build().is_ok());
+
+        assert_matches!(
+            Config::builder()
+                .max_block_size(MAX_CAR_FRAME_SIZE + 1)
+                .receive_maximum(2 * MAX_CAR_FRAME_SIZE)
+                .build(),
+            Err(ConfigError::BlockSizeOverFrameLimit { .. })
+        );
+
+        assert_matches!(
+            Config::builder()
+                .receive_maximum(500_000)
+                .max_block_size(1_000_000)
+                .build(),
+            Err(ConfigError::ReceiveMaximumBelowBlockSize { .. })
+        );
+
+        assert_matches!(
+            Config::builder().max_roots_per_round(0).build(),
+            Err(ConfigError::ZeroMaxRootsPerRound)
+        );
+
+        let config = Config::builder()
+            .max_block_size(256 * 1024)
+            .send_buffer_blocks(8)
+            .build()
+            .unwrap();
+        assert_eq!(config.max_block_size, 256 * 1024);
+        assert_eq!(config.send_buffer_blocks, 8);
+    }
 }
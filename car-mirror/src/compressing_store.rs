@@ -0,0 +1,133 @@
+use bytes::Bytes;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use libipld::Cid;
+use std::io::{Read, Write};
+use wnfs_common::{utils::CondSend, BlockStore, BlockStoreError};
+
+/// A block store wrapper that keeps blocks deflate-compressed in the inner store,
+/// compressing them on `put_block_keyed` and decompressing them on `get_block`.
+///
+/// Blocks are keyed by the CID of their uncompressed bytes, and `get_block`
+/// returns the uncompressed bytes again, so to car mirror this looks like any
+/// other block store. Received blocks are verified against their CID before
+/// they're compressed and stored, see `IncrementalDagVerification::verify_and_store_block`.
+///
+/// The inner store must not check that the bytes it stores hash to their CID,
+/// since compressed bytes don't.
+#[derive(Debug, Clone)]
+pub struct CompressingBlockStore<B> {
+    inner: B,
+    level: Compression,
+}
+
+impl<B> CompressingBlockStore<B> {
+    /// Wrap given store, compressing blocks with the default compression level.
+    pub fn new(inner: B) -> Self {
+        Self::with_level(inner, Compression::default().level())
+    }
+
+    /// Wrap given store, compressing blocks with given compression level
+    /// from 0 (no compression) to 9 (best compression).
+    pub fn with_level(inner: B, level: u32) -> Self {
+        Self {
+            inner,
+            level: Compression::new(level),
+        }
+    }
+
+    /// The wrapped store, which holds the compressed blocks.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B: BlockStore> BlockStore for CompressingBlockStore<B> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        let compressed = self.inner.get_block(cid).await?;
+        let mut bytes = Vec::new();
+        DeflateDecoder::new(compressed.as_ref())
+            .read_to_end(&mut bytes)
+            .map_err(|e| anyhow::anyhow!("Couldn't decompress block {cid}: {e}"))?;
+        Ok(bytes.into())
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        let bytes = bytes.into();
+        let mut encoder = DeflateEncoder::new(Vec::new(), self.level);
+        encoder
+            .write_all(&bytes)
+            .map_err(|e| anyhow::anyhow!("Couldn't compress block {cid}: {e}"))?;
+        let compressed = encoder
+            .finish()
+            .map_err(|e| anyhow::anyhow!("Couldn't compress block {cid}: {e}"))?;
+        self.inner.put_block_keyed(cid, compressed).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.inner.has_block(cid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::Config,
+        pull, push,
+        test_utils::{setup_random_dag, total_dag_blocks},
+    };
+    use testresult::TestResult;
+    use wnfs_common::{MemoryBlockStore, CODEC_RAW};
+
+    #[test_log::test(async_std::test)]
+    async fn test_stores_compressed_blocks() -> TestResult {
+        let store = &CompressingBlockStore::new(MemoryBlockStore::new());
+        let block = Bytes::from(vec![42; 1024]);
+        let cid = store.put_block(block.clone(), CODEC_RAW).await?;
+
+        // The CID is computed from the uncompressed bytes
+        assert_eq!(
+            cid,
+            MemoryBlockStore::new()
+                .put_block(block.clone(), CODEC_RAW)
+                .await?
+        );
+        assert_eq!(store.get_block(&cid).await?, block);
+        assert!(store.inner().get_block(&cid).await?.len() < block.len());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_mirror_round_trip() -> TestResult {
+        let (root, ref original_store) = setup_random_dag(256, 1024).await?;
+        let config = &Config::default();
+
+        // Push into a compressing store, verifying the uncompressed blocks
+        let server_store = &CompressingBlockStore::new(MemoryBlockStore::new());
+        push::tests::simulate_protocol(root, config, original_store, server_store).await?;
+        assert_eq!(
+            total_dag_blocks(root, server_store).await?,
+            total_dag_blocks(root, original_store).await?
+        );
+
+        // Pull back out of the compressing store, verifying the decompressed blocks
+        let client_store = &MemoryBlockStore::new();
+        pull::tests::simulate_protocol(root, config, client_store, server_store).await?;
+
+        assert_eq!(
+            client_store.get_block(&root).await?,
+            original_store.get_block(&root).await?
+        );
+        assert_eq!(
+            total_dag_blocks(root, client_store).await?,
+            total_dag_blocks(root, original_store).await?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use anyhow::{anyhow, bail, Result};
+use libipld::Cid;
+use std::io::Cursor;
+
+/// The CBOR tag for CID links in DAG-CBOR
+const CID_TAG: u64 = 42;
+
+/// The maximum depth of nested arrays, maps and tags in a DAG-CBOR block.
+///
+/// Blocks nested deeper than this are rejected, since decoding them into
+/// `Ipld` later, e.g. by the application, can exhaust the stack.
+const MAX_NESTING_DEPTH: usize = 256;
+
+/// Finds all CID links in a DAG-CBOR block.
+///
+/// Unlike a full `Ipld` decode, this doesn't fail on CBOR tags other than
+/// the CID link tag 42. Those tags are skipped, but links inside the tagged
+/// values are still found.
+///
+/// Tag 42 values must be byte strings starting with a CID. By default, they're
+/// read leniently: Like libipld, bytes trailing the CID are ignored, and links
+/// from encoders that leave out the multibase identity prefix are found too.
+/// If `strict`, values must be exactly the prefix followed by one CID, so links
+/// from non-conformant encoders surface as errors.
+///
+/// This errors on blocks nested deeper than `MAX_NESTING_DEPTH`.
+pub(crate) fn references<E: Extend<Cid>>(block: &[u8], strict: bool, refs: &mut E) -> Result<()> {
+    let mut reader = Reader { block, pos: 0 };
+    // The number of data items that are left to read per nesting level
+    let mut pending: Vec<u64> = vec![1];
+
+    while let Some(remaining) = pending.last_mut() {
+        if *remaining == 0 {
+            pending.pop();
+            continue;
+        }
+        *remaining -= 1;
+
+        let (major, argument) = reader.read_head()?;
+        let nested = match major {
+            // unsigned & negative integers, floats & simple values
+            0 | 1 | 7 => 0,
+            // byte & text strings
+            2 | 3 => {
+                reader.skip(argument)?;
+                0
+            }
+            // arrays
+            4 => argument,
+            // maps
+            5 => argument.saturating_mul(2),
+            // tags
+            6 if argument == CID_TAG => {
+                refs.extend(Some(reader.read_link(strict)?));
+                0
+            }
+            6 => 1,
+            _ => unreachable!("CBOR major types are 3 bits"),
+        };
+
+        if nested > 0 {
+            if pending.len() > MAX_NESTING_DEPTH {
+                bail!("DAG-CBOR block is nested deeper than {MAX_NESTING_DEPTH} levels");
+            }
+            pending.push(nested);
+        }
+    }
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    block: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: u64) -> Result<&'a [u8]> {
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len))
+            .filter(|end| *end <= self.block.len())
+            .ok_or_else(|| anyhow!("Unexpected end of DAG-CBOR block"))?;
+        let bytes = &self.block[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: u64) -> Result<()> {
+        self.read_bytes(len)?;
+        Ok(())
+    }
+
+    /// Reads the major type and argument of the next data item.
+    fn read_head(&mut self) -> Result<(u8, u64)> {
+        let initial = self.read_bytes(1)?[0];
+        let major = initial >> 5;
+        let info = initial & 0x1f;
+
+        let argument = match info {
+            0..=23 => u64::from(info),
+            24 => u64::from(self.read_bytes(1)?[0]),
+            25 => u64::from(u16::from_be_bytes(self.read_bytes(2)?.try_into()?)),
+            26 => u64::from(u32::from_be_bytes(self.read_bytes(4)?.try_into()?)),
+            27 => u64::from_be_bytes(self.read_bytes(8)?.try_into()?),
+            _ => bail!("Unsupported CBOR additional info {info}, indefinite lengths aren't allowed in DAG-CBOR"),
+        };
+
+        Ok((major, argument))
+    }
+
+    /// Reads the byte string of a CID link, after its tag.
+    fn read_link(&mut self, strict: bool) -> Result<Cid> {
+        let (major, len) = self.read_head()?;
+        if major != 2 {
+            bail!("Expected a byte string after CID tag, got major type {major}");
+        }
+
+        // CIDs in DAG-CBOR are prefixed with the identity multibase prefix.
+        // CIDs never start with a zero byte, so a missing prefix is unambiguous.
+        let cid_bytes = match self.read_bytes(len)? {
+            [0, cid_bytes @ ..] => cid_bytes,
+            _ if strict => bail!("Invalid CID link, missing the multibase identity prefix"),
+            cid_bytes => cid_bytes,
+        };
+
+        let mut cursor = Cursor::new(cid_bytes);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        if strict && cursor.position() as usize != cid_bytes.len() {
+            bail!("Invalid CID link {cid}, followed by trailing bytes");
+        }
+
+        Ok(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_random_dag;
+    use libipld::{Ipld, IpldCodec};
+    use libipld_core::{
+        codec::References,
+        multihash::{Code, MultihashDigest},
+    };
+    use std::{collections::BTreeMap, io::Cursor};
+    use testresult::TestResult;
+    use wnfs_common::{encode, BlockStore, CODEC_RAW};
+
+    fn raw_cid(data: &[u8]) -> Cid {
+        Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(data))
+    }
+
+    #[test]
+    fn test_references_skips_unknown_tags() -> TestResult {
+        let link_a = raw_cid(b"a");
+        let link_b = raw_cid(b"b");
+        let link_bytes = encode(&Ipld::Link(link_a), IpldCodec::DagCbor)?;
+        let nested_link_bytes = encode(&Ipld::Link(link_b), IpldCodec::DagCbor)?;
+
+        // An array of [link_a, tag 1234(link_b), tag 55799("text")]
+        let mut block = vec![0x83];
+        block.extend_from_slice(&link_bytes);
+        block.extend_from_slice(&[0xd9, 0x04, 0xd2]);
+        block.extend_from_slice(&nested_link_bytes);
+        block.extend_from_slice(&[0xd9, 0xd9, 0xf7, 0x64]);
+        block.extend_from_slice(b"text");
+
+        let mut refs = Vec::new();
+        references(&block, false, &mut refs)?;
+        assert_eq!(refs, vec![link_a, link_b]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_references_malformed_links() -> TestResult {
+        let link = raw_cid(b"a");
+        let link_bytes = link.to_bytes();
+        let tagged = |content: &[u8]| {
+            let mut block = vec![0xd8, 0x2a];
+            block.extend_from_slice(content);
+            block
+        };
+        let byte_string = |bytes: &[u8]| {
+            let mut content = vec![0x58, bytes.len() as u8];
+            content.extend_from_slice(bytes);
+            content
+        };
+        let links = |block: &[u8], strict: bool| -> Result<Vec<Cid>> {
+            let mut refs = Vec::new();
+            references(block, strict, &mut refs)?;
+            Ok(refs)
+        };
+
+        let mut prefixed = vec![0];
+        prefixed.extend_from_slice(&link_bytes);
+        assert_eq!(links(&tagged(&byte_string(&prefixed)), true)?, vec![link]);
+
+        // Missing the multibase identity prefix
+        let missing_prefix = tagged(&byte_string(&link_bytes));
+        assert_eq!(links(&missing_prefix, false)?, vec![link]);
+        assert!(links(&missing_prefix, true).is_err());
+
+        // Trailing bytes after the CID
+        prefixed.push(0xff);
+        let trailing_bytes = tagged(&byte_string(&prefixed));
+        assert_eq!(links(&trailing_bytes, false)?, vec![link]);
+        assert!(links(&trailing_bytes, true).is_err());
+
+        // A text string instead of a byte string can't be read either way
+        let mut text = vec![0x78, 4];
+        text.extend_from_slice(b"link");
+        assert!(links(&tagged(&text), false).is_err());
+        assert!(links(&tagged(&text), true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_references_rejects_truncated_blocks() -> TestResult {
+        let block = encode(
+            &Ipld::Map(BTreeMap::from([(
+                "link".to_string(),
+                Ipld::Link(raw_cid(b"a")),
+            )])),
+            IpldCodec::DagCbor,
+        )?;
+
+        assert!(references(&block[..block.len() - 1], false, &mut Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_references_rejects_deeply_nested_blocks() -> TestResult {
+        // 100k nested single-element arrays around a link
+        let mut block = vec![0x81; 100_000];
+        block.extend_from_slice(&encode(&Ipld::Link(raw_cid(b"a")), IpldCodec::DagCbor)?);
+
+        assert!(references(&block, false, &mut Vec::new()).is_err());
+
+        let cid = Cid::new_v1(IpldCodec::DagCbor.into(), Code::Sha2_256.digest(&block));
+        assert!(crate::common::references(cid, &block, Vec::new()).is_err());
+
+        // Nesting up to the limit is fine
+        let mut block = vec![0x81; MAX_NESTING_DEPTH];
+        block.extend_from_slice(&encode(&Ipld::Link(raw_cid(b"a")), IpldCodec::DagCbor)?);
+        let mut refs = Vec::new();
+        references(&block, false, &mut refs)?;
+        assert_eq!(refs, vec![raw_cid(b"a")]);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_references_matches_libipld() -> TestResult {
+        let (root, store) = setup_random_dag(64, 64).await?;
+        let mut to_visit = vec![root];
+
+        while let Some(cid) = to_visit.pop() {
+            let block = store.get_block(&cid).await?;
+            let codec = IpldCodec::try_from(cid.codec())?;
+
+            let mut expected = Vec::new();
+            <Ipld as References<IpldCodec>>::references(
+                codec,
+                &mut Cursor::new(&block),
+                &mut expected,
+            )?;
+
+            let refs = crate::common::references(cid, &block, Vec::new())?;
+
+            assert_eq!(refs, expected);
+            to_visit.extend(refs);
+        }
+
+        Ok(())
+    }
+}
@@ -1,9 +1,17 @@
-use crate::{cache::Cache, common::references, error::Error};
+use crate::{
+    cache::{Cache, NoCache},
+    error::Error,
+};
 use bytes::Bytes;
-use futures::{stream::try_unfold, Stream};
+use futures::{stream::try_unfold, Stream, TryStreamExt};
 use libipld_core::cid::Cid;
-use std::collections::{HashSet, VecDeque};
-use wnfs_common::{BlockStore, BlockStoreError};
+use serde::{Deserialize, Serialize};
+use serde_ipld_dagcbor::{DecodeError, EncodeError};
+use std::{
+    collections::{HashSet, TryReserveError, VecDeque},
+    convert::Infallible,
+};
+use wnfs_common::{BlockStore, BlockStoreError, CODEC_RAW};
 
 /// A struct that represents an ongoing walk through the Dag.
 #[derive(Clone, Debug)]
@@ -15,6 +23,50 @@ pub struct DagWalk {
     /// Whether to do a breadth-first or depth-first traversal.
     /// This controls whether newly discovered links are appended or prepended to the frontier.
     pub breadth_first: bool,
+    /// An optional limit on the number of CIDs in the frontier.
+    /// If exceeded, the traversal fails with `Error::FrontierTooLarge`.
+    pub max_frontier_size: Option<usize>,
+    /// Whether to not follow links to raw blocks, i.e. leaf data.
+    /// Raw blocks are still visited if they're one of the roots.
+    pub skip_raw_blocks: bool,
+}
+
+/// The serializable state of a `DagWalk`, to persist the progress of
+/// long traversals and resume them later, e.g. after a restart.
+///
+/// See `DagWalk::to_checkpoint` and `DagWalk::from_checkpoint`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DagWalkCheckpoint {
+    /// The CIDs to visit next, see `DagWalk::frontier`
+    #[serde(
+        serialize_with = "crate::serde_cid_vec::serialize_binary",
+        deserialize_with = "crate::serde_cid_vec::deserialize"
+    )]
+    pub frontier: Vec<Cid>,
+    /// The already visited CIDs, sorted, see `DagWalk::visited`
+    #[serde(
+        serialize_with = "crate::serde_cid_vec::serialize_binary",
+        deserialize_with = "crate::serde_cid_vec::deserialize"
+    )]
+    pub visited: Vec<Cid>,
+    /// See `DagWalk::breadth_first`
+    pub breadth_first: bool,
+    /// See `DagWalk::max_frontier_size`
+    pub max_frontier_size: Option<usize>,
+    /// See `DagWalk::skip_raw_blocks`
+    pub skip_raw_blocks: bool,
+}
+
+impl DagWalkCheckpoint {
+    /// Deserialize a checkpoint from dag-cbor bytes
+    pub fn from_dag_cbor(slice: impl AsRef<[u8]>) -> Result<Self, DecodeError<Infallible>> {
+        serde_ipld_dagcbor::from_slice(slice.as_ref())
+    }
+
+    /// Serialize a checkpoint into dag-cbor bytes
+    pub fn to_dag_cbor(&self) -> Result<Vec<u8>, EncodeError<TryReserveError>> {
+        serde_ipld_dagcbor::to_vec(self)
+    }
 }
 
 /// Represents the state that a traversed block was found in.
@@ -38,6 +90,15 @@ impl TraversedItem {
             Self::Missing(cid) => Err(Error::BlockStoreError(BlockStoreError::CIDNotFound(cid))),
         }
     }
+
+    /// Return the CID of this traversed item together with whether its
+    /// block is present locally. Unlike `to_cid`, this never fails.
+    pub fn to_cid_and_presence(self) -> (Cid, bool) {
+        match self {
+            Self::Have(cid) => (cid, true),
+            Self::Missing(cid) => (cid, false),
+        }
+    }
 }
 
 impl DagWalk {
@@ -70,6 +131,53 @@ impl DagWalk {
             frontier,
             visited,
             breadth_first,
+            max_frontier_size: None,
+            skip_raw_blocks: false,
+        }
+    }
+
+    /// Limit the number of CIDs in the frontier, bounding the memory used by
+    /// this traversal for very wide DAGs. If `next` would grow the frontier
+    /// beyond this, it returns `Error::FrontierTooLarge` instead.
+    pub fn with_max_frontier_size(mut self, max_frontier_size: Option<usize>) -> Self {
+        self.max_frontier_size = max_frontier_size;
+        self
+    }
+
+    /// Don't follow links to raw blocks, e.g. to only walk the structure of a
+    /// DAG without its leaf data.
+    pub fn with_skip_raw_blocks(mut self, skip_raw_blocks: bool) -> Self {
+        self.skip_raw_blocks = skip_raw_blocks;
+        self
+    }
+
+    /// Capture the state of this traversal, so it can be resumed via `from_checkpoint`.
+    ///
+    /// The checkpoint holds all visited CIDs, so it grows with the number of visited blocks.
+    pub fn to_checkpoint(&self) -> DagWalkCheckpoint {
+        let mut visited: Vec<Cid> = self.visited.iter().copied().collect();
+        visited.sort_unstable();
+
+        DagWalkCheckpoint {
+            frontier: self.frontier.iter().copied().collect(),
+            visited,
+            breadth_first: self.breadth_first,
+            max_frontier_size: self.max_frontier_size,
+            skip_raw_blocks: self.skip_raw_blocks,
+        }
+    }
+
+    /// Resume a traversal from a checkpoint created via `to_checkpoint`.
+    ///
+    /// The resumed traversal visits the same blocks in the same order as
+    /// the original one would have, given the DAG didn't change in between.
+    pub fn from_checkpoint(checkpoint: DagWalkCheckpoint) -> Self {
+        Self {
+            frontier: checkpoint.frontier.into(),
+            visited: checkpoint.visited.into_iter().collect(),
+            breadth_first: checkpoint.breadth_first,
+            max_frontier_size: checkpoint.max_frontier_size,
+            skip_raw_blocks: checkpoint.skip_raw_blocks,
         }
     }
 
@@ -112,26 +220,84 @@ impl DagWalk {
             .await
             .map_err(Error::BlockStoreError)?;
 
-        if has_block {
-            let refs = cache
-                .references(cid, store)
-                .await
-                .map_err(Error::BlockStoreError)?;
+        if !has_block {
+            return Ok(Some(TraversedItem::Missing(cid)));
+        }
 
-            for ref_cid in refs {
-                if !self.visited.contains(&ref_cid) {
-                    self.frontier.push_front(ref_cid);
-                }
+        let refs = cache
+            .references(cid, store)
+            .await
+            .map_err(Error::BlockStoreError)?;
+
+        self.extend_frontier(refs)?;
+
+        Ok(Some(TraversedItem::Have(cid)))
+    }
+
+    /// Like `next`, but also returns the bytes of the next block.
+    ///
+    /// The block is fetched from the store once and used both for finding
+    /// its links (on cache misses) and for returning it, so this is cheaper
+    /// than calling `next` and then `BlockStore::get_block`.
+    ///
+    /// Fails with `BlockStoreError::CIDNotFound` if the next block is missing,
+    /// like `TraversedItem::to_cid`.
+    pub async fn next_with_block(
+        &mut self,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+    ) -> Result<Option<(Cid, Bytes)>, Error> {
+        let Some(cid) = self.frontier_next() else {
+            return Ok(None);
+        };
+
+        let block = store
+            .get_block(&cid)
+            .await
+            .map_err(Error::BlockStoreError)?;
+
+        let refs = match cache
+            .get_references_cache(cid)
+            .await
+            .map_err(Error::BlockStoreError)?
+        {
+            Some(refs) => refs,
+            None => {
+                let refs = cache
+                    .reference_extractor()
+                    .references(cid, &block)
+                    .map_err(Error::ParsingError)?;
+                cache
+                    .put_references_cache(cid, refs.clone())
+                    .await
+                    .map_err(Error::BlockStoreError)?;
+                refs
             }
+        };
+
+        self.extend_frontier(refs)?;
+
+        Ok(Some((cid, block)))
+    }
+
+    fn extend_frontier(&mut self, mut refs: Vec<Cid>) -> Result<(), Error> {
+        if self.skip_raw_blocks {
+            refs.retain(|cid| cid.codec() != CODEC_RAW);
         }
 
-        let item = if has_block {
-            TraversedItem::Have(cid)
-        } else {
-            TraversedItem::Missing(cid)
-        };
+        if let Some(max_frontier_size) = self.max_frontier_size {
+            if self.frontier.len() + refs.len() > max_frontier_size {
+                return Err(Error::FrontierTooLarge { max_frontier_size });
+            }
+        }
+
+        for ref_cid in refs {
+            if !self.visited.contains(&ref_cid) {
+                self.frontier.push_front(ref_cid);
+            }
+        }
 
-        Ok(Some(item))
+        Ok(())
     }
 
     /// Turn this traversal into a stream
@@ -146,6 +312,20 @@ impl DagWalk {
         }))
     }
 
+    /// Turn this traversal into a stream of blocks, see `next_with_block`.
+    ///
+    /// Fails on the first missing block.
+    pub fn stream_with_blocks<'a>(
+        self,
+        store: &'a impl BlockStore,
+        cache: &'a impl Cache,
+    ) -> impl Stream<Item = Result<(Cid, Bytes), Error>> + Unpin + 'a {
+        Box::pin(try_unfold(self, move |mut this| async move {
+            let block = this.next_with_block(store, cache).await?;
+            Ok(block.map(|b| (b, this)))
+        }))
+    }
+
     /// Turn this traversal into a stream that takes ownership of the store & cache.
     ///
     /// In most cases `store` and `cache` should be cheaply-clonable types, so giving
@@ -167,6 +347,42 @@ impl DagWalk {
         ))
     }
 
+    /// Turn this traversal into a stream of CIDs and whether their blocks are
+    /// present in the store.
+    ///
+    /// Missing blocks don't end the stream, but their links can't be followed,
+    /// so the stream only contains the missing blocks closest to the roots.
+    /// This is useful for finding out which parts of a DAG are incomplete
+    /// in a local store, e.g. before starting a sync:
+    ///
+    /// ```
+    /// # use car_mirror::{cache::NoCache, dag_walk::DagWalk};
+    /// # use futures::TryStreamExt;
+    /// # use wnfs_common::{BlockStore, MemoryBlockStore, CODEC_RAW};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> anyhow::Result<()> {
+    /// # let store = &MemoryBlockStore::new();
+    /// # let root = store.put_block(b"Hello, world!".to_vec(), CODEC_RAW).await?;
+    /// let holes: Vec<_> = DagWalk::breadth_first([root])
+    ///     .stream_reporting_missing(store, &NoCache)
+    ///     .try_filter_map(|(cid, present)| async move { Ok((!present).then_some(cid)) })
+    ///     .try_collect()
+    ///     .await?;
+    ///
+    /// assert!(holes.is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream_reporting_missing<'a>(
+        self,
+        store: &'a impl BlockStore,
+        cache: &'a impl Cache,
+    ) -> impl Stream<Item = Result<(Cid, bool), Error>> + Unpin + 'a {
+        self.stream(store, cache)
+            .map_ok(TraversedItem::to_cid_and_presence)
+    }
+
     /// Find out whether the traversal is finished.
     ///
     /// The next call to `next` would result in `None` if this returns true.
@@ -180,10 +396,40 @@ impl DagWalk {
             .any(|frontier_cid| !self.visited.contains(frontier_cid))
     }
 
+    /// Return the CID that the next call to `next` would visit, without
+    /// advancing the traversal.
+    ///
+    /// Returns `None` if the traversal is finished.
+    ///
+    /// This is useful e.g. to prefetch blocks before visiting them.
+    pub fn peek_next(&self) -> Option<Cid> {
+        // The frontier may contain CIDs that were visited since they were
+        // added, which `frontier_next` would skip over.
+        let not_visited = |cid: &&Cid| !self.visited.contains(*cid);
+        if self.breadth_first {
+            self.frontier.iter().rev().find(not_visited).copied()
+        } else {
+            self.frontier.iter().find(not_visited).copied()
+        }
+    }
+
     /// Skip a node from the traversal for now.
     pub fn skip_walking(&mut self, block: (Cid, Bytes)) -> Result<(), Error> {
+        self.skip_walking_with_cache(block, &NoCache)
+    }
+
+    /// Like `skip_walking`, but finds the node's references via the `cache`'s
+    /// `Cache::reference_extractor`, e.g. for blocks with custom codecs.
+    pub fn skip_walking_with_cache(
+        &mut self,
+        block: (Cid, Bytes),
+        cache: &impl Cache,
+    ) -> Result<(), Error> {
         let (cid, bytes) = block;
-        let refs = references(cid, bytes, HashSet::new()).map_err(Error::ParsingError)?;
+        let refs = cache
+            .reference_extractor()
+            .references(cid, &bytes)
+            .map_err(Error::ParsingError)?;
         self.visited.insert(cid);
         self.frontier
             .retain(|frontier_cid| !refs.contains(frontier_cid));
@@ -195,7 +441,10 @@ impl DagWalk {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cache::NoCache;
+    use crate::{
+        cache::{CodecReferenceExtractor, NoCache, WithReferenceExtractor},
+        test_utils::setup_random_dag,
+    };
     use futures::TryStreamExt;
     use libipld::{cbor::DagCborCodec, Ipld};
     use testresult::TestResult;
@@ -261,6 +510,159 @@ mod tests {
 
         Ok(())
     }
+
+    #[test_log::test(async_std::test)]
+    async fn test_peek_next() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+
+        for breadth_first in [true, false] {
+            let mut dag_walk = DagWalk::new([root], breadth_first);
+            loop {
+                let peeked = dag_walk.peek_next();
+                // Peeking doesn't advance the traversal
+                assert_eq!(dag_walk.peek_next(), peeked);
+
+                let next = dag_walk.next(store, &NoCache).await?;
+                assert_eq!(next.map(|item| item.to_cid()).transpose()?, peeked);
+                if next.is_none() {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_resume_from_checkpoint() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+
+        for breadth_first in [true, false] {
+            let cids = DagWalk::new([root], breadth_first)
+                .stream(store, &NoCache)
+                .and_then(|item| async move { item.to_cid() })
+                .try_collect::<Vec<_>>()
+                .await?;
+
+            let mut dag_walk = DagWalk::new([root], breadth_first);
+            let mut resumed_cids = Vec::new();
+            for _ in 0..cids.len() / 2 {
+                let item = dag_walk
+                    .next(store, &NoCache)
+                    .await?
+                    .expect("walk not done");
+                resumed_cids.push(item.to_cid()?);
+            }
+
+            let checkpoint = dag_walk.to_checkpoint().to_dag_cbor()?;
+            let resumed = DagWalk::from_checkpoint(DagWalkCheckpoint::from_dag_cbor(checkpoint)?);
+            resumed_cids.extend(
+                resumed
+                    .stream(store, &NoCache)
+                    .and_then(|item| async move { item.to_cid() })
+                    .try_collect::<Vec<_>>()
+                    .await?,
+            );
+
+            assert_eq!(resumed_cids, cids);
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_stream_with_blocks() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+
+        let cids = DagWalk::breadth_first([root])
+            .stream(store, &NoCache)
+            .and_then(|item| async move { item.to_cid() })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let blocks = DagWalk::breadth_first([root])
+            .stream_with_blocks(store, &NoCache)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        assert_eq!(blocks.iter().map(|(cid, _)| *cid).collect::<Vec<_>>(), cids);
+        for (cid, block) in blocks {
+            assert_eq!(block, store.get_block(&cid).await?);
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_stream_reporting_missing() -> TestResult {
+        let store = &MemoryBlockStore::new();
+
+        // cid_root ---> cid_present
+        //            -> cid_missing
+
+        let cid_present = store
+            .put_block(
+                encode(&Ipld::String("present".into()), DagCborCodec)?,
+                DagCborCodec.into(),
+            )
+            .await?;
+        let cid_missing = MemoryBlockStore::new()
+            .put_block(
+                encode(&Ipld::String("missing".into()), DagCborCodec)?,
+                DagCborCodec.into(),
+            )
+            .await?;
+
+        let cid_root = store
+            .put_block(
+                encode(
+                    &Ipld::List(vec![Ipld::Link(cid_present), Ipld::Link(cid_missing)]),
+                    DagCborCodec,
+                )?,
+                DagCborCodec.into(),
+            )
+            .await?;
+
+        let items = DagWalk::breadth_first([cid_root])
+            .stream_reporting_missing(store, &NoCache)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        assert_eq!(
+            items,
+            vec![(cid_root, true), (cid_present, true), (cid_missing, false)]
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_skip_walking_custom_codec() -> TestResult {
+        // A made-up codec whose blocks are a single binary CID
+        const CODEC_CID: u64 = 0x300001;
+        let extractor = CodecReferenceExtractor::new()
+            .with_codec(CODEC_CID, |_, block| Ok(vec![Cid::try_from(block)?]));
+        let cache = &WithReferenceExtractor::new(NoCache, extractor);
+
+        let store = &MemoryBlockStore::new();
+        let leaf = store
+            .put_block(b"Hello, skipped world!".to_vec(), CODEC_RAW)
+            .await?;
+        let block = Bytes::from(leaf.to_bytes());
+        let root = store.put_block(block.clone(), CODEC_CID).await?;
+
+        let mut dag_walk = DagWalk::breadth_first([root, leaf]);
+        assert!(dag_walk
+            .clone()
+            .skip_walking((root, block.clone()))
+            .is_err());
+
+        // Skipping the root also skips the leaf it links to
+        dag_walk.skip_walking_with_cache((root, block), cache)?;
+        assert!(dag_walk.next(store, cache).await?.is_none());
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
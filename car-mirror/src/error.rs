@@ -1,5 +1,6 @@
 use crate::incremental_verification::BlockState;
 use libipld::Cid;
+use std::time::Duration;
 use wnfs_common::BlockStoreError;
 
 /// Errors raised from the CAR mirror library
@@ -42,6 +43,107 @@ pub enum Error {
         cid: Cid,
     },
 
+    /// Raised when merging receiver states with bloom filters that don't have
+    /// the same size and hash count. See `ReceiverState::merge`.
+    #[error("Can't merge bloom filters with {hash_count} hashes & {bytes} bytes and {other_hash_count} hashes & {other_bytes} bytes")]
+    IncompatibleBlooms {
+        /// The hash count of the first bloom filter
+        hash_count: usize,
+        /// The size in bytes of the first bloom filter
+        bytes: usize,
+        /// The hash count of the other bloom filter
+        other_hash_count: usize,
+        /// The size in bytes of the other bloom filter
+        other_bytes: usize,
+    },
+
+    /// Raised when no new frame arrived within the configured idle timeout
+    /// while receiving a CAR stream. See `Config::stream_idle_timeout`.
+    #[error("Didn't receive any data for {timeout:?} while streaming, aborting request.")]
+    StreamIdleTimeout {
+        /// The configured idle timeout
+        timeout: Duration,
+    },
+
+    /// Raised when a CAR stream ended without the end-of-round block, even though
+    /// `Config::require_end_of_round` is set. This usually means the stream was truncated.
+    #[error("CAR stream ended without an end of round marker, it was likely truncated.")]
+    MissingEndOfRound,
+
+    /// Raised when a received message contains a bloom filter larger than
+    /// `Config::max_bloom_bytes`.
+    #[error("Bloom filter of {bloom_bytes} bytes exceeds the maximum of {max_bloom_bytes} bytes")]
+    BloomTooLarge {
+        /// The size of the received bloom filter in bytes
+        bloom_bytes: usize,
+        /// The configured maximum bloom filter size
+        max_bloom_bytes: usize,
+    },
+
+    /// Raised when a received message contains more root CIDs than
+    /// `Config::max_incoming_roots`.
+    #[error("Message with {num_roots} roots exceeds the maximum of {max_incoming_roots} roots")]
+    TooManyRoots {
+        /// The number of roots in the received message
+        num_roots: usize,
+        /// The configured maximum number of incoming roots
+        max_incoming_roots: usize,
+    },
+
+    /// Raised when a received message contains a bloom filter that can't be used,
+    /// e.g. because of a zero or absurdly large hash count. See `PullRequest::validate`.
+    #[error("Invalid bloom filter with {hash_count} hashes and {bloom_bytes} bytes")]
+    InvalidBloom {
+        /// The hash count of the received bloom filter
+        hash_count: u32,
+        /// The size of the received bloom filter in bytes
+        bloom_bytes: usize,
+    },
+
+    /// Raised when a push checkpoint wasn't issued for the given root by
+    /// the server's `CheckpointKey`, e.g. because it was forged or corrupted.
+    #[error("Invalid push checkpoint for root {root}")]
+    InvalidCheckpoint {
+        /// The root CID the checkpoint was used with
+        root: Cid,
+    },
+
+    /// Raised when the roots declared in a received CAR file's header are
+    /// neither wanted nor already available below the `root` of the transfer,
+    /// i.e. the CAR is unrelated to the DAG. Checked before any blocks are stored.
+    #[error("CAR file roots {car_roots:?} aren't related to the DAG below {root}")]
+    UnrelatedCarRoots {
+        /// The root CID of the transfer
+        root: Cid,
+        /// The roots declared in the CAR file header
+        car_roots: Vec<Cid>,
+    },
+
+    /// Raised when `Config::require_matching_car_roots` is set and the roots
+    /// declared in a CAR file header are neither the requested root nor any of
+    /// its subgraph roots that are still missing. Checked before any blocks are stored.
+    #[error("CAR file roots {car_roots:?} don't match the root {root} or any of its missing subgraph roots")]
+    MismatchedCarRoots {
+        /// The root CID of the transfer
+        root: Cid,
+        /// The roots declared in the CAR file header
+        car_roots: Vec<Cid>,
+    },
+
+    /// Raised when sending a protocol round with `Config::leaf_first` set.
+    /// Receivers verify blocks starting from the root, so they can't verify
+    /// leaves-first rounds. Only exports support leaves-first order.
+    #[error("Leaves-first block order is only supported for exports, not for protocol rounds")]
+    LeafFirstRound,
+
+    /// Raised when a DAG traversal's frontier would grow beyond the configured
+    /// maximum, usually because the DAG is very wide. See `Config::max_frontier_size`.
+    #[error("DAG traversal frontier exceeded the maximum of {max_frontier_size} CIDs")]
+    FrontierTooLarge {
+        /// The configured maximum frontier size
+        max_frontier_size: usize,
+    },
+
     /// An error rasied from the blockstore.
     #[error("BlockStore error: {0}")]
     BlockStoreError(#[from] BlockStoreError),
@@ -65,6 +167,40 @@ pub enum Error {
     CarFileError(#[from] iroh_car::Error),
 }
 
+impl Error {
+    /// Whether this error is likely caused by a single bad or interrupted
+    /// round, e.g. a corrupted block or a dropped connection, so that
+    /// starting a fresh round can still succeed.
+    ///
+    /// Blocks received before such an error are kept in the store, so
+    /// the next round continues from there.
+    ///
+    /// Other errors, like exceeded limits from the `Config` or failing
+    /// block stores, would just happen again.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Self::StreamIdleTimeout { .. }
+            | Self::MissingEndOfRound
+            | Self::IncrementalVerificationError(_)
+            | Self::CarFileError(_) => true,
+            Self::TooManyBytes { .. }
+            | Self::BlockSizeExceeded { .. }
+            | Self::UnsupportedCodec { .. }
+            | Self::UnsupportedHashCode { .. }
+            | Self::IncompatibleBlooms { .. }
+            | Self::BloomTooLarge { .. }
+            | Self::TooManyRoots { .. }
+            | Self::InvalidBloom { .. }
+            | Self::InvalidCheckpoint { .. }
+            | Self::UnrelatedCarRoots { .. }
+            | Self::MismatchedCarRoots { .. }
+            | Self::FrontierTooLarge { .. }
+            | Self::BlockStoreError(_)
+            | Self::ParsingError(_) => false,
+        }
+    }
+}
+
 /// Errors related to incremental verification
 #[derive(thiserror::Error, Debug)]
 pub enum IncrementalVerificationError {
@@ -89,3 +225,29 @@ pub enum IncrementalVerificationError {
         actual_cid: Box<Cid>,
     },
 }
+
+/// Errors raised when building an inconsistent `Config` via `Config::builder`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Raised when `max_block_size` is larger than what `iroh-car` can frame.
+    #[error("Maximum block size of {max_block_size} bytes exceeds the CAR frame limit of {max_frame_size} bytes")]
+    BlockSizeOverFrameLimit {
+        /// The configured maximum block size
+        max_block_size: usize,
+        /// The maximum CAR frame size supported by `iroh-car`
+        max_frame_size: usize,
+    },
+
+    /// Raised when `receive_maximum` can't even fit a single block of `max_block_size`.
+    #[error("Receive maximum of {receive_maximum} bytes is below the maximum block size of {max_block_size} bytes")]
+    ReceiveMaximumBelowBlockSize {
+        /// The configured receive maximum
+        receive_maximum: usize,
+        /// The configured maximum block size
+        max_block_size: usize,
+    },
+
+    /// Raised when `max_roots_per_round` is zero, which would prevent any progress.
+    #[error("Maximum roots per round must be at least 1")]
+    ZeroMaxRootsPerRound,
+}
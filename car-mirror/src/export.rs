@@ -0,0 +1,136 @@
+use crate::{
+    cache::Cache,
+    common::{block_send_block_stream_with, Config},
+    error::Error,
+};
+use futures::TryStreamExt;
+use iroh_car::{CarHeader, CarWriter};
+use libipld::Cid;
+use std::collections::HashSet;
+use wnfs_common::BlockStore;
+
+/// Writes the complete DAG below `root` from `store` into a CARv1 file,
+/// with `root` as the only root in its header.
+///
+/// Blocks are written parents-first, in the same order `block_send` sends
+/// them, but without any byte limit. Fails if any block below `root` is
+/// missing from `store`.
+///
+/// Returns the writer after the last block was written.
+pub async fn to_car_writer<W: tokio::io::AsyncWrite + Unpin + Send>(
+    root: Cid,
+    writer: W,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<W, Error> {
+    to_car_writer_with(root, writer, &Config::default(), store, cache).await
+}
+
+/// Like `to_car_writer`, but walks the DAG with given `config`.
+///
+/// With `Config::leaf_first`, blocks are written leaves-first instead, i.e.
+/// each block only after all blocks it links to. The header still names `root`,
+/// which is then the last block in the file.
+pub async fn to_car_writer_with<W: tokio::io::AsyncWrite + Unpin + Send>(
+    root: Cid,
+    writer: W,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<W, Error> {
+    let mut block_stream =
+        block_send_block_stream_with(root, None, |_| 0, HashSet::new(), config, store, cache)
+            .await?;
+
+    let mut writer = CarWriter::new(CarHeader::new_v1(vec![root]), writer);
+    while let Some((cid, block)) = block_stream.try_next().await? {
+        writer.write(cid, block).await?;
+    }
+
+    Ok(writer.finish().await?)
+}
+
+/// Like `to_car_writer`, but creates or truncates the file at `path`.
+///
+/// This needs to run within a tokio runtime.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn to_car_file(
+    root: Cid,
+    path: impl AsRef<std::path::Path>,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<(), Error> {
+    use tokio::io::AsyncWriteExt;
+
+    let file = tokio::fs::File::create(path)
+        .await
+        .map_err(iroh_car::Error::from)?;
+    let mut file = to_car_writer(root, tokio::io::BufWriter::new(file), store, cache).await?;
+    file.flush().await.map_err(iroh_car::Error::from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::{block_receive_car_stream, compute_missing, references, Config},
+        test_utils::{setup_random_dag, total_dag_blocks},
+    };
+    use iroh_car::CarReader;
+    use std::io::Cursor;
+    use testresult::TestResult;
+    use wnfs_common::MemoryBlockStore;
+
+    #[test_log::test(tokio::test)]
+    async fn test_to_car_file() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let path = std::env::temp_dir().join(format!("car-mirror-export-{root}.car"));
+
+        to_car_file(root, &path, store, NoCache).await?;
+        let bytes = tokio::fs::read(&path).await?;
+        tokio::fs::remove_file(&path).await?;
+
+        let reader = CarReader::new(Cursor::new(bytes.clone())).await?;
+        assert_eq!(reader.header().roots(), &[root]);
+        let blocks = reader.stream().try_collect::<Vec<_>>().await?;
+        assert_eq!(blocks.len(), total_dag_blocks(root, store).await?);
+
+        // The export can be imported again
+        let imported = &MemoryBlockStore::new();
+        let config = &Config::default();
+        block_receive_car_stream(root, Cursor::new(bytes), config, imported, NoCache).await?;
+        assert!(compute_missing(root, imported, NoCache).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_to_car_writer_leaf_first() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            leaf_first: true,
+            ..Config::default()
+        };
+
+        let bytes = to_car_writer_with(root, Vec::new(), config, store, NoCache).await?;
+        let reader = CarReader::new(Cursor::new(bytes)).await?;
+        assert_eq!(reader.header().roots(), &[root]);
+        let blocks = reader.stream().try_collect::<Vec<_>>().await?;
+
+        // Every block comes after all blocks it links to
+        let mut seen = HashSet::new();
+        for (cid, block) in &blocks {
+            for link in references(*cid, block, Vec::new())? {
+                assert!(seen.contains(&link), "{cid} written before its link {link}");
+            }
+            seen.insert(*cid);
+        }
+
+        assert_eq!(blocks.len(), total_dag_blocks(root, store).await?);
+        assert_eq!(blocks.last().map(|(cid, _)| *cid), Some(root));
+
+        Ok(())
+    }
+}
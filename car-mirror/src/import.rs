@@ -0,0 +1,198 @@
+use crate::{
+    cache::Cache,
+    common::Config,
+    error::Error,
+    incremental_verification::{BlockState, IncrementalDagVerification},
+};
+use bytes::Bytes;
+use iroh_car::CarReader;
+use libipld::Cid;
+use wnfs_common::{utils::CondSend, BlockStore};
+
+/// Reads a CAR file, verifies that its blocks are part of the DAG below
+/// `root` and stores them in `store`, e.g. to bootstrap a store from a snapshot.
+///
+/// Blocks need to appear after a block that links to them, like in CAR files
+/// from `export::to_car_writer`. Unlike `block_receive_car_stream`, this reads
+/// the whole file: Blocks that are in `store` already are skipped, instead of
+/// ending the import.
+///
+/// Returns the CIDs of all stored blocks, in the order they appear in the file.
+///
+/// Fails at the first block that doesn't hash to its CID with
+/// `IncrementalVerificationError::DigestMismatch`, or that isn't linked to from
+/// `root` via earlier blocks with `IncrementalVerificationError::ExpectedWantedBlock`.
+/// Blocks before it stay stored. The `config`'s limits on block sizes, codecs
+/// and the DAG traversal frontier apply, too.
+pub async fn from_car_reader<R: tokio::io::AsyncRead + Unpin + CondSend>(
+    root: Cid,
+    reader: R,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<Vec<Cid>, Error> {
+    let mut reader = CarReader::new(reader).await?;
+    let mut dag_verification =
+        IncrementalDagVerification::from_config([root], config, &store, &cache).await?;
+    let mut stored = Vec::new();
+
+    while let Some((cid, block)) = reader.next_block().await? {
+        if let Some(allowed_codecs) = &config.allowed_codecs {
+            if !allowed_codecs.contains(&cid.codec()) {
+                return Err(Error::UnsupportedCodec { cid });
+            }
+        }
+
+        if block.len() > config.max_block_size {
+            return Err(Error::BlockSizeExceeded {
+                cid,
+                block_bytes: block.len(),
+                max_block_size: config.max_block_size,
+            });
+        }
+
+        if dag_verification.block_state(cid) == BlockState::Have {
+            tracing::trace!(%cid, "Skipping block that's stored already");
+            continue;
+        }
+
+        dag_verification
+            .verify_and_store_block((cid, Bytes::from(block)), &store, &cache)
+            .await?;
+        stored.push(cid);
+    }
+
+    Ok(stored)
+}
+
+/// Like `from_car_reader`, but reads the CAR file at `path`.
+///
+/// This needs to run within a tokio runtime.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn from_car_file(
+    root: Cid,
+    path: impl AsRef<std::path::Path>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<Vec<Cid>, Error> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(iroh_car::Error::from)?;
+    from_car_reader(root, tokio::io::BufReader::new(file), config, store, cache).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::compute_missing,
+        error::IncrementalVerificationError,
+        export,
+        test_utils::{
+            corrupt_car_stream, get_cid_at_approx_path, setup_random_dag, total_dag_blocks,
+        },
+    };
+    use assert_matches::assert_matches;
+    use futures::TryStreamExt;
+    use std::io::Cursor;
+    use testresult::TestResult;
+    use tokio_util::io::StreamReader;
+    use wnfs_common::MemoryBlockStore;
+
+    #[test_log::test(tokio::test)]
+    async fn test_from_car_file() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let path = std::env::temp_dir().join(format!("car-mirror-import-{root}.car"));
+        export::to_car_file(root, &path, store, NoCache).await?;
+
+        let imported = &MemoryBlockStore::new();
+        let result = from_car_file(root, &path, &Config::default(), imported, NoCache).await;
+        tokio::fs::remove_file(&path).await?;
+
+        assert_eq!(result?.len(), total_dag_blocks(root, store).await?);
+        assert!(compute_missing(root, imported, NoCache).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_from_car_reader_skips_stored_blocks() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let car = export::to_car_writer(root, Vec::new(), store, NoCache).await?;
+
+        // The store already has a subgraph of the DAG
+        let imported = &MemoryBlockStore::new();
+        let subgraph_root = get_cid_at_approx_path(vec![0], root, store).await?;
+        let subgraph = export::to_car_writer(subgraph_root, Vec::new(), store, NoCache).await?;
+        let subgraph_cids = from_car_reader(
+            subgraph_root,
+            Cursor::new(subgraph),
+            &Config::default(),
+            imported,
+            NoCache,
+        )
+        .await?;
+
+        let cids = from_car_reader(
+            root,
+            Cursor::new(car),
+            &Config::default(),
+            imported,
+            NoCache,
+        )
+        .await?;
+
+        assert_eq!(
+            cids.len() + subgraph_cids.len(),
+            total_dag_blocks(root, store).await?
+        );
+        assert!(compute_missing(root, imported, NoCache).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_from_car_reader_rejects_bad_blocks() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+
+        let corrupt_cid = get_cid_at_approx_path(vec![0, 0], root, store).await?;
+        let car_stream = corrupt_car_stream(root, corrupt_cid, store).await?;
+        let reader = StreamReader::new(car_stream.map_err(std::io::Error::other));
+        let result = from_car_reader(
+            root,
+            reader,
+            &Config::default(),
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::DigestMismatch { cid, .. }
+            )) if *cid == corrupt_cid
+        );
+
+        // Blocks of another DAG aren't connected to the root
+        let (other_root, ref other_store) = setup_random_dag(16, 1024).await?;
+        let other_car = export::to_car_writer(other_root, Vec::new(), other_store, NoCache).await?;
+        let result = from_car_reader(
+            root,
+            Cursor::new(other_car),
+            &Config::default(),
+            MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::ExpectedWantedBlock { cid, .. }
+            )) if *cid == other_root
+        );
+
+        Ok(())
+    }
+}
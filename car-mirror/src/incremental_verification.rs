@@ -1,17 +1,29 @@
 use crate::{
     cache::Cache,
-    common::ReceiverState,
+    common::{compute_cid, Config, ReceiverState, VerifyOnlyStore},
     dag_walk::{DagWalk, TraversedItem},
     error::{Error, IncrementalVerificationError},
 };
 use bytes::Bytes;
 use deterministic_bloom::runtime_size::BloomFilter;
+use iroh_car::CarReader;
 use libipld_core::{
-    cid::Cid,
-    multihash::{Code, MultihashDigest},
+    cid::{Cid, Version},
+    multihash::Code,
 };
-use std::{collections::HashSet, matches};
-use wnfs_common::BlockStore;
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    matches,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use wnfs_common::{
+    utils::{BoxStream, CondSend, CondSync},
+    BlockStore,
+};
+
+/// The multicodec code of DAG-PB, the only codec that CIDv0s can have
+const DAG_PB: u64 = 0x70;
 
 /// A data structure that keeps state about incremental DAG verification.
 #[derive(Clone, Debug)]
@@ -20,6 +32,13 @@ pub struct IncrementalDagVerification {
     pub want_cids: HashSet<Cid>,
     /// All the CIDs that are available locally.
     pub have_cids: HashSet<Cid>,
+    /// An optional limit on the frontier of the DAG traversals used for
+    /// verification. See `DagWalk::with_max_frontier_size`.
+    pub max_frontier_size: Option<usize>,
+    /// Whether CIDv0s and CIDv1s of the same DAG-PB block are treated as
+    /// equivalent. If set, a CID in `want_cids` or `have_cids` also stands
+    /// for its other CID version. See `Config::normalize_cid_versions`.
+    pub normalize_cid_versions: bool,
 }
 
 /// The state of a block retrieval
@@ -33,6 +52,148 @@ pub enum BlockState {
     Unexpected,
 }
 
+/// Hooks that get called for blocks that fail verification while receiving
+/// blocks, e.g. for auditing or banning peers that send corrupt data, or
+/// for diagnosing why transfers need many rounds. Senders get notified about
+/// requests for blocks outside the DAG.
+///
+/// Set it via `Config::verification_observer`. Since a config is passed per
+/// transfer, observers can carry information about the peer they're observing.
+///
+/// All hooks do nothing by default.
+pub trait VerificationObserver: CondSync + Debug {
+    /// Called when a block's bytes don't hash to its CID.
+    fn on_digest_mismatch(&self, root: Cid, cid: Cid, actual_cid: Cid) {
+        let _ = (root, cid, actual_cid);
+    }
+
+    /// Called when a block was received that can't be shown to be linked
+    /// to from the root (yet).
+    ///
+    /// This can happen with honest peers, too: Bloom filter false positives
+    /// make peers skip blocks that would've linked to this block.
+    fn on_unexpected_block(&self, root: Cid, cid: Cid) {
+        let _ = (root, cid);
+    }
+
+    /// Called when a block exceeds `Config::max_block_size`.
+    fn on_oversized_block(&self, root: Cid, cid: Cid, block_bytes: usize, max_block_size: usize) {
+        let _ = (root, cid, block_bytes, max_block_size);
+    }
+
+    /// Called when the receiver stops reading the current round early at
+    /// given block, because it's either a block it already has (`BlockState::Have`),
+    /// or one it can't show to be linked to from the root yet (`BlockState::Unexpected`).
+    ///
+    /// Rounds that get interrupted often mean the sender has outdated information
+    /// about the receiver, e.g. because of bloom filter false positives.
+    fn on_round_interrupted(&self, root: Cid, cid: Cid, reason: BlockState) {
+        let _ = (root, cid, reason);
+    }
+
+    /// Called on the sending end, when the receiver asks for subgraph roots
+    /// that aren't part of the DAG below the root. These are ignored.
+    ///
+    /// Honest peers don't do this, so repeated calls for the same peer
+    /// can indicate that it's probing for blocks.
+    ///
+    /// Without an observer, senders log a warning for every such request.
+    /// With an observer, logging is up to the observer, e.g. sampled via
+    /// `UnrelatedRootsLogSampler`, so probing peers can't flood the logs.
+    fn on_unrelated_roots(&self, root: Cid, unrelated_roots: &[Cid]) {
+        let _ = (root, unrelated_roots);
+    }
+
+    /// Called on the receiving end with a snapshot of its receiver state
+    /// every `Config::receiver_state_every` stored blocks of a round.
+    ///
+    /// With a duplex transport, the snapshot can be sent to the sending end
+    /// as a refined request without waiting for the round to end. Otherwise it
+    /// can be used for reporting progress or persisting where to resume from.
+    fn on_receiver_state(&self, root: Cid, state: &ReceiverState) {
+        let _ = (root, state);
+    }
+}
+
+/// A `VerificationObserver` that logs a warning for every n-th request for
+/// subgraph roots that aren't part of the requested DAG, see
+/// `VerificationObserver::on_unrelated_roots`.
+///
+/// Requests are counted across all transfers that share the observer, e.g.
+/// all requests of a server that has it set in its config.
+#[derive(Debug, Default)]
+pub struct UnrelatedRootsLogSampler {
+    every: usize,
+    requests: AtomicUsize,
+}
+
+impl UnrelatedRootsLogSampler {
+    /// Log a warning for every `every`-th request. With `0`, nothing is logged.
+    pub fn new(every: usize) -> Self {
+        Self {
+            every,
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many requests for unrelated subgraph roots were seen so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+impl VerificationObserver for UnrelatedRootsLogSampler {
+    fn on_unrelated_roots(&self, root: Cid, unrelated_roots: &[Cid]) {
+        let request = self.requests.fetch_add(1, Ordering::Relaxed);
+        if self.every > 0 && request % self.every == 0 {
+            log_unrelated_roots(root, unrelated_roots);
+        }
+    }
+}
+
+/// Logs a warning about a request for subgraph roots outside the DAG below `root`.
+pub(crate) fn log_unrelated_roots(root: Cid, unrelated_roots: &[Cid]) {
+    let unrelated_roots = unrelated_roots
+        .iter()
+        .map(|cid| cid.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    tracing::warn!(
+        %root,
+        unrelated_roots = %unrelated_roots,
+        "got asked for DAG-unrelated blocks"
+    );
+}
+
+/// Runs the CPU-bound digest computations for received blocks off the task
+/// that receives them, e.g. on a thread pool, so that hashing large blocks
+/// overlaps with receiving the next ones.
+///
+/// Set it via `Config::digest_spawner`. With tokio, spawning the jobs via
+/// `tokio::task::spawn_blocking` works well.
+pub trait DigestSpawner: CondSync + Debug {
+    /// Runs given job eventually, e.g. on another thread.
+    ///
+    /// Jobs that are dropped without running make receivers hash the block
+    /// themselves.
+    fn spawn(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+/// Validates CAR files at rest, e.g. for offline auditing tools.
+///
+/// This runs the same incremental verification that's used when receiving
+/// blocks during the protocol, but doesn't store any blocks. Each block needs
+/// to hash to its CID and needs to be linked to from the roots in the CAR
+/// header via blocks that appear earlier in the file.
+///
+/// Only the links of verified blocks are kept in memory.
+pub struct CarValidator<R> {
+    reader: CarReader<R>,
+    roots: Vec<Cid>,
+    lenient: bool,
+}
+
 impl IncrementalDagVerification {
     /// Initiate incremental DAG verification of given roots.
     ///
@@ -42,10 +203,43 @@ impl IncrementalDagVerification {
         roots: impl IntoIterator<Item = Cid>,
         store: &impl BlockStore,
         cache: &impl Cache,
+    ) -> Result<Self, Error> {
+        Self::new_with_max_frontier_size(roots, None, store, cache).await
+    }
+
+    /// Like `new`, but limits the frontier of the DAG traversals used for
+    /// verification, failing with `Error::FrontierTooLarge` if exceeded.
+    pub async fn new_with_max_frontier_size(
+        roots: impl IntoIterator<Item = Cid>,
+        max_frontier_size: Option<usize>,
+        store: &impl BlockStore,
+        cache: &impl Cache,
     ) -> Result<Self, Error> {
         let mut this = Self {
             want_cids: roots.into_iter().collect(),
             have_cids: HashSet::new(),
+            max_frontier_size,
+            normalize_cid_versions: false,
+        };
+
+        this.update_have_cids(store, cache).await?;
+
+        Ok(this)
+    }
+
+    /// Like `new`, but configured via the verification-related settings
+    /// in `config`, i.e. `Config::max_frontier_size` and `Config::normalize_cid_versions`.
+    pub async fn from_config(
+        roots: impl IntoIterator<Item = Cid>,
+        config: &Config,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+    ) -> Result<Self, Error> {
+        let mut this = Self {
+            want_cids: roots.into_iter().collect(),
+            have_cids: HashSet::new(),
+            max_frontier_size: config.max_frontier_size,
+            normalize_cid_versions: config.normalize_cid_versions,
         };
 
         this.update_have_cids(store, cache).await?;
@@ -62,7 +256,8 @@ impl IncrementalDagVerification {
         store: &impl BlockStore,
         cache: &impl Cache,
     ) -> Result<(), Error> {
-        let mut dag_walk = DagWalk::breadth_first(self.want_cids.iter().cloned());
+        let mut dag_walk = DagWalk::breadth_first(self.want_cids.iter().cloned())
+            .with_max_frontier_size(self.max_frontier_size);
 
         while let Some(item) = dag_walk.next(store, cache).await? {
             match item {
@@ -70,6 +265,18 @@ impl IncrementalDagVerification {
                     self.mark_as_have(cid);
                 }
                 TraversedItem::Missing(cid) => {
+                    if self.normalize_cid_versions {
+                        if let Some(other_cid) = other_cid_version(cid) {
+                            if store.has_block(&other_cid).await? {
+                                tracing::trace!(%cid, %other_cid, "Found block under other CID version");
+                                // Keep the linked CID, so it ends up in the have bloom
+                                self.mark_as_have(cid);
+                                dag_walk.frontier.push_back(other_cid);
+                                continue;
+                            }
+                        }
+                    }
+
                     tracing::trace!(%cid, "Missing block, adding to want list");
                     self.mark_as_want(cid);
                 }
@@ -86,26 +293,45 @@ impl IncrementalDagVerification {
     }
 
     fn mark_as_want(&mut self, want: Cid) {
-        if self.have_cids.contains(&want) {
-            tracing::warn!(%want, "Marking a CID as wanted, that we have previously marked as having!");
-            self.have_cids.remove(&want);
+        for cid in self.equivalent_cids(want) {
+            if self.have_cids.remove(&cid) {
+                tracing::warn!(%want, "Marking a CID as wanted, that we have previously marked as having!");
+            }
         }
         self.want_cids.insert(want);
     }
 
     fn mark_as_have(&mut self, have: Cid) {
-        self.want_cids.remove(&have);
+        for cid in self.equivalent_cids(have) {
+            self.want_cids.remove(&cid);
+        }
         self.have_cids.insert(have);
     }
 
+    /// The given CID, and its other CID version if `normalize_cid_versions` is set.
+    ///
+    /// `want_cids` and `have_cids` keep CIDs in the version they're linked with,
+    /// since senders look up missing subgraph roots as-is. Only lookups treat
+    /// both versions as equivalent.
+    fn equivalent_cids(&self, cid: Cid) -> impl Iterator<Item = Cid> {
+        let other_cid = other_cid_version(cid).filter(|_| self.normalize_cid_versions);
+        std::iter::once(cid).chain(other_cid)
+    }
+
     /// Check the state of a CID to find out whether
     /// - we expect it as one of the next possible blocks to receive (Want)
     /// - we have already stored it (Have)
     /// - we don't know whether we need it (Unexpected)
     pub fn block_state(&self, cid: Cid) -> BlockState {
-        if self.want_cids.contains(&cid) {
+        if self
+            .equivalent_cids(cid)
+            .any(|cid| self.want_cids.contains(&cid))
+        {
             BlockState::Want
-        } else if self.have_cids.contains(&cid) {
+        } else if self
+            .equivalent_cids(cid)
+            .any(|cid| self.have_cids.contains(&cid))
+        {
             BlockState::Have
         } else {
             BlockState::Unexpected
@@ -122,13 +348,41 @@ impl IncrementalDagVerification {
     /// This *may* fail, even if the block is part of the graph below the roots,
     /// if intermediate blocks between the roots and this block are missing.
     ///
-    /// This *may* add the block to the blockstore, but still fail to verify, specifically
-    /// if the block's bytes don't match the hash in the CID.
+    /// Blocks are only handed to `BlockStore::put_block_keyed` once they were received
+    /// completely and their bytes hash to their CID, in a single call with all of the
+    /// block's bytes. A transfer that gets interrupted mid-block never stores a partial
+    /// block. Stores need to uphold the same guarantee on their end, see the crate docs
+    /// on block stores.
+    ///
+    /// Verification always hashes the canonical block bytes as they appear in the CAR
+    /// file. Stores are free to keep blocks in another representation, e.g. compressed
+    /// via `CompressingBlockStore`, as long as `BlockStore::get_block` returns the
+    /// canonical bytes again.
+    ///
+    /// Blocks are hashed as a whole, not incrementally while their CAR frame streams in.
+    /// Incremental hashing wouldn't reduce peak memory, since `BlockStore::put_block_keyed`
+    /// needs the complete block anyway, and a digest mismatch can only be detected after
+    /// the last byte was hashed. When receiving, peak memory per block is bounded by
+    /// `iroh-car`'s 4MB frame limit, and blocks above `Config::max_block_size` are
+    /// rejected before they're hashed.
     pub async fn verify_and_store_block(
         &mut self,
         block: (Cid, Bytes),
         store: &impl BlockStore,
         cache: &impl Cache,
+    ) -> Result<(), Error> {
+        self.verify_and_store_block_with_digest(block, None, store, cache)
+            .await
+    }
+
+    /// Like `verify_and_store_block`, but with the `actual_cid` that the
+    /// block's bytes hash to, if it was computed already, see `Config::digest_spawner`.
+    pub(crate) async fn verify_and_store_block_with_digest(
+        &mut self,
+        block: (Cid, Bytes),
+        actual_cid: Option<Cid>,
+        store: &impl BlockStore,
+        cache: &impl Cache,
     ) -> Result<(), Error> {
         let (cid, bytes) = block;
 
@@ -141,16 +395,21 @@ impl IncrementalDagVerification {
             .into());
         }
 
-        let hash_func: Code = cid
-            .hash()
-            .code()
-            .try_into()
-            .map_err(|_| Error::UnsupportedHashCode { cid })?;
+        let actual_cid = match actual_cid {
+            Some(actual_cid) => actual_cid,
+            None => {
+                let hash_func: Code = cid
+                    .hash()
+                    .code()
+                    .try_into()
+                    .map_err(|_| Error::UnsupportedHashCode { cid })?;
 
-        let hash = hash_func.digest(bytes.as_ref());
+                compute_cid(cid.codec(), hash_func, bytes.as_ref())
+            }
+        };
 
-        if &hash != cid.hash() {
-            let actual_cid = Cid::new_v1(cid.codec(), hash);
+        // Only compare hashes, the CID version may differ
+        if actual_cid.hash() != cid.hash() {
             return Err(IncrementalVerificationError::DigestMismatch {
                 cid: Box::new(cid),
                 actual_cid: Box::new(actual_cid),
@@ -168,33 +427,35 @@ impl IncrementalDagVerification {
         Ok(())
     }
 
-    /// Computes the receiver state for the current incremental dag verification state.
-    /// This takes the have CIDs and turns them into
-    pub fn into_receiver_state(self, bloom_fpr: fn(u64) -> f64) -> ReceiverState {
-        let missing_subgraph_roots = self.want_cids.into_iter().collect();
+    /// Iterates over the missing subgraph roots, i.e. the CIDs that
+    /// `into_receiver_state` would put into `ReceiverState::missing_subgraph_roots`,
+    /// without collecting them into a `Vec`.
+    ///
+    /// Unlike with `into_receiver_state`, these aren't sorted.
+    /// Together with `have_cids_bloom`, this allows serializing wire messages
+    /// for huge want-sets without an intermediate allocation, see
+    /// `PushResponse::dag_cbor_from_iter`.
+    pub fn missing_subgraph_roots(&self) -> impl ExactSizeIterator<Item = &Cid> + Clone + '_ {
+        self.want_cids.iter()
+    }
 
+    /// Computes the bloom of have CIDs that `into_receiver_state` would
+    /// put into `ReceiverState::have_cids_bloom`.
+    ///
+    /// This is `None` if there are no have CIDs, or no missing subgraph roots,
+    /// since then the bloom isn't needed.
+    pub fn have_cids_bloom(&self, bloom_fpr: fn(u64) -> f64) -> Option<BloomFilter> {
         let bloom_capacity = self.have_cids.len() as u64;
 
-        if bloom_capacity == 0 {
-            return ReceiverState {
-                missing_subgraph_roots,
-                have_cids_bloom: None,
-            };
-        }
-
-        if missing_subgraph_roots.is_empty() {
-            // We're done. No need to compute a bloom.
-            return ReceiverState {
-                missing_subgraph_roots,
-                have_cids_bloom: None,
-            };
+        if bloom_capacity == 0 || self.want_cids.is_empty() {
+            return None;
         }
 
         let target_fpr = bloom_fpr(bloom_capacity);
         let mut bloom = BloomFilter::new_from_fpr_po2(bloom_capacity, target_fpr);
 
         self.have_cids
-            .into_iter()
+            .iter()
             .for_each(|cid| bloom.insert(&cid.to_bytes()));
 
         tracing::debug!(
@@ -207,9 +468,367 @@ impl IncrementalDagVerification {
             "built 'have cids' bloom",
         );
 
+        Some(bloom)
+    }
+
+    /// Computes the receiver state for the current incremental dag verification state.
+    /// This takes the have CIDs and turns them into
+    ///
+    /// The missing subgraph roots are sorted, so that the same state always
+    /// results in the same wire messages.
+    ///
+    /// See `ReceiverState::optimal_bloom_params` for how the bloom is sized.
+    pub fn into_receiver_state(self, bloom_fpr: fn(u64) -> f64) -> ReceiverState {
+        self.receiver_state(bloom_fpr)
+    }
+
+    /// Like `into_receiver_state`, but keeps the verification state around,
+    /// e.g. for snapshots of the receiver state in the middle of a round.
+    pub fn receiver_state(&self, bloom_fpr: fn(u64) -> f64) -> ReceiverState {
+        let have_cids_bloom = self.have_cids_bloom(bloom_fpr);
+
+        let mut missing_subgraph_roots: Vec<Cid> = self.want_cids.iter().copied().collect();
+        missing_subgraph_roots.sort_unstable();
+
         ReceiverState {
             missing_subgraph_roots,
-            have_cids_bloom: Some(bloom),
+            have_cids_bloom,
+            max_blocks: None,
         }
     }
 }
+
+impl<R: tokio::io::AsyncRead + Unpin + CondSend> CarValidator<R> {
+    /// Reads the CAR header from given reader in preparation of
+    /// validating the blocks that follow.
+    pub async fn new(reader: R) -> Result<Self, Error> {
+        let reader = CarReader::new(reader).await?;
+        let roots = reader.header().roots().to_vec();
+        Ok(Self {
+            reader,
+            roots,
+            lenient: false,
+        })
+    }
+
+    /// Keeps validating blocks after one failed to validate, instead of
+    /// ending the stream at the first error.
+    ///
+    /// Errors from reading the CAR file itself still end the stream,
+    /// since there's no way to find the next block after them.
+    pub fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// The roots from the CAR header that blocks are validated against.
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Returns a stream with one result per block in the CAR file.
+    ///
+    /// Yields the block's CID if it was valid, otherwise an error such as
+    /// `IncrementalVerificationError::DigestMismatch` for corrupted blocks or
+    /// `IncrementalVerificationError::ExpectedWantedBlock` for blocks that
+    /// aren't linked to from the roots or appear more than once.
+    pub fn stream<'a>(self, cache: impl Cache + 'a) -> BoxStream<'a, Result<Cid, Error>>
+    where
+        R: 'a,
+    {
+        let Self {
+            mut reader,
+            roots,
+            lenient,
+        } = self;
+
+        Box::pin(async_stream::stream! {
+            let store = VerifyOnlyStore::new(cache);
+
+            let mut dag_verification =
+                match IncrementalDagVerification::new(roots, &store, &store).await {
+                    Ok(dag_verification) => dag_verification,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+
+            loop {
+                let (cid, bytes) = match reader.next_block().await {
+                    Ok(Some(block)) => block,
+                    Ok(None) => break,
+                    Err(e) => {
+                        yield Err(Error::CarFileError(e));
+                        break;
+                    }
+                };
+
+                match dag_verification
+                    .verify_and_store_block((cid, Bytes::from(bytes)), &store, &store)
+                    .await
+                {
+                    Ok(()) => yield Ok(cid),
+                    Err(e) => {
+                        tracing::debug!(%cid, %e, "Block failed validation");
+                        yield Err(e);
+                        if !lenient {
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Returns the CID of the same block with the other CID version,
+/// if it can be expressed as both a CIDv0 and a CIDv1.
+fn other_cid_version(cid: Cid) -> Option<Cid> {
+    match cid.version() {
+        Version::V0 => Some(Cid::new_v1(DAG_PB, *cid.hash())),
+        Version::V1 if cid.codec() == DAG_PB => Cid::new_v0(*cid.hash()).ok(),
+        Version::V1 => None,
+    }
+}
+
+impl<R> Debug for CarValidator<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CarValidator")
+            .field("roots", &self.roots)
+            .field("lenient", &self.lenient)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::{block_send, Config},
+        pull, push,
+        test_utils::{corrupt_block, setup_random_dag, total_dag_blocks},
+    };
+    use assert_matches::assert_matches;
+    use futures::{StreamExt, TryStreamExt};
+    use iroh_car::{CarHeader, CarWriter};
+    use libipld::{Ipld, IpldCodec};
+    use libipld_core::multihash::MultihashDigest;
+    use std::{collections::BTreeMap, io::Cursor};
+    use testresult::TestResult;
+    use wnfs_common::{encode, MemoryBlockStore, CODEC_RAW};
+
+    async fn car_with_corrupted_block() -> anyhow::Result<(Vec<u8>, Cid, Cid)> {
+        let cid_a = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(b"a"));
+        let cid_b = Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(b"b"));
+
+        let (_, corrupted) = corrupt_block(cid_a, Bytes::from_static(b"a"));
+
+        let mut writer = CarWriter::new(CarHeader::new_v1(vec![cid_a, cid_b]), Vec::new());
+        writer.write(cid_a, corrupted).await?;
+        writer.write(cid_b, b"b").await?;
+
+        Ok((writer.finish().await?, cid_a, cid_b))
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_car_validator_valid_car() -> TestResult {
+        let (root, ref store) = setup_random_dag(64, 1024).await?;
+        let car = block_send(root, None, &Config::default(), store, NoCache).await?;
+
+        let validator = CarValidator::new(Cursor::new(car.bytes)).await?;
+        assert_eq!(validator.roots(), &[root]);
+
+        let cids: Vec<Cid> = validator.stream(NoCache).try_collect().await?;
+
+        assert_eq!(cids.len(), total_dag_blocks(root, store).await?);
+        assert_eq!(cids.first(), Some(&root));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_car_validator_stops_at_first_error() -> TestResult {
+        let (car, _, _) = car_with_corrupted_block().await?;
+
+        let results: Vec<_> = CarValidator::new(Cursor::new(car))
+            .await?
+            .stream(NoCache)
+            .collect()
+            .await;
+
+        assert_eq!(results.len(), 1);
+        assert_matches!(
+            results[0],
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::DigestMismatch { .. }
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_car_validator_lenient() -> TestResult {
+        let (car, _, cid_b) = car_with_corrupted_block().await?;
+
+        let results: Vec<_> = CarValidator::new(Cursor::new(car))
+            .await?
+            .lenient()
+            .stream(NoCache)
+            .collect()
+            .await;
+
+        assert_eq!(results.len(), 2);
+        assert_matches!(
+            results[0],
+            Err(Error::IncrementalVerificationError(
+                IncrementalVerificationError::DigestMismatch { .. }
+            ))
+        );
+        assert_matches!(results[1], Ok(cid) if cid == cid_b);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_into_receiver_state_sorts_missing_subgraph_roots() -> TestResult {
+        let cids = (0..100u8)
+            .map(|i| Cid::new_v1(CODEC_RAW, Code::Sha2_256.digest(&[i])))
+            .collect::<Vec<_>>();
+
+        let verification = IncrementalDagVerification {
+            want_cids: cids.iter().copied().collect(),
+            have_cids: HashSet::new(),
+            max_frontier_size: None,
+            normalize_cid_versions: false,
+        };
+
+        let mut sorted_cids = cids;
+        sorted_cids.sort();
+
+        let state = verification.into_receiver_state(Config::default().bloom_fpr);
+        assert_eq!(state.missing_subgraph_roots, sorted_cids);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_normalize_cid_versions() -> TestResult {
+        let store = &MemoryBlockStore::new();
+        let leaf = encode(
+            &Ipld::Map(BTreeMap::from([
+                ("Data".to_string(), Ipld::Bytes(b"leaf".to_vec())),
+                ("Links".to_string(), Ipld::List(Vec::new())),
+            ])),
+            IpldCodec::DagPb,
+        )?;
+        let leaf_v0 = Cid::new_v0(Code::Sha2_256.digest(&leaf))?;
+        let leaf_v1 = Cid::new_v1(DAG_PB, *leaf_v0.hash());
+        store.put_block_keyed(leaf_v0, leaf).await?;
+
+        // The DAG links to the leaf via its CIDv1, but it's stored under its CIDv0
+        let root = store
+            .put_block(
+                encode(&Ipld::List(vec![Ipld::Link(leaf_v1)]), IpldCodec::DagCbor)?,
+                IpldCodec::DagCbor.into(),
+            )
+            .await?;
+
+        let exact =
+            IncrementalDagVerification::from_config([root], &Config::default(), store, &NoCache)
+                .await?;
+        assert_eq!(exact.want_cids, HashSet::from([leaf_v1]));
+
+        let config = &Config {
+            normalize_cid_versions: true,
+            ..Config::default()
+        };
+        let normalized =
+            IncrementalDagVerification::from_config([root], config, store, &NoCache).await?;
+        assert!(normalized.want_cids.is_empty());
+        assert_eq!(normalized.block_state(leaf_v0), BlockState::Have);
+        assert_eq!(normalized.block_state(leaf_v1), BlockState::Have);
+
+        Ok(())
+    }
+
+    /// Stores a DAG-PB block with given data and links under its CIDv0.
+    async fn put_dag_pb_v0(store: &impl BlockStore, data: &[u8], links: &[Cid]) -> TestResult<Cid> {
+        let links = links
+            .iter()
+            .map(|cid| {
+                Ipld::Map(BTreeMap::from([
+                    ("Hash".to_string(), Ipld::Link(*cid)),
+                    ("Name".to_string(), Ipld::String(String::new())),
+                    ("Tsize".to_string(), Ipld::Integer(0)),
+                ]))
+            })
+            .collect();
+        let block = encode(
+            &Ipld::Map(BTreeMap::from([
+                ("Data".to_string(), Ipld::Bytes(data.to_vec())),
+                ("Links".to_string(), Ipld::List(links)),
+            ])),
+            IpldCodec::DagPb,
+        )?;
+        let cid = Cid::new_v0(Code::Sha2_256.digest(&block))?;
+        store.put_block_keyed(cid, block).await?;
+        Ok(cid)
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_normalize_cid_versions_round_trip() -> TestResult {
+        // A DAG that links via CIDv0s, like older UnixFS DAGs
+        let sender_store = &MemoryBlockStore::new();
+        let mut leaves = Vec::new();
+        for i in 0..4u8 {
+            leaves.push(put_dag_pb_v0(sender_store, &[i; 200], &[]).await?);
+        }
+        let root = put_dag_pb_v0(sender_store, b"root", &leaves).await?;
+
+        // Small rounds, so the receiver has to ask for missing subgraph roots
+        let config = &Config {
+            normalize_cid_versions: true,
+            receive_maximum: 400,
+            ..Config::default()
+        };
+
+        let receiver_store = &MemoryBlockStore::new();
+        let mut request = pull::request(root, None, config, receiver_store, NoCache).await?;
+        for _ in 0..10 {
+            if request.indicates_finished() {
+                break;
+            }
+            let response = pull::response(root, request, config, sender_store, NoCache).await?;
+            request = pull::request(root, Some(response), config, receiver_store, NoCache).await?;
+        }
+        assert!(request.indicates_finished());
+
+        let receiver_store = &MemoryBlockStore::new();
+        let mut response = push::response(
+            root,
+            push::request(root, None, config, sender_store, NoCache).await?,
+            config,
+            receiver_store,
+            NoCache,
+        )
+        .await?;
+        for _ in 0..10 {
+            if response.indicates_finished() {
+                break;
+            }
+            let request =
+                push::request(root, Some(response), config, sender_store, NoCache).await?;
+            response = push::response(root, request, config, receiver_store, NoCache).await?;
+        }
+        assert!(response.indicates_finished());
+
+        for cid in leaves {
+            assert!(receiver_store.has_block(&cid).await?);
+        }
+
+        Ok(())
+    }
+}
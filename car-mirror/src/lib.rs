@@ -10,6 +10,26 @@
 //! or take a look at the [specification].
 //!
 //! [specification]: https://github.com/wnfs-wg/car-mirror-spec
+//!
+//! ## Async runtimes
+//!
+//! This crate doesn't depend on any particular async runtime. Streaming
+//! functions use the `tokio::io` traits, which don't need a tokio runtime,
+//! and timeouts such as `Config::stream_idle_timeout` use `futures-timer`.
+//! It can be used from tokio, async-std or wasm alike.
+//!
+//! ## Block stores
+//!
+//! Received blocks are only stored once they're complete and verified, see
+//! `IncrementalDagVerification::verify_and_store_block`. Receivers treat any
+//! block that `BlockStore::has_block` reports as present as complete, and won't
+//! ask for it again.
+//!
+//! So `BlockStore` implementations used with this crate must only make a block
+//! visible once all of its bytes were written. E.g. stores that put each block
+//! into a file should write it to a temporary file first and then rename it to
+//! its final path, so a crash in the middle of a write doesn't leave behind a
+//! truncated block that later reads as corrupt.
 
 /// Test utilities. Enabled with the `test_utils` feature flag.
 #[cfg(any(test, feature = "test_utils"))]
@@ -18,6 +38,8 @@ pub mod test_utils;
 
 /// Module with local caching strategies and mechanisms that greatly enhance CAR mirror performance
 pub mod cache;
+/// Authenticated checkpoints that let servers resume push verification without state.
+pub mod checkpoint;
 /// Code that's common among the push and pull protocol sides (most of the code).
 ///
 /// This code is less concerened about the "client" and "server" ends of the protocol, but
@@ -28,12 +50,22 @@ pub mod cache;
 ///
 /// Consider the functions in here mostly internal, and refer to the `push` and `pull` modules instead.
 pub mod common;
+/// A block store wrapper that keeps blocks compressed. Enabled with the `compression` feature flag.
+#[cfg(feature = "compression")]
+#[cfg_attr(docsrs, doc(cfg(feature = "compression")))]
+pub mod compressing_store;
 /// Algorithms for walking IPLD directed acyclic graphs
 pub mod dag_walk;
 /// Error types
 mod error;
+/// Exporting DAGs from a local store into CAR files, outside of the protocol.
+pub mod export;
+/// Importing DAGs from CAR files into a local store, outside of the protocol.
+pub mod import;
 /// Algorithms for doing incremental verification of IPLD DAGs against a root hash on the receiving end.
 pub mod incremental_verification;
+/// A bounded in-memory block store with LRU eviction, e.g. for caching gateways.
+pub mod lru_store;
 /// Data types that are sent over-the-wire and relevant serialization code.
 pub mod messages;
 /// The CAR mirror pull protocol. Meant to be used qualified, i.e. `pull::request` and `pull::response`.
@@ -133,6 +165,7 @@ pub mod messages;
 ///     let chunk_stream = pull::response_streaming(
 ///         root,
 ///         request,
+///         config,
 ///         &server_store,
 ///         &server_cache
 ///     ).await?;
@@ -311,6 +344,7 @@ pub mod pull;
 ///     let chunk_stream = push::request_streaming(
 ///         root,
 ///         last_response,
+///         config,
 ///         &client_store,
 ///         &client_cache
 ///     ).await?;
@@ -407,8 +441,19 @@ pub mod pull;
 /// # }
 /// ```
 pub mod push;
+/// A block store wrapper that rejects writing oversized blocks.
+pub mod size_limited_store;
+/// A block store wrapper for receiving DAGs with all-or-nothing semantics.
+pub mod staging;
+/// Bidirectional sync for peers that each have blocks of a DAG the other lacks.
+/// Meant to be used qualified, i.e. `sync::bidirectional`.
+pub mod sync;
+/// A `Transport` abstraction and generic drivers that run the protocol rounds over it,
+/// so new transports only need to move bytes.
+pub mod transport;
 
 pub use error::*;
 
+pub(crate) mod dag_cbor;
 pub(crate) mod serde_bloom_bytes;
 pub(crate) mod serde_cid_vec;
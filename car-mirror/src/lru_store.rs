@@ -0,0 +1,242 @@
+use bytes::Bytes;
+use libipld::Cid;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+use wnfs_common::{utils::CondSend, BlockStore, BlockStoreError};
+
+/// An in-memory block store that holds at most a configured amount of block
+/// bytes, evicting the least recently used blocks once that's exceeded.
+///
+/// This is useful e.g. for caching gateways that pull DAGs from an upstream
+/// server, verify them and re-serve them to downstream clients, without
+/// keeping every block they've ever seen.
+///
+/// Reading (`get_block` and `has_block`) and writing a block marks it as
+/// recently used. Clones share the same blocks.
+///
+/// # Eviction during transfers
+///
+/// Blocks can get evicted at any time, including in the middle of a transfer:
+/// - While receiving, blocks that were already verified may be evicted.
+///   Verification of the rest of the transfer still succeeds, since verification
+///   state is kept in memory, but the next round will ask for evicted blocks again.
+///   If the DAG doesn't fit into the store, pulling it may not converge.
+/// - When serving a DAG from this store, e.g. via `pull::response`, evicted blocks
+///   can't be sent anymore. If the sender hits such a block, it fails with
+///   `BlockStoreError::CIDNotFound`.
+///
+/// So to reliably re-serve a DAG, make sure the byte cap comfortably exceeds
+/// the size of the DAGs you pull.
+#[derive(Debug, Clone)]
+pub struct BoundedLruBlockStore {
+    inner: Arc<Mutex<LruState>>,
+}
+
+#[derive(Debug)]
+struct LruState {
+    max_total_bytes: usize,
+    total_bytes: usize,
+    /// A counter that increases with every use of a block
+    clock: u64,
+    /// Blocks with the clock value of their last use
+    blocks: HashMap<Cid, (Bytes, u64)>,
+    /// Block CIDs by the clock value of their last use, least recent first
+    recency: BTreeMap<u64, Cid>,
+}
+
+impl BoundedLruBlockStore {
+    /// Create an empty store that holds at most `max_total_bytes` of block data.
+    pub fn new(max_total_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(LruState {
+                max_total_bytes,
+                total_bytes: 0,
+                clock: 0,
+                blocks: HashMap::new(),
+                recency: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// The total amount of block bytes currently stored.
+    pub fn total_bytes(&self) -> Result<usize, BlockStoreError> {
+        Ok(self.lock()?.total_bytes)
+    }
+
+    /// The number of blocks currently stored.
+    pub fn len(&self) -> Result<usize, BlockStoreError> {
+        Ok(self.lock()?.blocks.len())
+    }
+
+    /// Whether the store holds no blocks at all.
+    pub fn is_empty(&self) -> Result<bool, BlockStoreError> {
+        Ok(self.lock()?.blocks.is_empty())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, LruState>, BlockStoreError> {
+        Ok(self
+            .inner
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?)
+    }
+}
+
+impl LruState {
+    /// Mark the block as most recently used and return it, if present.
+    fn touch(&mut self, cid: &Cid) -> Option<Bytes> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (bytes, last_used) = self.blocks.get_mut(cid)?;
+        self.recency.remove(last_used);
+        self.recency.insert(clock, *cid);
+        *last_used = clock;
+        Some(bytes.clone())
+    }
+
+    fn evict_until_fits(&mut self, additional_bytes: usize) {
+        while self.total_bytes + additional_bytes > self.max_total_bytes {
+            let Some((_, cid)) = self.recency.pop_first() else {
+                return;
+            };
+
+            if let Some((bytes, _)) = self.blocks.remove(&cid) {
+                tracing::trace!(%cid, num_bytes = bytes.len(), "Evicting block");
+                self.total_bytes -= bytes.len();
+            }
+        }
+    }
+}
+
+impl BlockStore for BoundedLruBlockStore {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        self.lock()?
+            .touch(cid)
+            .ok_or(BlockStoreError::CIDNotFound(*cid))
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        let bytes = bytes.into();
+        let mut state = self.lock()?;
+
+        if state.touch(&cid).is_some() {
+            return Ok(());
+        }
+
+        if bytes.len() > state.max_total_bytes {
+            return Err(BlockStoreError::Custom(anyhow::anyhow!(
+                "Block {cid} with {} bytes doesn't fit into the store's maximum of {} bytes",
+                bytes.len(),
+                state.max_total_bytes
+            )));
+        }
+
+        state.evict_until_fits(bytes.len());
+
+        let clock = state.clock;
+        state.total_bytes += bytes.len();
+        state.recency.insert(clock, cid);
+        state.blocks.insert(cid, (bytes, clock));
+
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        Ok(self.lock()?.touch(cid).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::{block_receive, block_send, compute_missing, Config},
+        test_utils::{setup_random_dag, total_dag_blocks, total_dag_bytes},
+        Error,
+    };
+    use assert_matches::assert_matches;
+    use testresult::TestResult;
+    use wnfs_common::CODEC_RAW;
+
+    #[test_log::test(async_std::test)]
+    async fn test_evicts_least_recently_used() -> TestResult {
+        let store = &BoundedLruBlockStore::new(30);
+        let a = store.put_block(vec![1; 10], CODEC_RAW).await?;
+        let b = store.put_block(vec![2; 10], CODEC_RAW).await?;
+        let c = store.put_block(vec![3; 10], CODEC_RAW).await?;
+
+        // Use `a`, so `b` is the least recently used
+        store.get_block(&a).await?;
+        let d = store.put_block(vec![4; 10], CODEC_RAW).await?;
+
+        assert!(store.has_block(&a).await?);
+        assert!(!store.has_block(&b).await?);
+        assert!(store.has_block(&c).await?);
+        assert!(store.has_block(&d).await?);
+        assert_eq!(store.total_bytes()?, 30);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_pull_and_reserve_when_dag_fits() -> TestResult {
+        let (root, ref upstream) = setup_random_dag(64, 1024).await?;
+        let config = &Config::default();
+        let gateway = &BoundedLruBlockStore::new(2 * total_dag_bytes(root, upstream).await?);
+
+        let mut last_state = None;
+        loop {
+            let car = block_send(root, last_state, config, upstream, NoCache).await?;
+            let state = block_receive(root, Some(car), config, gateway, NoCache).await?;
+            if state.missing_subgraph_roots.is_empty() {
+                break;
+            }
+            last_state = Some(state);
+        }
+
+        assert_eq!(
+            total_dag_blocks(root, gateway).await?,
+            total_dag_blocks(root, upstream).await?
+        );
+        // Re-serving works, since nothing was evicted
+        block_send(root, None, config, gateway, NoCache).await?;
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_eviction_mid_transfer() -> TestResult {
+        let (root, ref upstream) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            receive_maximum: 10_000_000,
+            ..Config::default()
+        };
+        let max_total_bytes = total_dag_bytes(root, upstream).await? / 4;
+        let gateway = &BoundedLruBlockStore::new(max_total_bytes);
+
+        // Receiving the whole DAG at once still verifies, even though blocks
+        // get evicted along the way.
+        let car = block_send(root, None, config, upstream, NoCache).await?;
+        let state = block_receive(root, Some(car), config, gateway, NoCache).await?;
+        assert!(state.missing_subgraph_roots.is_empty());
+        assert!(gateway.total_bytes()? <= max_total_bytes);
+
+        // But the next round would ask for evicted blocks again
+        assert!(!compute_missing(root, gateway, NoCache).await?.is_empty());
+
+        // Evicted blocks can't be served anymore
+        let result = block_send(root, None, config, gateway, NoCache).await;
+        assert_matches!(
+            result,
+            Err(Error::BlockStoreError(BlockStoreError::CIDNotFound(_)))
+        );
+
+        Ok(())
+    }
+}
@@ -1,49 +1,163 @@
-use std::{collections::TryReserveError, convert::Infallible};
-
+use std::{
+    collections::{HashSet, TryReserveError},
+    convert::Infallible,
+};
+
+use crate::{
+    cache::Cache,
+    common::Config,
+    dag_walk::{DagWalk, TraversedItem},
+    error::Error,
+};
+use deterministic_bloom::runtime_size::BloomFilter;
 use libipld_core::cid::Cid;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_ipld_dagcbor::{DecodeError, EncodeError};
+use wnfs_common::BlockStore;
+
+/// The maximum bloom filter hash count accepted in received messages.
+///
+/// Receivers size their blooms for the configured false positive rate, which
+/// needs far fewer hashes. Each hash is computed for every block the sending
+/// end checks against the bloom, so this bounds the work a peer can cause.
+pub const MAX_BLOOM_HASH_COUNT: u32 = 256;
 
 /// Initial message for pull requests.
 ///
 /// Over-the-wire data type from the [specification].
 ///
+/// Fields are declared in DAG-CBOR's canonical map key order
+/// (shorter keys first, then bytewise), since serde serializes
+/// struct fields in declaration order.
+///
 /// [specification]: https://github.com/fission-codes/spec/blob/86fcfb07d507f1df4fdaaf49088abecbb1dda76a/car-pool/car-mirror/http.md#12-requestor-payload
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PullRequestWire")]
 pub struct PullRequest {
-    /// Requested CID roots
-    #[serde(rename = "rs", with = "crate::serde_cid_vec")]
-    pub resources: Vec<Cid>,
+    /// How `resources` are encoded on the wire, declared via the message
+    /// version. Version 0, the default, isn't serialized.
+    ///
+    /// Messages with resources in another encoding than their version
+    /// declares fail to deserialize.
+    pub cid_encoding: CidEncoding,
+
+    /// Bloom filter Binary
+    pub bloom_bytes: Vec<u8>,
 
     /// Bloom filter hash count
-    #[serde(rename = "bk")]
     pub bloom_hash_count: u32,
 
-    /// Bloom filter Binary
-    #[serde(rename = "bb")]
-    #[serde(with = "crate::serde_bloom_bytes")]
-    pub bloom_bytes: Vec<u8>,
+    /// Optional hint for the maximum number of blocks to send in response
+    pub max_blocks: Option<u64>,
+
+    /// Requested CID roots
+    pub resources: Vec<Cid>,
 }
 
 /// The response sent after the initial and subsequent push requests.
 ///
 /// Wire data type from the [specification].
 ///
+/// Fields are declared in DAG-CBOR's canonical map key order
+/// (shorter keys first, then bytewise), since serde serializes
+/// struct fields in declaration order.
+///
 /// [specification]: https://github.com/fission-codes/spec/blob/86fcfb07d507f1df4fdaaf49088abecbb1dda76a/car-pool/car-mirror/http.md#23-provider-payload
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PushResponseWire")]
 pub struct PushResponse {
-    /// Incomplete subgraph roots
-    #[serde(rename = "sr", with = "crate::serde_cid_vec")]
-    pub subgraph_roots: Vec<Cid>,
+    /// How `subgraph_roots` are encoded on the wire, declared via the
+    /// message version. Version 0, the default, isn't serialized.
+    ///
+    /// Messages with subgraph roots in another encoding than their version
+    /// declares fail to deserialize.
+    pub cid_encoding: CidEncoding,
+
+    /// Bloom filter Binary
+    pub bloom_bytes: Vec<u8>,
 
     /// Bloom filter hash count
-    #[serde(rename = "bk")]
     pub bloom_hash_count: u32,
 
-    /// Bloom filter Binary
-    #[serde(rename = "bb")]
-    #[serde(with = "crate::serde_bloom_bytes")]
-    pub bloom_bytes: Vec<u8>,
+    /// Optional opaque checkpoint of the server's verification state,
+    /// which the client should echo back with its next push request.
+    /// See the `checkpoint` module.
+    pub checkpoint: Option<Vec<u8>>,
+
+    /// Incomplete subgraph roots
+    pub subgraph_roots: Vec<Cid>,
+}
+
+/// How lists of CIDs are encoded in wire messages.
+///
+/// This is declared via the version field (`v`) of `PullRequest` and
+/// `PushResponse`. Messages of either version can be decoded, but peers
+/// from before version 1 fail to decode version 1 messages, so only send
+/// these to peers that are known to support them, see `Config::cid_encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CidEncoding {
+    /// Version 0: An array of CID strings, as in the specification.
+    #[default]
+    Strings,
+    /// Version 1: A single byte string of concatenated binary CIDs.
+    ///
+    /// This is less than half the size of `Strings`, which matters for
+    /// messages with thousands of missing subgraph roots.
+    Binary,
+}
+
+/// The direction of a protocol, i.e. which side sends blocks.
+///
+/// This determines which wire message the block receiving end
+/// sends its `ReceiverState` with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The client sends blocks to the server, which answers with `PushResponse`s.
+    Push,
+    /// The server sends blocks to the client, which asks for them with `PullRequest`s.
+    Pull,
+}
+
+/// Either of the wire messages sent by the block receiving end.
+///
+/// Useful for code that's generic over the protocol direction,
+/// see `ReceiverState::to_message` and `ReceiverState::from_message`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A message from the push protocol
+    PushResponse(PushResponse),
+    /// A message from the pull protocol
+    PullRequest(PullRequest),
+}
+
+impl Message {
+    /// The protocol direction this message is used in.
+    pub fn direction(&self) -> Direction {
+        match self {
+            Self::PushResponse(_) => Direction::Push,
+            Self::PullRequest(_) => Direction::Pull,
+        }
+    }
+
+    /// Whether this message indicates that the protocol is finished.
+    pub fn indicates_finished(&self) -> bool {
+        match self {
+            Self::PushResponse(response) => response.indicates_finished(),
+            Self::PullRequest(request) => request.indicates_finished(),
+        }
+    }
+}
+
+impl From<PushResponse> for Message {
+    fn from(response: PushResponse) -> Self {
+        Self::PushResponse(response)
+    }
+}
+
+impl From<PullRequest> for Message {
+    fn from(request: PullRequest) -> Self {
+        Self::PullRequest(request)
+    }
 }
 
 impl PushResponse {
@@ -52,6 +166,75 @@ impl PushResponse {
         self.subgraph_roots.is_empty()
     }
 
+    /// Checks that this response is safe to process, see `PullRequest::validate`.
+    pub fn validate(&self, config: &Config) -> Result<(), Error> {
+        validate_message(
+            &self.subgraph_roots,
+            self.bloom_hash_count,
+            &self.bloom_bytes,
+            config,
+        )
+    }
+
+    /// Of given `roots`, returns the ones that the receiving end has
+    /// all blocks below, according to this response.
+    ///
+    /// This is useful for reporting progress on pushes of multiple roots.
+    /// Since a response only lists subgraph roots, this walks the DAG below
+    /// each root in the sending end's `store` to find out whether any of the
+    /// subgraph roots are part of it.
+    ///
+    /// Receivers may only report *some* of their missing subgraph roots,
+    /// see `Config::max_roots_per_round`, so roots may turn up as finished
+    /// early. Only `indicates_finished` is definite.
+    pub async fn finished_roots(
+        &self,
+        roots: impl IntoIterator<Item = Cid>,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+    ) -> Result<Vec<Cid>, Error> {
+        Ok(self.partition_roots(roots, store, cache).await?.0)
+    }
+
+    /// Of given `roots`, returns the ones that the receiving end is still
+    /// missing blocks of, according to this response.
+    ///
+    /// This is the complement of `finished_roots`, see its documentation
+    /// for caveats.
+    pub async fn remaining_roots(
+        &self,
+        roots: impl IntoIterator<Item = Cid>,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+    ) -> Result<Vec<Cid>, Error> {
+        Ok(self.partition_roots(roots, store, cache).await?.1)
+    }
+
+    async fn partition_roots(
+        &self,
+        roots: impl IntoIterator<Item = Cid>,
+        store: &impl BlockStore,
+        cache: &impl Cache,
+    ) -> Result<(Vec<Cid>, Vec<Cid>), Error> {
+        let subgraph_roots = self.subgraph_roots.iter().collect::<HashSet<_>>();
+        let mut finished = Vec::new();
+        let mut remaining = Vec::new();
+
+        'roots: for root in roots {
+            let mut dag_walk = DagWalk::breadth_first([root]);
+            while let Some(item) = dag_walk.next(store, cache).await? {
+                let (TraversedItem::Have(cid) | TraversedItem::Missing(cid)) = item;
+                if subgraph_roots.contains(&cid) {
+                    remaining.push(root);
+                    continue 'roots;
+                }
+            }
+            finished.push(root);
+        }
+
+        Ok((finished, remaining))
+    }
+
     /// Deserialize a push response from dag-cbor bytes
     pub fn from_dag_cbor(slice: impl AsRef<[u8]>) -> Result<Self, DecodeError<Infallible>> {
         serde_ipld_dagcbor::from_slice(slice.as_ref())
@@ -61,6 +244,46 @@ impl PushResponse {
     pub fn to_dag_cbor(&self) -> Result<Vec<u8>, EncodeError<TryReserveError>> {
         serde_ipld_dagcbor::to_vec(self)
     }
+
+    /// The number of bytes of this response's dag-cbor serialization,
+    /// computed without allocating it, e.g. to guard against regressions
+    /// of round sizes in tests.
+    pub fn wire_size(&self) -> usize {
+        dag_cbor_size(self)
+    }
+
+    /// Serialize a push response into dag-cbor bytes from its parts, taking
+    /// the subgraph roots lazily from an iterator.
+    ///
+    /// This results in the same bytes as `to_dag_cbor` would for a push response
+    /// with the roots collected into `subgraph_roots` and given `cid_encoding`,
+    /// but without allocating that `Vec`.
+    /// This matters for servers with many concurrent transfers of DAGs with
+    /// huge numbers of missing subgraph roots, see
+    /// `IncrementalDagVerification::missing_subgraph_roots` and
+    /// `IncrementalDagVerification::have_cids_bloom`.
+    pub fn dag_cbor_from_iter<'a>(
+        cid_encoding: CidEncoding,
+        have_cids_bloom: Option<&BloomFilter>,
+        checkpoint: Option<&[u8]>,
+        subgraph_roots: impl ExactSizeIterator<Item = &'a Cid> + Clone,
+    ) -> Result<Vec<u8>, EncodeError<TryReserveError>> {
+        let (bloom_hash_count, bloom_bytes) = match have_cids_bloom {
+            Some(bloom) => (bloom.hash_count() as u32, bloom.as_bytes()),
+            None => (3, &[][..]),
+        };
+
+        serde_ipld_dagcbor::to_vec(&PushResponseRef {
+            cid_encoding,
+            bloom_bytes,
+            bloom_hash_count,
+            checkpoint,
+            subgraph_roots: LazyCids {
+                cids: subgraph_roots,
+                encoding: cid_encoding,
+            },
+        })
+    }
 }
 
 impl PullRequest {
@@ -69,6 +292,25 @@ impl PullRequest {
         self.resources.is_empty()
     }
 
+    /// Checks that this request is safe to process, which is worth doing
+    /// for every request received from an untrusted peer.
+    ///
+    /// Rejects requests with more than `config.max_incoming_roots` roots with
+    /// `Error::TooManyRoots`, with bloom filters larger than `config.max_bloom_bytes`
+    /// with `Error::BloomTooLarge` and with bloom filters that aren't usable,
+    /// e.g. because their hash count is zero, exceeds `MAX_BLOOM_HASH_COUNT` or
+    /// exceeds the number of bits in the bloom, with `Error::InvalidBloom`.
+    ///
+    /// The hash count of requests without a bloom filter isn't checked.
+    pub fn validate(&self, config: &Config) -> Result<(), Error> {
+        validate_message(
+            &self.resources,
+            self.bloom_hash_count,
+            &self.bloom_bytes,
+            config,
+        )
+    }
+
     /// Deserialize a pull request from dag-cbor bytes
     pub fn from_dag_cbor(slice: impl AsRef<[u8]>) -> Result<Self, DecodeError<Infallible>> {
         serde_ipld_dagcbor::from_slice(slice.as_ref())
@@ -78,6 +320,288 @@ impl PullRequest {
     pub fn to_dag_cbor(&self) -> Result<Vec<u8>, EncodeError<TryReserveError>> {
         serde_ipld_dagcbor::to_vec(self)
     }
+
+    /// The number of bytes of this request's dag-cbor serialization,
+    /// computed without allocating it, e.g. to guard against regressions
+    /// of round sizes in tests.
+    pub fn wire_size(&self) -> usize {
+        dag_cbor_size(self)
+    }
+}
+
+impl Serialize for PullRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PullRequestRef {
+            cid_encoding: self.cid_encoding,
+            bloom_bytes: &self.bloom_bytes,
+            bloom_hash_count: self.bloom_hash_count,
+            max_blocks: self.max_blocks,
+            resources: LazyCids {
+                cids: self.resources.iter(),
+                encoding: self.cid_encoding,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl Serialize for PushResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PushResponseRef {
+            cid_encoding: self.cid_encoding,
+            bloom_bytes: &self.bloom_bytes,
+            bloom_hash_count: self.bloom_hash_count,
+            checkpoint: self.checkpoint.as_deref(),
+            subgraph_roots: LazyCids {
+                cids: self.subgraph_roots.iter(),
+                encoding: self.cid_encoding,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl CidEncoding {
+    /// The message version that declares this encoding.
+    pub fn version(self) -> u64 {
+        match self {
+            Self::Strings => 0,
+            Self::Binary => 1,
+        }
+    }
+
+    /// The encoding declared by given message version, if it's supported.
+    pub fn from_version(version: u64) -> Option<Self> {
+        match version {
+            0 => Some(Self::Strings),
+            1 => Some(Self::Binary),
+            _ => None,
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Serialize for CidEncoding {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.version())
+    }
+}
+
+impl<'de> Deserialize<'de> for CidEncoding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = u64::deserialize(deserializer)?;
+        Self::from_version(version).ok_or_else(|| {
+            serde::de::Error::custom(format!("unsupported message version {version}"))
+        })
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private
+//--------------------------------------------------------------------------------------------------
+
+/// Counts the bytes written to it, without keeping them.
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The number of bytes of a message's dag-cbor serialization.
+fn dag_cbor_size(message: &impl Serialize) -> usize {
+    let mut counter = ByteCounter(0);
+    serde_ipld_dagcbor::to_writer(&mut counter, message)
+        .expect("Serializing messages only fails for failing writers");
+    counter.0
+}
+
+/// Wire format of `PullRequest`, used to deserialize it.
+#[derive(Deserialize)]
+struct PullRequestWire {
+    #[serde(rename = "v", default)]
+    cid_encoding: CidEncoding,
+
+    #[serde(rename = "bb")]
+    #[serde(with = "crate::serde_bloom_bytes")]
+    bloom_bytes: Vec<u8>,
+
+    #[serde(rename = "bk")]
+    bloom_hash_count: u32,
+
+    #[serde(rename = "mb", default)]
+    max_blocks: Option<u64>,
+
+    #[serde(
+        rename = "rs",
+        deserialize_with = "crate::serde_cid_vec::deserialize_with_encoding"
+    )]
+    resources: (Vec<Cid>, CidEncoding),
+}
+
+/// Wire format of `PushResponse`, used to deserialize it.
+#[derive(Deserialize)]
+struct PushResponseWire {
+    #[serde(rename = "v", default)]
+    cid_encoding: CidEncoding,
+
+    #[serde(rename = "bb")]
+    #[serde(with = "crate::serde_bloom_bytes")]
+    bloom_bytes: Vec<u8>,
+
+    #[serde(rename = "bk")]
+    bloom_hash_count: u32,
+
+    #[serde(rename = "cp", default, with = "serde_bytes")]
+    checkpoint: Option<Vec<u8>>,
+
+    #[serde(
+        rename = "sr",
+        deserialize_with = "crate::serde_cid_vec::deserialize_with_encoding"
+    )]
+    subgraph_roots: (Vec<Cid>, CidEncoding),
+}
+
+impl TryFrom<PullRequestWire> for PullRequest {
+    type Error = String;
+
+    fn try_from(wire: PullRequestWire) -> Result<Self, Self::Error> {
+        let (resources, found_encoding) = wire.resources;
+        check_cid_encoding(wire.cid_encoding, found_encoding)?;
+        Ok(Self {
+            cid_encoding: wire.cid_encoding,
+            bloom_bytes: wire.bloom_bytes,
+            bloom_hash_count: wire.bloom_hash_count,
+            max_blocks: wire.max_blocks,
+            resources,
+        })
+    }
+}
+
+impl TryFrom<PushResponseWire> for PushResponse {
+    type Error = String;
+
+    fn try_from(wire: PushResponseWire) -> Result<Self, Self::Error> {
+        let (subgraph_roots, found_encoding) = wire.subgraph_roots;
+        check_cid_encoding(wire.cid_encoding, found_encoding)?;
+        Ok(Self {
+            cid_encoding: wire.cid_encoding,
+            bloom_bytes: wire.bloom_bytes,
+            bloom_hash_count: wire.bloom_hash_count,
+            checkpoint: wire.checkpoint,
+            subgraph_roots,
+        })
+    }
+}
+
+/// Checks that CIDs were found in the encoding that the message version declares.
+fn check_cid_encoding(declared: CidEncoding, found: CidEncoding) -> Result<(), String> {
+    if declared != found {
+        return Err(format!(
+            "message version {} declares {declared:?} CIDs, but they're encoded as {found:?}",
+            declared.version()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Borrowed version of `PullRequest`, used to serialize it.
+#[derive(Serialize)]
+struct PullRequestRef<'a, I> {
+    #[serde(rename = "v", skip_serializing_if = "CidEncoding::is_default")]
+    cid_encoding: CidEncoding,
+
+    #[serde(rename = "bb")]
+    #[serde(serialize_with = "crate::serde_bloom_bytes::serialize")]
+    bloom_bytes: &'a [u8],
+
+    #[serde(rename = "bk")]
+    bloom_hash_count: u32,
+
+    #[serde(rename = "mb", skip_serializing_if = "Option::is_none")]
+    max_blocks: Option<u64>,
+
+    #[serde(rename = "rs")]
+    resources: LazyCids<I>,
+}
+
+/// Borrowed version of `PushResponse`, used to serialize it, also from
+/// the parts passed to `PushResponse::dag_cbor_from_iter`.
+#[derive(Serialize)]
+struct PushResponseRef<'a, I> {
+    #[serde(rename = "v", skip_serializing_if = "CidEncoding::is_default")]
+    cid_encoding: CidEncoding,
+
+    #[serde(rename = "bb")]
+    #[serde(serialize_with = "crate::serde_bloom_bytes::serialize")]
+    bloom_bytes: &'a [u8],
+
+    #[serde(rename = "bk")]
+    bloom_hash_count: u32,
+
+    #[serde(
+        rename = "cp",
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    checkpoint: Option<&'a [u8]>,
+
+    #[serde(rename = "sr")]
+    subgraph_roots: LazyCids<I>,
+}
+
+/// Serializes CIDs from an iterator with given encoding, via `serde_cid_vec`.
+struct LazyCids<I> {
+    cids: I,
+    encoding: CidEncoding,
+}
+
+impl<'a, I: ExactSizeIterator<Item = &'a Cid> + Clone> Serialize for LazyCids<I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.encoding {
+            CidEncoding::Strings => crate::serde_cid_vec::serialize(self.cids.clone(), serializer),
+            CidEncoding::Binary => {
+                crate::serde_cid_vec::serialize_binary(self.cids.clone(), serializer)
+            }
+        }
+    }
+}
+
+fn validate_message(
+    roots: &[Cid],
+    bloom_hash_count: u32,
+    bloom_bytes: &[u8],
+    config: &Config,
+) -> Result<(), Error> {
+    config.check_incoming_roots(roots)?;
+    config.check_bloom_bytes(bloom_bytes)?;
+
+    if bloom_bytes.is_empty() {
+        return Ok(());
+    }
+
+    let bloom_bits = bloom_bytes.len() as u64 * 8;
+    if bloom_hash_count == 0
+        || bloom_hash_count > MAX_BLOOM_HASH_COUNT
+        || bloom_hash_count as u64 > bloom_bits
+    {
+        return Err(Error::InvalidBloom {
+            hash_count: bloom_hash_count,
+            bloom_bytes: bloom_bytes.len(),
+        });
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -85,10 +609,15 @@ mod test {
     use crate::{
         cache::NoCache,
         common::{Config, ReceiverState},
+        dag_walk::DagWalk,
+        error::Error,
         incremental_verification::IncrementalDagVerification,
-        messages::{PullRequest, PushResponse},
+        messages::{
+            CidEncoding, Direction, Message, PullRequest, PushResponse, MAX_BLOOM_HASH_COUNT,
+        },
     };
     use anyhow::Result;
+    use assert_matches::assert_matches;
     use testresult::TestResult;
     use wnfs_common::MemoryBlockStore;
     use wnfs_unixfs_file::builder::FileBuilder;
@@ -108,6 +637,12 @@ mod test {
     }
 
     async fn partial_receiver_state() -> Result<ReceiverState> {
+        Ok(partial_verification()
+            .await?
+            .into_receiver_state(Config::default().bloom_fpr))
+    }
+
+    async fn partial_verification() -> Result<IncrementalDagVerification> {
         let store = &MemoryBlockStore::new();
         let store2 = &MemoryBlockStore::new();
 
@@ -127,7 +662,7 @@ mod test {
         dag.want_cids.insert(root_cid);
         dag.update_have_cids(store, &NoCache).await?;
 
-        Ok(dag.into_receiver_state(Config::default().bloom_fpr))
+        Ok(dag)
     }
 
     #[test_log::test(async_std::test)]
@@ -159,6 +694,150 @@ mod test {
         Ok(())
     }
 
+    #[test_log::test(async_std::test)]
+    async fn test_wire_size() -> TestResult {
+        let receiver_state = partial_receiver_state().await?;
+        let pull_request: PullRequest = receiver_state.clone().into();
+        let push_response: PushResponse = receiver_state.into();
+
+        assert_eq!(pull_request.wire_size(), pull_request.to_dag_cbor()?.len());
+        assert_eq!(
+            push_response.wire_size(),
+            push_response.to_dag_cbor()?.len()
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_binary_cid_encoding() -> TestResult {
+        let receiver_state = partial_receiver_state().await?;
+        let mut pull_request: PullRequest = receiver_state.clone().into();
+        let mut push_response: PushResponse = receiver_state.into();
+        let string_sizes = (
+            pull_request.to_dag_cbor()?.len(),
+            push_response.to_dag_cbor()?.len(),
+        );
+
+        pull_request.cid_encoding = CidEncoding::Binary;
+        push_response.cid_encoding = CidEncoding::Binary;
+        let pull_bytes = pull_request.to_dag_cbor()?;
+        let push_bytes = push_response.to_dag_cbor()?;
+
+        assert!(pull_bytes.len() < string_sizes.0);
+        assert!(push_bytes.len() < string_sizes.1);
+        assert_eq!(PullRequest::from_dag_cbor(&pull_bytes)?, pull_request);
+        assert_eq!(PushResponse::from_dag_cbor(&push_bytes)?, push_response);
+
+        let json = serde_json::to_string(&pull_request)?;
+        assert_eq!(serde_json::from_str::<PullRequest>(&json)?, pull_request);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_version() -> TestResult {
+        let request = PullRequest {
+            cid_encoding: CidEncoding::Strings,
+            bloom_bytes: Vec::new(),
+            bloom_hash_count: 3,
+            max_blocks: None,
+            resources: Vec::new(),
+        };
+
+        // Version 0 stays compatible with peers that don't know about versions
+        let json = serde_json::to_value(&request)?;
+        assert_eq!(json.get("v"), None);
+
+        let binary = PullRequest {
+            cid_encoding: CidEncoding::Binary,
+            ..request
+        };
+        let json = serde_json::to_value(&binary)?;
+        assert_eq!(json.get("v"), Some(&serde_json::json!(1)));
+
+        let mut unsupported = json.clone();
+        unsupported["v"] = serde_json::json!(2);
+        assert!(serde_json::from_value::<PullRequest>(unsupported).is_err());
+
+        // The CIDs must be encoded as the version declares
+        let mut mismatched = json;
+        mismatched["v"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<PullRequest>(mismatched).is_err());
+        let mut bytes = binary.to_dag_cbor()?;
+        let version_key = bytes.windows(2).position(|w| w == [0x61, b'v']).unwrap();
+        // Replace version 1 with version 0
+        assert_eq!(bytes[version_key + 2], 0x01);
+        bytes[version_key + 2] = 0x00;
+        assert!(PullRequest::from_dag_cbor(&bytes).is_err());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_validate() -> TestResult {
+        let config = &Config::default();
+        let request = PullRequest::from(loaded_receiver_state().await?);
+        assert!(request.validate(config).is_ok());
+
+        let request = |bloom_hash_count: u32, bloom_bytes: usize| PullRequest {
+            cid_encoding: CidEncoding::default(),
+            resources: Vec::new(),
+            bloom_hash_count,
+            bloom_bytes: vec![0; bloom_bytes],
+            max_blocks: None,
+        };
+
+        // Without a bloom, the hash count doesn't matter
+        assert!(request(u32::MAX, 0).validate(config).is_ok());
+        assert!(request(MAX_BLOOM_HASH_COUNT, 32).validate(config).is_ok());
+        for (bloom_hash_count, bloom_bytes) in [(0, 32), (MAX_BLOOM_HASH_COUNT + 1, 64), (9, 1)] {
+            assert_matches!(
+                request(bloom_hash_count, bloom_bytes).validate(config),
+                Err(Error::InvalidBloom { hash_count, .. }) if hash_count == bloom_hash_count
+            );
+        }
+
+        let response = PushResponse {
+            cid_encoding: CidEncoding::default(),
+            subgraph_roots: Vec::new(),
+            bloom_hash_count: 3,
+            bloom_bytes: vec![0; config.max_bloom_bytes + 1],
+            checkpoint: None,
+        };
+        assert_matches!(response.validate(config), Err(Error::BloomTooLarge { .. }));
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_push_response_dag_cbor_from_iter() -> TestResult {
+        let dag = partial_verification().await?;
+        let bloom = dag.have_cids_bloom(Config::default().bloom_fpr);
+        assert!(bloom.is_some());
+
+        for checkpoint in [None, Some(vec![1, 2, 3])] {
+            let bytes = PushResponse::dag_cbor_from_iter(
+                CidEncoding::Strings,
+                bloom.as_ref(),
+                checkpoint.as_deref(),
+                dag.missing_subgraph_roots(),
+            )?;
+
+            let mut push_response: PushResponse = dag
+                .clone()
+                .into_receiver_state(Config::default().bloom_fpr)
+                .into();
+            push_response.checkpoint = checkpoint;
+            // The lazily serialized roots aren't sorted
+            push_response.subgraph_roots = dag.missing_subgraph_roots().copied().collect();
+
+            assert_eq!(bytes, push_response.to_dag_cbor()?);
+        }
+
+        Ok(())
+    }
+
     #[test_log::test(async_std::test)]
     async fn test_pull_request_have_everything_indicates_finished() -> TestResult {
         let pull_request: PullRequest = loaded_receiver_state().await?.into();
@@ -186,4 +865,73 @@ mod test {
         assert!(!push_response.indicates_finished());
         Ok(())
     }
+
+    #[test_log::test(async_std::test)]
+    async fn test_receiver_state_message_roundtrip() -> TestResult {
+        let receiver_state = partial_receiver_state().await?;
+
+        for direction in [Direction::Push, Direction::Pull] {
+            let message = receiver_state.to_message(direction);
+            assert_eq!(message.direction(), direction);
+
+            let state_back = ReceiverState::from_message(message.clone());
+            assert_eq!(state_back.to_message(direction), message);
+        }
+
+        let pull_request = PullRequest::from(receiver_state.clone());
+        assert_eq!(
+            receiver_state.to_message(Direction::Pull),
+            Message::PullRequest(pull_request)
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_push_response_finished_and_remaining_roots() -> TestResult {
+        let store = &MemoryBlockStore::new();
+
+        let root_a = FileBuilder::new()
+            .content_bytes(vec![1; 500_000])
+            .build()?
+            .store(store)
+            .await?;
+
+        let root_b = FileBuilder::new()
+            .content_bytes(vec![2; 500_000])
+            .build()?
+            .store(store)
+            .await?;
+
+        // Pretend the receiver has root_b's root block, but is missing one below it
+        let mut dag_walk = DagWalk::breadth_first([root_b]);
+        dag_walk.next(store, &NoCache).await?;
+        let child_b = dag_walk
+            .next(store, &NoCache)
+            .await?
+            .expect("file has more than one block")
+            .to_cid()?;
+
+        let push_response = PushResponse {
+            cid_encoding: CidEncoding::default(),
+            bloom_bytes: vec![],
+            bloom_hash_count: 3,
+            checkpoint: None,
+            subgraph_roots: vec![child_b],
+        };
+
+        let roots = [root_a, root_b];
+        assert_eq!(
+            push_response.finished_roots(roots, store, &NoCache).await?,
+            vec![root_a]
+        );
+        assert_eq!(
+            push_response
+                .remaining_roots(roots, store, &NoCache)
+                .await?,
+            vec![root_b]
+        );
+
+        Ok(())
+    }
 }
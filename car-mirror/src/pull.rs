@@ -2,7 +2,7 @@ use crate::{
     cache::Cache,
     common::{
         block_receive, block_receive_car_stream, block_send, block_send_block_stream,
-        stream_car_frames, CarFile, CarStream, Config, ReceiverState,
+        stream_car_frames, with_end_of_round, CarFile, CarStream, Config, ReceiverState,
     },
     error::Error,
     messages::PullRequest,
@@ -23,6 +23,10 @@ use wnfs_common::{utils::CondSend, BlockStore};
 /// make sure to check the `request.indicates_finished()`.
 /// If true, the "client" already has all data and the request
 /// doesn't need to be sent.
+///
+/// Blocks behind identity-hash CIDs count as available, since their data
+/// is inlined in the CID. Requests for an identity CID root are thus
+/// always finished, without any blocks needing to be in `store`.
 pub async fn request(
     root: Cid,
     last_response: Option<CarFile>,
@@ -30,9 +34,8 @@ pub async fn request(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<PullRequest, Error> {
-    Ok(block_receive(root, last_response, config, store, cache)
-        .await?
-        .into())
+    let receiver_state = block_receive(root, last_response, config, store, cache).await?;
+    Ok(into_request(receiver_state, config))
 }
 
 /// On the "client" side, handle a streaming response from a pull request.
@@ -46,9 +49,8 @@ pub async fn handle_response_streaming(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<PullRequest, Error> {
-    Ok(block_receive_car_stream(root, stream, config, store, cache)
-        .await?
-        .into())
+    let receiver_state = block_receive_car_stream(root, stream, config, store, cache).await?;
+    Ok(into_request(receiver_state, config))
 }
 
 /// Respond to a CAR mirror pull request on the "server" side.
@@ -59,40 +61,104 @@ pub async fn response(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<CarFile, Error> {
-    let receiver_state = Some(ReceiverState::from(request));
+    let receiver_state = Some(ReceiverState::from_message_checked(request, config)?);
     block_send(root, receiver_state, config, store, cache).await
 }
 
+/// Like `response`, but responds with the `precomputed` CAR file to cold pull
+/// requests, i.e. from clients that don't have any blocks of `root` yet,
+/// instead of walking the store.
+///
+/// The precomputed CAR file should be the result of a cold `response` with
+/// the same `root` and `config`, e.g. generated when the content is published.
+/// Other requests, or precomputed CAR files that exceed the
+/// `config.receive_maximum`, fall back to `response`.
+pub async fn response_from_car(
+    root: Cid,
+    precomputed: CarFile,
+    request: PullRequest,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<CarFile, Error> {
+    let receiver_state = ReceiverState::from_message_checked(request, config)?;
+
+    if receiver_state.is_cold(root) && precomputed.bytes.len() <= config.receive_maximum {
+        tracing::debug!(%root, "Responding with precomputed CAR file to cold pull");
+        return Ok(precomputed);
+    }
+
+    block_send(root, Some(receiver_state), config, store, cache).await
+}
+
 /// On the "server" side, respond to a pull request with a stream.
 ///
 /// This can especially speed up cold pull requests.
+///
+/// Like `response`, this checks the request against the `config`'s limits.
 pub async fn response_streaming<'a>(
     root: Cid,
     request: PullRequest,
+    config: &Config,
     store: impl BlockStore + 'a,
     cache: impl Cache + 'a,
 ) -> Result<CarStream<'a>, Error> {
-    let block_stream = block_send_block_stream(root, Some(request.into()), store, cache).await?;
+    let receiver_state = ReceiverState::from_message_checked(request, config)?;
+    let block_stream =
+        block_send_block_stream(root, Some(receiver_state), config, store, cache).await?;
     let car_stream = stream_car_frames(block_stream).await?;
     Ok(car_stream)
 }
 
+/// Like `response_streaming`, but ends the stream with an end-of-round block.
+///
+/// This way clients with `Config::require_end_of_round` set can tell apart
+/// a round that the server finished intentionally from a dropped connection.
+/// See `with_end_of_round`.
+pub async fn response_streaming_with_end_of_round<'a>(
+    root: Cid,
+    request: PullRequest,
+    config: &Config,
+    store: impl BlockStore + 'a,
+    cache: impl Cache + 'a,
+) -> Result<CarStream<'a>, Error> {
+    let receiver_state = ReceiverState::from_message_checked(request, config)?;
+    let block_stream =
+        block_send_block_stream(root, Some(receiver_state), config, store, cache).await?;
+    let car_stream = stream_car_frames(with_end_of_round(block_stream)).await?;
+    Ok(car_stream)
+}
+
+pub(crate) fn into_request(receiver_state: ReceiverState, config: &Config) -> PullRequest {
+    PullRequest {
+        cid_encoding: config.cid_encoding,
+        ..PullRequest::from(ReceiverState {
+            max_blocks: config.max_blocks_per_round,
+            ..receiver_state
+        })
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::{
         cache::{InMemoryCache, NoCache},
-        common::Config,
+        common::{compute_missing, Config, ReceiverState},
         dag_walk::DagWalk,
+        error::Error,
+        messages::{CidEncoding, PullRequest},
         pull,
-        test_utils::{setup_random_dag, store_test_unixfs, Metrics},
+        test_utils::{setup_random_dag, store_test_unixfs, total_dag_blocks, Metrics},
     };
     use anyhow::Result;
+    use assert_matches::assert_matches;
     use futures::TryStreamExt;
     use libipld::Cid;
+    use libipld_core::multihash::Multihash;
     use std::collections::HashSet;
     use testresult::TestResult;
     use tokio_util::io::StreamReader;
-    use wnfs_common::{BlockStore, MemoryBlockStore};
+    use wnfs_common::{BlockStore, MemoryBlockStore, CODEC_RAW};
 
     pub(crate) async fn simulate_protocol(
         root: Cid,
@@ -118,6 +184,55 @@ mod tests {
         Ok(metrics)
     }
 
+    #[test_log::test(async_std::test)]
+    async fn test_response_from_car() -> TestResult {
+        let (root, ref server_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            receive_maximum: 16 * 1024,
+            ..Config::default()
+        };
+        let precomputed = pull::response(
+            root,
+            pull::request(root, None, config, &MemoryBlockStore::new(), NoCache).await?,
+            config,
+            server_store,
+            NoCache,
+        )
+        .await?;
+
+        // Cold requests get the precomputed CAR file, even if the store is empty
+        let cold_request =
+            pull::request(root, None, config, &MemoryBlockStore::new(), NoCache).await?;
+        let response = pull::response_from_car(
+            root,
+            precomputed.clone(),
+            cold_request,
+            config,
+            &MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await?;
+        assert_eq!(response.bytes, precomputed.bytes);
+
+        // Subsequent requests fall back to walking the store
+        let client_store = &MemoryBlockStore::new();
+        let next_request =
+            pull::request(root, Some(response), config, client_store, NoCache).await?;
+        assert!(!next_request.indicates_finished());
+        let response = pull::response_from_car(
+            root,
+            precomputed.clone(),
+            next_request,
+            config,
+            server_store,
+            NoCache,
+        )
+        .await?;
+        assert_ne!(response.bytes, precomputed.bytes);
+
+        Ok(())
+    }
+
     #[test_log::test(async_std::test)]
     async fn test_transfer() -> TestResult {
         let client_store = &MemoryBlockStore::new();
@@ -142,6 +257,112 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test(async_std::test)]
+    async fn test_single_raw_block_transfer() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let server_store = &MemoryBlockStore::new();
+        let root = server_store
+            .put_block(b"Hello World".to_vec(), CODEC_RAW)
+            .await?;
+        let config = &Config::default();
+
+        // No bloom is computed for the first request
+        let request = pull::request(root, None, config, client_store, &NoCache).await?;
+        assert!(request.bloom_bytes.is_empty());
+
+        let metrics = simulate_protocol(root, config, client_store, server_store).await?;
+        assert_eq!(metrics.len(), 1);
+        assert!(client_store.has_block(&root).await?);
+
+        // Once the client has the block, there's nothing left to request
+        let request = pull::request(root, None, config, client_store, &NoCache).await?;
+        assert!(request.indicates_finished());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_identity_cid_root_transfer() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let server_store = &MemoryBlockStore::new();
+        let root = Cid::new_v1(CODEC_RAW, Multihash::wrap(0x00, b"Hello World")?);
+        let config = &Config::default();
+
+        // The client has the block inlined in the CID, so it doesn't request anything
+        let request = pull::request(root, None, config, client_store, &NoCache).await?;
+        assert!(request.indicates_finished());
+        let metrics = simulate_protocol(root, config, client_store, server_store).await?;
+        assert!(metrics.is_empty());
+
+        // Servers answer cold requests with the inlined block, even if their store is empty
+        let empty_root = Cid::new_v1(CODEC_RAW, Multihash::wrap(0x00, &[])?);
+        let response = pull::response(
+            empty_root,
+            ReceiverState {
+                missing_subgraph_roots: vec![empty_root],
+                have_cids_bloom: None,
+                max_blocks: None,
+            }
+            .into(),
+            config,
+            server_store,
+            NoCache,
+        )
+        .await?;
+        let request =
+            pull::request(empty_root, Some(response), config, client_store, &NoCache).await?;
+        assert!(request.indicates_finished());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_transfer_max_blocks_per_round() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let (root, ref server_store) = setup_random_dag(64, 1024).await?;
+        let total_blocks = total_dag_blocks(root, server_store).await?;
+
+        let config = &Config {
+            max_blocks_per_round: Some(4),
+            ..Config::default()
+        };
+
+        let metrics = simulate_protocol(root, config, client_store, server_store).await?;
+
+        assert!(metrics.len() >= total_blocks / 4);
+        assert_eq!(total_dag_blocks(root, client_store).await?, total_blocks);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_transfer_skip_leaf_data() -> TestResult {
+        let (root, ref server_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
+        let client_store = &MemoryBlockStore::new();
+        let config = &Config {
+            skip_leaf_data: true,
+            ..Config::default()
+        };
+
+        simulate_protocol(root, config, client_store, server_store).await?;
+
+        // The client has the whole structure
+        let mut dag_walk = DagWalk::breadth_first([root]);
+        while let Some(item) = dag_walk.next(server_store, &NoCache).await? {
+            let cid = item.to_cid()?;
+            if cid.codec() != CODEC_RAW {
+                assert!(client_store.has_block(&cid).await?);
+            }
+        }
+
+        // And only leaf data is missing
+        let missing = compute_missing(root, client_store, NoCache).await?;
+        assert!(!missing.is_empty());
+        assert!(missing.iter().all(|cid| cid.codec() == CODEC_RAW));
+
+        Ok(())
+    }
+
     #[test_log::test(async_std::test)]
     async fn test_streaming_transfer() -> TestResult {
         let client_store = MemoryBlockStore::new();
@@ -160,7 +381,8 @@ mod tests {
 
         while !request.indicates_finished() {
             let car_stream =
-                pull::response_streaming(root, request, &server_store, &server_cache).await?;
+                pull::response_streaming(root, request, config, &server_store, &server_cache)
+                    .await?;
 
             let byte_stream = StreamReader::new(
                 car_stream.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
@@ -178,6 +400,29 @@ mod tests {
 
         Ok(())
     }
+
+    #[test_log::test(async_std::test)]
+    async fn test_response_streaming_checks_request() -> TestResult {
+        let (root, ref store) = setup_random_dag(16, 1024).await?;
+        let config = &Config::builder().max_incoming_roots(1).build()?;
+        let other_root = store.put_block(b"other".to_vec(), CODEC_RAW).await?;
+        let request = PullRequest {
+            cid_encoding: CidEncoding::default(),
+            resources: vec![root, other_root],
+            bloom_hash_count: 3,
+            bloom_bytes: Vec::new(),
+            max_blocks: None,
+        };
+
+        let result = pull::response_streaming(root, request.clone(), config, store, NoCache).await;
+        assert_matches!(result.err(), Some(Error::TooManyRoots { .. }));
+
+        let result =
+            pull::response_streaming_with_end_of_round(root, request, config, store, NoCache).await;
+        assert_matches!(result.err(), Some(Error::TooManyRoots { .. }));
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1,14 +1,20 @@
 use crate::{
     cache::Cache,
+    checkpoint::{self, CheckpointKey},
     common::{
-        block_receive, block_receive_car_stream, block_send, block_send_block_stream,
-        stream_car_frames, CarFile, CarStream, Config, ReceiverState,
+        block_receive, block_receive_car_stream, block_receive_car_stream_resumed, block_send,
+        block_send_block_stream, block_send_delta, stream_car_frames, CarFile, CarStream, Config,
+        ReceiverState,
     },
     error::Error,
     messages::PushResponse,
 };
+use futures::stream::AbortHandle;
 use libipld_core::cid::Cid;
-use wnfs_common::{utils::CondSend, BlockStore};
+use wnfs_common::{
+    utils::{boxed_stream, CondSend},
+    BlockStore,
+};
 
 /// Create a CAR mirror push request.
 ///
@@ -20,6 +26,11 @@ use wnfs_common::{utils::CondSend, BlockStore};
 ///
 /// The returned request body is a CAR file from some of the first
 /// blocks below the root.
+///
+/// Blocks behind identity-hash CIDs are read from the CID itself, since
+/// their data is inlined, and every "server" already has them. Pushes of an
+/// identity CID root thus finish in a single round, even if the block isn't
+/// in either store.
 pub async fn request(
     root: Cid,
     last_response: Option<PushResponse>,
@@ -27,7 +38,62 @@ pub async fn request(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<CarFile, Error> {
-    let receiver_state = last_response.map(ReceiverState::from);
+    let receiver_state = last_response
+        .map(|response| ReceiverState::from_message_checked(response, config))
+        .transpose()?;
+    block_send(root, receiver_state, config, store, cache).await
+}
+
+/// Like `request`, but for incremental syncs where the "server" is known
+/// to have the complete DAG below `base_root` already, e.g. from an earlier push.
+///
+/// Only blocks below `root` that aren't part of the DAG below `base_root` are
+/// walked and sent. This is more precise than relying on the bloom filter in
+/// `last_response`, which is still used for the remaining blocks.
+///
+/// The complete DAG below `base_root` needs to be available in `store`.
+/// If the server is missing parts of it, the push can't finish.
+pub async fn request_delta(
+    root: Cid,
+    base_root: Cid,
+    last_response: Option<PushResponse>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<CarFile, Error> {
+    let receiver_state = last_response
+        .map(|response| ReceiverState::from_message_checked(response, config))
+        .transpose()?;
+    block_send_delta(root, base_root, receiver_state, config, store, cache).await
+}
+
+/// Like `request`, but sends the `precomputed` CAR file for cold transfers,
+/// e.g. on the first request for `root`, instead of walking the store.
+///
+/// The precomputed CAR file should be the result of a cold `request` with
+/// the same `root` and `config`, e.g. generated when the content is published.
+/// Subsequent rounds, or precomputed CAR files that exceed the
+/// `config.receive_maximum`, fall back to `request`.
+pub async fn request_from_car(
+    root: Cid,
+    precomputed: CarFile,
+    last_response: Option<PushResponse>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<CarFile, Error> {
+    let receiver_state = last_response
+        .map(|response| ReceiverState::from_message_checked(response, config))
+        .transpose()?;
+
+    let is_cold = receiver_state
+        .as_ref()
+        .map_or(true, |state| state.is_cold(root));
+    if is_cold && precomputed.bytes.len() <= config.receive_maximum {
+        tracing::debug!(%root, "Sending precomputed CAR file for cold push");
+        return Ok(precomputed);
+    }
+
     block_send(root, receiver_state, config, store, cache).await
 }
 
@@ -39,15 +105,35 @@ pub async fn request(
 pub async fn request_streaming<'a>(
     root: Cid,
     last_response: Option<PushResponse>,
+    config: &Config,
     store: impl BlockStore + 'a,
     cache: impl Cache + 'a,
 ) -> Result<CarStream<'a>, Error> {
     let receiver_state = last_response.map(|s| s.into());
-    let block_stream = block_send_block_stream(root, receiver_state, store, cache).await?;
+    let block_stream = block_send_block_stream(root, receiver_state, config, store, cache).await?;
     let car_stream = stream_car_frames(block_stream).await?;
     Ok(car_stream)
 }
 
+/// Like `request_streaming`, but also returns a handle to stop the stream early.
+///
+/// Once the "server" answered, e.g. with a `PushResponse` that
+/// `indicates_finished`, the rest of the CAR stream isn't needed anymore.
+/// Calling `AbortHandle::abort` ends the stream at the next frame, without
+/// reading any more blocks from the store, instead of relying on the
+/// transport to close the stream.
+pub async fn request_streaming_abortable<'a>(
+    root: Cid,
+    last_response: Option<PushResponse>,
+    config: &Config,
+    store: impl BlockStore + 'a,
+    cache: impl Cache + 'a,
+) -> Result<(CarStream<'a>, AbortHandle), Error> {
+    let car_stream = request_streaming(root, last_response, config, store, cache).await?;
+    let (car_stream, abort_handle) = futures::stream::abortable(car_stream);
+    Ok((boxed_stream(car_stream), abort_handle))
+}
+
 /// Create a response for a CAR mirror push request.
 ///
 /// This takes in the CAR file from the request body and stores its blocks
@@ -63,9 +149,8 @@ pub async fn response(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<PushResponse, Error> {
-    Ok(block_receive(root, Some(request), config, store, cache)
-        .await?
-        .into())
+    let receiver_state = block_receive(root, Some(request), config, store, cache).await?;
+    Ok(into_response(receiver_state, config))
 }
 
 /// Respond to a push request on the "server" side in a streaming fashing
@@ -74,6 +159,10 @@ pub async fn response(
 /// This will read from the `request` until the server realizes it got
 /// some bytes it already had. Then it'll create an updated bloom filter
 /// and send a `PushResponse`, interrupting the incoming stream.
+///
+/// To find out where and why a round got interrupted, set
+/// `Config::verification_observer` and implement
+/// `VerificationObserver::on_round_interrupted`.
 pub async fn response_streaming(
     root: Cid,
     request: impl tokio::io::AsyncRead + Unpin + CondSend,
@@ -81,15 +170,69 @@ pub async fn response_streaming(
     store: impl BlockStore,
     cache: impl Cache,
 ) -> Result<PushResponse, Error> {
-    Ok(
-        block_receive_car_stream(root, request, config, store, cache)
-            .await?
-            .into(),
-    )
+    let receiver_state = block_receive_car_stream(root, request, config, store, cache).await?;
+    Ok(into_response(receiver_state, config))
+}
+
+/// Like `response_streaming`, but resumes verification from the `checkpoint`
+/// the client echoed from the last response, and issues a new checkpoint
+/// with the response, unless the push is finished.
+///
+/// This lets servers that don't keep state between rounds avoid walking the
+/// DAG they already have below the `root` on every round.
+/// See `checkpoint::CheckpointKey` for details.
+///
+/// Checkpoints that don't fit into a header are omitted, see
+/// `checkpoint::fits_header`, so the next round starts from the `root` again.
+///
+/// Fails with `Error::InvalidCheckpoint` if the checkpoint wasn't issued for
+/// `root` with given `key`.
+pub async fn response_streaming_with_checkpoint(
+    root: Cid,
+    request: impl tokio::io::AsyncRead + Unpin + CondSend,
+    checkpoint: Option<&[u8]>,
+    key: &CheckpointKey,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<PushResponse, Error> {
+    let want_cids = match checkpoint {
+        Some(checkpoint) => key.open(root, checkpoint)?,
+        None => vec![root],
+    };
+
+    let receiver_state =
+        block_receive_car_stream_resumed(root, want_cids, request, config, store, cache).await?;
+
+    let checkpoint = (!receiver_state.missing_subgraph_roots.is_empty())
+        .then(|| key.seal(root, &receiver_state.missing_subgraph_roots))
+        .filter(|checkpoint| {
+            let fits = checkpoint::fits_header(checkpoint);
+            if !fits {
+                tracing::debug!(
+                    %root,
+                    missing_subgraph_roots = receiver_state.missing_subgraph_roots.len(),
+                    "Omitting checkpoint that's too large for a header"
+                );
+            }
+            fits
+        });
+
+    Ok(PushResponse {
+        checkpoint,
+        ..into_response(receiver_state, config)
+    })
+}
+
+fn into_response(receiver_state: ReceiverState, config: &Config) -> PushResponse {
+    PushResponse {
+        cid_encoding: config.cid_encoding,
+        ..PushResponse::from(receiver_state)
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::{
         cache::{InMemoryCache, NoCache},
         common::Config,
@@ -102,12 +245,14 @@ mod tests {
     };
     use anyhow::Result;
     use futures::TryStreamExt;
-    use libipld::Cid;
+    use iroh_car::CarReader;
+    use libipld::{cbor::DagCborCodec, Cid, Ipld};
+    use libipld_core::multihash::Multihash;
     use proptest::collection::vec;
-    use std::collections::HashSet;
+    use std::{collections::HashSet, io::Cursor};
     use testresult::TestResult;
     use tokio_util::io::StreamReader;
-    use wnfs_common::{BlockStore, MemoryBlockStore};
+    use wnfs_common::{encode, BlockStore, MemoryBlockStore, CODEC_RAW};
 
     pub(crate) async fn simulate_protocol(
         root: Cid,
@@ -159,6 +304,68 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test(async_std::test)]
+    async fn test_single_raw_block_transfer() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let server_store = &MemoryBlockStore::new();
+        let root = client_store
+            .put_block(b"Hello World".to_vec(), CODEC_RAW)
+            .await?;
+
+        let metrics =
+            simulate_protocol(root, &Config::default(), client_store, server_store).await?;
+
+        assert_eq!(metrics.len(), 1);
+        assert!(server_store.has_block(&root).await?);
+
+        // The CAR file names the single block as its root
+        let stream =
+            push::request_streaming(root, None, &Config::default(), client_store, &NoCache).await?;
+        let reader =
+            CarReader::new(StreamReader::new(stream.map_err(std::io::Error::other))).await?;
+        assert_eq!(reader.header().roots(), &[root]);
+        let blocks = reader.stream().try_collect::<Vec<_>>().await?;
+        assert_eq!(blocks, vec![(root, b"Hello World".to_vec())]);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_identity_cid_root_transfer() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let server_store = &MemoryBlockStore::new();
+        let root = Cid::new_v1(CODEC_RAW, Multihash::wrap(0x00, b"Hello World")?);
+
+        // Neither store needs the block, its data is inlined in the CID
+        let metrics =
+            simulate_protocol(root, &Config::default(), client_store, server_store).await?;
+        assert_eq!(metrics.len(), 1);
+
+        let empty_root = Cid::new_v1(CODEC_RAW, Multihash::wrap(0x00, &[])?);
+        let metrics =
+            simulate_protocol(empty_root, &Config::default(), client_store, server_store).await?;
+        assert_eq!(metrics.len(), 1);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_identity_cid_root_links_transfer() -> TestResult {
+        let client_store = &MemoryBlockStore::new();
+        let server_store = &MemoryBlockStore::new();
+        let leaf = client_store
+            .put_block(b"Hello World".to_vec(), CODEC_RAW)
+            .await?;
+        let root_bytes = encode(&Ipld::List(vec![Ipld::Link(leaf)]), DagCborCodec)?;
+        let root = Cid::new_v1(DagCborCodec.into(), Multihash::wrap(0x00, &root_bytes)?);
+
+        simulate_protocol(root, &Config::default(), client_store, server_store).await?;
+
+        assert!(server_store.has_block(&leaf).await?);
+
+        Ok(())
+    }
+
     #[test_log::test(async_std::test)]
     async fn test_streaming_transfer() -> TestResult {
         let client_store = MemoryBlockStore::new();
@@ -175,8 +382,14 @@ mod tests {
 
         let mut last_response = None;
         loop {
-            let stream =
-                push::request_streaming(root, last_response, &client_store, &client_cache).await?;
+            let stream = push::request_streaming(
+                root,
+                last_response,
+                &Config::default(),
+                &client_store,
+                &client_cache,
+            )
+            .await?;
 
             let byte_stream = StreamReader::new(
                 stream.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
@@ -195,6 +408,69 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test(async_std::test)]
+    async fn test_streaming_request_abort() -> TestResult {
+        let (root, ref client_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
+
+        let (mut stream, abort_handle) = push::request_streaming_abortable(
+            root,
+            None,
+            &Config::default(),
+            client_store,
+            NoCache,
+        )
+        .await?;
+
+        // The CAR header and the root block
+        assert!(stream.try_next().await?.is_some());
+        assert!(stream.try_next().await?.is_some());
+
+        abort_handle.abort();
+        assert!(stream.try_next().await?.is_none());
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_delta_transfer() -> TestResult {
+        let (root, ref client_store) = setup_random_dag(256, 1024).await?;
+        let base_root = get_cid_at_approx_path(vec![0], root, client_store).await?;
+        let server_store = &MemoryBlockStore::new();
+        let config = &Config {
+            receive_maximum: 10_000_000,
+            ..Config::default()
+        };
+
+        // The server already has the complete base DAG
+        simulate_protocol(base_root, config, client_store, server_store).await?;
+        let base_cids = DagWalk::breadth_first([base_root])
+            .stream(client_store, &NoCache)
+            .and_then(|item| async move { item.to_cid() })
+            .try_collect::<HashSet<_>>()
+            .await?;
+
+        let request =
+            push::request_delta(root, base_root, None, config, client_store, NoCache).await?;
+        let sent_cids = CarReader::new(Cursor::new(request.bytes.clone()))
+            .await?
+            .stream()
+            .map_ok(|(cid, _)| cid)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        // Only blocks that aren't part of the base DAG are sent
+        assert!(sent_cids.iter().all(|cid| !base_cids.contains(cid)));
+        assert_eq!(
+            sent_cids.len(),
+            total_dag_blocks(root, client_store).await? - base_cids.len()
+        );
+
+        let response = push::response(root, request, config, server_store, NoCache).await?;
+        assert!(response.indicates_finished());
+
+        Ok(())
+    }
+
     #[test_log::test(async_std::test)]
     async fn test_deduplicating_transfer() -> TestResult {
         let (root, ref client_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
@@ -65,9 +65,10 @@ where
     deserializer.deserialize_any(BytesOrStringVisitor)
 }
 
-pub(crate) fn serialize<S>(bloom_bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+pub(crate) fn serialize<S, B>(bloom_bytes: &B, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
+    B: AsRef<[u8]> + ?Sized,
 {
     if serializer.is_human_readable() {
         serializer.serialize_str(
@@ -1,28 +1,125 @@
-use std::str::FromStr;
+use std::{io::Cursor, str::FromStr};
 
+use crate::messages::CidEncoding;
 use libipld::Cid;
-use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+use serde::{
+    de::{SeqAccess, Visitor},
+    ser::SerializeSeq,
+    Deserializer, Serializer,
+};
 
+/// Deserializes CIDs from either encoding, i.e. from an array of strings
+/// or from a single byte string of concatenated binary CIDs.
 pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Cid>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let strings: Vec<String> = Vec::<String>::deserialize(deserializer)?;
-    let cids = strings
-        .iter()
-        .map(|s| Cid::from_str(s))
-        .collect::<Result<Vec<Cid>, _>>()
-        .map_err(serde::de::Error::custom)?;
+    let (cids, _) = deserialize_with_encoding(deserializer)?;
     Ok(cids)
 }
 
-pub(crate) fn serialize<S>(cids: &Vec<Cid>, serializer: S) -> Result<S::Ok, S::Error>
+/// Like `deserialize`, but also returns the encoding that the CIDs were
+/// found in, so it can be checked against a message's declared version.
+pub(crate) fn deserialize_with_encoding<'de, D>(
+    deserializer: D,
+) -> Result<(Vec<Cid>, CidEncoding), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct CidsVisitor;
+
+    impl<'de> Visitor<'de> for CidsVisitor {
+        type Value = (Vec<Cid>, CidEncoding);
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            formatter.write_str("an array of CID strings or bytes of concatenated binary CIDs")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut cids = Vec::new();
+            while let Some(string) = seq.next_element::<String>()? {
+                cids.push(Cid::from_str(&string).map_err(serde::de::Error::custom)?);
+            }
+            Ok((cids, CidEncoding::Strings))
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok((read_binary_cids(v)?, CidEncoding::Binary))
+        }
+
+        fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok((read_binary_cids(v)?, CidEncoding::Binary))
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok((read_binary_cids(&v)?, CidEncoding::Binary))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            let bytes = data_encoding::BASE64URL_NOPAD
+                .decode(v.as_bytes())
+                .map_err(serde::de::Error::custom)?;
+            Ok((read_binary_cids(&bytes)?, CidEncoding::Binary))
+        }
+    }
+
+    deserializer.deserialize_any(CidsVisitor)
+}
+
+pub(crate) fn serialize<'a, S, I>(cids: I, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
+    I: IntoIterator<Item = &'a Cid>,
+    I::IntoIter: ExactSizeIterator,
 {
+    let cids = cids.into_iter();
     let mut seq = serializer.serialize_seq(Some(cids.len()))?;
     for cid in cids {
         seq.serialize_element(&cid.to_string())?;
     }
     seq.end()
 }
+
+/// Serializes CIDs as a single byte string of concatenated binary CIDs,
+/// or its base64url encoding for human-readable formats.
+pub(crate) fn serialize_binary<'a, S, I>(cids: I, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    I: IntoIterator<Item = &'a Cid>,
+{
+    let mut bytes = Vec::new();
+    for cid in cids {
+        cid.write_bytes(&mut bytes)
+            .map_err(serde::ser::Error::custom)?;
+    }
+
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&data_encoding::BASE64URL_NOPAD.encode(&bytes))
+    } else {
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+fn read_binary_cids<E: serde::de::Error>(bytes: &[u8]) -> Result<Vec<Cid>, E> {
+    let mut reader = Cursor::new(bytes);
+    let mut cids = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        cids.push(Cid::read_bytes(&mut reader).map_err(serde::de::Error::custom)?);
+    }
+    Ok(cids)
+}
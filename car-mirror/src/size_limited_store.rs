@@ -0,0 +1,109 @@
+use crate::common::Config;
+use bytes::Bytes;
+use libipld::Cid;
+use wnfs_common::{utils::CondSend, BlockStore, BlockStoreError};
+
+/// A block store wrapper that rejects writing blocks over a maximum size
+/// with `BlockStoreError::MaximumBlockSizeExceeded`.
+///
+/// Receiving blocks already checks `Config::max_block_size`, but this makes sure
+/// no oversized block lands in the store, no matter which code path writes it.
+/// Wrap it around the innermost store, e.g. below `cache::CacheMissing`.
+#[derive(Debug, Clone)]
+pub struct SizeLimitedBlockStore<B> {
+    inner: B,
+    max_block_size: usize,
+}
+
+impl<B> SizeLimitedBlockStore<B> {
+    /// Wrap given store, rejecting blocks with more than `max_block_size` bytes.
+    pub fn new(inner: B, max_block_size: usize) -> Self {
+        Self {
+            inner,
+            max_block_size,
+        }
+    }
+
+    /// Wrap given store, rejecting blocks over `Config::max_block_size`.
+    pub fn from_config(inner: B, config: &Config) -> Self {
+        Self::new(inner, config.max_block_size)
+    }
+
+    /// The wrapped store.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B: BlockStore> BlockStore for SizeLimitedBlockStore<B> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        self.inner.get_block(cid).await
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        let bytes = bytes.into();
+        if bytes.len() > self.max_block_size {
+            tracing::warn!(%cid, num_bytes = bytes.len(), max_block_size = self.max_block_size, "Rejecting oversized block");
+            return Err(BlockStoreError::MaximumBlockSizeExceeded(bytes.len()));
+        }
+
+        self.inner.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.inner.has_block(cid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::Config,
+        push,
+        test_utils::{setup_random_dag, total_dag_blocks},
+    };
+    use assert_matches::assert_matches;
+    use testresult::TestResult;
+    use wnfs_common::{MemoryBlockStore, CODEC_RAW};
+
+    #[test_log::test(async_std::test)]
+    async fn test_rejects_oversized_blocks() -> TestResult {
+        let store = &SizeLimitedBlockStore::new(MemoryBlockStore::new(), 1024);
+
+        let cid = store.put_block(vec![1; 1024], CODEC_RAW).await?;
+        assert!(store.has_block(&cid).await?);
+
+        let result = store.put_block(vec![2; 1025], CODEC_RAW).await;
+        assert_matches!(result, Err(BlockStoreError::MaximumBlockSizeExceeded(1025)));
+
+        let oversized_cid = MemoryBlockStore::new()
+            .put_block(vec![2; 1025], CODEC_RAW)
+            .await?;
+        let result = store.put_block_keyed(oversized_cid, vec![2; 1025]).await;
+        assert_matches!(result, Err(BlockStoreError::MaximumBlockSizeExceeded(1025)));
+        assert!(!store.inner().has_block(&oversized_cid).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_receives_blocks_within_limit() -> TestResult {
+        let (root, ref client_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config::default();
+        let server_store = &SizeLimitedBlockStore::from_config(MemoryBlockStore::new(), config);
+
+        push::tests::simulate_protocol(root, config, client_store, server_store).await?;
+
+        assert_eq!(
+            total_dag_blocks(root, server_store).await?,
+            total_dag_blocks(root, client_store).await?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::Error;
+use bytes::Bytes;
+use libipld::Cid;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use wnfs_common::{utils::CondSend, BlockStore, BlockStoreError};
+
+/// A block store wrapper that keeps all written blocks in a staging area
+/// in memory, instead of writing them to the inner store right away.
+///
+/// Reads see both the staged blocks and the blocks in the inner store,
+/// so this can be passed to `block_receive` or the `push::response` and
+/// `pull::handle_response` functions in place of the inner store.
+///
+/// Once the receiving end reports that there are no missing subgraph roots
+/// anymore, i.e. the whole DAG is verified complete, call `promote` to move
+/// the staged blocks into the inner store. On failure, call `discard` instead.
+/// This gives all-or-nothing semantics for receiving DAGs.
+///
+/// Clones share the same staging area.
+///
+/// # Example
+///
+/// ```
+/// # use anyhow::Result;
+/// # use car_mirror::{cache::NoCache, common::{block_receive, block_send, Config}, staging::StagingBlockStore};
+/// # use wnfs_common::{BlockStore, MemoryBlockStore};
+/// # use wnfs_unixfs_file::builder::FileBuilder;
+/// #
+/// # #[async_std::main]
+/// # async fn main() -> Result<()> {
+/// # let sender_store = MemoryBlockStore::new();
+/// # let root = FileBuilder::new()
+/// #     .content_bytes(vec![42; 500_000])
+/// #     .build()?
+/// #     .store(&sender_store)
+/// #     .await?;
+/// let config = &Config::default();
+/// let store = MemoryBlockStore::new();
+/// let staging = StagingBlockStore::new(store.clone());
+///
+/// let mut last_state = None;
+/// loop {
+///     let car = block_send(root, last_state, config, &sender_store, NoCache).await?;
+///     let state = block_receive(root, Some(car), config, &staging, NoCache).await?;
+///     if state.missing_subgraph_roots.is_empty() {
+///         break;
+///     }
+///     last_state = Some(state);
+/// }
+///
+/// assert!(!store.has_block(&root).await?);
+/// staging.promote().await?;
+/// assert!(store.has_block(&root).await?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct StagingBlockStore<B: BlockStore> {
+    inner: B,
+    staged: Arc<RwLock<HashMap<Cid, Bytes>>>,
+}
+
+impl<B: BlockStore> StagingBlockStore<B> {
+    /// Create a new staging area in front of given inner store.
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            staged: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Get a reference to the inner store.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// The number of blocks currently staged.
+    pub fn staged_len(&self) -> Result<usize, BlockStoreError> {
+        Ok(self
+            .staged
+            .read()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .len())
+    }
+
+    /// Move all staged blocks into the inner store.
+    ///
+    /// The staging area is emptied before writing, so concurrent reads may
+    /// briefly miss blocks. If writing to the inner store fails, all blocks are
+    /// staged again, so `promote` can be retried. Blocks that were already
+    /// written will be written again on retry.
+    ///
+    /// Returns the number of promoted blocks.
+    pub async fn promote(&self) -> Result<usize, Error> {
+        let staged = std::mem::take(
+            &mut *self
+                .staged
+                .write()
+                .map_err(|_| BlockStoreError::from(anyhow::anyhow!("Poisoned lock")))?,
+        );
+
+        let mut result = Ok(());
+        for (cid, bytes) in staged.iter() {
+            result = self.inner.put_block_keyed(*cid, bytes.clone()).await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        if let Err(e) = result {
+            if let Ok(mut restaged) = self.staged.write() {
+                restaged.extend(staged);
+            }
+            return Err(e.into());
+        }
+
+        Ok(staged.len())
+    }
+
+    /// Drop all staged blocks without writing them to the inner store.
+    ///
+    /// Returns the number of discarded blocks.
+    pub fn discard(&self) -> Result<usize, BlockStoreError> {
+        let mut staged = self
+            .staged
+            .write()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+        let discarded = staged.len();
+        staged.clear();
+        Ok(discarded)
+    }
+}
+
+impl<B: BlockStore> BlockStore for StagingBlockStore<B> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        let staged = self
+            .staged
+            .read()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .get(cid)
+            .cloned();
+
+        match staged {
+            Some(bytes) => Ok(bytes),
+            None => self.inner.get_block(cid).await,
+        }
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        self.staged
+            .write()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .insert(cid, bytes.into());
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        let staged = self
+            .staged
+            .read()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .contains_key(cid);
+
+        if staged {
+            return Ok(true);
+        }
+
+        self.inner.has_block(cid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::{block_receive, block_send, Config},
+        test_utils::{setup_random_dag, total_dag_blocks},
+    };
+    use testresult::TestResult;
+    use wnfs_common::MemoryBlockStore;
+
+    async fn receive_into(
+        root: Cid,
+        sender_store: &MemoryBlockStore,
+        receiver_store: &StagingBlockStore<MemoryBlockStore>,
+    ) -> TestResult {
+        let config = &Config::default();
+        let mut last_state = None;
+        loop {
+            let car = block_send(root, last_state, config, sender_store, NoCache).await?;
+            let state = block_receive(root, Some(car), config, receiver_store, NoCache).await?;
+            if state.missing_subgraph_roots.is_empty() {
+                return Ok(());
+            }
+            last_state = Some(state);
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_staging_block_store_promote() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
+        let store = MemoryBlockStore::new();
+        let staging = StagingBlockStore::new(store.clone());
+
+        receive_into(root, sender_store, &staging).await?;
+
+        let dag_blocks = total_dag_blocks(root, sender_store).await?;
+        assert!(!store.has_block(&root).await?);
+        assert_eq!(total_dag_blocks(root, &staging).await?, dag_blocks);
+        assert_eq!(staging.staged_len()?, dag_blocks);
+
+        assert_eq!(staging.promote().await?, dag_blocks);
+        assert_eq!(staging.staged_len()?, 0);
+        assert_eq!(total_dag_blocks(root, &store).await?, dag_blocks);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_staging_block_store_discard() -> TestResult {
+        let (root, ref sender_store) = setup_random_dag(64, 1024).await?;
+        let store = MemoryBlockStore::new();
+        let staging = StagingBlockStore::new(store.clone());
+
+        receive_into(root, sender_store, &staging).await?;
+
+        let dag_blocks = total_dag_blocks(root, sender_store).await?;
+        assert_eq!(staging.discard()?, dag_blocks);
+        assert!(!staging.has_block(&root).await?);
+        assert!(!store.has_block(&root).await?);
+
+        Ok(())
+    }
+}
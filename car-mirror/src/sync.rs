@@ -0,0 +1,175 @@
+use crate::{
+    cache::Cache,
+    common::{
+        block_receive, block_send_excluding, compute_missing, CarFile, Config, ReceiverState,
+    },
+    error::Error,
+    messages::PullRequest,
+    pull::into_request,
+};
+use libipld::Cid;
+use std::collections::HashSet;
+use wnfs_common::BlockStore;
+
+/// A message of a bidirectional sync, sent from one peer to the other.
+///
+/// It combines the semantics of a `PullRequest`, telling the other peer which
+/// blocks the sending peer still wants, with a CAR file of blocks that the
+/// other peer asked for in its last message, like a push round.
+#[derive(Debug, Clone)]
+pub struct SyncMessage {
+    /// The blocks below the root that the sending peer still wants.
+    pub request: PullRequest,
+    /// Blocks that the receiving peer asked for in its last message.
+    ///
+    /// This is `None` on the first message, if the receiving peer didn't want
+    /// any blocks, or if the sending peer has none of the blocks it wanted.
+    pub car: Option<CarFile>,
+}
+
+/// Handle the `last_message` from the peer and create the next message of a
+/// bidirectional sync of the DAG below `root`.
+///
+/// This is for peers that each have blocks of the DAG that the other lacks,
+/// e.g. in peer-to-peer meshes. Instead of running a push and then a pull,
+/// each message sends the peer the blocks it asked for and asks for the
+/// blocks we still want, so both directions share the round-trips.
+///
+/// Peers take turns: On the first message for a particular `root`, set
+/// `last_message` to `None`. Then send the returned message to the peer,
+/// which calls this function with it and sends its result back, and so on.
+///
+/// Blocks in the CAR file of `last_message` are verified against the `root`
+/// and stored in `store`, just like in `pull::request`. Only blocks from the
+/// parts of the DAG that are in `store` are sent to the peer.
+///
+/// Returns `None` once both peers have the complete DAG below `root`, in
+/// which case no more messages need to be sent.
+///
+/// If some blocks of the DAG are missing from both peers, the sync doesn't
+/// finish, since messages won't carry any blocks. Consider limiting the number
+/// of rounds in that case.
+pub async fn bidirectional(
+    root: Cid,
+    last_message: Option<SyncMessage>,
+    config: &Config,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<Option<SyncMessage>, Error> {
+    let (peer_request, car) = match last_message {
+        Some(SyncMessage { request, car }) => (Some(request), car),
+        None => (None, None),
+    };
+
+    let receiver_state = block_receive(root, car, config, &store, &cache).await?;
+    let request = into_request(receiver_state, config);
+
+    let Some(peer_request) = peer_request else {
+        return Ok(Some(SyncMessage { request, car: None }));
+    };
+
+    if peer_request.indicates_finished() {
+        if request.indicates_finished() {
+            return Ok(None);
+        }
+
+        return Ok(Some(SyncMessage { request, car: None }));
+    }
+
+    // Blocks we're missing ourselves can't be sent, nor can their links be followed
+    let excluded: HashSet<Cid> = compute_missing(root, &store, &cache)
+        .await?
+        .into_iter()
+        .collect();
+    let peer_state = ReceiverState::from_message_checked(peer_request, config)?;
+    let car =
+        block_send_excluding(root, Some(peer_state), excluded, config, &store, &cache).await?;
+
+    Ok(Some(SyncMessage {
+        request,
+        car: (!car.bytes.is_empty()).then_some(car),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::NoCache, test_utils::total_dag_blocks};
+    use libipld::{cbor::DagCborCodec, Ipld};
+    use testresult::TestResult;
+    use wnfs_common::{encode, MemoryBlockStore};
+
+    async fn put_ipld(ipld: &Ipld, stores: &[&MemoryBlockStore]) -> TestResult<Cid> {
+        let bytes = encode(ipld, DagCborCodec)?;
+        let mut cid = None;
+        for store in stores {
+            cid = Some(store.put_block(bytes.clone(), DagCborCodec.into()).await?);
+        }
+        Ok(cid.expect("at least one store"))
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_bidirectional_sync() -> TestResult {
+        let peer_a = &MemoryBlockStore::new();
+        let peer_b = &MemoryBlockStore::new();
+        let config = &Config::default();
+
+        // root ---> node_a ---> a_0, a_1, ...
+        //       \-> node_b ---> b_0, b_1, ...
+        //
+        // Peer A only has the `node_a` half of the DAG, peer B only the `node_b` half.
+        let mut links_a = Vec::new();
+        let mut links_b = Vec::new();
+        for i in 0..10 {
+            links_a.push(Ipld::Link(
+                put_ipld(&Ipld::String(format!("a_{i}")), &[peer_a]).await?,
+            ));
+            links_b.push(Ipld::Link(
+                put_ipld(&Ipld::String(format!("b_{i}")), &[peer_b]).await?,
+            ));
+        }
+        let node_a = put_ipld(&Ipld::List(links_a), &[peer_a]).await?;
+        let node_b = put_ipld(&Ipld::List(links_b), &[peer_b]).await?;
+        let root = put_ipld(
+            &Ipld::List(vec![Ipld::Link(node_a), Ipld::Link(node_b)]),
+            &[peer_a, peer_b],
+        )
+        .await?;
+
+        let mut rounds = 0;
+        let mut last_message = None;
+        let mut peers = [peer_a, peer_b];
+        loop {
+            rounds += 1;
+            assert!(rounds < 10, "sync didn't converge");
+
+            let Some(message) =
+                bidirectional(root, last_message, config, peers[0], NoCache).await?
+            else {
+                break;
+            };
+            last_message = Some(message);
+            peers.reverse();
+        }
+
+        assert_eq!(total_dag_blocks(root, peer_a).await?, 23);
+        assert_eq!(total_dag_blocks(root, peer_b).await?, 23);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_bidirectional_sync_finishes_with_complete_peers() -> TestResult {
+        let peer_a = &MemoryBlockStore::new();
+        let peer_b = &MemoryBlockStore::new();
+        let config = &Config::default();
+        let root = put_ipld(&Ipld::String("root".into()), &[peer_a, peer_b]).await?;
+
+        let message = bidirectional(root, None, config, peer_a, NoCache).await?;
+        assert!(message.is_some());
+        let message = bidirectional(root, message, config, peer_b, NoCache).await?;
+        assert!(message.is_none());
+
+        Ok(())
+    }
+}
@@ -1,9 +1,87 @@
-use crate::common::references;
+use crate::{
+    cache::{Cache, NoCache},
+    common::{block_send_block_stream, references, stream_car_frames, CarStream, Config},
+    dag_walk::DagWalk,
+    incremental_verification::IncrementalDagVerification,
+};
 use anyhow::Result;
 use bytes::Bytes;
+use futures::TryStreamExt;
 use libipld::{Cid, Ipld, IpldCodec};
-use std::io::Write;
-use wnfs_common::{encode, BlockStore, MemoryBlockStore};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use wnfs_common::{
+    encode,
+    utils::{boxed_stream, CondSend},
+    BlockStore, BlockStoreError, MemoryBlockStore,
+};
+
+/// A `MemoryBlockStore` that refuses to store blocks once the total
+/// amount of stored bytes would exceed a cap.
+///
+/// Useful for testing how receiving blocks behaves when the underlying
+/// store rejects writes mid-transfer.
+#[derive(Debug, Clone)]
+pub struct BoundedMemoryBlockStore {
+    store: MemoryBlockStore,
+    max_total_bytes: usize,
+    total_bytes: Arc<AtomicUsize>,
+}
+
+impl BoundedMemoryBlockStore {
+    /// Create an empty store that holds at most `max_total_bytes` of block data.
+    pub fn new(max_total_bytes: usize) -> Self {
+        Self {
+            store: MemoryBlockStore::new(),
+            max_total_bytes,
+            total_bytes: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The total amount of block bytes stored so far.
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes.load(Ordering::SeqCst)
+    }
+}
+
+impl BlockStore for BoundedMemoryBlockStore {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        self.store.get_block(cid).await
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        if self.store.has_block(&cid).await? {
+            // Storing a block twice doesn't take up more space
+            return Ok(());
+        }
+
+        let bytes = bytes.into();
+        let block_bytes = bytes.len();
+        let total_bytes = self.total_bytes.fetch_add(block_bytes, Ordering::SeqCst) + block_bytes;
+        if total_bytes > self.max_total_bytes {
+            self.total_bytes.fetch_sub(block_bytes, Ordering::SeqCst);
+            return Err(BlockStoreError::Custom(anyhow::anyhow!(
+                "Storing {block_bytes} more bytes would exceed the store's maximum of {} bytes",
+                self.max_total_bytes
+            )));
+        }
+
+        self.store.put_block_keyed(cid, bytes).await
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.store.has_block(cid).await
+    }
+}
 
 /// Take a list of dag-cbor IPLD blocks and store all of them as dag-cbor in a
 /// MemoryBlockStore & return it.
@@ -29,6 +107,71 @@ pub async fn setup_existing_blockstore(
     Ok(())
 }
 
+/// Mutate the bytes of a block, so they don't hash to its CID anymore.
+///
+/// Returns the original CID paired with the mutated bytes, e.g. to check that
+/// receivers reject such blocks with `IncrementalVerificationError::DigestMismatch`.
+pub fn corrupt_block(cid: Cid, bytes: Bytes) -> (Cid, Bytes) {
+    let mut bytes = bytes.to_vec();
+    match bytes.last_mut() {
+        Some(last) => *last ^= 0xff,
+        None => bytes.push(0),
+    }
+    (cid, bytes.into())
+}
+
+/// Create the CAR stream of a cold transfer of the DAG below `root` from `store`,
+/// but with the block `corrupt_cid` corrupted via `corrupt_block`.
+pub async fn corrupt_car_stream<'a>(
+    root: Cid,
+    corrupt_cid: Cid,
+    store: impl BlockStore + 'a,
+) -> Result<CarStream<'a>> {
+    let blocks = block_send_block_stream(root, None, &Config::default(), store, NoCache).await?;
+    let blocks = blocks.map_ok(move |(cid, bytes)| {
+        if cid == corrupt_cid {
+            corrupt_block(cid, bytes)
+        } else {
+            (cid, bytes)
+        }
+    });
+    Ok(stream_car_frames(boxed_stream(blocks)).await?)
+}
+
+/// Collect all blocks of the DAG below `root` from `store`, in the
+/// breadth-first order that a cold transfer sends them in.
+pub async fn dag_blocks_breadth_first(
+    root: Cid,
+    store: &impl BlockStore,
+) -> Result<Vec<(Cid, Bytes)>> {
+    Ok(DagWalk::breadth_first([root])
+        .stream_with_blocks(store, &NoCache)
+        .try_collect()
+        .await?)
+}
+
+/// Run incremental verification of the DAG below `root` one block at a time,
+/// verifying and storing `blocks` into `store` in the given order.
+///
+/// Unlike receiving a CAR file, this doesn't parse or frame anything, so it
+/// isolates the cost of `IncrementalDagVerification::verify_and_store_block`,
+/// e.g. for benchmarks. Get the blocks via `dag_blocks_breadth_first`.
+pub async fn verify_blocks_one_by_one(
+    root: Cid,
+    blocks: impl IntoIterator<Item = (Cid, Bytes)>,
+    store: &impl BlockStore,
+    cache: &impl Cache,
+) -> Result<()> {
+    let mut dag_verification = IncrementalDagVerification::new([root], store, cache).await?;
+    for block in blocks {
+        dag_verification
+            .verify_and_store_block(block, store, cache)
+            .await?;
+    }
+
+    Ok(())
+}
+
 /// Print a DAG as a dot file with truncated CIDs
 pub fn dag_to_dot(
     writer: &mut impl Write,
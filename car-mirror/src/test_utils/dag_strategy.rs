@@ -1,6 +1,7 @@
+use crate::common::compute_cid;
 use bytes::Bytes;
 use libipld::{Cid, Ipld, IpldCodec};
-use libipld_core::multihash::{Code, MultihashDigest};
+use libipld_core::multihash::Code;
 use proptest::{
     prelude::{Rng, RngCore},
     strategy::Strategy,
@@ -30,7 +31,7 @@ pub fn arb_ipld_dag<T: Debug + Clone>(
 pub fn links_to_ipld(cids: Vec<Cid>, _: &mut TestRng) -> (Cid, Ipld) {
     let ipld = Ipld::List(cids.into_iter().map(Ipld::Link).collect());
     let bytes = encode(&ipld, IpldCodec::DagCbor).unwrap();
-    let cid = Cid::new_v1(IpldCodec::DagCbor.into(), Code::Blake3_256.digest(&bytes));
+    let cid = compute_cid(IpldCodec::DagCbor.into(), Code::Blake3_256, &bytes);
     (cid, ipld)
 }
 
@@ -38,7 +39,7 @@ pub fn links_to_ipld(cids: Vec<Cid>, _: &mut TestRng) -> (Cid, Ipld) {
 pub fn links_to_dag_cbor(cids: Vec<Cid>, _: &mut TestRng) -> (Cid, Bytes) {
     let ipld = Ipld::List(cids.into_iter().map(Ipld::Link).collect());
     let bytes: Bytes = encode(&ipld, IpldCodec::DagCbor).unwrap().into();
-    let cid = Cid::new_v1(IpldCodec::DagCbor.into(), Code::Blake3_256.digest(&bytes));
+    let cid = compute_cid(IpldCodec::DagCbor.into(), Code::Blake3_256, &bytes);
     (cid, bytes)
 }
 
@@ -71,7 +72,7 @@ pub fn links_to_padded_ipld(
         };
 
         let bytes = encode(&ipld, codec).unwrap();
-        let cid = Cid::new_v1(codec.into(), Code::Blake3_256.digest(&bytes));
+        let cid = compute_cid(codec.into(), Code::Blake3_256, &bytes);
         (cid, ipld)
     }
 }
@@ -1,6 +1,6 @@
 ///! Crate-local test utilities
 use super::{arb_ipld_dag, links_to_padded_ipld, setup_blockstore, Rvg};
-use crate::{cache::NoCache, common::references, dag_walk::DagWalk, error::Error};
+use crate::{cache::NoCache, common::references, dag_walk::DagWalk};
 use anyhow::Result;
 use futures::TryStreamExt;
 use libipld::{Cid, Ipld};
@@ -70,15 +70,8 @@ pub(crate) async fn setup_random_dag(
 
 pub(crate) async fn total_dag_bytes(root: Cid, store: &impl BlockStore) -> Result<usize> {
     Ok(DagWalk::breadth_first([root])
-        .stream(store, &NoCache)
-        .and_then(|item| async move { item.to_cid() })
-        .try_filter_map(|cid| async move {
-            let block = store
-                .get_block(&cid)
-                .await
-                .map_err(Error::BlockStoreError)?;
-            Ok(Some(block.len()))
-        })
+        .stream_with_blocks(store, &NoCache)
+        .map_ok(|(_, block)| block.len())
         .try_collect::<Vec<_>>()
         .await?
         .into_iter()
@@ -0,0 +1,756 @@
+use crate::{
+    cache::Cache,
+    common::{compute_cid, CarStream, Config, ReceiverState},
+    error::Error,
+    incremental_verification::{BlockState, VerificationObserver},
+    messages::PushResponse,
+    pull, push,
+};
+use bytes::Bytes;
+use futures::{Future, TryStreamExt};
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld, IpldCodec};
+use libipld_core::multihash::Code;
+use serde_ipld_dagcbor::{DecodeError, EncodeError};
+use std::{
+    collections::{BTreeMap, TryReserveError},
+    convert::Infallible,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio_util::io::StreamReader;
+use wnfs_common::{
+    utils::{boxed_stream, BoxStream, CondSend},
+    BlockStore, BlockStoreError,
+};
+
+/// A way of exchanging car mirror messages with a remote peer, e.g. via
+/// HTTP requests, WebSockets or libp2p streams.
+///
+/// Transports only move bytes, `drive_push` and `drive_pull` take care of
+/// running the protocol rounds until they're finished.
+pub trait Transport {
+    /// The error type of this transport.
+    type Error;
+
+    /// Send one round of a push for `root` to the peer and return the
+    /// dag-cbor encoded `PushResponse` it answered with.
+    ///
+    /// The peer may answer before `request` is fully sent, in which case
+    /// the rest of it can be dropped.
+    ///
+    /// `checkpoint` is the `PushResponse::checkpoint` from the last round,
+    /// if any, which should be echoed back to the peer.
+    fn send_push_round(
+        &mut self,
+        root: Cid,
+        request: CarStream<'static>,
+        checkpoint: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<Bytes, Self::Error>>;
+
+    /// Send a dag-cbor encoded `PullRequest` for `root` to the peer and
+    /// return the CAR file it answers with as a stream of bytes.
+    fn recv_pull_round(
+        &mut self,
+        root: Cid,
+        request: Bytes,
+    ) -> impl Future<Output = Result<BoxStream<'static, Result<Bytes, std::io::Error>>, Self::Error>>;
+}
+
+/// Statistics about a finished protocol run via `drive_push` or `drive_pull`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// The number of rounds it took to finish the protocol.
+    pub rounds: usize,
+    /// The total number of bytes sent to the peer.
+    pub bytes_sent: usize,
+    /// The total number of bytes received from the peer.
+    pub bytes_received: usize,
+    /// The number of blocks transferred, i.e. sent to the peer for pushes,
+    /// or received and stored for pulls.
+    ///
+    /// For pushes this may include blocks the peer ended up not reading,
+    /// if it answered before the round was finished.
+    pub blocks_transferred: usize,
+    /// The number of received blocks that couldn't be shown to be linked to
+    /// from the root yet, see `VerificationObserver::on_unexpected_block`.
+    ///
+    /// Honest peers mostly send these because of bloom filter false positives,
+    /// so a high count relative to `rounds` means the bloom filter is sized
+    /// too small, see `Config::bloom_fpr`.
+    ///
+    /// Only pulls receive blocks, so this is always 0 for pushes.
+    pub unexpected_blocks: usize,
+    /// A record of all transferred blocks, if `Config::record_manifest` is set.
+    pub manifest: Option<Manifest>,
+}
+
+/// A record of the blocks that crossed the wire during a protocol run via
+/// `drive_push` or `drive_pull`, e.g. for audit logs.
+///
+/// Like `TransferStats::blocks_transferred`, for pushes these are the blocks
+/// sent to the peer, for pulls the blocks received and stored.
+///
+/// Encoded as dag-cbor, manifests are content-addressed via `Manifest::cid`,
+/// so they can be signed or compared cheaply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// The root of the transferred DAG
+    pub root: Cid,
+    /// The CIDs of all transferred blocks in transfer order
+    pub cids: Vec<Cid>,
+    /// The total number of bytes of the transferred blocks, without CAR framing
+    pub total_bytes: u64,
+}
+
+/// Run (possibly multiple rounds of) the car mirror push protocol via given transport,
+/// until the peer has the complete DAG under `root`.
+///
+/// The full DAG under `root` needs to be available in `store`.
+///
+/// Each round emits an info-level event with the `car_mirror::round` target,
+/// reporting request & response bytes and whether the round made progress.
+pub async fn drive_push<T>(
+    root: Cid,
+    config: &Config,
+    transport: &mut T,
+    store: impl BlockStore + Clone + 'static,
+    cache: impl Cache + Clone + 'static,
+) -> Result<TransferStats, T::Error>
+where
+    T: Transport,
+    T::Error: From<Error> + From<DecodeError<Infallible>>,
+{
+    let mut stats = TransferStats::default();
+    let mut last_response: Option<PushResponse> = None;
+    let manifest = config
+        .record_manifest
+        .then(|| Arc::new(Mutex::new(Manifest::new(root))));
+
+    loop {
+        stats.rounds += 1;
+
+        let checkpoint = last_response
+            .as_ref()
+            .and_then(|response| response.checkpoint.clone());
+
+        // The peer may answer before we've finished sending, e.g. when it
+        // finds out it already has some blocks, or that it has everything.
+        // We stop producing CAR frames at that point.
+        let (car_stream, stop_upload) = push::request_streaming_abortable(
+            root,
+            last_response.clone(),
+            config,
+            store.clone(),
+            cache.clone(),
+        )
+        .await?;
+
+        // Each chunk is a CAR frame. The first one is the CAR header.
+        let request_bytes = Arc::new(AtomicUsize::new(0));
+        let request_chunks = Arc::new(AtomicUsize::new(0));
+        let request_bytes_counter = Arc::clone(&request_bytes);
+        let request_chunks_counter = Arc::clone(&request_chunks);
+        let manifest_recorder = manifest.clone();
+        let car_stream = boxed_stream(car_stream.inspect_ok(move |chunk| {
+            request_bytes_counter.fetch_add(chunk.len(), Ordering::Relaxed);
+            let chunks = request_chunks_counter.fetch_add(1, Ordering::Relaxed);
+            if let (Some(manifest), true) = (&manifest_recorder, chunks > 0) {
+                if let Some((cid, block_bytes)) = parse_car_frame(chunk) {
+                    if let Ok(mut manifest) = manifest.lock() {
+                        manifest.record(cid, block_bytes);
+                    }
+                }
+            }
+        }));
+
+        let response = transport
+            .send_push_round(root, car_stream, checkpoint)
+            .await;
+        stop_upload.abort();
+        let response_bytes = response?;
+
+        let push_response = PushResponse::from_dag_cbor(&response_bytes)?;
+        // Don't request an arbitrary amount of subgraphs from a malicious peer
+        push_response.validate(config)?;
+
+        let request_bytes = request_bytes.load(Ordering::Relaxed);
+        stats.bytes_sent += request_bytes;
+        stats.bytes_received += response_bytes.len();
+        stats.blocks_transferred += request_chunks.load(Ordering::Relaxed).saturating_sub(1);
+
+        log_round(
+            "push",
+            root,
+            stats.rounds,
+            request_bytes,
+            response_bytes.len(),
+            last_response.as_ref() != Some(&push_response),
+        );
+
+        if push_response.indicates_finished() {
+            stats.manifest = manifest.and_then(|manifest| Some(manifest.lock().ok()?.clone()));
+            return Ok(stats);
+        }
+
+        last_response = Some(push_response);
+    }
+}
+
+/// Run (possibly multiple rounds of) the car mirror pull protocol via given transport,
+/// until `store` contains the complete DAG under `root`.
+///
+/// If a round fails with a recoverable error, e.g. because of a corrupted block
+/// in the middle of the response, a fresh round is started from the blocks stored
+/// so far, up to `config.round_retries` times. See `Error::is_recoverable`.
+///
+/// Each round emits an info-level event with the `car_mirror::round` target,
+/// reporting request & response bytes and whether the round made progress.
+pub async fn drive_pull<T>(
+    root: Cid,
+    config: &Config,
+    transport: &mut T,
+    store: impl BlockStore,
+    cache: impl Cache,
+) -> Result<TransferStats, T::Error>
+where
+    T: Transport,
+    T::Error: From<Error> + From<EncodeError<TryReserveError>>,
+{
+    let mut stats = TransferStats::default();
+    let store = &WriteCountingBlockStore {
+        store,
+        blocks_written: AtomicUsize::new(0),
+        manifest: config
+            .record_manifest
+            .then(|| Mutex::new(Manifest::new(root))),
+    };
+    let observer = Arc::new(CountingObserver {
+        inner: config.verification_observer.clone(),
+        unexpected_blocks: AtomicUsize::new(0),
+    });
+    let config = &Config {
+        verification_observer: Some(observer.clone()),
+        ..config.clone()
+    };
+    let mut pull_request = pull::request(root, None, config, store, &cache).await?;
+    let mut retries = 0;
+
+    while !pull_request.indicates_finished() {
+        stats.rounds += 1;
+
+        let request_body = Bytes::from(pull_request.to_dag_cbor()?);
+        let request_bytes = request_body.len();
+
+        let response = transport.recv_pull_round(root, request_body).await?;
+
+        let response_bytes = AtomicUsize::new(0);
+        let reader = StreamReader::new(response.inspect_ok(|chunk| {
+            response_bytes.fetch_add(chunk.len(), Ordering::Relaxed);
+        }));
+
+        let result = pull::handle_response_streaming(root, reader, config, store, &cache).await;
+
+        let response_bytes = response_bytes.into_inner();
+        stats.bytes_sent += request_bytes;
+        stats.bytes_received += response_bytes;
+
+        let next_request = match result {
+            Ok(next_request) => next_request,
+            Err(err) if err.is_recoverable() && retries < config.round_retries => {
+                retries += 1;
+                tracing::warn!(%root, round = stats.rounds, retries, %err, "Retrying failed pull round");
+                // Blocks from before the failure were stored, so this continues from there
+                pull::request(root, None, config, store, &cache).await?
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        log_round(
+            "pull",
+            root,
+            stats.rounds,
+            request_bytes,
+            response_bytes,
+            next_request != pull_request,
+        );
+
+        pull_request = next_request;
+    }
+
+    stats.blocks_transferred = store.blocks_written.load(Ordering::Relaxed);
+    stats.unexpected_blocks = observer.unexpected_blocks.load(Ordering::Relaxed);
+    stats.manifest = store
+        .manifest
+        .as_ref()
+        .and_then(|manifest| Some(manifest.lock().ok()?.clone()));
+    Ok(stats)
+}
+
+impl Manifest {
+    fn new(root: Cid) -> Self {
+        Self {
+            root,
+            cids: Vec::new(),
+            total_bytes: 0,
+        }
+    }
+
+    fn record(&mut self, cid: Cid, block_bytes: usize) {
+        self.cids.push(cid);
+        self.total_bytes += block_bytes as u64;
+    }
+
+    /// Serialize this manifest into dag-cbor bytes, with CIDs encoded as links.
+    pub fn to_dag_cbor(&self) -> Result<Vec<u8>, Error> {
+        let ipld = Ipld::Map(BTreeMap::from([
+            ("root".to_string(), Ipld::Link(self.root)),
+            (
+                "cids".to_string(),
+                Ipld::List(self.cids.iter().copied().map(Ipld::Link).collect()),
+            ),
+            (
+                "totalBytes".to_string(),
+                Ipld::Integer(self.total_bytes.into()),
+            ),
+        ]));
+
+        DagCborCodec.encode(&ipld).map_err(Error::ParsingError)
+    }
+
+    /// Deserialize a manifest from dag-cbor bytes, see `to_dag_cbor`.
+    pub fn from_dag_cbor(bytes: impl AsRef<[u8]>) -> Result<Self, Error> {
+        let invalid = || Error::ParsingError(anyhow::anyhow!("Invalid manifest"));
+
+        let ipld: Ipld = DagCborCodec
+            .decode(bytes.as_ref())
+            .map_err(Error::ParsingError)?;
+
+        let Ipld::Link(root) = ipld.get("root").map_err(|_| invalid())? else {
+            return Err(invalid());
+        };
+        let Ipld::List(links) = ipld.get("cids").map_err(|_| invalid())? else {
+            return Err(invalid());
+        };
+        let Ipld::Integer(total_bytes) = ipld.get("totalBytes").map_err(|_| invalid())? else {
+            return Err(invalid());
+        };
+
+        let cids = links
+            .iter()
+            .map(|link| match link {
+                Ipld::Link(cid) => Ok(*cid),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            root: *root,
+            cids,
+            total_bytes: (*total_bytes).try_into().map_err(|_| invalid())?,
+        })
+    }
+
+    /// The CID of this manifest's dag-cbor encoding, e.g. for signing it.
+    pub fn cid(&self) -> Result<Cid, Error> {
+        Ok(compute_cid(
+            IpldCodec::DagCbor.into(),
+            Code::Sha2_256,
+            &self.to_dag_cbor()?,
+        ))
+    }
+}
+
+/// Reads the CID and the number of block bytes from a CAR frame, i.e. a
+/// varint of the frame length, followed by the CID and the block bytes.
+fn parse_car_frame(frame: &[u8]) -> Option<(Cid, usize)> {
+    let mut frame_len: u64 = 0;
+    let mut prefix_len = 0;
+    for (i, byte) in frame.iter().enumerate().take(9) {
+        frame_len |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            prefix_len = i + 1;
+            break;
+        }
+    }
+    if prefix_len == 0 {
+        return None;
+    }
+
+    let mut reader = Cursor::new(frame.get(prefix_len..)?);
+    let cid = Cid::read_bytes(&mut reader).ok()?;
+    let block_bytes = usize::try_from(frame_len)
+        .ok()?
+        .checked_sub(reader.position() as usize)?;
+    Some((cid, block_bytes))
+}
+
+/// Wraps a block store to count the blocks written to it during pulls,
+/// and record them into a manifest if enabled.
+struct WriteCountingBlockStore<S> {
+    store: S,
+    blocks_written: AtomicUsize,
+    manifest: Option<Mutex<Manifest>>,
+}
+
+impl<S: BlockStore> BlockStore for WriteCountingBlockStore<S> {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, BlockStoreError> {
+        self.store.get_block(cid).await
+    }
+
+    async fn put_block_keyed(
+        &self,
+        cid: Cid,
+        bytes: impl Into<Bytes> + CondSend,
+    ) -> Result<(), BlockStoreError> {
+        let bytes = bytes.into();
+        let block_bytes = bytes.len();
+        self.store.put_block_keyed(cid, bytes).await?;
+        self.blocks_written.fetch_add(1, Ordering::Relaxed);
+        if let Some(manifest) = &self.manifest {
+            manifest
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+                .record(cid, block_bytes);
+        }
+        Ok(())
+    }
+
+    async fn has_block(&self, cid: &Cid) -> Result<bool, BlockStoreError> {
+        self.store.has_block(cid).await
+    }
+}
+
+/// Wraps the configured verification observer, if any, to count
+/// unexpected blocks during pulls.
+#[derive(Debug)]
+struct CountingObserver {
+    inner: Option<Arc<dyn VerificationObserver>>,
+    unexpected_blocks: AtomicUsize,
+}
+
+impl VerificationObserver for CountingObserver {
+    fn on_digest_mismatch(&self, root: Cid, cid: Cid, actual_cid: Cid) {
+        if let Some(inner) = &self.inner {
+            inner.on_digest_mismatch(root, cid, actual_cid);
+        }
+    }
+
+    fn on_unexpected_block(&self, root: Cid, cid: Cid) {
+        self.unexpected_blocks.fetch_add(1, Ordering::Relaxed);
+        if let Some(inner) = &self.inner {
+            inner.on_unexpected_block(root, cid);
+        }
+    }
+
+    fn on_oversized_block(&self, root: Cid, cid: Cid, block_bytes: usize, max_block_size: usize) {
+        if let Some(inner) = &self.inner {
+            inner.on_oversized_block(root, cid, block_bytes, max_block_size);
+        }
+    }
+
+    fn on_round_interrupted(&self, root: Cid, cid: Cid, reason: BlockState) {
+        if let Some(inner) = &self.inner {
+            inner.on_round_interrupted(root, cid, reason);
+        }
+    }
+
+    fn on_unrelated_roots(&self, root: Cid, unrelated_roots: &[Cid]) {
+        if let Some(inner) = &self.inner {
+            inner.on_unrelated_roots(root, unrelated_roots);
+        }
+    }
+
+    fn on_receiver_state(&self, root: Cid, state: &ReceiverState) {
+        if let Some(inner) = &self.inner {
+            inner.on_receiver_state(root, state);
+        }
+    }
+}
+
+/// Emits a `car_mirror::round` event at info level after each
+/// protocol round, so dashboards can be built from logs.
+///
+/// A round made progress if the receiver's state changed, i.e. if the
+/// receiving end got at least one block it was missing before.
+fn log_round(
+    protocol: &'static str,
+    root: Cid,
+    round: usize,
+    request_bytes: usize,
+    response_bytes: usize,
+    made_progress: bool,
+) {
+    tracing::info!(
+        target: "car_mirror::round",
+        protocol,
+        %root,
+        round,
+        request_bytes,
+        response_bytes,
+        made_progress,
+        "Finished car mirror round"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::NoCache,
+        common::{block_receive, block_send},
+        messages::PullRequest,
+        test_utils::{setup_random_dag, total_dag_blocks, total_dag_bytes},
+    };
+    use assert_matches::assert_matches;
+    use testresult::TestResult;
+    use wnfs_common::MemoryBlockStore;
+
+    /// A transport that answers rounds from an in-memory "server" store
+    #[derive(Debug)]
+    struct InProcessTransport {
+        store: MemoryBlockStore,
+    }
+
+    impl Transport for InProcessTransport {
+        type Error = anyhow::Error;
+
+        async fn send_push_round(
+            &mut self,
+            root: Cid,
+            request: CarStream<'static>,
+            _checkpoint: Option<Vec<u8>>,
+        ) -> Result<Bytes, Self::Error> {
+            let reader = StreamReader::new(request.map_err(std::io::Error::other));
+            let response =
+                push::response_streaming(root, reader, &Config::default(), &self.store, NoCache)
+                    .await?;
+            Ok(response.to_dag_cbor()?.into())
+        }
+
+        async fn recv_pull_round(
+            &mut self,
+            root: Cid,
+            request: Bytes,
+        ) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, Self::Error> {
+            let request = PullRequest::from_dag_cbor(request)?;
+            let car_stream = pull::response_streaming(
+                root,
+                request,
+                &Config::default(),
+                self.store.clone(),
+                NoCache,
+            )
+            .await?;
+            Ok(boxed_stream(car_stream.map_err(std::io::Error::other)))
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_drive_push() -> TestResult {
+        let (root, client_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
+        let transport = &mut InProcessTransport {
+            store: MemoryBlockStore::new(),
+        };
+
+        let stats = drive_push(
+            root,
+            &Config::default(),
+            transport,
+            client_store.clone(),
+            NoCache,
+        )
+        .await?;
+
+        assert!(stats.bytes_sent > 0);
+        assert_eq!(
+            total_dag_blocks(root, &transport.store).await?,
+            total_dag_blocks(root, &client_store).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_drive_pull() -> TestResult {
+        let (root, server_store) = setup_random_dag(256, 10 * 1024 /* 10 KiB */).await?;
+        let transport = &mut InProcessTransport {
+            store: server_store.clone(),
+        };
+        let client_store = &MemoryBlockStore::new();
+
+        let stats = drive_pull(root, &Config::default(), transport, client_store, NoCache).await?;
+
+        assert_eq!(
+            stats.blocks_transferred,
+            total_dag_blocks(root, &server_store).await?
+        );
+        assert_eq!(
+            total_dag_blocks(root, client_store).await?,
+            total_dag_blocks(root, &server_store).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_drive_records_manifest() -> TestResult {
+        let (root, server_store) = setup_random_dag(64, 1024).await?;
+        let config = &Config {
+            record_manifest: true,
+            ..Config::default()
+        };
+
+        let transport = &mut InProcessTransport {
+            store: server_store.clone(),
+        };
+        let client_store = MemoryBlockStore::new();
+        let stats = drive_pull(root, config, transport, &client_store, NoCache).await?;
+        let manifest = stats.manifest.expect("manifest was recorded");
+
+        assert_eq!(manifest.root, root);
+        assert_eq!(manifest.cids.len(), stats.blocks_transferred);
+        assert_eq!(
+            manifest.total_bytes as usize,
+            total_dag_bytes(root, &server_store).await?
+        );
+
+        let bytes = manifest.to_dag_cbor()?;
+        assert_eq!(Manifest::from_dag_cbor(&bytes)?, manifest);
+        assert_eq!(manifest.cid()?, manifest.clone().cid()?);
+
+        let transport = &mut InProcessTransport {
+            store: MemoryBlockStore::new(),
+        };
+        let stats = drive_push(root, config, transport, client_store, NoCache).await?;
+        let manifest = stats.manifest.expect("manifest was recorded");
+
+        assert_eq!(manifest.root, root);
+        assert_eq!(manifest.cids.len(), stats.blocks_transferred);
+        assert_eq!(manifest.cids.first(), Some(&root));
+
+        // Not recorded by default
+        let transport = &mut InProcessTransport {
+            store: server_store.clone(),
+        };
+        let stats = drive_pull(
+            root,
+            &Config::default(),
+            transport,
+            &MemoryBlockStore::new(),
+            NoCache,
+        )
+        .await?;
+        assert_eq!(stats.manifest, None);
+
+        Ok(())
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_drive_pull_counts_unexpected_blocks() -> TestResult {
+        let (root, server_store) = setup_random_dag(256, 1024 /* 1 KiB */).await?;
+        let transport = &mut InProcessTransport {
+            store: server_store.clone(),
+        };
+        let client_store = &MemoryBlockStore::new();
+
+        // Give the client part of the DAG, so it sends a bloom filter
+        let prefix_config = &Config {
+            receive_maximum: 64 * 1024,
+            ..Config::default()
+        };
+        let car = block_send(root, None, prefix_config, &server_store, NoCache).await?;
+        block_receive(root, Some(car), prefix_config, client_store, NoCache).await?;
+
+        // A bloom filter with lots of false positives makes the server
+        // skip blocks the client doesn't have
+        let config = &Config {
+            bloom_fpr: |_| 0.5,
+            ..Config::default()
+        };
+        let stats = drive_pull(root, config, transport, client_store, NoCache).await?;
+
+        assert!(stats.unexpected_blocks > 0);
+        assert_eq!(
+            total_dag_blocks(root, client_store).await?,
+            total_dag_blocks(root, &server_store).await?
+        );
+
+        Ok(())
+    }
+
+    /// Corrupts the last byte of the fourth block in the first
+    /// `corrupt_rounds` pull responses
+    #[derive(Debug)]
+    struct CorruptingTransport {
+        inner: InProcessTransport,
+        corrupt_rounds: usize,
+    }
+
+    impl Transport for CorruptingTransport {
+        type Error = anyhow::Error;
+
+        async fn send_push_round(
+            &mut self,
+            root: Cid,
+            request: CarStream<'static>,
+            checkpoint: Option<Vec<u8>>,
+        ) -> Result<Bytes, Self::Error> {
+            self.inner.send_push_round(root, request, checkpoint).await
+        }
+
+        async fn recv_pull_round(
+            &mut self,
+            root: Cid,
+            request: Bytes,
+        ) -> Result<BoxStream<'static, Result<Bytes, std::io::Error>>, Self::Error> {
+            let response = self.inner.recv_pull_round(root, request).await?;
+            if self.corrupt_rounds == 0 {
+                return Ok(response);
+            }
+            self.corrupt_rounds -= 1;
+
+            // The first frame is the CAR header
+            let mut frames = response.try_collect::<Vec<_>>().await?;
+            let mut frame = frames[4].to_vec();
+            *frame.last_mut().expect("non-empty frame") ^= 0xff;
+            frames[4] = frame.into();
+            Ok(boxed_stream(futures::stream::iter(
+                frames.into_iter().map(Ok),
+            )))
+        }
+    }
+
+    #[test_log::test(async_std::test)]
+    async fn test_drive_pull_retries_corrupted_rounds() -> TestResult {
+        let (root, server_store) = setup_random_dag(256, 1024 /* 1 KiB */).await?;
+        let client_store = &MemoryBlockStore::new();
+        let transport = &mut CorruptingTransport {
+            inner: InProcessTransport {
+                store: server_store.clone(),
+            },
+            corrupt_rounds: 1,
+        };
+
+        let config = &Config {
+            round_retries: 0,
+            ..Config::default()
+        };
+        let result = drive_pull(root, config, transport, client_store, NoCache).await;
+        assert_matches!(
+            result.map_err(|err| err.downcast::<Error>()),
+            Err(Ok(Error::IncrementalVerificationError(_)))
+        );
+
+        transport.corrupt_rounds = 1;
+        drive_pull(root, &Config::default(), transport, client_store, NoCache).await?;
+        assert_eq!(
+            total_dag_blocks(root, client_store).await?,
+            total_dag_blocks(root, &server_store).await?
+        );
+
+        Ok(())
+    }
+}